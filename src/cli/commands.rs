@@ -1,28 +1,67 @@
-use crate::core::context_generator::{build_context_output, format_output};
-use crate::core::file_selector::select_files;
-use crate::domain::models::ContextConfig;
+use crate::core::context_generator::{
+    UnreadFiles, attach_command_outputs, attach_project_summary, build_context_output,
+    check_template, count_tokens, dominant_files, file_block_overhead, fill_template, format_chat,
+    format_output, include_directory_readmes, render_html, stream_context_output, token_breakdown,
+};
+use crate::core::file_selector::{
+    BudgetPlan, SelectionDiff, SelectionSession, apply_token_budget, diff_selections, loaded_file,
+    plan_token_budget, select_files, select_paths,
+};
+use crate::core::grouping::{group_by_directory, split_output_path};
+use crate::core::hashing::canonical_context_hash;
+use crate::core::imports::dependency_closure;
+use crate::core::manifest::{
+    ManifestDiff, build_manifest, diff_manifests, selection_from_manifest,
+};
+use crate::core::prefix::{common_prefix, strip_file_prefix, strip_map_prefix};
+use crate::domain::models::{
+    AnchorFormat, BudgetStrategy, ChatProvider, CliError, ClipboardGuard, CommandOutput,
+    ContextConfig, ContextOutput, Deadline, ErrorFormat, ErrorKind, FileContext, InclusionReason,
+    OutputFormat, OutputOptions, PathPrefix, PathSeparator, Preset, ProgressStyle, PromptTemplate,
+    SavedSession, TestFilter, TokenBudget, Tokenizer, WatchdogGuard,
+};
+use crate::infra::command::{external_token_count, run_command};
+use crate::infra::config_file::{FileConfig, find_config_file, load_config_file};
 use crate::infra::file_system::{
-    generate_file_map, list_code_files, list_code_files_with_gitignore, read_file_contents,
+    FileMapPruning, WalkOptions, build_dir_globs, exclude_by_mime, exclude_generated_files,
+    exclude_large_files, exclude_long_line_files, exclude_resolved_paths, file_map_from_paths,
+    filter_listed_files, filter_test_files, generate_file_map, inclusion_reasons, list_code_files,
+    list_code_files_with_gitignore, order_by_patterns, read_file_contents, read_order_file,
+    rebase_on_root, restrict_to_sparse_checkout,
+};
+use crate::infra::git::{
+    commit_message, find_repo_root, list_changed_files, list_files_at_ref, read_changes_since_head,
+    read_file_at_ref,
 };
 use crate::infra::logger::{print_welcome_message, setup_logger};
-use crate::infra::output::write_output;
-use clap::{Parser, Subcommand};
+use crate::infra::manifest::{read_manifest, write_manifest};
+use crate::infra::output::{create_writer, write_output};
+use crate::infra::project::read_project_summary;
+use crate::infra::session::{
+    clear_session, last_selection_path, read_session, session_path, state_dir, write_session,
+};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use crossterm::{
+    ExecutableCommand,
     event::{self, Event, KeyCode, KeyModifiers},
     execute,
-    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+    style::{Color as TermColor, ResetColor, SetForegroundColor},
+    terminal::{EnterAlternateScreen, LeaveAlternateScreen, disable_raw_mode, enable_raw_mode},
 };
 use log::{debug, info, warn};
 use ratatui::{
+    Terminal,
     backend::CrosstermBackend,
     layout::{Constraint, Direction, Layout},
     style::{Color, Modifier, Style},
     text::{Span, Text},
     widgets::{Block, Borders, Paragraph},
-    Terminal,
 };
-use std::io;
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 use std::time::Duration;
 
 #[derive(Parser)]
@@ -34,38 +73,789 @@ pub struct Cli {
 
     #[arg(short, long, action = clap::ArgAction::Count)]
     pub verbose: u8,
+
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        default_value_t = ErrorFormat::Human,
+        help = "How errors are reported on stderr"
+    )]
+    pub error_format: ErrorFormat,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Don't print the welcome banner; logs and progress are unaffected"
+    )]
+    pub no_banner: bool,
 }
 
 #[derive(Subcommand)]
 pub enum Commands {
-    Generate {
-        #[arg(long, default_value = ".")]
-        path: String,
+    Generate(Box<GenerateArgs>),
+    /// Report which scanned files would fit in a token budget, without generating anything
+    Plan(Box<PlanArgs>),
+}
+
+#[derive(Args, Debug)]
+pub struct PlanArgs {
+    #[command(flatten)]
+    pub scan: ScanArgs,
+
+    #[arg(long, help = "Token budget to plan for")]
+    pub max_tokens: usize,
+
+    #[command(flatten)]
+    pub budget: BudgetArgs,
+}
+
+// Flags choosing which files a scan finds, shared by every command that scans
+#[derive(Args, Debug)]
+pub struct ScanArgs {
+    #[arg(
+        long,
+        default_value = ".",
+        value_delimiter = ',',
+        help = "Directory to scan; repeat it or separate with commas to combine several roots"
+    )]
+    pub path: Vec<String>,
+
+    #[arg(long)]
+    pub ext: Option<String>,
+
+    #[arg(long)]
+    pub exclude: Option<String>,
+
+    #[arg(
+        long = "exclude-dir-glob",
+        value_name = "GLOB",
+        help = "Prune directories whose path below the root matches this glob, e.g. '**/generated-*'; files are unaffected (repeatable)"
+    )]
+    pub exclude_dir_glob: Vec<String>,
+
+    #[arg(
+        long,
+        help = "Comma-separated globs relative to the root; only matching files are included"
+    )]
+    pub include: Option<String>,
+
+    #[arg(long, default_value = ".git")]
+    pub exclude_version_control_dir: String,
+
+    #[arg(long, default_value = "true")]
+    pub apply_dot_git_ignore: bool,
+
+    #[arg(
+        long,
+        help = "Follow symbolic links while scanning; links that loop back are reported and skipped"
+    )]
+    pub follow_symlinks: bool,
+
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Descend at most this many directories below the root; 0 scans only the root's files"
+    )]
+    pub max_depth: Option<usize>,
+
+    #[arg(
+        long,
+        help = "Only include files inside the sparse-checkout definition of the repository"
+    )]
+    pub respect_sparse: bool,
+
+    #[arg(
+        long,
+        help = "Fail if .gitignore rules are applied but the path isn't inside a git repository"
+    )]
+    pub require_git: bool,
+
+    #[arg(
+        long,
+        help = "Skip test files (e.g. *_test.rs, tests/, test_*.py, *.test.ts, __tests__/)"
+    )]
+    pub exclude_tests: bool,
+
+    #[arg(
+        long,
+        conflicts_with = "exclude_tests",
+        help = "Only include test files, by the same conventions as --exclude-tests"
+    )]
+    pub tests_only: bool,
+
+    #[arg(
+        long,
+        help = "Skip files that look generated: named like --generated-patterns and at least --generated-min-bytes"
+    )]
+    pub exclude_generated: bool,
+
+    #[arg(
+        long,
+        default_value = "*.lock,*.generated.*,*_pb2.py",
+        help = "Comma-separated file name patterns for --exclude-generated"
+    )]
+    pub generated_patterns: String,
+
+    #[arg(
+        long,
+        default_value_t = 32 * 1024,
+        help = "Minimum size in bytes for --exclude-generated to drop a matching file"
+    )]
+    pub generated_min_bytes: u64,
+
+    #[arg(
+        long,
+        value_name = "SIZE",
+        value_parser = parse_byte_size,
+        help = "Skip files larger than this many bytes; accepts k, M, and G suffixes, e.g. 500k"
+    )]
+    pub max_file_size: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "TYPES",
+        value_delimiter = ',',
+        help = "Skip files whose sniffed content type matches, e.g. image/*,application/octet-stream"
+    )]
+    pub exclude_mime: Vec<String>,
+}
+
+// How `--max-tokens` fills its budget
+#[derive(Args, Debug)]
+pub struct BudgetArgs {
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = BudgetStrategy::SmallestFirst,
+        help = "Which files --max-tokens keeps first"
+    )]
+    pub budget_strategy: BudgetStrategy,
+
+    #[arg(
+        long,
+        help = "Comma-separated path fragments in priority order for --budget-strategy priority"
+    )]
+    pub budget_priority: Option<String>,
+}
+
+#[derive(Args, Debug)]
+#[command(group(
+    clap::ArgGroup::new("clipboard")
+        .args(["clipboard_output", "dry_clipboard"])
+        .multiple(true)
+))]
+pub struct GenerateArgs {
+    #[command(flatten)]
+    pub scan: ScanArgs,
+
+    #[arg(long)]
+    pub output: Option<String>,
+
+    #[arg(long)]
+    pub auto: bool,
+
+    #[arg(
+        long,
+        help = "User prompt, or a path to a file whose contents become the prompt"
+    )]
+    pub prompt: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "REF",
+        num_args = 0..=1,
+        default_missing_value = "HEAD",
+        conflicts_with = "prompt",
+        help = "Use this commit's message as the prompt [default: HEAD]"
+    )]
+    pub prompt_from_commit: Option<String>,
+
+    #[arg(
+        long,
+        value_enum,
+        value_name = "NAME",
+        conflicts_with_all = ["prompt", "prompt_from_commit"],
+        help = "Use a built-in prompt; see --list-templates"
+    )]
+    pub prompt_template_name: Option<PromptTemplate>,
+
+    #[arg(long, help = "Print the built-in prompt templates and exit")]
+    pub list_templates: bool,
+
+    #[arg(
+        long,
+        requires = "prompt",
+        help = "Use --prompt verbatim even if it names an existing file"
+    )]
+    pub prompt_literal: bool,
+
+    #[arg(
+        long,
+        help = "Copy the output to clipboard (requires X11/Wayland on Linux)"
+    )]
+    pub clipboard_output: bool,
+
+    #[arg(
+        long,
+        help = "Run as if --clipboard-output were set, but only log the size instead of copying"
+    )]
+    pub dry_clipboard: bool,
+
+    #[arg(
+        long,
+        requires = "clipboard",
+        help = "With --clipboard-output or --dry-clipboard, also put an HTML rendering of the context on the clipboard for rich editors"
+    )]
+    pub clipboard_html: bool,
+
+    #[arg(
+        long,
+        default_value_t = 4 * 1024 * 1024,
+        help = "Ask before copying more than this many bytes to the clipboard"
+    )]
+    pub confirm_clipboard_size: u64,
+
+    #[arg(long, help = "Copy to the clipboard without asking, whatever the size")]
+    pub yes: bool,
+
+    #[arg(
+        long,
+        value_parser = clap::value_parser!(u8).range(3..),
+        help = "Use exactly this many backticks for every code fence (minimum 3)"
+    )]
+    pub fence: Option<u8>,
+
+    #[arg(
+        long,
+        help = "Write a JSON manifest of the included files and summarize changes against the previous one"
+    )]
+    pub manifest: Option<String>,
+
+    #[arg(
+        long,
+        help = "Only keep selected files that fit in this many tokens, listing the ones left out"
+    )]
+    pub max_tokens: Option<usize>,
+
+    #[arg(
+        long,
+        value_enum,
+        help = "Count tokens with this BPE encoding instead of estimating from characters"
+    )]
+    pub tokenizer: Option<Tokenizer>,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = ProgressStyle::Braille,
+        help = "Scan spinner: braille, ascii for limited fonts, or none to hide it (-v does not affect it)"
+    )]
+    pub progress_style: ProgressStyle,
+
+    #[arg(
+        long,
+        default_value_t = 250,
+        help = "Milliseconds between scan progress refreshes"
+    )]
+    pub progress_interval: u64,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Select exactly the files listed in a previously written manifest, skipping the scan"
+    )]
+    pub select_from_manifest: Option<String>,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["git_ref", "select_from_manifest"],
+        help = "Read newline-delimited file paths from stdin instead of scanning, e.g. from `git diff --name-only`; the usual filters still apply"
+    )]
+    pub stdin: bool,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Emit files in the order of the glob patterns listed in this file, one per line; unmatched files follow, sorted"
+    )]
+    pub order_file: Option<String>,
+
+    #[arg(
+        long,
+        help = "Represent binary files in the output with a short <binary/> placeholder"
+    )]
+    pub binary_placeholder: bool,
+
+    #[arg(
+        long,
+        help = "Print a stable hash of the context that ignores volatile sections"
+    )]
+    pub print_hash: bool,
+
+    #[arg(
+        long,
+        help = "After generating, list each file's token count, largest first, with the total"
+    )]
+    pub show_token_breakdown: bool,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["git_ref", "select_from_manifest"],
+        help = "Only scan, print how many files match and their total bytes, then exit"
+    )]
+    pub count_only: bool,
+
+    #[arg(
+        long,
+        value_name = "PERCENT",
+        value_parser = clap::value_parser!(u8).range(1..=100),
+        help = "Warn on stderr about files taking more than this percent of the output tokens"
+    )]
+    pub warn_file_share: Option<u8>,
 
-        #[arg(long)]
-        ext: Option<String>,
+    #[arg(
+        long,
+        default_value_t = true,
+        action = clap::ArgAction::Set,
+        help = "Keep runs of blank lines as-is; pass false to collapse 3+ blank lines into one"
+    )]
+    pub keep_blank_lines: bool,
 
-        #[arg(long)]
-        exclude: Option<String>,
+    #[arg(
+        long,
+        help = "Strip trailing whitespace from every line of file contents"
+    )]
+    pub trim_trailing_whitespace: bool,
 
-        #[arg(long)]
-        output: Option<String>,
+    #[arg(
+        long,
+        help = "Leave directories without any included files out of the file map"
+    )]
+    pub clamp_empty_dirs: bool,
 
-        #[arg(long)]
-        auto: bool,
+    #[arg(
+        long,
+        help = "Tag every file with a blake3 hash of its content (hash=\"...\" in XML, a hash field in JSON)"
+    )]
+    pub with_hashes: bool,
 
-        #[arg(long)]
-        prompt: Option<String>,
+    #[arg(
+        long,
+        help = "Append a bulleted list of the included file paths to the user instructions"
+    )]
+    pub prompt_file_list: bool,
+
+    #[arg(
+        long,
+        help = "Prepend a <project> section with name, version, and dependencies from Cargo.toml or package.json"
+    )]
+    pub project_summary: bool,
+
+    #[arg(
+        long,
+        help = "Prepend each selected directory's README.md ahead of its files"
+    )]
+    pub include_readmes: bool,
+
+    #[arg(
+        long,
+        value_name = "DEPTH",
+        value_parser = clap::value_parser!(u8).range(1..),
+        requires = "output",
+        conflicts_with_all = ["clipboard_output", "manifest", "print_hash"],
+        help = "Write one context per directory at this depth, named after --output"
+    )]
+    pub split_by_dir: Option<u8>,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = AnchorFormat::None,
+        help = "Embed line references in file contents for precise citations"
+    )]
+    pub anchor_format: AnchorFormat,
+
+    #[arg(
+        long,
+        conflicts_with = "anchor_format",
+        help = "Number every line of file contents; shorthand for --anchor-format gutter"
+    )]
+    pub line_numbers: bool,
+
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 0,
+        help = "Only add --anchor-format line references to files longer than N lines"
+    )]
+    pub line_numbers_min_lines: usize,
+
+    #[arg(
+        long = "merge-ext",
+        value_name = "EXT",
+        help = "Concatenate all files with this extension into one block, each introduced by a `file:` comment (repeatable)"
+    )]
+    pub merge_ext: Vec<String>,
+
+    #[arg(long, value_enum, help = "Output format [default: xml]")]
+    pub format: Option<OutputFormat>,
+
+    #[arg(
+        long,
+        requires = "output",
+        help = "Infer the output format from the --output extension unless --format is given"
+    )]
+    pub output_format_auto: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        conflicts_with = "format",
+        help = "Emit a JSON chat request for this provider: the context as the system message, the prompt as the user message"
+    )]
+    pub chat_format: Option<ChatProvider>,
+
+    #[arg(
+        long,
+        value_name = "TEMPLATE",
+        help = "Splice the output into this template at every {{RICH_PROMPT_CONTEXT}} marker"
+    )]
+    pub into: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "COMMAND",
+        help = "Pipe the output to this token-counting command and report if its count differs from the estimate"
+    )]
+    pub verify_tokens: Option<String>,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["print_hash", "show_token_breakdown", "split_by_dir", "clipboard_html", "chat_format", "into", "verify_tokens"],
+        help = "Write the output incrementally as files are processed (xml format only)"
+    )]
+    pub stream: bool,
+
+    #[arg(
+        long,
+        help = "List TODO/FIXME/HACK comments from the included files in a <todos> section"
+    )]
+    pub collect_todos: bool,
+
+    #[arg(
+        long,
+        help = "List which included files each file imports in a <dependency_graph> section"
+    )]
+    pub with_graph: bool,
+
+    #[arg(
+        long,
+        help = "Emit only the code and markdown cells of .ipynb notebooks, without outputs or metadata"
+    )]
+    pub ipynb_extract: bool,
+
+    #[arg(
+        long,
+        help = "Don't leave a blank line between one file's closing fence and the next file's header"
+    )]
+    pub no_trailing_fence_newline: bool,
+
+    #[arg(
+        long,
+        help = "Emit only top-level signatures and their doc comments instead of full file bodies"
+    )]
+    pub symbols_only: bool,
+
+    #[arg(
+        long = "attach-command",
+        value_name = "CMD",
+        help = "Run this shell command in the root and attach its output in a <command_output> section (repeatable)"
+    )]
+    pub attach_command: Vec<String>,
+
+    #[arg(
+        long,
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Truncate lines longer than this many characters, marking the cut"
+    )]
+    pub max_line_length: Option<u32>,
+
+    #[arg(
+        long,
+        requires = "max_line_length",
+        help = "With --max-line-length, leave out files that have a longer line at scan time instead of truncating it"
+    )]
+    pub skip_file: bool,
+
+    #[arg(
+        long,
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Truncate each file after the last whole line within this many tokens, marking the cut"
+    )]
+    pub max_file_tokens: Option<u32>,
+
+    #[arg(
+        long,
+        requires = "max_file_tokens",
+        help = "Cut --max-file-tokens truncations at the end of the last whole function or type"
+    )]
+    pub snap_to_functions: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = PathSeparator::Slash,
+        help = "Separator in emitted paths: / on every OS, or native for backslashes on Windows"
+    )]
+    pub path_separator: PathSeparator,
+
+    #[arg(
+        long,
+        help = "Remove this leading path from file headers and the file map"
+    )]
+    pub strip_prefix: Option<PathBuf>,
+
+    #[arg(
+        long,
+        conflicts_with = "strip_prefix",
+        help = "Remove the longest directory shared by all paths from file headers and the file map"
+    )]
+    pub flatten: bool,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["include_readmes", "exclude_generated", "max_file_size", "exclude_mime", "project_summary", "select_from_manifest"],
+        help = "Read files as of this git commit, branch, or tag instead of the working tree"
+    )]
+    pub git_ref: Option<String>,
+
+    #[arg(
+        long,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        conflicts_with_all = ["git_ref", "select_from_manifest"],
+        help = "Only include files changed since HEAD, staged or not, and untracked files; --only-changed=false turns off a preset's"
+    )]
+    pub only_changed: Option<bool>,
+
+    #[arg(
+        long,
+        value_name = "LINES",
+        help = "With --only-changed, include files that differ from HEAD as a unified diff with this many lines of context"
+    )]
+    pub diff_context: Option<u32>,
+
+    #[arg(
+        long,
+        value_name = "ENTRY",
+        help = "Only include the entry file and the files it reaches through local imports"
+    )]
+    pub closure: Option<String>,
+
+    #[arg(
+        long,
+        value_enum,
+        help = "Apply a named bundle of defaults; flags given explicitly still win"
+    )]
+    pub preset: Option<Preset>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with = "no_config",
+        help = "Read defaults from this TOML file instead of the nearest rich-prompt.toml"
+    )]
+    pub config: Option<String>,
+
+    #[arg(
+        long,
+        help = "Don't look for a rich-prompt.toml in the current directory or its parents"
+    )]
+    pub no_config: bool,
+
+    #[arg(long, help = "Print which criteria included each file")]
+    pub explain: bool,
+
+    #[arg(
+        long,
+        default_value_t = DEFAULT_READ_JOBS,
+        value_parser = clap::value_parser!(u16).range(1..),
+        help = "Maximum number of files read in parallel"
+    )]
+    pub read_jobs: u16,
+
+    #[arg(
+        long,
+        value_name = "DURATION",
+        value_parser = parse_duration,
+        help = "Abort with exit code 124 if the run takes longer than this, e.g. 90s, 5m, or 500ms"
+    )]
+    pub timeout: Option<Duration>,
+
+    #[command(flatten)]
+    pub budget: BudgetArgs,
+}
+
+const DEFAULT_READ_JOBS: u16 = 8;
+
+const REVIEW_PR_DIFF_CONTEXT: u32 = 3;
+
+/// Fills in the defaults of `--preset` wherever the matching flag wasn't given.
+fn apply_preset(args: &mut GenerateArgs) {
+    match args.preset {
+        Some(Preset::ReviewPr) => {
+            let only_changed = *args
+                .only_changed
+                .get_or_insert(args.git_ref.is_none() && args.select_from_manifest.is_none());
+            if only_changed {
+                args.diff_context.get_or_insert(REVIEW_PR_DIFF_CONTEXT);
+            }
+            // Streaming and an inferred output format each rule out a default format
+            if !args.stream && !args.output_format_auto {
+                args.format.get_or_insert(OutputFormat::Markdown);
+            }
+            if args.prompt.is_none() && args.prompt_from_commit.is_none() {
+                args.prompt_template_name
+                    .get_or_insert(PromptTemplate::CodeReview);
+            }
+        }
+        None => {}
+    }
+}
+
+/// One `name: description` line per `--prompt-template-name` value.
+fn template_list() -> String {
+    PromptTemplate::value_variants()
+        .iter()
+        .filter_map(|template| template.to_possible_value())
+        .map(|value| {
+            format!(
+                "{}: {}\n",
+                value.get_name(),
+                value
+                    .get_help()
+                    .map(|help| help.to_string())
+                    .unwrap_or_default()
+            )
+        })
+        .collect()
+}
+
+/// The user instructions from `--prompt-from-commit`, `--prompt-template-name`, or `--prompt`.
+fn user_prompt(args: &GenerateArgs) -> anyhow::Result<Option<String>> {
+    if let Some(git_ref) = &args.prompt_from_commit {
+        return commit_message(args.scan.first_root(), git_ref).map(Some);
+    }
+    if let Some(template) = args.prompt_template_name {
+        return Ok(Some(template.instructions().to_string()));
+    }
+    args.prompt
+        .as_deref()
+        .map(|prompt| resolve_prompt(prompt, args.prompt_literal))
+        .transpose()
+}
+
+/// `--config`, else the nearest `rich-prompt.toml` above the current directory, unless
+/// `--no-config` is set.
+fn load_defaults(args: &GenerateArgs) -> anyhow::Result<Option<FileConfig>> {
+    let path = match &args.config {
+        Some(path) => PathBuf::from(path),
+        None if args.no_config => return Ok(None),
+        None => match find_config_file(&std::env::current_dir()?) {
+            Some(path) => path,
+            None => return Ok(None),
+        },
+    };
+    info!("Using defaults from {}", path.display());
+    load_config_file(&path).map(Some)
+}
+
+/// Fills flags left off the command line from the config file. Precedence, highest first:
+/// explicit flags, `--preset`, the config file, then the flags' built-in defaults.
+fn apply_config_file(args: &mut GenerateArgs, defaults: FileConfig) {
+    args.scan.ext = args.scan.ext.take().or(defaults.ext);
+    args.scan.exclude = args.scan.exclude.take().or(defaults.exclude);
+    if args.scan.exclude_dir_glob.is_empty() {
+        args.scan.exclude_dir_glob = defaults.exclude_dir_glob.unwrap_or_default();
+    }
+    args.scan.include = args.scan.include.take().or(defaults.include);
+    args.output = args.output.take().or(defaults.output);
+    args.prompt = args.prompt.take().or(defaults.prompt);
+    args.max_tokens = args.max_tokens.or(defaults.max_tokens);
+    args.max_file_tokens = args.max_file_tokens.or(defaults.max_file_tokens);
+}
+
+/// Splits the `--ext` list, lowercased so `--ext RS` matches `main.rs`.
+fn parse_extensions(ext: Option<&str>) -> Vec<String> {
+    ext.map(|list| list.split(',').map(|e| e.trim().to_lowercase()).collect())
+        .unwrap_or_default()
+}
+
+/// Parses a byte count with an optional binary `k`, `M`, or `G` suffix, e.g. `500k` or `2M`.
+fn parse_byte_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (number, multiplier) = match value.char_indices().last() {
+        Some((index, 'k' | 'K')) => (&value[..index], 1 << 10),
+        Some((index, 'm' | 'M')) => (&value[..index], 1 << 20),
+        Some((index, 'g' | 'G')) => (&value[..index], 1 << 30),
+        _ => (value, 1),
+    };
+    number
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .ok_or_else(|| {
+            format!(
+                "invalid size '{}': expected bytes like 4096, 500k, or 2M",
+                value
+            )
+        })
+}
 
-        #[arg(long, default_value = ".git")]
-        exclude_version_control_dir: String,
+/// Parses a duration with an `ms`, `s`, `m`, or `h` suffix; a bare number is seconds.
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number.parse().map_err(|_| {
+        format!(
+            "invalid duration '{}': expected a number like 90s, 5m, or 500ms",
+            value
+        )
+    })?;
+    let seconds = |multiplier: u64| {
+        number
+            .checked_mul(multiplier)
+            .map(Duration::from_secs)
+            .ok_or_else(|| format!("invalid duration '{}': out of range", value))
+    };
+    match unit {
+        "ms" => Ok(Duration::from_millis(number)),
+        "" | "s" => seconds(1),
+        "m" => seconds(60),
+        "h" => seconds(3600),
+        _ => Err(format!(
+            "invalid duration '{}': the unit must be ms, s, m, or h",
+            value
+        )),
+    }
+}
 
-        #[arg(long, default_value = "true")]
-        apply_dot_git_ignore: bool,
+/// Loads `--prompt` from a file when it names one, unless `literal` forces the text as-is.
+fn resolve_prompt(prompt: &str, literal: bool) -> anyhow::Result<String> {
+    let path = Path::new(prompt);
+    if literal || !path.is_file() {
+        return Ok(prompt.to_string());
+    }
 
-        #[arg(long, help = "Copy the output to clipboard (requires X11/Wayland on Linux)")]
-        clipboard_output: bool,
-    },
+    info!("Reading prompt from file {}", path.display());
+    fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Failed to read prompt file {}: {}", path.display(), e))
 }
 
 fn get_prompt_input() -> anyhow::Result<Option<String>> {
@@ -75,11 +865,9 @@ fn get_prompt_input() -> anyhow::Result<Option<String>> {
     let backend = CrosstermBackend::new(stdout);
     let mut terminal = Terminal::new(backend)?;
 
-
     let mut prompt_text = String::new();
     let mut cursor_position = 0;
 
-
     loop {
         terminal.draw(|f| {
             let chunks = Layout::default()
@@ -106,63 +894,53 @@ fn get_prompt_input() -> anyhow::Result<Option<String>> {
                 .block(Block::default().borders(Borders::ALL).title("Prompt"));
             f.render_widget(input, chunks[1]);
 
-            f.set_cursor_position((
-                chunks[1].x + 1 + cursor_position as u16,
-                chunks[1].y + 1,
-            ));
+            f.set_cursor_position((chunks[1].x + 1 + cursor_position as u16, chunks[1].y + 1));
 
             let mut text = Text::default();
-            text.extend(vec![
-                Span::styled("Press ", Style::default().fg(Color::DarkGray))
-            ]);
+            text.extend(vec![Span::styled(
+                "Press ",
+                Style::default().fg(Color::DarkGray),
+            )]);
             let controls = Paragraph::new(text);
             f.render_widget(controls, chunks[2]);
         })?;
 
-        if let Ok(true) = event::poll(Duration::from_millis(100)) {
-            if let Event::Key(key) = event::read()? {
-                match key.code {
-                    KeyCode::Enter => {
-                        // Submit the prompt
-                        break;
-                    }
-                    KeyCode::Esc => {
-                        // Skip providing a prompt
-                        prompt_text.clear();
-                        break;
-                    }
-                    KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
-                        // Cancel operation
-                        prompt_text.clear();
-                        break;
-                    }
-                    KeyCode::Char(c) => {
-                        prompt_text.insert(cursor_position, c);
-                        cursor_position += 1;
-                    }
-                    KeyCode::Backspace => {
-                        if cursor_position > 0 {
-                            prompt_text.remove(cursor_position - 1);
-                            cursor_position -= 1;
-                        }
-                    }
-                    KeyCode::Delete => {
-                        if cursor_position < prompt_text.len() {
-                            prompt_text.remove(cursor_position);
-                        }
-                    }
-                    KeyCode::Left => {
-                        if cursor_position > 0 {
-                            cursor_position -= 1;
-                        }
-                    }
-                    KeyCode::Right => {
-                        if cursor_position < prompt_text.len() {
-                            cursor_position += 1;
-                        }
-                    }
-                    _ => {}
+        if let Ok(true) = event::poll(Duration::from_millis(100))
+            && let Event::Key(key) = event::read()?
+        {
+            match key.code {
+                KeyCode::Enter => {
+                    // Submit the prompt
+                    break;
+                }
+                KeyCode::Esc => {
+                    // Skip providing a prompt
+                    prompt_text.clear();
+                    break;
                 }
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                    // Cancel operation
+                    prompt_text.clear();
+                    break;
+                }
+                KeyCode::Char(c) => {
+                    prompt_text.insert(cursor_position, c);
+                    cursor_position += 1;
+                }
+                KeyCode::Backspace if cursor_position > 0 => {
+                    prompt_text.remove(cursor_position - 1);
+                    cursor_position -= 1;
+                }
+                KeyCode::Delete if cursor_position < prompt_text.len() => {
+                    prompt_text.remove(cursor_position);
+                }
+                KeyCode::Left => {
+                    cursor_position = cursor_position.saturating_sub(1);
+                }
+                KeyCode::Right if cursor_position < prompt_text.len() => {
+                    cursor_position += 1;
+                }
+                _ => {}
             }
         }
     }
@@ -180,61 +958,120 @@ fn get_prompt_input() -> anyhow::Result<Option<String>> {
     }
 }
 
-pub fn run() -> anyhow::Result<()> {
+/// Runs the CLI and returns the exit code; errors `main` should print are returned as `Err`.
+pub fn run() -> anyhow::Result<ExitCode> {
     let cli = Cli::parse();
 
     setup_logger(cli.verbose)?;
-    print_welcome_message();
+    print_welcome_message(!cli.no_banner);
 
     match cli.command {
-        Commands::Generate {
-            path,
-            ext,
-            exclude,
-            output,
-            auto,
-            prompt,
-            exclude_version_control_dir,
-            apply_dot_git_ignore,
-            clipboard_output,
-        } => {
+        Commands::Generate(mut args) => {
+            if args.list_templates {
+                print!("{}", template_list());
+                return Ok(ExitCode::SUCCESS);
+            }
+            let deadline = args.timeout.and_then(Deadline::after);
+            let watchdog = WatchdogGuard::default();
+            if let Some(deadline) = deadline {
+                spawn_watchdog(deadline, cli.error_format, watchdog.clone());
+            }
+            apply_preset(&mut args);
+            if let Some(defaults) = load_defaults(&args)? {
+                apply_config_file(&mut args, defaults);
+            }
             info!("Starting generate command");
-            debug!(
-                "Command parameters: path={}, ext={:?}, exclude={:?}, output={:?}, auto={}, prompt={:?}, exclude_version_control_dir={}, apply_dot_git_ignore={}, clipboard_output={}",
-                path,
-                ext,
-                exclude,
-                output,
-                auto,
-                prompt,
-                exclude_version_control_dir,
-                apply_dot_git_ignore,
-                clipboard_output
-            );
-
-            let extensions: Vec<&str> = match &ext {
-                Some(ext_value) => ext_value.split(',').map(str::trim).collect(),
-                None => Vec::new(),
-            };
-
-            let excludes: Vec<&str> = match &exclude {
-                Some(exclude_value) => exclude_value.split(',').map(str::trim).collect(),
-                None => Vec::new(),
-            };
+            debug!("Command parameters: {:?}", args);
 
             let mut config = ContextConfig {
-                root_path: path.clone(),
-                extensions: extensions.iter().map(|&s| s.to_string()).collect(),
-                exclude_patterns: excludes.iter().map(|&s| s.to_string()).collect(),
-                output_path: output.clone(),
-                auto_select: auto,
-                user_prompt: prompt,
-                exclude_version_control_dir,
-                apply_dot_git_ignore,
-                clipboard_output,
+                output_path: args.output.clone(),
+                auto_select: args.auto || args.select_from_manifest.is_some(),
+                user_prompt: user_prompt(&args)?,
+                clipboard_output: args.clipboard_output || args.dry_clipboard,
+                dry_clipboard: args.dry_clipboard,
+                clipboard_html: args.clipboard_html,
+                clipboard_guard: Some(ClipboardGuard {
+                    max_bytes: args.confirm_clipboard_size,
+                    assume_yes: args.yes,
+                }),
+                output_options: OutputOptions {
+                    fence: args.fence.map(usize::from),
+                    binary_placeholder: args.binary_placeholder,
+                    compact_blank_lines: !args.keep_blank_lines,
+                    trim_trailing_whitespace: args.trim_trailing_whitespace,
+                    anchor_format: match args.line_numbers {
+                        true => AnchorFormat::Gutter,
+                        false => args.anchor_format,
+                    },
+                    anchor_min_lines: args.line_numbers_min_lines,
+                    merge_extensions: args
+                        .merge_ext
+                        .iter()
+                        .map(|ext| ext.trim_start_matches('.').to_lowercase())
+                        .collect(),
+                    prompt_file_list: args.prompt_file_list,
+                    list_roots: args.scan.path.iter().map(PathBuf::from).collect(),
+                    with_hashes: args.with_hashes,
+                    collect_todos: args.collect_todos,
+                    symbols_only: args.symbols_only,
+                    max_line_length: args.max_line_length.map(|max| max as usize),
+                    max_file_tokens: args.max_file_tokens.map(|max| max as usize),
+                    snap_to_functions: args.snap_to_functions,
+                    path_separator: args.path_separator,
+                    with_graph: args.with_graph,
+                    ipynb_extract: args.ipynb_extract,
+                    no_trailing_fence_newline: args.no_trailing_fence_newline,
+                    tokenizer: args.tokenizer,
+                },
+                manifest_path: args.manifest.clone(),
+                print_hash: args.print_hash,
+                show_token_breakdown: args.show_token_breakdown,
+                warn_file_share: args.warn_file_share,
+                count_only: args.count_only,
+                output_format: args.format,
+                output_format_auto: args.output_format_auto,
+                chat_format: args.chat_format,
+                into_template: args.into.as_deref().map(read_template).transpose()?,
+                verify_tokens: args.verify_tokens.clone(),
+                attach_commands: args.attach_command.clone(),
+                stream: args.stream,
+                explain: args.explain,
+                read_jobs: usize::from(args.read_jobs),
+                git_ref: args.git_ref.clone(),
+                only_changed: args.only_changed.unwrap_or(false),
+                diff_context: args.diff_context,
+                closure: args.closure.clone(),
+                progress_style: args.progress_style,
+                progress_interval_ms: args.progress_interval,
+                strip_prefix: match &args.strip_prefix {
+                    Some(path) => Some(PathPrefix::Path(path.clone())),
+                    None => args.flatten.then_some(PathPrefix::Common),
+                },
+                include_readmes: args.include_readmes,
+                split_by_dir: args.split_by_dir.map(usize::from),
+                clamp_empty_dirs: args.clamp_empty_dirs,
+                project_summary: args.project_summary,
+                select_from_manifest: args.select_from_manifest.clone(),
+                paths_from_stdin: args.stdin,
+                order_file: args.order_file.clone(),
+                token_budget: args
+                    .max_tokens
+                    .map(|max_tokens| args.budget.token_budget(max_tokens)),
+                skip_long_lines: args
+                    .max_line_length
+                    .filter(|_| args.skip_file)
+                    .map(|max| max as usize),
+                deadline,
+                watchdog,
+                ..scan_config(&args.scan)
             };
 
-            match generate_context(&mut config) {
+            let result = generate_context(&mut config);
+            if !config.watchdog.settle() {
+                // The watchdog is reporting the timeout and ending the process
+                return Ok(ExitCode::from(TIMEOUT_EXIT_CODE));
+            }
+            match result {
                 Ok(_) => {
                     info!("Context generation completed successfully");
                 }
@@ -244,18 +1081,222 @@ pub fn run() -> anyhow::Result<()> {
                     {
                         info!("{}", e);
                         info!("Operation cancelled by user");
-                        return Ok(());
+                        return Ok(ExitCode::SUCCESS);
+                    }
+                    if error_kind(&e) == ErrorKind::Timeout {
+                        report_error(&e, cli.error_format);
+                        return Ok(ExitCode::from(TIMEOUT_EXIT_CODE));
+                    }
+                    if cli.error_format == ErrorFormat::Json {
+                        report_error(&e, cli.error_format);
+                        return Ok(ExitCode::FAILURE);
                     }
                     return Err(e);
                 }
             }
         }
+        Commands::Plan(args) => {
+            info!("Starting plan command");
+            debug!("Command parameters: {:?}", args);
+            plan_budget(&args)?;
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+impl ScanArgs {
+    /// The first `--path`, which anchors git lookups.
+    fn first_root(&self) -> &str {
+        self.path.first().map_or(".", String::as_str)
+    }
+}
+
+impl BudgetArgs {
+    fn token_budget(&self, max_tokens: usize) -> TokenBudget {
+        TokenBudget {
+            max_tokens,
+            strategy: self.budget_strategy,
+            priority: self
+                .budget_priority
+                .as_deref()
+                .map(|list| list.split(',').map(|p| p.trim().to_string()).collect())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// The part of the config the `ScanArgs` decide, with everything else left at its default.
+fn scan_config(scan: &ScanArgs) -> ContextConfig {
+    ContextConfig {
+        root_paths: scan.path.clone(),
+        extensions: parse_extensions(scan.ext.as_deref()),
+        exclude_patterns: scan
+            .exclude
+            .as_deref()
+            .map(|list| list.split(',').map(|p| p.trim().to_string()).collect())
+            .unwrap_or_default(),
+        exclude_dir_globs: scan.exclude_dir_glob.clone(),
+        include_patterns: scan
+            .include
+            .as_deref()
+            .map(|list| list.split(',').map(|p| p.trim().to_string()).collect())
+            .unwrap_or_default(),
+        exclude_version_control_dir: scan.exclude_version_control_dir.clone(),
+        apply_dot_git_ignore: scan.apply_dot_git_ignore,
+        respect_sparse: scan.respect_sparse,
+        require_git: scan.require_git,
+        follow_symlinks: scan.follow_symlinks,
+        max_depth: scan.max_depth,
+        test_filter: if scan.exclude_tests {
+            TestFilter::ExcludeTests
+        } else if scan.tests_only {
+            TestFilter::TestsOnly
+        } else {
+            TestFilter::All
+        },
+        generated_filter: scan.exclude_generated.then(|| {
+            (
+                scan.generated_patterns
+                    .split(',')
+                    .map(|p| p.trim().to_string())
+                    .filter(|p| !p.is_empty())
+                    .collect(),
+                scan.generated_min_bytes,
+            )
+        }),
+        max_file_size: scan.max_file_size,
+        exclude_mime: scan.exclude_mime.clone(),
+        ..Default::default()
+    }
+}
+
+/// Scans like `generate --auto` and prints which files `--max-tokens` would keep.
+fn plan_budget(args: &PlanArgs) -> anyhow::Result<()> {
+    let config = scan_config(&args.scan);
+    let extensions: Vec<&str> = config.extensions.iter().map(|s| s.as_str()).collect();
+    let excludes: Vec<&str> = config.exclude_patterns.iter().map(|s| s.as_str()).collect();
+
+    let paths = scan_files(&config, &extensions, &excludes)?;
+    let files = select_files(
+        paths,
+        |path: &PathBuf| read_file_contents(path),
+        true,
+        SelectionSession::default(),
+        usize::from(DEFAULT_READ_JOBS),
+        None,
+    )?;
+    let file_count = files.len();
+    // Costs as `generate` counts them, less the file map and prompt a plan doesn't build
+    let options = OutputOptions::default();
+    let costs = files
+        .into_iter()
+        .map(|file| {
+            let tokens =
+                count_tokens(&file.content, None) + file_block_overhead(&file.path, &options);
+            (file.path, tokens)
+        })
+        .collect();
+
+    let budget = args.budget.token_budget(args.max_tokens);
+    print_budget_plan(&plan_token_budget(costs, &budget, 0), file_count, &budget)
+}
+
+fn print_budget_plan(
+    plan: &BudgetPlan,
+    file_count: usize,
+    budget: &TokenBudget,
+) -> anyhow::Result<()> {
+    let mut stdout = io::stdout();
+    stdout.execute(SetForegroundColor(TermColor::Green))?;
+    writeln!(
+        stdout,
+        "\n📋 {} of {} files fit in the {}-token budget ({} tokens, {}):",
+        plan.fitting.len(),
+        file_count,
+        budget.max_tokens,
+        plan.used_tokens,
+        budget
+            .strategy
+            .to_possible_value()
+            .map(|value| value.get_name().to_string())
+            .unwrap_or_default()
+    )?;
+    stdout.execute(ResetColor)?;
+    for (path, tokens) in &plan.fitting {
+        writeln!(stdout, "  + {} ({} tokens)", path.display(), tokens)?;
+    }
+
+    if let Some((path, tokens)) = &plan.overflow {
+        stdout.execute(SetForegroundColor(TermColor::Yellow))?;
+        writeln!(
+            stdout,
+            "\n⛔ First file that doesn't fit: {} ({} tokens)",
+            path.display(),
+            tokens
+        )?;
+        stdout.execute(ResetColor)?;
     }
+
     Ok(())
 }
 
+/// Format implied by the extension of `output_path`, for `--output-format-auto`.
+fn format_for_output_path(output_path: &str) -> Option<OutputFormat> {
+    let extension = Path::new(output_path).extension()?.to_str()?;
+    match extension.to_lowercase().as_str() {
+        "json" => Some(OutputFormat::Json),
+        "tsv" => Some(OutputFormat::Tsv),
+        "html" | "htm" => Some(OutputFormat::HtmlDetails),
+        "md" | "markdown" => Some(OutputFormat::Markdown),
+        "xml" | "txt" => Some(OutputFormat::Xml),
+        _ => None,
+    }
+}
+
+/// The explicit `--format` if given, else one inferred from the output path with
+/// `--output-format-auto`, else xml.
+fn resolve_output_format(config: &ContextConfig) -> OutputFormat {
+    if let Some(format) = config.output_format {
+        return format;
+    }
+    let inferred = config
+        .output_path
+        .as_deref()
+        .filter(|_| config.output_format_auto)
+        .and_then(format_for_output_path);
+    if let Some(format) = inferred {
+        info!(
+            "Using the {:?} format for the output file extension",
+            format
+        );
+    }
+    inferred.unwrap_or_default()
+}
+
 fn generate_context(config: &mut ContextConfig) -> anyhow::Result<()> {
-    if config.user_prompt.is_none() {
+    config.output_format = Some(resolve_output_format(config));
+    if config.stream && config.output_format != Some(OutputFormat::Xml) {
+        return Err(anyhow::anyhow!(
+            "--stream only supports the xml output format"
+        ));
+    }
+    if config.diff_context.is_some() && !config.only_changed {
+        return Err(anyhow::anyhow!("--diff-context needs --only-changed"));
+    }
+
+    let extensions: Vec<&str> = config.extensions.iter().map(|s| s.as_str()).collect();
+    let excludes: Vec<&str> = config.exclude_patterns.iter().map(|s| s.as_str()).collect();
+
+    if config.count_only {
+        let files = scan_files(config, &extensions, &excludes)?;
+        println!("{}", file_count_summary(&files));
+        return Ok(());
+    }
+
+    let interactive = io::stdin().is_terminal() && io::stdout().is_terminal();
+    if config.user_prompt.is_none() && !interactive {
+        info!("Not running in a terminal, skipping prompt input");
+    } else if config.user_prompt.is_none() {
         info!("Asking for user prompt");
         match get_prompt_input()? {
             Some(prompt) => {
@@ -268,75 +1309,1091 @@ fn generate_context(config: &mut ContextConfig) -> anyhow::Result<()> {
         }
     }
 
-    let extensions: Vec<&str> = config.extensions.iter().map(|s| s.as_str()).collect();
-    let excludes: Vec<&str> = config.exclude_patterns.iter().map(|s| s.as_str()).collect();
-
-    info!("Scanning for files in {}", config.root_path);
-    let available_files = if config.apply_dot_git_ignore {
-        list_code_files_with_gitignore(
-            &config.root_path,
-            &extensions,
-            &excludes,
-            &config.exclude_version_control_dir,
-            config.apply_dot_git_ignore,
-        )?
-    } else {
-        list_code_files(&config.root_path, &extensions, &excludes)?
+    let available_files = match &config.select_from_manifest {
+        Some(manifest_path) => {
+            info!("Selecting files from manifest {}", manifest_path);
+            let manifest = read_manifest(Path::new(manifest_path))?.ok_or_else(|| {
+                CliError::new(
+                    ErrorKind::ManifestNotFound,
+                    format!("Manifest not found: {}", manifest_path),
+                )
+            })?;
+            selection_from_manifest(&manifest, Path::is_file)
+        }
+        None => scan_files(config, &extensions, &excludes)?,
     };
 
     if available_files.is_empty() {
         warn!("No files found with the specified extensions");
-        return Err(anyhow::anyhow!(
-            "No files found matching the specified criteria"
-        ));
+        return Err(CliError::new(
+            ErrorKind::NoFilesFound,
+            "No files found matching the specified criteria",
+        )
+        .into());
     }
 
     info!("Generating file map");
-    let file_map = generate_file_map(
-        &config.root_path,
-        &excludes,
-        &config.exclude_version_control_dir,
-        config.apply_dot_git_ignore,
-    )?;
+    let file_map = build_file_map(config, &available_files, &extensions, &excludes)?;
 
     info!("Selecting files");
-    let selected_files = select_files(
-        available_files,
-        |path: &PathBuf| read_file_contents(&path),
-        config.auto_select,
-    )?;
+    let session_file = session_path(config.root_path());
+    let session = if config.auto_select {
+        SelectionSession::default()
+    } else {
+        prepare_session(&session_file, &available_files, interactive)?
+    };
+    let preselected = if config.select_from_manifest.is_some() {
+        available_files.clone()
+    } else {
+        session.initial_selection.clone()
+    };
+    check_deadline(config)?;
+    let git_ref = config.git_ref.as_deref();
+    let read = with_deadline(config.deadline, |path: &PathBuf| {
+        match (git_ref, config.diff_context) {
+            (Some(git_ref), _) => read_file_at_ref(config.root_of(path), git_ref, path),
+            (None, Some(context)) => read_changes_since_head(config.root_of(path), path, context),
+            (None, None) => read_file_contents(path),
+        }
+    });
+    let tokenizer = config.output_options.tokenizer;
+    // A streamed run reads each file only as its block is written
+    let mut selected_files = if config.stream {
+        select_paths(
+            available_files,
+            &read,
+            config.auto_select,
+            session,
+            tokenizer,
+        )?
+    } else {
+        select_files(
+            available_files,
+            &read,
+            config.auto_select,
+            session,
+            config.read_jobs,
+            tokenizer,
+        )?
+    };
+    if !config.auto_select {
+        clear_session(&session_file)?;
+    }
+
+    if let Some(budget) = &config.token_budget {
+        // What the output costs with no files at all: the file map, prompt and wrapping tags
+        let bare_output = ContextOutput {
+            file_map: file_map.clone(),
+            user_instructions: config.user_prompt.clone().unwrap_or_default(),
+            ..Default::default()
+        };
+        let reserved = count_tokens(&render_output(config, &bare_output)?, tokenizer);
+        let fitted = apply_token_budget(selected_files, budget, reserved, &|file| {
+            let content_tokens = match config.stream {
+                // Not read yet: read it just to count it
+                true => count_tokens(&read(&file.path).unwrap_or_default(), tokenizer),
+                false => count_tokens(&file.content, tokenizer),
+            };
+            content_tokens + file_block_overhead(&file.path, &config.output_options)
+        });
+        print_budget_skips(&fitted.skipped, budget.max_tokens)?;
+        selected_files = fitted.selected;
+    }
+
+    if let Some(order_file) = &config.order_file {
+        let patterns = read_order_file(Path::new(order_file))?;
+        selected_files = order_by_patterns(selected_files, |path| config.root_of(path), &patterns);
+    }
 
     if selected_files.is_empty() {
         warn!("No files were selected");
-        return Err(anyhow::anyhow!("No files were selected"));
+        return Err(CliError::new(ErrorKind::NoFilesSelected, "No files were selected").into());
     }
 
-    info!("Building context output");
-    let output = build_context_output(selected_files, file_map, config.user_prompt.clone());
-    let formatted_output = format_output(&output);
+    if !config.auto_select && interactive {
+        confirm_selection_changes(config.root_path(), &selected_files)?;
+    }
 
-    info!("Writing output");
-    write_output(
-        &formatted_output,
-        config.output_path.clone(),
-        config.clipboard_output,
-    )
-}
+    let scanned: HashSet<PathBuf> = selected_files.iter().map(|f| f.path.clone()).collect();
+    if config.include_readmes {
+        selected_files =
+            include_directory_readmes(selected_files, |path: &PathBuf| read_file_contents(path));
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    if config.explain {
+        print_inclusion_reasons(config, &selected_files, &scanned, &preselected)?;
+    }
 
-    #[test]
-    fn test_cli_parsing() {
-        let cli = Cli::try_parse_from(&[
-            "rich-prompt",
-            "generate",
-            "--path",
-            "./src",
-            "--ext",
-            ".rs",
-            "--exclude",
+    let prefix = match &config.strip_prefix {
+        Some(PathPrefix::Path(path)) => Some(path.clone()),
+        Some(PathPrefix::Common) => Some(common_prefix(&selected_files, &file_map)),
+        None => None,
+    }
+    .filter(|prefix| !prefix.as_os_str().is_empty());
+    let file_map = match &prefix {
+        Some(prefix) => {
+            info!("Stripping {} from paths", prefix.display());
+            strip_map_prefix(&file_map, prefix)?
+        }
+        None => file_map,
+    };
+
+    if let (Some(depth), Some(output_path)) = (config.split_by_dir, &config.output_path) {
+        return write_split_outputs(
+            config,
+            selected_files,
+            file_map,
+            depth,
+            output_path,
+            prefix.as_deref(),
+        );
+    }
+
+    let original_paths = match &prefix {
+        Some(prefix) => strip_file_prefix(&mut selected_files, prefix),
+        None => HashMap::new(),
+    };
+
+    let command_outputs = config
+        .attach_commands
+        .iter()
+        .map(|command| run_command(config.root_path(), command))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    check_deadline(config)?;
+    if config.stream {
+        return stream_output(
+            config,
+            selected_files,
+            file_map,
+            command_outputs,
+            &original_paths,
+            &read,
+        );
+    }
+
+    info!("Building context output");
+    let mut output = build_context_output(
+        selected_files,
+        file_map,
+        config.user_prompt.clone(),
+        &config.output_options,
+    )?;
+    if config.project_summary {
+        match read_project_summary(config.root_path())? {
+            Some(summary) => {
+                attach_project_summary(&mut output, &summary, config.output_options.tokenizer)
+            }
+            None => info!("No Cargo.toml or package.json found, omitting project summary"),
+        }
+    }
+    attach_command_outputs(
+        &mut output,
+        command_outputs,
+        config.output_options.tokenizer,
+    );
+    let formatted_output = render_output(config, &output)?;
+    check_deadline(config)?;
+
+    info!("Writing output");
+    let clipboard_html = config.clipboard_html.then(|| render_html(&output));
+    config.watchdog.while_writing(|| {
+        write_output(
+            &formatted_output,
+            config.output_path.clone(),
+            config.clipboard_output,
+            clipboard_html,
+            config.clipboard_guard,
+            config.dry_clipboard,
+        )
+    })?;
+
+    if let Some(command) = &config.verify_tokens {
+        match check_token_count(command, &formatted_output, output.token_count)? {
+            Some(report) => {
+                warn!("{}", report);
+                let mut stderr = io::stderr();
+                stderr.execute(SetForegroundColor(TermColor::Yellow))?;
+                writeln!(stderr, "\n🔢 {}", report)?;
+                stderr.execute(ResetColor)?;
+            }
+            None => info!("`{}` agrees with the token estimate", command),
+        }
+    }
+
+    if config.print_hash {
+        let hash = canonical_context_hash(&formatted_output);
+        info!("Context hash: {}", hash);
+        // On stderr, so piping the context to a file or another tool leaves the hash out
+        writeln!(io::stderr(), "\n🔑 Context hash: {}", hash)?;
+    }
+
+    if config.show_token_breakdown {
+        print_token_breakdown(&token_breakdown(&output))?;
+    }
+    if let Some(max_percent) = config.warn_file_share {
+        report_dominant_files(&dominant_files(&output, max_percent))?;
+    }
+
+    update_manifest(config, &output, &original_paths)
+}
+
+// On stderr, so the warning never mixes into context printed to stdout
+fn report_dominant_files(dominant: &[(PathBuf, f64)]) -> anyhow::Result<()> {
+    if dominant.is_empty() {
+        return Ok(());
+    }
+
+    let mut stderr = io::stderr();
+    stderr.execute(SetForegroundColor(TermColor::Yellow))?;
+    writeln!(
+        stderr,
+        "\n⚖️  {} files dominate the output; consider --exclude or --max-file-tokens:",
+        dominant.len()
+    )?;
+    stderr.execute(ResetColor)?;
+    for (path, share) in dominant {
+        writeln!(stderr, "  - {} ({:.1}% of tokens)", path.display(), share)?;
+    }
+
+    Ok(())
+}
+
+fn print_token_breakdown(breakdown: &[(PathBuf, usize)]) -> anyhow::Result<()> {
+    let mut stderr = io::stderr();
+    stderr.execute(SetForegroundColor(TermColor::Cyan))?;
+    writeln!(stderr, "\n📊 Tokens per file:")?;
+    stderr.execute(ResetColor)?;
+
+    let total: usize = breakdown.iter().map(|(_, tokens)| tokens).sum();
+    let width = total.to_string().len();
+    for (path, tokens) in breakdown {
+        writeln!(stderr, "  {:>width$}  {}", tokens, path.display())?;
+    }
+    writeln!(stderr, "  {:>width$}  total", total)?;
+
+    Ok(())
+}
+
+/// Runs the `--verify-tokens` command on the output and describes how far its count is
+/// from `estimated`, or `None` when they agree.
+fn check_token_count(
+    command: &str,
+    formatted_output: &str,
+    estimated: usize,
+) -> anyhow::Result<Option<String>> {
+    let external = external_token_count(command, formatted_output)?;
+    if external == estimated {
+        return Ok(None);
+    }
+    let difference = external.abs_diff(estimated);
+    Ok(Some(format!(
+        "Token count mismatch: `{}` counted {}, rich-prompt estimated {} ({} {}, {:.1}%)",
+        command,
+        external,
+        estimated,
+        if estimated > external {
+            "over"
+        } else {
+            "under"
+        },
+        difference,
+        difference as f64 * 100.0 / external.max(1) as f64
+    )))
+}
+
+fn render_output(config: &ContextConfig, output: &ContextOutput) -> anyhow::Result<String> {
+    let rendered = match config.chat_format {
+        Some(provider) => format_chat(output, provider)?,
+        None => format_output(output, config.output_format.unwrap_or_default())?,
+    };
+    match &config.into_template {
+        Some(template) => fill_template(template, &rendered),
+        None => Ok(rendered),
+    }
+}
+
+// Read and checked before the scan, so a bad template fails fast rather than after selection
+fn read_template(path: &str) -> anyhow::Result<String> {
+    let template = fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Failed to read template {}: {}", path, e))?;
+    check_template(&template).map_err(|e| anyhow::anyhow!("Template {}: {}", path, e))?;
+    Ok(template)
+}
+
+fn stream_output(
+    config: &ContextConfig,
+    selected_files: Vec<FileContext>,
+    file_map: String,
+    command_outputs: Vec<CommandOutput>,
+    original_paths: &HashMap<PathBuf, PathBuf>,
+    read: &dyn Fn(&PathBuf) -> anyhow::Result<String>,
+) -> anyhow::Result<()> {
+    info!("Streaming context output");
+    let project = if config.project_summary {
+        let summary = read_project_summary(config.root_path())?;
+        if summary.is_none() {
+            info!("No Cargo.toml or package.json found, omitting project summary");
+        }
+        summary
+    } else {
+        None
+    };
+
+    let output = config.watchdog.while_writing(|| {
+        let mut writer = create_writer(
+            &config.output_path,
+            config.clipboard_output,
+            None,
+            config.clipboard_guard,
+            config.dry_clipboard,
+        );
+        // Read by the path on disk, before `--strip-prefix`/`--flatten` shortened it
+        let load = |file: FileContext| {
+            let source = original_paths.get(&file.path).unwrap_or(&file.path);
+            loaded_file(source.clone(), read(source))
+        };
+        let output = stream_context_output(
+            UnreadFiles {
+                files: selected_files,
+                load: &load,
+            },
+            file_map,
+            config.user_prompt.clone(),
+            &config.output_options,
+            project.as_ref(),
+            command_outputs,
+            &mut |chunk: &str| writer.write_chunk(chunk),
+        )?;
+        writer.finish()?;
+        anyhow::Ok(output)
+    })?;
+    if let Some(max_percent) = config.warn_file_share {
+        report_dominant_files(&dominant_files(&output, max_percent))?;
+    }
+
+    update_manifest(config, &output, original_paths)
+}
+
+/// Writes the `--manifest`, if requested. `original_paths` maps paths shortened by
+/// `--strip-prefix`/`--flatten` back, so the manifest can still be replayed.
+fn update_manifest(
+    config: &ContextConfig,
+    output: &ContextOutput,
+    original_paths: &HashMap<PathBuf, PathBuf>,
+) -> anyhow::Result<()> {
+    if let Some(manifest_path) = &config.manifest_path {
+        let manifest_path = Path::new(manifest_path);
+        let mut manifest = build_manifest(output);
+        for entry in &mut manifest.files {
+            if let Some(original) = original_paths.get(Path::new(&entry.path)) {
+                entry.path = original.display().to_string();
+            }
+        }
+        if let Some(previous) = read_manifest(manifest_path)? {
+            print_manifest_diff(&diff_manifests(&previous, &manifest))?;
+        }
+        config
+            .watchdog
+            .while_writing(|| write_manifest(manifest_path, &manifest))?;
+    }
+
+    Ok(())
+}
+
+/// Gitignore rules are applied even outside a repository, which can surprise; say so, or
+/// refuse with `--require-git`.
+fn check_git_repo(config: &ContextConfig, root: &str) -> anyhow::Result<()> {
+    match find_repo_root(root) {
+        Some(repo) => debug!("Scanning inside the git repository at {}", repo.display()),
+        None if config.require_git => {
+            return Err(CliError::new(
+                ErrorKind::NotAGitRepo,
+                format!("{} is not inside a git repository (--require-git)", root),
+            )
+            .into());
+        }
+        None => info!(
+            "No git repository found at or above {}; applying any .gitignore files anyway",
+            root
+        ),
+    }
+    Ok(())
+}
+
+/// `--stdin` file list: one path per line, dropping blank lines and paths that aren't files.
+fn read_path_list(reader: impl io::BufRead) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let path = PathBuf::from(line);
+        if path.is_file() {
+            files.push(path);
+        } else {
+            warn!("Skipping {}: not a file", path.display());
+        }
+    }
+    Ok(files)
+}
+
+/// `--count-only` report: how many files matched and their combined size on disk.
+fn file_count_summary(files: &[PathBuf]) -> String {
+    let bytes: u64 = files
+        .iter()
+        .filter_map(|path| fs::metadata(path).ok())
+        .map(|metadata| metadata.len())
+        .sum();
+    format!("{} files matched, {} bytes", files.len(), bytes)
+}
+
+fn walk_options(config: &ContextConfig) -> WalkOptions<'static> {
+    WalkOptions {
+        follow_symlinks: config.follow_symlinks,
+        max_depth: config.max_depth,
+        deadline: config.deadline,
+        progress_style: config.progress_style,
+        progress_interval: Duration::from_millis(config.progress_interval_ms),
+        include_patterns: &[],
+    }
+}
+
+// Exit code of a run stopped by `--timeout`, the same as coreutils `timeout`
+const TIMEOUT_EXIT_CODE: u8 = 124;
+
+// How long the watchdog lets the deadline checks in the scan and read loops fail cleanly first
+const WATCHDOG_GRACE: Duration = Duration::from_secs(1);
+
+// How often the watchdog looks again while output is being written
+const WATCHDOG_POLL: Duration = Duration::from_millis(20);
+
+fn check_deadline(config: &ContextConfig) -> anyhow::Result<()> {
+    match config.deadline {
+        Some(deadline) => Ok(deadline.check()?),
+        None => Ok(()),
+    }
+}
+
+/// `read`, failing with a timeout error instead once `deadline` has passed.
+fn with_deadline(
+    deadline: Option<Deadline>,
+    read: impl Fn(&PathBuf) -> anyhow::Result<String> + Sync,
+) -> impl Fn(&PathBuf) -> anyhow::Result<String> + Sync {
+    move |path| {
+        if let Some(deadline) = deadline {
+            deadline.check()?;
+        }
+        read(path)
+    }
+}
+
+fn report_error(error: &anyhow::Error, error_format: ErrorFormat) {
+    match error_format {
+        ErrorFormat::Json => eprintln!("{}", error_report_json(error)),
+        ErrorFormat::Human => eprintln!("Error: {:#}", error),
+    }
+}
+
+/// Ends the process if it outlives `deadline`, for waits no deadline check can interrupt,
+/// like the selector, the prompt input, or a git call. Leaves the terminal usable first.
+/// Output being written is finished first, and a run that already ended is left alone.
+fn spawn_watchdog(deadline: Deadline, error_format: ErrorFormat, watchdog: WatchdogGuard) {
+    std::thread::spawn(move || {
+        std::thread::sleep(deadline.remaining() + WATCHDOG_GRACE);
+        while watchdog.is_writing() {
+            std::thread::sleep(WATCHDOG_POLL);
+        }
+        if !watchdog.settle() {
+            return;
+        }
+        if crossterm::terminal::is_raw_mode_enabled().unwrap_or(false) {
+            let _ = disable_raw_mode();
+            let _ = execute!(
+                io::stdout(),
+                LeaveAlternateScreen,
+                crossterm::event::DisableMouseCapture
+            );
+        }
+        let error = deadline
+            .check()
+            .map_err(anyhow::Error::from)
+            .expect_err("the deadline has passed");
+        report_error(&error, error_format);
+        // The main thread is blocked, so its exit code can't be returned from `run`
+        std::process::exit(i32::from(TIMEOUT_EXIT_CODE));
+    });
+}
+
+// One map per root; a `--stdin` list maps just the listed files, since nothing was walked
+fn build_file_map(
+    config: &ContextConfig,
+    available_files: &[PathBuf],
+    extensions: &[&str],
+    excludes: &[&str],
+) -> anyhow::Result<String> {
+    if config.paths_from_stdin {
+        return Ok(file_map_from_paths(available_files));
+    }
+
+    let mut file_map = String::new();
+    for root in &config.root_paths {
+        file_map.push_str(&match &config.git_ref {
+            Some(git_ref) => file_map_from_paths(&filter_listed_files(
+                list_files_at_ref(root, git_ref)?,
+                root,
+                &[],
+                excludes,
+                &[],
+            )?),
+            None => generate_file_map(
+                root,
+                excludes,
+                &config.exclude_version_control_dir,
+                config.apply_dot_git_ignore,
+                FileMapPruning {
+                    clamp_to_extensions: config.clamp_empty_dirs.then_some(extensions),
+                    excluded: &own_artifacts(config),
+                },
+                &build_dir_globs(&config.exclude_dir_globs)?,
+                walk_options(config),
+            )?,
+        });
+    }
+    Ok(file_map)
+}
+
+fn scan_files(
+    config: &ContextConfig,
+    extensions: &[&str],
+    excludes: &[&str],
+) -> anyhow::Result<Vec<PathBuf>> {
+    let listed = if config.paths_from_stdin {
+        info!("Reading file paths from stdin");
+        Some(read_path_list(io::stdin().lock())?)
+    } else {
+        None
+    };
+
+    let mut available_files = Vec::new();
+    let mut seen = HashSet::new();
+    for root in &config.root_paths {
+        for path in scan_root(config, root, extensions, excludes, listed.as_deref())? {
+            // Nested or repeated roots list the same file under different paths; symlinks
+            // are left alone, a linked file is scanned under each of its names
+            let absolute = std::path::absolute(&path).unwrap_or_else(|_| path.clone());
+            if seen.insert(absolute) {
+                available_files.push(path);
+            } else {
+                debug!(
+                    "Skipping {}, already scanned from another root",
+                    path.display()
+                );
+            }
+        }
+    }
+
+    for path in listed.iter().flatten() {
+        let roots = &config.root_paths;
+        if !roots
+            .iter()
+            .any(|root| rebase_on_root(path, Path::new(root)).is_some())
+        {
+            warn!("Skipping {}: not below any --path root", path.display());
+        }
+    }
+
+    let available_files = match &config.generated_filter {
+        Some((patterns, min_bytes)) => {
+            let patterns: Vec<&str> = patterns.iter().map(|s| s.as_str()).collect();
+            exclude_generated_files(available_files, &patterns, *min_bytes)
+        }
+        None => available_files,
+    };
+
+    let available_files = match config.max_file_size {
+        Some(max_bytes) => exclude_large_files(available_files, max_bytes),
+        None => available_files,
+    };
+
+    let available_files = match config.skip_long_lines {
+        Some(max_chars) => exclude_long_line_files(available_files, max_chars),
+        None => available_files,
+    };
+
+    let available_files = match config.exclude_mime.is_empty() {
+        true => available_files,
+        false => exclude_by_mime(available_files, &config.exclude_mime),
+    };
+
+    let available_files = exclude_resolved_paths(available_files, &own_artifacts(config));
+
+    match &config.closure {
+        Some(entry) => closure_of(config, entry, &available_files),
+        None => Ok(available_files),
+    }
+}
+
+// Lists one root, or the `--stdin` paths below it, with the filters whose patterns are
+// relative to it
+fn scan_root(
+    config: &ContextConfig,
+    root: &str,
+    extensions: &[&str],
+    excludes: &[&str],
+    listed: Option<&[PathBuf]>,
+) -> anyhow::Result<Vec<PathBuf>> {
+    info!("Scanning for files in {}", root);
+    let dir_globs = build_dir_globs(&config.exclude_dir_globs)?;
+    let includes: Vec<&str> = config.include_patterns.iter().map(|s| s.as_str()).collect();
+    let walk = WalkOptions {
+        include_patterns: &includes,
+        ..walk_options(config)
+    };
+    let available_files = if let Some(listed) = listed {
+        let below_root = listed
+            .iter()
+            .filter_map(|path| rebase_on_root(path, Path::new(root)))
+            .collect();
+        filter_listed_files(below_root, root, extensions, excludes, &includes)?
+    } else if let Some(git_ref) = &config.git_ref {
+        filter_listed_files(
+            list_files_at_ref(root, git_ref)?,
+            root,
+            extensions,
+            excludes,
+            &includes,
+        )?
+    } else if config.apply_dot_git_ignore {
+        check_git_repo(config, root)?;
+        list_code_files_with_gitignore(
+            root,
+            extensions,
+            excludes,
+            &config.exclude_version_control_dir,
+            config.apply_dot_git_ignore,
+            &dir_globs,
+            walk,
+        )?
+    } else {
+        list_code_files(root, extensions, excludes, &dir_globs, walk)?
+    };
+
+    let available_files = if config.only_changed {
+        let changed: HashSet<PathBuf> = list_changed_files(root)?.into_iter().collect();
+        available_files
+            .into_iter()
+            .filter(|path| changed.contains(path))
+            .collect()
+    } else {
+        available_files
+    };
+
+    let available_files = if config.respect_sparse {
+        restrict_to_sparse_checkout(available_files, root)?
+    } else {
+        available_files
+    };
+
+    Ok(filter_test_files(available_files, root, config.test_filter))
+}
+
+// The entry may be given relative to the working directory or to any scanned root
+fn closure_of(
+    config: &ContextConfig,
+    entry: &str,
+    available_files: &[PathBuf],
+) -> anyhow::Result<Vec<PathBuf>> {
+    let read = |path: &PathBuf| match &config.git_ref {
+        Some(git_ref) => read_file_at_ref(config.root_of(path), git_ref, path),
+        None => read_file_contents(path),
+    };
+    dependency_closure(Path::new(entry), available_files, read).or_else(|e| {
+        config
+            .root_paths
+            .iter()
+            .find_map(|root| {
+                dependency_closure(&Path::new(root).join(entry), available_files, read).ok()
+            })
+            .ok_or(e)
+    })
+}
+
+// Files rich-prompt writes itself; scanning them would feed old output back into the context
+fn own_artifacts(config: &ContextConfig) -> Vec<PathBuf> {
+    let mut artifacts = vec![state_dir(config.root_path())];
+    artifacts.extend(config.output_path.iter().map(PathBuf::from));
+    artifacts.extend(config.manifest_path.iter().map(PathBuf::from));
+    artifacts
+}
+
+// Offers to resume an interrupted selection and wires up autosave for this one
+fn prepare_session(
+    session_file: &Path,
+    available_files: &[PathBuf],
+    interactive: bool,
+) -> anyhow::Result<SelectionSession> {
+    let mut session = SelectionSession::default();
+
+    if interactive && let Some(saved) = read_session(session_file)? {
+        let resumable: Vec<PathBuf> = saved
+            .selected
+            .into_iter()
+            .filter(|path| available_files.contains(path))
+            .collect();
+        if !resumable.is_empty() && confirm_resume(resumable.len())? {
+            session.initial_selection = resumable;
+        }
+    }
+
+    let path = session_file.to_path_buf();
+    session.autosave = Some(Box::new(move |selected: &[PathBuf]| {
+        let saved = SavedSession {
+            selected: selected.to_vec(),
+        };
+        if let Err(e) = write_session(&path, &saved) {
+            warn!("Failed to autosave selection: {}", e);
+        }
+    }));
+
+    Ok(session)
+}
+
+fn confirm_resume(file_count: usize) -> anyhow::Result<bool> {
+    ask_yes_no(&format!(
+        "\n♻️  Found an unfinished selection of {} files. Resume it? [Y/n] ",
+        file_count
+    ))
+}
+
+fn ask_yes_no(question: &str) -> anyhow::Result<bool> {
+    let mut stdout = io::stdout();
+    stdout.execute(SetForegroundColor(TermColor::Yellow))?;
+    write!(stdout, "{}", question)?;
+    stdout.execute(ResetColor)?;
+    stdout.flush()?;
+
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    let answer = answer.trim().to_lowercase();
+    Ok(answer.is_empty() || answer == "y" || answer == "yes")
+}
+
+// Shows what changed since the last confirmed selection and asks before generating.
+// The confirmed selection is then saved as the baseline for the next run.
+fn confirm_selection_changes(root: &str, selected_files: &[FileContext]) -> anyhow::Result<()> {
+    let last_selection = last_selection_path(root);
+    let current: Vec<PathBuf> = selected_files.iter().map(|f| f.path.clone()).collect();
+
+    if let Some(previous) = read_session(&last_selection)? {
+        let diff = diff_selections(&previous.selected, &current);
+        if !diff.is_empty() {
+            print_selection_diff(&diff)?;
+            if !ask_yes_no("\nGenerate with this selection? [Y/n] ")? {
+                return Err(anyhow::anyhow!("Selection cancelled at confirmation"));
+            }
+        }
+    }
+
+    write_session(&last_selection, &SavedSession { selected: current })
+}
+
+fn print_selection_diff(diff: &SelectionDiff) -> anyhow::Result<()> {
+    let mut stdout = io::stdout();
+
+    stdout.execute(SetForegroundColor(TermColor::Cyan))?;
+    writeln!(stdout, "\n🧾 Selection changes since the last run:")?;
+    stdout.execute(ResetColor)?;
+
+    stdout.execute(SetForegroundColor(TermColor::Green))?;
+    for path in &diff.added {
+        writeln!(stdout, "  + {}", path.display())?;
+    }
+    stdout.execute(SetForegroundColor(TermColor::Red))?;
+    for path in &diff.removed {
+        writeln!(stdout, "  - {}", path.display())?;
+    }
+    stdout.execute(ResetColor)?;
+
+    Ok(())
+}
+
+fn write_split_outputs(
+    config: &ContextConfig,
+    files: Vec<FileContext>,
+    file_map: String,
+    depth: usize,
+    output_path: &str,
+    prefix: Option<&Path>,
+) -> anyhow::Result<()> {
+    let groups = group_by_directory(files, |path| config.root_of(path), depth);
+    info!("Writing {} split outputs", groups.len());
+
+    for (group, mut group_files) in groups {
+        if let Some(prefix) = prefix {
+            strip_file_prefix(&mut group_files, prefix);
+        }
+        let output = build_context_output(
+            group_files,
+            file_map.clone(),
+            config.user_prompt.clone(),
+            &config.output_options,
+        )?;
+        let formatted_output = render_output(config, &output)?;
+        let path = split_output_path(Path::new(output_path), &group);
+        config.watchdog.while_writing(|| {
+            write_output(
+                &formatted_output,
+                Some(path.display().to_string()),
+                false,
+                None,
+                None,
+                false,
+            )
+        })?;
+    }
+
+    Ok(())
+}
+
+fn print_inclusion_reasons(
+    config: &ContextConfig,
+    files: &[FileContext],
+    scanned: &HashSet<PathBuf>,
+    preselected: &[PathBuf],
+) -> anyhow::Result<()> {
+    let extensions: Vec<&str> = config.extensions.iter().map(|s| s.as_str()).collect();
+    let includes: Vec<&str> = config.include_patterns.iter().map(|s| s.as_str()).collect();
+
+    let mut stderr = io::stderr();
+    stderr.execute(SetForegroundColor(TermColor::Cyan))?;
+    writeln!(stderr, "\n🔎 Why each file was included:")?;
+    stderr.execute(ResetColor)?;
+    for file in files {
+        let mut reasons = if scanned.contains(&file.path) {
+            inclusion_reasons(
+                &file.path,
+                config.root_of(&file.path),
+                &extensions,
+                &includes,
+            )
+        } else {
+            vec![InclusionReason::AlwaysInclude]
+        };
+        if preselected.contains(&file.path) {
+            reasons.push(InclusionReason::Preselected);
+        }
+        let reasons: Vec<String> = reasons.iter().map(ToString::to_string).collect();
+        writeln!(
+            stderr,
+            "  - {}: {}",
+            file.path.display(),
+            reasons.join(", ")
+        )?;
+    }
+
+    Ok(())
+}
+
+fn print_budget_skips(skipped: &[(PathBuf, usize)], max_tokens: usize) -> anyhow::Result<()> {
+    if skipped.is_empty() {
+        return Ok(());
+    }
+
+    let mut stderr = io::stderr();
+    stderr.execute(SetForegroundColor(TermColor::Yellow))?;
+    writeln!(
+        stderr,
+        "\n✂️  {} files did not fit in the {}-token budget:",
+        skipped.len(),
+        max_tokens
+    )?;
+    stderr.execute(ResetColor)?;
+    for (path, tokens) in skipped {
+        writeln!(stderr, "  - {} ({} tokens)", path.display(), tokens)?;
+    }
+
+    Ok(())
+}
+
+fn error_kind(error: &anyhow::Error) -> ErrorKind {
+    if let Some(cli_error) = error.downcast_ref::<CliError>() {
+        cli_error.kind
+    } else if error.downcast_ref::<io::Error>().is_some() {
+        ErrorKind::Io
+    } else {
+        ErrorKind::Other
+    }
+}
+
+pub fn error_report_json(error: &anyhow::Error) -> String {
+    serde_json::json!({
+        "error": format!("{:#}", error),
+        "kind": error_kind(error),
+    })
+    .to_string()
+}
+
+fn print_manifest_diff(diff: &ManifestDiff) -> anyhow::Result<()> {
+    let mut stderr = io::stderr();
+
+    stderr.execute(SetForegroundColor(TermColor::Cyan))?;
+    writeln!(stderr, "\n📝 Changes since the previous run:")?;
+    stderr.execute(ResetColor)?;
+
+    if diff.is_empty() {
+        writeln!(stderr, "  (no changes)")?;
+        return Ok(());
+    }
+
+    for path in &diff.added {
+        writeln!(stderr, "  + {}", path)?;
+    }
+    for path in &diff.removed {
+        writeln!(stderr, "  - {}", path)?;
+    }
+    for (path, before, after) in &diff.changed {
+        writeln!(stderr, "  ~ {} ({} → {} tokens)", path, before, after)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prompt_from_existing_file() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let prompt_file = temp_dir.path().join("prompt.md");
+        fs::write(&prompt_file, "Review the parser").unwrap();
+        let prompt_path = prompt_file.to_string_lossy();
+
+        assert_eq!(
+            resolve_prompt(&prompt_path, false).unwrap(),
+            "Review the parser"
+        );
+        assert_eq!(resolve_prompt(&prompt_path, true).unwrap(), prompt_path);
+    }
+
+    #[test]
+    fn test_prompt_literal_when_no_file() {
+        let prompt = "Explain src/main.rs";
+        assert_eq!(resolve_prompt(prompt, false).unwrap(), prompt);
+    }
+
+    #[test]
+    fn test_output_format_from_extension() {
+        let resolve = |output: &str| {
+            resolve_output_format(&ContextConfig {
+                output_path: Some(output.to_string()),
+                output_format_auto: true,
+                ..ContextConfig::default()
+            })
+        };
+
+        assert_eq!(resolve("context.json"), OutputFormat::Json);
+        assert_eq!(resolve("stats.TSV"), OutputFormat::Tsv);
+        assert_eq!(resolve("review.html"), OutputFormat::HtmlDetails);
+        assert_eq!(resolve("context.md"), OutputFormat::Markdown);
+        assert_eq!(resolve("notes.markdown"), OutputFormat::Markdown);
+        assert_eq!(resolve("context.xml"), OutputFormat::Xml);
+        assert_eq!(resolve("context.txt"), OutputFormat::Xml);
+        assert_eq!(resolve("context"), OutputFormat::Xml);
+    }
+
+    #[test]
+    fn test_explicit_format_beats_output_extension() {
+        let explicit = ContextConfig {
+            output_path: Some("context.json".to_string()),
+            output_format: Some(OutputFormat::Tsv),
+            output_format_auto: true,
+            ..ContextConfig::default()
+        };
+        let not_enabled = ContextConfig {
+            output_path: Some("context.json".to_string()),
+            ..ContextConfig::default()
+        };
+
+        assert_eq!(resolve_output_format(&explicit), OutputFormat::Tsv);
+        assert_eq!(resolve_output_format(&not_enabled), OutputFormat::Xml);
+    }
+
+    #[test]
+    fn test_uppercase_extensions_match() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        for file in ["a.rs", "b.py", "c.md"] {
+            fs::write(temp_dir.path().join(file), "").unwrap();
+        }
+        let root = temp_dir.path().to_string_lossy().to_string();
+
+        let extensions = parse_extensions(Some("RS, .PY"));
+        let extensions: Vec<&str> = extensions.iter().map(String::as_str).collect();
+        let mut files = list_code_files(
+            &root,
+            &extensions,
+            &[],
+            &build_dir_globs(&[]).unwrap(),
+            WalkOptions::default(),
+        )
+        .unwrap();
+        files.sort();
+
+        assert_eq!(extensions, vec!["rs", ".py"]);
+        assert_eq!(
+            files,
+            vec![temp_dir.path().join("a.rs"), temp_dir.path().join("b.py")]
+        );
+    }
+
+    #[test]
+    fn test_require_git_outside_repo() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        fs::write(temp_dir.path().join("main.rs"), "fn main() {}").unwrap();
+        let mut config = ContextConfig {
+            root_paths: vec![temp_dir.path().to_string_lossy().to_string()],
+            apply_dot_git_ignore: true,
+            ..ContextConfig::default()
+        };
+
+        assert!(scan_files(&config, &[], &[]).is_ok());
+
+        config.require_git = true;
+        let error = scan_files(&config, &[], &[]).unwrap_err();
+        assert_eq!(error_kind(&error), ErrorKind::NotAGitRepo);
+        assert!(error.to_string().contains("is not inside a git repository"));
+
+        fs::create_dir(temp_dir.path().join(".git")).unwrap();
+        assert!(scan_files(&config, &[], &[]).is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_verify_tokens_reports_discrepancy() {
+        let report = check_token_count("cat > /dev/null; echo 120", "<file_map>", 100)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            report,
+            "Token count mismatch: `cat > /dev/null; echo 120` counted 120, rich-prompt estimated 100 (under 20, 16.7%)"
+        );
+        assert_eq!(check_token_count("echo 100", "", 100).unwrap(), None);
+    }
+
+    #[test]
+    fn test_cli_parsing() {
+        let cli = Cli::try_parse_from([
+            "rich-prompt",
+            "generate",
+            "--path",
+            "./src",
+            "--ext",
+            ".rs",
+            "--exclude",
             ".git",
             "--auto",
             "--prompt",
@@ -348,33 +2405,500 @@ mod tests {
         ])
         .unwrap();
 
-        match cli.command {
-            Commands::Generate {
-                path,
-                ext,
-                exclude,
-                auto,
-                prompt,
-                exclude_version_control_dir,
-                apply_dot_git_ignore,
-                clipboard_output,
-                ..
-            } => {
-                assert_eq!(path, "./src");
-                assert_eq!(ext, Some(".rs".to_string()));
-                assert_eq!(exclude, Some(".git".to_string()));
-                assert!(auto);
-                assert_eq!(prompt, Some("Test prompt".to_string()));
-                assert_eq!(exclude_version_control_dir, ".svn");
-                assert_eq!(apply_dot_git_ignore, true);
-                assert_eq!(clipboard_output, true);
-            }
+        let Commands::Generate(args) = cli.command else {
+            panic!("expected generate");
+        };
+        let GenerateArgs {
+            scan:
+                ScanArgs {
+                    path,
+                    ext,
+                    exclude,
+                    exclude_version_control_dir,
+                    apply_dot_git_ignore,
+                    ..
+                },
+            auto,
+            prompt,
+            clipboard_output,
+            warn_file_share,
+            ..
+        } = *args;
+
+        assert_eq!(path, vec!["./src"]);
+        assert_eq!(ext, Some(".rs".to_string()));
+        assert_eq!(exclude, Some(".git".to_string()));
+        assert!(auto);
+        assert_eq!(prompt, Some("Test prompt".to_string()));
+        assert_eq!(exclude_version_control_dir, ".svn");
+        assert!(apply_dot_git_ignore);
+        assert!(clipboard_output);
+        // Opt-in, so nothing is reported unless asked for
+        assert_eq!(warn_file_share, None);
+    }
+
+    #[test]
+    fn test_review_pr_preset() {
+        let parse = |extra: &[&str]| {
+            let cli = Cli::try_parse_from(
+                ["rich-prompt", "generate", "--preset", "review-pr"]
+                    .iter()
+                    .chain(extra),
+            )
+            .unwrap();
+            let Commands::Generate(mut args) = cli.command else {
+                panic!("expected generate");
+            };
+            apply_preset(&mut args);
+            args
+        };
+
+        let preset = parse(&[]);
+        assert_eq!(preset.only_changed, Some(true));
+        assert_eq!(preset.diff_context, Some(3));
+        assert_eq!(preset.format, Some(OutputFormat::Markdown));
+        assert_eq!(
+            user_prompt(&preset).unwrap().as_deref(),
+            Some(PromptTemplate::CodeReview.instructions())
+        );
+
+        let overridden = parse(&[
+            "--prompt",
+            "Focus on the parser",
+            "--diff-context",
+            "10",
+            "--format",
+            "json",
+        ]);
+        assert_eq!(overridden.only_changed, Some(true));
+        assert_eq!(overridden.diff_context, Some(10));
+        assert_eq!(overridden.format, Some(OutputFormat::Json));
+        assert_eq!(
+            user_prompt(&overridden).unwrap().as_deref(),
+            Some("Focus on the parser")
+        );
+
+        let other_template = parse(&["--prompt-template-name", "bug-hunt"]);
+        assert_eq!(
+            other_template.prompt_template_name,
+            Some(PromptTemplate::BugHunt)
+        );
+
+        let whole_tree = parse(&["--only-changed=false"]);
+        assert_eq!(whole_tree.only_changed, Some(false));
+        assert_eq!(whole_tree.diff_context, None);
+
+        let at_ref = parse(&["--git-ref", "main"]);
+        assert_eq!(at_ref.only_changed, Some(false));
+        assert_eq!(at_ref.diff_context, None);
+
+        let streamed = parse(&["--stream"]);
+        assert_eq!(streamed.format, None);
+    }
+
+    #[test]
+    fn test_cli_parsing_multiple_paths() {
+        let parse = |extra: &[&str]| {
+            let cli = Cli::try_parse_from(["rich-prompt", "generate"].iter().chain(extra)).unwrap();
+            let Commands::Generate(args) = cli.command else {
+                panic!("expected generate");
+            };
+            args.scan.path
+        };
+
+        assert_eq!(parse(&[]), vec!["."]);
+        assert_eq!(
+            parse(&["--path", "./src", "--path", "./proto"]),
+            vec!["./src", "./proto"]
+        );
+        assert_eq!(
+            parse(&["--path", "./src,./proto"]),
+            vec!["./src", "./proto"]
+        );
+    }
+
+    #[test]
+    fn test_scan_across_roots() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let src = temp_dir.path().join("src");
+        let proto = temp_dir.path().join("proto");
+        fs::create_dir_all(src.join("nested")).unwrap();
+        fs::create_dir_all(&proto).unwrap();
+        fs::write(src.join("main.rs"), "fn main() {}").unwrap();
+        fs::write(src.join("nested/util.rs"), "fn util() {}").unwrap();
+        fs::write(proto.join("api.proto"), "syntax = \"proto3\";").unwrap();
+        let root = |path: &Path| path.to_string_lossy().to_string();
+        let config = ContextConfig {
+            // The nested root repeats a file the first root already lists
+            root_paths: vec![root(&src), root(&proto), root(&src.join("nested"))],
+            ..ContextConfig::default()
+        };
+
+        let files = scan_files(&config, &["rs", "proto"], &[]).unwrap();
+        let mut expected = vec![
+            src.join("main.rs"),
+            src.join("nested/util.rs"),
+            proto.join("api.proto"),
+        ];
+        let mut sorted = files.clone();
+        sorted.sort();
+        expected.sort();
+        assert_eq!(sorted, expected);
+        assert_eq!(config.root_of(&proto.join("api.proto")), root(&proto));
+    }
+
+    #[test]
+    fn test_cli_parsing_fence() {
+        let cli = Cli::try_parse_from(["rich-prompt", "generate", "--fence", "4"]).unwrap();
+        let Commands::Generate(args) = cli.command else {
+            panic!("expected generate");
+        };
+        assert_eq!(args.fence, Some(4));
+
+        assert!(Cli::try_parse_from(["rich-prompt", "generate", "--fence", "2"]).is_err());
+    }
+
+    #[test]
+    fn test_prompt_templates() {
+        let cli = Cli::try_parse_from([
+            "rich-prompt",
+            "generate",
+            "--prompt-template-name",
+            "bug-hunt",
+        ])
+        .unwrap();
+        let Commands::Generate(args) = cli.command else {
+            panic!("expected generate");
+        };
+        assert_eq!(
+            user_prompt(&args).unwrap().as_deref(),
+            Some(PromptTemplate::BugHunt.instructions())
+        );
+
+        let list = template_list();
+        let names: Vec<&str> = list
+            .lines()
+            .filter_map(|line| line.split(':').next())
+            .collect();
+        assert_eq!(names, vec!["code-review", "bug-hunt", "docs", "refactor"]);
+        assert!(list.contains("bug-hunt: Look for bugs"));
+
+        assert!(
+            Cli::try_parse_from([
+                "rich-prompt",
+                "generate",
+                "--prompt-template-name",
+                "docs",
+                "--prompt",
+                "x",
+            ])
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_config_file_fills_unset_flags() {
+        let cli = Cli::try_parse_from(["rich-prompt", "generate", "--ext", "rs"]).unwrap();
+        let Commands::Generate(mut args) = cli.command else {
+            panic!("expected generate");
+        };
+        apply_config_file(
+            &mut args,
+            FileConfig {
+                ext: Some("py".to_string()),
+                exclude: Some("target".to_string()),
+                max_tokens: Some(50000),
+                ..FileConfig::default()
+            },
+        );
+        assert_eq!(args.scan.ext.as_deref(), Some("rs"));
+        assert_eq!(args.scan.exclude.as_deref(), Some("target"));
+        assert_eq!(args.max_tokens, Some(50000));
+        assert_eq!(args.output, None);
+
+        assert!(
+            Cli::try_parse_from([
+                "rich-prompt",
+                "generate",
+                "--config",
+                "a.toml",
+                "--no-config"
+            ])
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_timeout_trips_on_slow_reader() {
+        let files: Vec<PathBuf> = ["a.rs", "b.rs", "c.rs"].iter().map(PathBuf::from).collect();
+        let slow_read = |_: &PathBuf| {
+            std::thread::sleep(Duration::from_millis(30));
+            Ok("fn f() {}".to_string())
+        };
+
+        let error = select_files(
+            files.clone(),
+            with_deadline(Deadline::after(Duration::from_millis(20)), slow_read),
+            true,
+            SelectionSession::default(),
+            1,
+            None,
+        )
+        .unwrap_err();
+        assert_eq!(error_kind(&error), ErrorKind::Timeout);
+        assert!(error.to_string().starts_with("Timed out after"));
+
+        let read = select_files(
+            files,
+            with_deadline(Deadline::after(Duration::from_secs(60)), slow_read),
+            true,
+            SelectionSession::default(),
+            1,
+            None,
+        )
+        .unwrap();
+        assert_eq!(read.len(), 3);
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration("1h"), Ok(Duration::from_secs(3600)));
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert!(parse_duration("5 minutes").is_err());
+        assert!(parse_duration("s").is_err());
+        assert!(parse_duration(&format!("{}h", u64::MAX / 60)).is_err());
+        assert_eq!(Deadline::after(Duration::MAX), None);
+    }
+
+    #[test]
+    fn test_watchdog_waits_for_writes_and_reports_once() {
+        let watchdog = WatchdogGuard::default();
+        let observer = watchdog.clone();
+
+        assert!(watchdog.while_writing(|| observer.is_writing()));
+        assert!(!observer.is_writing());
+
+        assert!(watchdog.settle());
+        assert!(!observer.settle());
+    }
+
+    #[test]
+    fn test_cli_parsing_clipboard_html() {
+        let parse = |flags: &[&str]| {
+            Cli::try_parse_from(["rich-prompt", "generate"].iter().chain(flags)).map(|cli| {
+                let Commands::Generate(args) = cli.command else {
+                    panic!("expected generate");
+                };
+                args.clipboard_html
+            })
+        };
+        assert!(parse(&["--clipboard-html", "--clipboard-output"]).unwrap());
+        assert!(parse(&["--clipboard-html", "--dry-clipboard"]).unwrap());
+        assert!(parse(&["--clipboard-html"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_max_file_size() {
+        let parse = |size: &str| {
+            let cli = Cli::try_parse_from(["rich-prompt", "generate", "--max-file-size", size])?;
+            let Commands::Generate(args) = cli.command else {
+                panic!("expected generate");
+            };
+            Ok::<_, clap::Error>(args.scan.max_file_size)
+        };
+        assert_eq!(parse("4096").unwrap(), Some(4096));
+        assert_eq!(parse("500k").unwrap(), Some(500 * 1024));
+        assert_eq!(parse("2M").unwrap(), Some(2 * 1024 * 1024));
+        assert_eq!(parse("1g").unwrap(), Some(1 << 30));
+        assert!(parse("2MB").is_err());
+        assert!(parse("k").is_err());
+    }
+
+    #[test]
+    fn test_cli_parsing_line_numbers() {
+        let cli = Cli::try_parse_from(["rich-prompt", "generate", "--line-numbers"]).unwrap();
+        let Commands::Generate(args) = cli.command else {
+            panic!("expected generate");
+        };
+        assert!(args.line_numbers);
+        assert!(
+            Cli::try_parse_from([
+                "rich-prompt",
+                "generate",
+                "--line-numbers",
+                "--anchor-format",
+                "prefix",
+            ])
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_cli_parsing_split_by_dir() {
+        let cli = Cli::try_parse_from([
+            "rich-prompt",
+            "generate",
+            "--split-by-dir",
+            "1",
+            "--output",
+            "context.md",
+        ])
+        .unwrap();
+        let Commands::Generate(args) = cli.command else {
+            panic!("expected generate");
+        };
+        assert_eq!(args.split_by_dir, Some(1));
+
+        assert!(Cli::try_parse_from(["rich-prompt", "generate", "--split-by-dir", "1"]).is_err());
+    }
+
+    #[test]
+    fn test_read_path_list() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let main = temp_dir.path().join("main.rs");
+        let lib = temp_dir.path().join("lib.rs");
+        fs::write(&main, "fn main() {}").unwrap();
+        fs::write(&lib, "pub fn f() {}").unwrap();
+        let missing = temp_dir.path().join("deleted.rs");
+
+        let input = format!(
+            "{}\n\n  {}  \n{}\n{}\n",
+            main.display(),
+            lib.display(),
+            missing.display(),
+            temp_dir.path().display()
+        );
+        let files = read_path_list(io::Cursor::new(input)).unwrap();
+        assert_eq!(files, vec![main, lib]);
+
+        let cli = Cli::try_parse_from(["rich-prompt", "generate", "--stdin", "--auto"]).unwrap();
+        let Commands::Generate(args) = cli.command else {
+            panic!("expected generate");
+        };
+        assert!(args.stdin && args.auto);
+    }
+
+    #[test]
+    fn test_stdin_paths_are_filtered_like_a_scan() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let root = temp_dir.path();
+        for file in [
+            "src/main.rs",
+            "src/notes.md",
+            "vendor/dep.rs",
+            "docs/api.rs",
+        ] {
+            let path = root.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "x").unwrap();
         }
+        let config = ContextConfig {
+            root_paths: vec![root.to_string_lossy().to_string()],
+            include_patterns: vec!["src/**".to_string(), "vendor/**".to_string()],
+            paths_from_stdin: true,
+            ..ContextConfig::default()
+        };
+        let listed: Vec<PathBuf> = [
+            "src/main.rs",
+            "src/notes.md",
+            "vendor/dep.rs",
+            "docs/api.rs",
+        ]
+        .iter()
+        .map(|file| root.join(file))
+        .collect();
+
+        let files = scan_root(
+            &config,
+            config.root_path(),
+            &["rs"],
+            &["vendor"],
+            Some(&listed),
+        )
+        .unwrap();
+        assert_eq!(files, vec![root.join("src/main.rs")]);
+        assert_eq!(
+            build_file_map(&config, &files, &["rs"], &[]).unwrap(),
+            format!(
+                "{}\n├── {}\n",
+                root.join("src").display(),
+                files[0].display()
+            )
+        );
+    }
+
+    #[test]
+    fn test_count_only_writes_no_output() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let src = temp_dir.path().join("src");
+        fs::create_dir(&src).unwrap();
+        fs::write(src.join("main.rs"), "fn main() {}\n").unwrap();
+        fs::write(src.join("lib.rs"), "pub mod a;\n").unwrap();
+        fs::write(src.join("notes.md"), "# Notes\n").unwrap();
+        let output_path = temp_dir.path().join("context.txt");
+        let mut config = ContextConfig {
+            root_paths: vec![src.to_string_lossy().to_string()],
+            extensions: vec!["rs".to_string()],
+            output_path: Some(output_path.to_string_lossy().to_string()),
+            auto_select: true,
+            count_only: true,
+            ..Default::default()
+        };
+
+        let files = scan_files(&config, &["rs"], &[]).unwrap();
+        assert_eq!(file_count_summary(&files), "2 files matched, 24 bytes");
+
+        generate_context(&mut config).unwrap();
+        assert!(!output_path.exists());
+    }
+
+    #[test]
+    fn test_json_error_report_for_failed_run() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let mut config = ContextConfig {
+            root_paths: vec![temp_dir.path().to_string_lossy().to_string()],
+            auto_select: true,
+            user_prompt: Some(String::new()),
+            ..Default::default()
+        };
+
+        let error = generate_context(&mut config).unwrap_err();
+        let report: serde_json::Value = serde_json::from_str(&error_report_json(&error)).unwrap();
+
+        assert_eq!(report["kind"], "no_files_found");
+        assert_eq!(
+            report["error"],
+            "No files found matching the specified criteria"
+        );
+
+        let io_error = anyhow::Error::new(io::Error::other("disk on fire"));
+        let report: serde_json::Value =
+            serde_json::from_str(&error_report_json(&io_error)).unwrap();
+        assert_eq!(report["kind"], "io");
+    }
+
+    #[test]
+    fn test_cli_parsing_keep_blank_lines() {
+        let cli = Cli::try_parse_from(["rich-prompt", "generate"]).unwrap();
+        let Commands::Generate(args) = cli.command else {
+            panic!("expected generate");
+        };
+        assert!(args.keep_blank_lines);
+
+        let cli = Cli::try_parse_from(["rich-prompt", "generate", "--keep-blank-lines", "false"])
+            .unwrap();
+        let Commands::Generate(args) = cli.command else {
+            panic!("expected generate");
+        };
+        assert!(!args.keep_blank_lines);
     }
 
     #[test]
     fn test_cli_parsing_with_optional_args() {
-        let cli = Cli::try_parse_from(&[
+        let cli = Cli::try_parse_from([
             "rich-prompt",
             "generate",
             "--path",
@@ -385,27 +2909,32 @@ mod tests {
         ])
         .unwrap();
 
-        match cli.command {
-            Commands::Generate {
-                path,
-                ext,
-                exclude,
-                auto,
-                prompt,
-                exclude_version_control_dir,
-                apply_dot_git_ignore,
-                clipboard_output,
-                ..
-            } => {
-                assert_eq!(path, "./src");
-                assert_eq!(ext, None);
-                assert_eq!(exclude, None);
-                assert!(auto);
-                assert_eq!(prompt, None);
-                assert_eq!(exclude_version_control_dir, ".svn");
-                assert_eq!(apply_dot_git_ignore, true);
-                assert_eq!(clipboard_output, false);
-            }
-        }
+        let Commands::Generate(args) = cli.command else {
+            panic!("expected generate");
+        };
+        let GenerateArgs {
+            scan:
+                ScanArgs {
+                    path,
+                    ext,
+                    exclude,
+                    exclude_version_control_dir,
+                    apply_dot_git_ignore,
+                    ..
+                },
+            auto,
+            prompt,
+            clipboard_output,
+            ..
+        } = *args;
+
+        assert_eq!(path, vec!["./src"]);
+        assert_eq!(ext, None);
+        assert_eq!(exclude, None);
+        assert!(auto);
+        assert_eq!(prompt, None);
+        assert_eq!(exclude_version_control_dir, ".svn");
+        assert!(apply_dot_git_ignore);
+        assert!(!clipboard_output);
     }
-}
\ No newline at end of file
+}
@@ -1,12 +1,41 @@
-use crate::domain::models::{ContextOutput, FileContext};
+use crate::core::hashing::content_hash;
+use crate::core::imports::{KnownFiles, imports_among};
+use crate::core::notebook::{extract_notebook, is_notebook};
+use crate::core::outline::{outline, symbol_starts};
+use crate::domain::models::{
+    AnchorFormat, AnthropicChat, ChatMessage, ChatProvider, CommandOutput, ContextOutput,
+    ExtensionSummary, FileContext, JsonContext, JsonFile, OpenAiChat, OutputFormat, OutputOptions,
+    PathSeparator, ProjectSummary, Tokenizer,
+};
 use crossterm::{
     ExecutableCommand,
     style::{Color, ResetColor, SetForegroundColor},
 };
-use log::{debug, info};
+use log::{debug, info, warn};
+use std::collections::{BTreeMap, HashSet};
 use std::io::{Write, stdout};
+use std::path::{MAIN_SEPARATOR, Path, PathBuf};
+
+/// How `tokenizer` computes token counts, for progress output.
+fn token_count_method(tokenizer: Option<Tokenizer>) -> &'static str {
+    match tokenizer {
+        Some(Tokenizer::Cl100kBase) => "cl100k_base",
+        Some(Tokenizer::O200kBase) => "o200k_base",
+        None => "estimated",
+    }
+}
 
-fn count_tokens(content: &str) -> usize {
+/// Tokens in `content` under `tokenizer`; `None` estimates them from the characters.
+pub fn count_tokens(content: &str, tokenizer: Option<Tokenizer>) -> usize {
+    let bpe = match tokenizer {
+        Some(Tokenizer::Cl100kBase) => tiktoken_rs::cl100k_base_singleton(),
+        Some(Tokenizer::O200kBase) => tiktoken_rs::o200k_base_singleton(),
+        None => return estimate_tokens(content),
+    };
+    bpe.encode_ordinary(content).len()
+}
+
+fn estimate_tokens(content: &str) -> usize {
     // A more accurate token counting method that approximates GPT tokenization
     const TOKEN_AVG_CHARS: f32 = 4.0; // average characters per token
 
@@ -22,52 +51,809 @@ fn count_tokens(content: &str) -> usize {
     estimated_tokens
 }
 
-pub fn build_context_output(
+const DEFAULT_FENCE_LEN: usize = 3;
+
+fn escape_xml_attr(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+// The block template supplies the newline before the closing fence, so a file's own
+// trailing newline would otherwise show up as an extra blank line.
+fn strip_trailing_newline(content: &str) -> &str {
+    content
+        .strip_suffix("\r\n")
+        .or_else(|| content.strip_suffix('\n'))
+        .unwrap_or(content)
+}
+
+fn longest_backtick_run(content: &str) -> usize {
+    content.split(|c| c != '`').map(str::len).max().unwrap_or(0)
+}
+
+/// Length in characters of the longest line of `content`.
+fn longest_line(content: &str) -> usize {
+    content
+        .lines()
+        .map(|line| line.chars().count())
+        .max()
+        .unwrap_or(0)
+}
+
+/// Cuts every line longer than `max` characters down to `max`, noting how much was dropped.
+/// Each line keeps its own terminator, `\n` or `\r\n`.
+fn truncate_long_lines(content: &str, max: usize) -> String {
+    content
+        .split_inclusive('\n')
+        .map(|line| {
+            let (body, ending) = split_line_ending(line);
+            match body.char_indices().nth(max) {
+                Some((cut, _)) => format!(
+                    "{} [truncated {} chars]{}",
+                    &body[..cut],
+                    body[cut..].chars().count(),
+                    ending
+                ),
+                None => line.to_string(),
+            }
+        })
+        .collect()
+}
+
+/// The leading whole lines of `content` that fit in `max` tokens followed by a marker
+/// counting the rest, or `None` when the whole file fits.
+fn truncate_to_tokens(
+    content: &str,
+    max: usize,
+    snap_to: Option<&[usize]>,
+    tokenizer: Option<Tokenizer>,
+) -> Option<String> {
+    let total = count_tokens(content, tokenizer);
+    if total <= max {
+        return None;
+    }
+
+    // Prefix counts grow with each line, so the last line end that fits can be bisected
+    let line_ends: Vec<usize> = content
+        .split_inclusive('\n')
+        .scan(0, |end, line| {
+            *end += line.len();
+            Some(*end)
+        })
+        .collect();
+    let fitting = line_ends.partition_point(|&end| count_tokens(&content[..end], tokenizer) <= max);
+    // Back up to where the last symbol that fits ends, unless none does
+    let fitting = snap_to
+        .and_then(|starts| {
+            starts
+                .iter()
+                .copied()
+                .filter(|&start| start > 0 && start <= fitting)
+                .max()
+        })
+        .unwrap_or(fitting);
+    let kept = fitting.checked_sub(1).map_or(0, |last| line_ends[last]);
+
+    let mut result = content[..kept].to_string();
+    if !result.is_empty() && !result.ends_with('\n') {
+        result.push('\n');
+    }
+    result.push_str(&format!(
+        "... (truncated, {} tokens omitted) ...\n",
+        total.saturating_sub(count_tokens(&result, tokenizer))
+    ));
+    Some(result)
+}
+
+// Line comment delimiters for the `file:` separators of a merged block and for TODO markers
+fn comment_delimiters(extension: &str) -> (&'static str, &'static str) {
+    match extension {
+        "sql" | "lua" | "hs" | "elm" => ("-- ", ""),
+        "py" | "sh" | "bash" | "rb" | "pl" | "r" | "toml" | "yaml" | "yml" | "ex" | "exs" => {
+            ("# ", "")
+        }
+        "html" | "htm" | "xml" | "md" | "vue" | "svelte" => ("<!-- ", " -->"),
+        "css" => ("/* ", " */"),
+        _ => ("// ", ""),
+    }
+}
+
+/// The lowercase extension of `path` when it is one of `extensions`.
+fn merge_extension(path: &Path, extensions: &[String]) -> Option<String> {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_lowercase)
+        .filter(|extension| extensions.contains(extension))
+}
+
+enum HeldBlock {
+    /// A rendered block and whether it ends on a closing fence without its newline.
+    Rendered(String, bool),
+    /// Index into `BlockSink::merged`.
+    Merged(usize),
+}
+
+/// Where rendered file blocks go. Without `--merge-ext` they're emitted as they come; with
+/// it, every block is held back until the end so each merged `*.ext` block can be emitted
+/// where its first file was.
+struct BlockSink<'a> {
+    emit: &'a mut dyn FnMut(&str) -> anyhow::Result<()>,
+    merge_extensions: &'a [String],
+    tokenizer: Option<Tokenizer>,
+    held: Vec<HeldBlock>,
+    /// Each merged extension with the sections of its files so far.
+    merged: Vec<(String, String)>,
+    /// Set while the last emitted block ends on a closing fence without its newline
+    open_fence: bool,
+}
+
+impl<'a> BlockSink<'a> {
+    fn new(
+        emit: &'a mut dyn FnMut(&str) -> anyhow::Result<()>,
+        options: &'a OutputOptions,
+    ) -> Self {
+        BlockSink {
+            emit,
+            merge_extensions: &options.merge_extensions,
+            tokenizer: options.tokenizer,
+            held: Vec::new(),
+            merged: Vec::new(),
+            open_fence: false,
+        }
+    }
+
+    fn push(&mut self, block: String, open_fence: bool) -> anyhow::Result<()> {
+        if self.merge_extensions.is_empty() {
+            (self.emit)(&block)?;
+            self.open_fence = open_fence;
+        } else {
+            self.held.push(HeldBlock::Rendered(block, open_fence));
+        }
+        Ok(())
+    }
+
+    /// Appends `file` to the merged block of its extension, after a `file: path` comment,
+    /// returning the tokens that comment adds. `None` when its extension isn't merged.
+    fn merge(&mut self, file: &FileContext) -> Option<usize> {
+        let extension = merge_extension(&file.path, self.merge_extensions)?;
+        let (open, close) = comment_delimiters(&extension);
+        let separator = format!("{}file: {}{}\n", open, file.path.display(), close);
+        let tokens = count_tokens(&separator, self.tokenizer);
+
+        let mut section = separator;
+        section.push_str(&file.content);
+        if !section.ends_with('\n') {
+            section.push('\n');
+        }
+        match self
+            .merged
+            .iter_mut()
+            .find(|(merged, _)| *merged == extension)
+        {
+            Some((_, block)) => {
+                block.push('\n');
+                block.push_str(&section);
+            }
+            None => {
+                debug!("Merging .{} files into one block", extension);
+                self.held.push(HeldBlock::Merged(self.merged.len()));
+                self.merged.push((extension, section));
+            }
+        }
+        Some(tokens)
+    }
+
+    /// Emits the held blocks, then the newline a last open fence still needs.
+    fn finish(mut self, fence: &str, fence_end: &str) -> anyhow::Result<()> {
+        for held in std::mem::take(&mut self.held) {
+            let (block, open_fence) = match held {
+                HeldBlock::Rendered(block, open_fence) => (block, open_fence),
+                HeldBlock::Merged(index) => {
+                    let (extension, content) = &self.merged[index];
+                    let path = PathBuf::from(format!("*.{}", extension));
+                    let block = format!(
+                        "\nFile: {}\n{}{}\n{}\n{}{}",
+                        path.display(),
+                        fence,
+                        fence_language_for(&path),
+                        strip_trailing_newline(content),
+                        fence,
+                        fence_end
+                    );
+                    (block, fence_end.is_empty())
+                }
+            };
+            (self.emit)(&block)?;
+            self.open_fence = open_fence;
+        }
+        // The last fence still ends its line, keeping the closing tag on a line of its own
+        if self.open_fence {
+            (self.emit)("\n")?;
+        }
+        Ok(())
+    }
+}
+
+fn compact_whitespace(content: &str, compact_blank_lines: bool, trim_trailing: bool) -> String {
+    let mut result = String::with_capacity(content.len());
+    // Terminators of the blank lines since the last non-blank one
+    let mut blank_run: Vec<&str> = Vec::new();
+
+    for line in content.split_inclusive('\n') {
+        let (body, ending) = split_line_ending(line);
+        let body = if trim_trailing { body.trim_end() } else { body };
+        if !compact_blank_lines {
+            result.push_str(body);
+            result.push_str(ending);
+            continue;
+        }
+
+        if body.trim().is_empty() {
+            blank_run.push(ending);
+            continue;
+        }
+        // Runs of one or two blank lines are kept; longer runs become one.
+        match blank_run.len() {
+            0 => {}
+            1 | 2 => blank_run.iter().for_each(|ending| result.push_str(ending)),
+            _ => result.push_str(blank_run[blank_run.len() - 1]),
+        }
+        blank_run.clear();
+        result.push_str(body);
+        result.push_str(ending);
+    }
+
+    if let Some(ending) = blank_run.last() {
+        result.push_str(ending);
+    }
+    result
+}
+
+// A line from `split_inclusive('\n')` and its terminator: `\n`, `\r\n`, or none at the end
+fn split_line_ending(line: &str) -> (&str, &str) {
+    let body = line.strip_suffix('\n').unwrap_or(line);
+    let body = body.strip_suffix('\r').unwrap_or(body);
+    (body, &line[body.len()..])
+}
+
+fn apply_anchors(content: &str, path: &Path, format: AnchorFormat) -> String {
+    if format == AnchorFormat::None {
+        return content.to_string();
+    }
+
+    let line_count = content.lines().count();
+    let width = line_count.to_string().len();
+    let path = path.display().to_string();
+
+    let mut anchored: Vec<String> = Vec::with_capacity(line_count);
+    for (index, line) in content.lines().enumerate() {
+        let number = index + 1;
+        anchored.push(match format {
+            AnchorFormat::None => line.to_string(),
+            AnchorFormat::Gutter => format!("{:>width$} | {}", number, line),
+            AnchorFormat::Prefix => format!("{}:{}: {}", path, number, line),
+        });
+    }
+
+    let mut result = anchored.join("\n");
+    if content.ends_with('\n') {
+        result.push('\n');
+    }
+    result
+}
+
+// Comment markers picked up by `--collect-todos`
+const TODO_MARKERS: &[&str] = &["TODO", "FIXME", "HACK"];
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+// Whether `before`, the start of a line up to a marker, opens a comment in the language of
+// `extension`; C-style languages also count `/*` blocks and their `*` continuation lines
+fn opens_comment(before: &str, extension: &str) -> bool {
+    let (open, _) = comment_delimiters(extension);
+    before.contains(open.trim_end())
+        || (open == "// " && (before.contains("/*") || before.trim_start().starts_with('*')))
+}
+
+/// `path:line: text` for every TODO/FIXME/HACK marker inside a comment, the text starting at
+/// the marker. Markers inside longer words (`TODOS`, `unHACKed`) or outside comments (a
+/// `"TODO"` string, a `TODO` identifier) don't count.
+fn collect_todos(path: &Path, content: &str) -> Vec<String> {
+    let extension = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    let mut todos = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        let marker_at = TODO_MARKERS.iter().find_map(|marker| {
+            line.match_indices(marker).map(|(at, _)| at).find(|&at| {
+                let before = line[..at].chars().next_back();
+                let after = line[at + marker.len()..].chars().next();
+                !before.is_some_and(is_word_char)
+                    && !after.is_some_and(is_word_char)
+                    && opens_comment(&line[..at], &extension)
+            })
+        });
+        if let Some(at) = marker_at {
+            todos.push(format!(
+                "{}:{}: {}",
+                path.display(),
+                index + 1,
+                line[at..].trim_end()
+            ));
+        }
+    }
+    todos
+}
+
+fn file_list_annotation(files: &[FileContext], roots: &[PathBuf]) -> String {
+    let mut list = String::from("Included files:");
+    for file in files {
+        let path = roots
+            .iter()
+            .find_map(|root| file.path.strip_prefix(root).ok())
+            .filter(|relative| !relative.as_os_str().is_empty())
+            .unwrap_or(&file.path);
+        list.push_str(&format!("\n- {}", path.display()));
+    }
+    list
+}
+
+const DIRECTORY_README: &str = "README.md";
+
+/// Inserts each directory's README ahead of the first selected file in that directory.
+/// READMEs that are already selected, empty, or that cannot be read are left alone.
+pub fn include_directory_readmes(
     files: Vec<FileContext>,
-    file_map: String,
-    user_prompt: Option<String>,
-) -> ContextOutput {
-    debug!("Building context output from {} files", files.len());
-    let mut file_contents = String::new();
+    file_reader: impl Fn(&PathBuf) -> anyhow::Result<String>,
+) -> Vec<FileContext> {
+    let selected: HashSet<PathBuf> = files.iter().map(|f| f.path.clone()).collect();
+    let mut seen_dirs = HashSet::new();
+    let mut result = Vec::with_capacity(files.len());
+
+    for file in files {
+        let dir = file.path.parent().unwrap_or(Path::new("")).to_path_buf();
+        if seen_dirs.insert(dir.clone()) {
+            let readme = dir.join(DIRECTORY_README);
+            // Most directories have no README, which isn't worth reading or reporting
+            if !selected.contains(&readme) && readme.is_file() {
+                match file_reader(&readme) {
+                    Ok(content) if content.trim().is_empty() => {
+                        debug!("Skipping empty README {}", readme.display());
+                    }
+                    Ok(content) => {
+                        debug!("Including directory README {}", readme.display());
+                        result.push(FileContext::new(readme, content));
+                    }
+                    Err(e) => warn!("Failed to read {}: {}", readme.display(), e),
+                }
+            }
+        }
+        result.push(file);
+    }
+
+    result
+}
+
+// Per-file results shared by the buffered and streaming builders.
+struct ProcessedFiles {
+    file_tokens: Vec<(PathBuf, usize)>,
+    files: Vec<FileContext>,
+    file_hashes: Vec<Option<String>>,
+    todos: Vec<String>,
+    /// Each file with the files it imports, before dropping files that weren't emitted.
+    imports: Vec<(PathBuf, Vec<PathBuf>)>,
+    total_tokens: usize,
+}
+
+/// Renders each file's block in order and hands it to `emit`. With `load`, files arrive
+/// unread: each is loaded just before its block and its content dropped right after.
+fn process_files(
+    files: Vec<FileContext>,
+    options: &OutputOptions,
+    show_progress: bool,
+    mut load: Option<&mut dyn FnMut(FileContext) -> anyhow::Result<Option<FileContext>>>,
+    emit: &mut dyn FnMut(&str) -> anyhow::Result<()>,
+) -> anyhow::Result<ProcessedFiles> {
+    let fence = "`".repeat(options.fence.unwrap_or(DEFAULT_FENCE_LEN));
+    let mut file_tokens = Vec::with_capacity(files.len());
+    let mut emitted_files = Vec::with_capacity(files.len());
+    let mut file_hashes = Vec::with_capacity(files.len());
+    let mut todos = Vec::new();
+    let mut imports = Vec::new();
+    let graph_paths: Vec<PathBuf> = match options.with_graph {
+        true => files.iter().map(|file| file.path.clone()).collect(),
+        false => Vec::new(),
+    };
+    let known_files = KnownFiles::new(&graph_paths);
     let mut total_tokens = 0;
+    let file_count = files.len();
+    let fence_end = if options.no_trailing_fence_newline {
+        ""
+    } else {
+        "\n"
+    };
+    let mut sink = BlockSink::new(emit, options);
+
+    if show_progress {
+        let mut stdout = stdout();
+        stdout.execute(SetForegroundColor(Color::Green)).unwrap();
+        writeln!(stdout, "\n🔄 Processing {} files...", files.len()).unwrap();
+        stdout.execute(ResetColor).unwrap();
+    }
+
+    for (index, file) in files.into_iter().enumerate() {
+        let mut file = match load.as_mut() {
+            Some(load) => match load(file)? {
+                Some(file) => file,
+                None => continue,
+            },
+            None => file,
+        };
+        // Of the content as read, so truncation or compaction don't change a file's hash
+        let hash = (options.with_hashes && file.binary_bytes.is_none())
+            .then(|| content_hash(&file.content));
+        if options.ipynb_extract && file.binary_bytes.is_none() && is_notebook(&file.path) {
+            match extract_notebook(&file.content) {
+                Ok(cells) => file.content = cells,
+                Err(e) => warn!(
+                    "Keeping {} as is, it isn't a readable notebook: {}",
+                    file.path.display(),
+                    e
+                ),
+            }
+        }
+        match file.binary_bytes {
+            Some(bytes) if options.binary_placeholder => {
+                let placeholder = format!(
+                    "\n<binary path=\"{}\" bytes=\"{}\"/>\n",
+                    escape_xml_attr(&file.path.display().to_string()),
+                    bytes
+                );
+                let tokens = count_tokens(&placeholder, options.tokenizer);
+                total_tokens += tokens;
+                file_tokens.push((file.path.clone(), tokens));
+
+                debug!("Adding binary placeholder for {}", file.path.display());
+                sink.push(placeholder, false)?;
+                emitted_files.push(file);
+                file_hashes.push(None);
+            }
+            Some(_) => {
+                debug!("Skipping binary file {}", file.path.display());
+            }
+            None => {
+                if let Some(max) = options.max_line_length
+                    && longest_line(&file.content) > max
+                {
+                    file.content = truncate_long_lines(&file.content, max);
+                }
+                // Before compaction or anchors, so line numbers match the file on disk
+                if options.collect_todos {
+                    todos.extend(collect_todos(&file.path, &file.content));
+                }
+                if options.with_graph {
+                    let local = imports_among(&file.path, &file.content, &known_files);
+                    if !local.is_empty() {
+                        imports.push((file.path.clone(), local));
+                    }
+                }
+                if options.symbols_only
+                    && let Some(outline) = outline(&file.path, &file.content)
+                {
+                    debug!("Reduced {} to its outline", file.path.display());
+                    file.content = outline;
+                }
+                if options.compact_blank_lines || options.trim_trailing_whitespace {
+                    file.content = compact_whitespace(
+                        &file.content,
+                        options.compact_blank_lines,
+                        options.trim_trailing_whitespace,
+                    );
+                }
+                if let Some(max) = options.max_file_tokens
+                    && let Some(truncated) = truncate_to_tokens(
+                        &file.content,
+                        max,
+                        options
+                            .snap_to_functions
+                            .then(|| symbol_starts(&file.path, &file.content))
+                            .flatten()
+                            .as_deref(),
+                        options.tokenizer,
+                    )
+                {
+                    debug!("Truncated {} to {} tokens", file.path.display(), max);
+                    file.content = truncated;
+                }
+                if options.anchor_format != AnchorFormat::None
+                    && file.content.lines().count() > options.anchor_min_lines
+                {
+                    file.content = apply_anchors(&file.content, &file.path, options.anchor_format);
+                }
 
-    let mut stdout = stdout();
-    stdout.execute(SetForegroundColor(Color::Green)).unwrap();
-    writeln!(stdout, "\n🔄 Processing {} files...", files.len()).unwrap();
-    stdout.execute(ResetColor).unwrap();
+                let tokens = count_tokens(&file.content, options.tokenizer);
+                total_tokens += tokens;
+                file_tokens.push((file.path.clone(), tokens));
 
-    for (index, file) in files.iter().enumerate() {
-        let tokens = count_tokens(&file.content);
-        total_tokens += tokens;
+                if let Some(fence_len) = options.fence {
+                    let run = longest_backtick_run(&file.content);
+                    if run >= fence_len {
+                        return Err(anyhow::anyhow!(
+                            "File {} contains a run of {} backticks, which would break the {}-backtick fence",
+                            file.path.display(),
+                            run,
+                            fence_len
+                        ));
+                    }
+                }
 
-        if index % 10 == 0 || index == files.len() - 1 {
+                let hash_attr = hash
+                    .as_ref()
+                    .map(|hash| format!(" hash=\"{}\"", hash))
+                    .unwrap_or_default();
+
+                debug!("Adding file {} with {} tokens", file.path.display(), tokens);
+                match sink.merge(&file) {
+                    Some(separator_tokens) => total_tokens += separator_tokens,
+                    None => sink.push(
+                        format!(
+                            "\nFile: {}{}\n{}{}\n{}\n{}{}",
+                            file.path.display(),
+                            hash_attr,
+                            fence,
+                            fence_language_for(&file.path),
+                            strip_trailing_newline(&file.content),
+                            fence,
+                            fence_end
+                        ),
+                        options.no_trailing_fence_newline,
+                    )?,
+                }
+                if load.is_some() {
+                    file.content = String::new();
+                }
+                emitted_files.push(file);
+                file_hashes.push(hash);
+            }
+        }
+
+        if show_progress && (index % 10 == 0 || index == file_count - 1) {
             print!(
-                "\r📦 Processed {}/{} files ({} tokens)",
+                "\r📦 Processed {}/{} files ({} tokens, {})",
                 index + 1,
-                files.len(),
-                total_tokens
+                file_count,
+                total_tokens,
+                token_count_method(options.tokenizer)
             );
             std::io::stdout().flush().unwrap();
         }
+    }
+    sink.finish(&fence, fence_end)?;
+    if show_progress {
+        println!();
+    }
 
-        debug!("Adding file {} with {} tokens", file.path.display(), tokens);
-        file_contents.push_str(&format!(
-            "\nFile: {}\n```{}\n{}\n```\n",
-            file.path.display(),
-            file.path.extension().and_then(|e| e.to_str()).unwrap_or(""),
-            file.content
-        ));
+    Ok(ProcessedFiles {
+        file_tokens,
+        files: emitted_files,
+        file_hashes,
+        todos,
+        imports,
+        total_tokens,
+    })
+}
+
+/// Rewrites `native` separators in file paths and the file map as forward slashes, unless
+/// the native separator was asked for. Paths only change where `native` isn't already `/`.
+fn apply_path_separator(
+    mut files: Vec<FileContext>,
+    file_map: String,
+    separator: PathSeparator,
+    native: char,
+) -> (Vec<FileContext>, String) {
+    if separator == PathSeparator::Native || native == '/' {
+        return (files, file_map);
+    }
+    for file in &mut files {
+        file.path = PathBuf::from(file.path.to_string_lossy().replace(native, "/"));
+    }
+    (files, file_map.replace(native, "/"))
+}
+
+pub fn build_context_output(
+    files: Vec<FileContext>,
+    file_map: String,
+    user_prompt: Option<String>,
+    options: &OutputOptions,
+) -> anyhow::Result<ContextOutput> {
+    debug!("Building context output from {} files", files.len());
+    let (files, file_map) =
+        apply_path_separator(files, file_map, options.path_separator, MAIN_SEPARATOR);
+    let priority_files = priority_paths(&files);
+    let mut file_contents = String::new();
+    let processed = process_files(files, options, true, None, &mut |block: &str| {
+        file_contents.push_str(block);
+        Ok(())
+    })?;
+    Ok(complete_output(
+        processed,
+        file_contents,
+        file_map,
+        priority_files,
+        user_prompt,
+        options,
+    ))
+}
+
+/// Selected files a streamed run hasn't read yet, and how to read them.
+pub struct UnreadFiles<'a> {
+    /// Paths and "must review" marks, with empty contents.
+    pub files: Vec<FileContext>,
+    /// Fills in the content of a file given under the path it was selected by; `None`
+    /// skips it.
+    pub load: &'a dyn Fn(FileContext) -> anyhow::Result<Option<FileContext>>,
+}
+
+/// Writes the XML context to `sink` piece by piece, reading each file just before its block
+/// and dropping its content after, so only one file is held at a time; with `--merge-ext`
+/// every block is still held until the end. The bytes match
+/// `format_output(.., OutputFormat::Xml)` on the buffered output; the returned output has
+/// empty `file_contents` and file contents.
+pub fn stream_context_output(
+    unread: UnreadFiles,
+    file_map: String,
+    user_prompt: Option<String>,
+    options: &OutputOptions,
+    project: Option<&ProjectSummary>,
+    command_outputs: Vec<CommandOutput>,
+    sink: &mut dyn FnMut(&str) -> anyhow::Result<()>,
+) -> anyhow::Result<ContextOutput> {
+    debug!("Streaming context output from {} files", unread.files.len());
+    // Rewriting separators changes the paths shown, not the ones the files are read from
+    let mut sources = unread
+        .files
+        .iter()
+        .map(|file| file.path.clone())
+        .collect::<Vec<_>>()
+        .into_iter();
+    let (files, file_map) = apply_path_separator(
+        unread.files,
+        file_map,
+        options.path_separator,
+        MAIN_SEPARATOR,
+    );
+    let project = project.map(render_project_summary).unwrap_or_default();
+    let priority_files = priority_paths(&files);
+    write_xml_head(&project, &file_map, &priority_files, sink)?;
+    let mut load = |mut file: FileContext| -> anyhow::Result<Option<FileContext>> {
+        let source = sources.next().unwrap_or_else(|| file.path.clone());
+        let shown = std::mem::replace(&mut file.path, source);
+        let priority = file.priority;
+        Ok((unread.load)(file)?.map(|loaded| FileContext {
+            path: shown,
+            priority,
+            ..loaded
+        }))
+    };
+    let processed = process_files(files, options, false, Some(&mut load), sink)?;
+    let mut output = complete_output(
+        processed,
+        String::new(),
+        file_map,
+        priority_files,
+        user_prompt,
+        options,
+    );
+    if !project.is_empty() {
+        output.token_count += count_tokens(&project, options.tokenizer);
+        output.project = project;
     }
-    println!();
+    attach_command_outputs(&mut output, command_outputs, options.tokenizer);
+    write_xml_tail(
+        &output.dependency_graph,
+        &output.todos,
+        &output.command_outputs,
+        &output.user_instructions,
+        sink,
+    )?;
+    Ok(output)
+}
+
+/// Tokens the header and fences around `path`'s block add to its content.
+pub fn file_block_overhead(path: &Path, options: &OutputOptions) -> usize {
+    let fence = "`".repeat(options.fence.unwrap_or(DEFAULT_FENCE_LEN));
+    count_tokens(
+        &format!(
+            "\nFile: {}\n{}{}\n\n{}\n",
+            path.display(),
+            fence,
+            fence_language_for(path),
+            fence
+        ),
+        options.tokenizer,
+    )
+}
+
+fn priority_paths(files: &[FileContext]) -> Vec<PathBuf> {
+    files
+        .iter()
+        .filter(|file| file.priority)
+        .map(|file| file.path.clone())
+        .collect()
+}
+
+fn priority_note(priority_files: &[PathBuf]) -> String {
+    let mut note = String::from("Review these files with particular attention:\n");
+    for path in priority_files {
+        note.push_str(&format!("- {}\n", path.display()));
+    }
+    note
+}
+
+fn complete_output(
+    processed: ProcessedFiles,
+    file_contents: String,
+    file_map: String,
+    priority_files: Vec<PathBuf>,
+    user_prompt: Option<String>,
+    options: &OutputOptions,
+) -> ContextOutput {
+    let ProcessedFiles {
+        file_tokens,
+        files: emitted_files,
+        file_hashes,
+        todos,
+        imports,
+        mut total_tokens,
+    } = processed;
+
+    let todo_tokens: usize = todos
+        .iter()
+        .map(|todo| count_tokens(todo, options.tokenizer))
+        .sum();
+    total_tokens += todo_tokens;
+    debug!(
+        "Collected {} TODOs with {} tokens",
+        todos.len(),
+        todo_tokens
+    );
+
+    let dependency_graph = render_dependency_graph(imports, &emitted_files);
+    let graph_tokens: usize = dependency_graph
+        .iter()
+        .map(|edge| count_tokens(edge, options.tokenizer))
+        .sum();
+    total_tokens += graph_tokens;
+    debug!("Dependency graph has {} tokens", graph_tokens);
 
-    let map_tokens = count_tokens(&file_map);
+    let map_tokens = count_tokens(&file_map, options.tokenizer);
     total_tokens += map_tokens;
     debug!("File map has {} tokens", map_tokens);
 
+    if !priority_files.is_empty() {
+        total_tokens += count_tokens(&priority_note(&priority_files), options.tokenizer);
+    }
+
+    let user_prompt = if options.prompt_file_list {
+        let list = file_list_annotation(&emitted_files, &options.list_roots);
+        Some(match user_prompt {
+            Some(prompt) => format!("{}\n\n{}", prompt, list),
+            None => list,
+        })
+    } else {
+        user_prompt
+    };
+
     let user_instructions = match user_prompt {
         Some(prompt) => {
             info!("Including user prompt in context");
-            let prompt_tokens = count_tokens(&prompt);
+            let prompt_tokens = count_tokens(&prompt, options.tokenizer);
             total_tokens += prompt_tokens;
             debug!("User prompt has {} tokens", prompt_tokens);
             prompt
@@ -83,87 +869,1885 @@ pub fn build_context_output(
         file_contents,
         user_instructions,
         token_count: total_tokens,
+        file_tokens,
+        files: emitted_files,
+        file_hashes,
+        project: String::new(),
+        todos,
+        dependency_graph,
+        priority_files,
+        command_outputs: Vec::new(),
+    }
+}
+
+// One `path -> import, ...` line per file importing other emitted files
+fn render_dependency_graph(
+    imports: Vec<(PathBuf, Vec<PathBuf>)>,
+    emitted_files: &[FileContext],
+) -> Vec<String> {
+    let emitted: HashSet<&PathBuf> = emitted_files.iter().map(|file| &file.path).collect();
+    imports
+        .into_iter()
+        .filter_map(|(path, imports)| {
+            let imports: Vec<String> = imports
+                .iter()
+                .filter(|import| emitted.contains(import))
+                .map(|import| import.display().to_string())
+                .collect();
+            (!imports.is_empty()).then(|| format!("{} -> {}", path.display(), imports.join(", ")))
+        })
+        .collect()
+}
+
+// Dependencies beyond this many are summarized as a count
+const MAX_SUMMARY_DEPENDENCIES: usize = 12;
+
+fn render_project_summary(summary: &ProjectSummary) -> String {
+    let mut project = format!("name: {}\n", summary.name);
+    if let Some(version) = &summary.version {
+        project.push_str(&format!("version: {}\n", version));
+    }
+    if !summary.dependencies.is_empty() {
+        let shown =
+            &summary.dependencies[..summary.dependencies.len().min(MAX_SUMMARY_DEPENDENCIES)];
+        project.push_str(&format!("dependencies: {}", shown.join(", ")));
+        let hidden = summary.dependencies.len() - shown.len();
+        if hidden > 0 {
+            project.push_str(&format!(" (+{} more)", hidden));
+        }
+        project.push('\n');
+    }
+    project.push_str(&format!("source: {}\n", summary.manifest));
+    project
+}
+
+/// Renders the project summary into the output's `<project>` section and counts its tokens.
+pub fn attach_project_summary(
+    output: &mut ContextOutput,
+    summary: &ProjectSummary,
+    tokenizer: Option<Tokenizer>,
+) {
+    let project = render_project_summary(summary);
+    let tokens = count_tokens(&project, tokenizer);
+    debug!("Project summary has {} tokens", tokens);
+    output.token_count += tokens;
+    output.project = project;
+}
+
+fn render_command_output(run: &CommandOutput) -> String {
+    let exit_code = run
+        .exit_code
+        .map(|code| format!(" exit_code=\"{}\"", code))
+        .unwrap_or_default();
+    let mut section = format!(
+        "<command_output cmd=\"{}\"{}>\n{}",
+        escape_xml_attr(&run.command),
+        exit_code,
+        run.output
+    );
+    if !run.output.is_empty() && !run.output.ends_with('\n') {
+        section.push('\n');
+    }
+    section.push_str("</command_output>");
+    section
+}
+
+/// Adds `--attach-command` results as `<command_output>` sections and counts their tokens.
+pub fn attach_command_outputs(
+    output: &mut ContextOutput,
+    runs: Vec<CommandOutput>,
+    tokenizer: Option<Tokenizer>,
+) {
+    for run in &runs {
+        let tokens = count_tokens(&render_command_output(run), tokenizer);
+        debug!("Output of `{}` has {} tokens", run.command, tokens);
+        output.token_count += tokens;
+    }
+    output.command_outputs = runs;
+}
+
+/// Per-file token counts, largest first, for `--show-token-breakdown`.
+pub fn token_breakdown(output: &ContextOutput) -> Vec<(PathBuf, usize)> {
+    let mut breakdown = output.file_tokens.clone();
+    breakdown.sort_by(|(a_path, a), (b_path, b)| b.cmp(a).then_with(|| a_path.cmp(b_path)));
+    breakdown
+}
+
+/// Files taking more than `max_percent` of the output's tokens, largest first, with their
+/// share in percent. A lone file is never flagged, since it has nothing to crowd out.
+pub fn dominant_files(output: &ContextOutput, max_percent: u8) -> Vec<(PathBuf, f64)> {
+    if output.file_tokens.len() < 2 {
+        return Vec::new();
     }
+    let total = output.token_count.max(1) as f64;
+    token_breakdown(output)
+        .into_iter()
+        .map(|(path, tokens)| (path, tokens as f64 * 100.0 / total))
+        .take_while(|(_, share)| *share > f64::from(max_percent))
+        .collect()
 }
 
-pub fn format_output(output: &ContextOutput) -> String {
+pub fn format_output(output: &ContextOutput, format: OutputFormat) -> anyhow::Result<String> {
     debug!(
-        "Formatting context output with {} tokens",
-        output.token_count
+        "Formatting context output as {:?} with {} tokens",
+        format, output.token_count
     );
-    let mut result = String::new();
 
-    result.push_str("<file_map>\n");
-    result.push_str(&output.file_map);
-    result.push_str("</file_map>\n\n\n");
+    match format {
+        OutputFormat::Xml => Ok(format_xml(output)),
+        OutputFormat::Json => Ok(serde_json::to_string_pretty(&build_json_context(output))?),
+        OutputFormat::Tsv => Ok(format_tsv(output)),
+        OutputFormat::HtmlDetails => Ok(format_html_details(output)),
+        OutputFormat::Markdown => Ok(format_markdown(output)),
+    }
+}
+
+fn write_xml_head(
+    project: &str,
+    file_map: &str,
+    priority_files: &[PathBuf],
+    sink: &mut dyn FnMut(&str) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    if !project.is_empty() {
+        sink("<project>\n")?;
+        sink(project)?;
+        sink("</project>\n\n")?;
+    }
 
-    result.push_str("<file_contents>");
-    result.push_str(&output.file_contents);
-    result.push_str("</file_contents>");
+    sink("<file_map>\n")?;
+    sink(file_map)?;
+    sink("</file_map>\n\n\n")?;
 
-    if !output.user_instructions.is_empty() {
-        result.push_str("\n\n<user_instructions>\n");
-        result.push_str(&output.user_instructions);
-        result.push_str("\n</user_instructions>");
+    if !priority_files.is_empty() {
+        sink("<priority_files>\n")?;
+        sink(&priority_note(priority_files))?;
+        sink("</priority_files>\n\n")?;
     }
 
-    result
+    sink("<file_contents>")
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::path::PathBuf;
+fn write_xml_tail(
+    dependency_graph: &[String],
+    todos: &[String],
+    command_outputs: &[CommandOutput],
+    user_instructions: &str,
+    sink: &mut dyn FnMut(&str) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    sink("</file_contents>")?;
 
-    #[test]
-    fn test_count_tokens() {
-        assert!(count_tokens("hello world") > 0);
-        assert!(count_tokens("") == 0);
-        assert!(count_tokens("one\ntwo\nthree") > 2);
+    for run in command_outputs {
+        sink("\n\n")?;
+        sink(&render_command_output(run))?;
+    }
 
-        let normal_text = "This is some normal text with a few words.";
-        let code_text = "```rust\nfn main() {\n    println!(\"Hello, world!\");\n}\n```";
+    if !dependency_graph.is_empty() {
+        sink("\n\n<dependency_graph>\n")?;
+        for edge in dependency_graph {
+            sink(edge)?;
+            sink("\n")?;
+        }
+        sink("</dependency_graph>")?;
+    }
 
-        assert!(count_tokens(code_text) > count_tokens(normal_text));
+    if !todos.is_empty() {
+        sink("\n\n<todos>\n")?;
+        for todo in todos {
+            sink(todo)?;
+            sink("\n")?;
+        }
+        sink("</todos>")?;
     }
 
-    #[test]
-    fn test_build_context_output() {
-        let files = vec![
-            FileContext {
-                path: PathBuf::from("test/file1.rs"),
-                content: "fn test() {}".to_string(),
-            },
-            FileContext {
-                path: PathBuf::from("test/file2.rs"),
-                content: "struct Test {}".to_string(),
-            },
-        ];
+    if !user_instructions.is_empty() {
+        sink("\n\n<user_instructions>\n")?;
+        sink(user_instructions)?;
+        sink("\n</user_instructions>")?;
+    }
 
-        let file_map = "test\n├── test/file1.rs\n├── test/file2.rs\n".to_string();
-        let user_prompt = Some("Refactor this code".to_string());
+    Ok(())
+}
+
+fn format_xml(output: &ContextOutput) -> String {
+    render_xml(output, &output.user_instructions)
+}
 
-        let output = build_context_output(files, file_map, user_prompt);
+fn render_xml(output: &ContextOutput, user_instructions: &str) -> String {
+    let mut result = String::new();
+    let mut push = |piece: &str| {
+        result.push_str(piece);
+        Ok(())
+    };
 
-        assert!(output.token_count > 0);
-        assert_eq!(output.user_instructions, "Refactor this code");
-        assert!(output.file_contents.contains("fn test() {}"));
-        assert!(output.file_contents.contains("struct Test {}"));
+    // Writing into a String cannot fail
+    write_xml_head(
+        &output.project,
+        &output.file_map,
+        &output.priority_files,
+        &mut push,
+    )
+    .unwrap();
+    push(&output.file_contents).unwrap();
+    write_xml_tail(
+        &output.dependency_graph,
+        &output.todos,
+        &output.command_outputs,
+        user_instructions,
+        &mut push,
+    )
+    .unwrap();
+
+    result
+}
+
+/// Placeholder in an `--into` template that receives the formatted context.
+pub const TEMPLATE_MARKER: &str = "{{RICH_PROMPT_CONTEXT}}";
+
+/// Fails when `template` has no `TEMPLATE_MARKER` for the context to go at.
+pub fn check_template(template: &str) -> anyhow::Result<()> {
+    if !template.contains(TEMPLATE_MARKER) {
+        return Err(anyhow::anyhow!(
+            "no {} marker to place the context at",
+            TEMPLATE_MARKER
+        ));
     }
+    Ok(())
+}
 
-    #[test]
-    fn test_format_output() {
-        let output = ContextOutput {
-            file_map: "dir1\n".to_string(),
-            file_contents: "content1\n".to_string(),
-            user_instructions: "prompt1".to_string(),
-            token_count: 3,
-        };
+/// Replaces every `TEMPLATE_MARKER` in `template` with `context`, keeping the text around it.
+pub fn fill_template(template: &str, context: &str) -> anyhow::Result<String> {
+    check_template(template)?;
+    debug!(
+        "Filling {} template markers",
+        template.matches(TEMPLATE_MARKER).count()
+    );
+    Ok(template.replace(TEMPLATE_MARKER, context))
+}
 
-        let formatted = format_output(&output);
+// User message when no instructions were given; chat APIs reject empty messages
+const DEFAULT_CHAT_REQUEST: &str = "Review the files in the provided context.";
 
-        assert!(formatted.contains("<file_map>\ndir1\n</file_map>"));
-        assert!(formatted.contains("<file_contents>content1\n</file_contents>"));
-        assert!(formatted.contains("<user_instructions>\nprompt1\n</user_instructions>"));
+/// A chat request for `provider`: the context without its instructions goes in the system
+/// message, the instructions in the user message.
+pub fn format_chat(output: &ContextOutput, provider: ChatProvider) -> anyhow::Result<String> {
+    debug!("Formatting context output as a {:?} chat request", provider);
+    let system = render_xml(output, "");
+    let user = ChatMessage {
+        role: "user".to_string(),
+        content: if output.user_instructions.is_empty() {
+            DEFAULT_CHAT_REQUEST.to_string()
+        } else {
+            output.user_instructions.clone()
+        },
+    };
+
+    let request = match provider {
+        ChatProvider::Openai => serde_json::to_string_pretty(&OpenAiChat {
+            messages: vec![
+                ChatMessage {
+                    role: "system".to_string(),
+                    content: system,
+                },
+                user,
+            ],
+        })?,
+        ChatProvider::Anthropic => serde_json::to_string_pretty(&AnthropicChat {
+            system,
+            messages: vec![user],
+        })?,
+    };
+    Ok(request)
+}
+
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// HTML flavor of the context for rich-text paste targets: every file block becomes a
+/// `<pre><code>` tagged with its language so editors can highlight it.
+pub fn render_html(output: &ContextOutput) -> String {
+    let mut html = String::from("<div class=\"rich-prompt\">\n");
+
+    html.push_str("<h2>File map</h2>\n<pre>");
+    html.push_str(&escape_html(&output.file_map));
+    html.push_str("</pre>\n");
+
+    html.push_str("<h2>File contents</h2>\n");
+    for file in &output.files {
+        let path = escape_html(&file.path.display().to_string());
+        match file.binary_bytes {
+            Some(bytes) => {
+                html.push_str(&format!(
+                    "<p><code>{}</code> (binary, {} bytes)</p>\n",
+                    path, bytes
+                ));
+            }
+            None => {
+                html.push_str(&format!(
+                    "<h3>{}</h3>\n<pre><code class=\"language-{}\">{}</code></pre>\n",
+                    path,
+                    escape_html(fence_language_for(&file.path)),
+                    escape_html(&file.content)
+                ));
+            }
+        }
+    }
+
+    if !output.user_instructions.is_empty() {
+        html.push_str("<h2>User instructions</h2>\n<p>");
+        html.push_str(&escape_html(&output.user_instructions).replace('\n', "<br>\n"));
+        html.push_str("</p>\n");
+    }
+
+    html.push_str("</div>\n");
+    html
+}
+
+// A collapsed block; GitHub needs the blank line after `</summary>` to render the body
+fn details_block(summary: &str, body: &str) -> String {
+    format!(
+        "<details>\n<summary>{}</summary>\n\n{}\n</details>\n",
+        summary, body
+    )
+}
+
+fn html_list(items: &[String]) -> String {
+    let entries: String = items
+        .iter()
+        .map(|item| format!("<li>{}</li>\n", escape_html(item)))
+        .collect();
+    format!("<ul>\n{}</ul>", entries)
+}
+
+/// GitHub-friendly context: the file map and every file in its own collapsed `<details>`
+/// block, so reviewers expand only what they need.
+fn format_html_details(output: &ContextOutput) -> String {
+    let mut blocks = Vec::new();
+    if !output.project.is_empty() {
+        blocks.push(details_block(
+            "Project",
+            &format!("<pre>{}</pre>", escape_html(output.project.trim_end())),
+        ));
+    }
+    blocks.push(details_block(
+        &format!("File map ({} files)", output.files.len()),
+        &format!("<pre>{}</pre>", escape_html(&output.file_map)),
+    ));
+    if !output.priority_files.is_empty() {
+        let paths: Vec<String> = output
+            .priority_files
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect();
+        blocks.push(details_block("Priority files", &html_list(&paths)));
+    }
+
+    for file in &output.files {
+        let path = escape_html(&file.path.display().to_string());
+        let body = match file.binary_bytes {
+            Some(bytes) => format!("<p>Binary file, {} bytes</p>", bytes),
+            None => format!("<pre><code>{}</code></pre>", escape_html(&file.content)),
+        };
+        blocks.push(details_block(&path, &body));
+    }
+
+    for run in &output.command_outputs {
+        blocks.push(details_block(
+            &format!("Output of <code>{}</code>", escape_html(&run.command)),
+            &format!("<pre>{}</pre>", escape_html(&run.output)),
+        ));
+    }
+    if !output.dependency_graph.is_empty() {
+        blocks.push(details_block(
+            "Dependency graph",
+            &html_list(&output.dependency_graph),
+        ));
+    }
+    if !output.todos.is_empty() {
+        blocks.push(details_block(
+            &format!("TODOs ({})", output.todos.len()),
+            &html_list(&output.todos),
+        ));
+    }
+
+    let mut result = blocks.join("\n");
+    if !output.user_instructions.is_empty() {
+        result.push('\n');
+        result.push_str(&escape_html(&output.user_instructions));
+        result.push('\n');
+    }
+
+    result
+}
+
+// A fence longer than any backtick run in `content`, so the content can't close it early
+fn markdown_fence(content: &str) -> String {
+    "`".repeat(longest_backtick_run(content).max(DEFAULT_FENCE_LEN - 1) + 1)
+}
+
+fn fenced(content: &str, language: &str) -> String {
+    let fence = markdown_fence(content);
+    format!(
+        "{}{}\n{}\n{}",
+        fence,
+        language,
+        strip_trailing_newline(content),
+        fence
+    )
+}
+
+/// Markdown context: a heading per section and each file in its own fenced block.
+fn format_markdown(output: &ContextOutput) -> String {
+    let mut sections = Vec::new();
+    if !output.project.is_empty() {
+        sections.push(format!("## Project\n\n{}", output.project.trim_end()));
+    }
+    sections.push(format!("## File map\n\n{}", fenced(&output.file_map, "")));
+    if !output.priority_files.is_empty() {
+        sections.push(format!(
+            "## Priority files\n\n{}",
+            strip_trailing_newline(&priority_note(&output.priority_files))
+        ));
+    }
+
+    sections.push("## Files".to_string());
+    for file in &output.files {
+        let body = match file.binary_bytes {
+            Some(bytes) => format!("Binary file, {} bytes", bytes),
+            None => fenced(&file.content, fence_language_for(&file.path)),
+        };
+        sections.push(format!("### `{}`\n\n{}", file.path.display(), body));
+    }
+
+    for run in &output.command_outputs {
+        sections.push(format!(
+            "## Output of `{}`\n\n{}",
+            run.command,
+            fenced(&run.output, "")
+        ));
+    }
+    if !output.dependency_graph.is_empty() {
+        sections.push(format!(
+            "## Dependency graph\n\n{}",
+            markdown_list(&output.dependency_graph)
+        ));
+    }
+    if !output.todos.is_empty() {
+        sections.push(format!("## TODOs\n\n{}", markdown_list(&output.todos)));
+    }
+    if !output.user_instructions.is_empty() {
+        sections.push(format!("## Instructions\n\n{}", output.user_instructions));
+    }
+
+    sections.join("\n\n") + "\n"
+}
+
+fn markdown_list(items: &[String]) -> String {
+    items
+        .iter()
+        .map(|item| format!("- {}", item))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+struct FileLanguage {
+    extensions: &'static [&'static str],
+    /// Code fence info string LLMs and highlighters expect.
+    fence: &'static str,
+    /// Display name used in file stats.
+    name: &'static str,
+}
+
+const LANGUAGES: &[FileLanguage] = &[
+    FileLanguage {
+        extensions: &["rs"],
+        fence: "rust",
+        name: "Rust",
+    },
+    FileLanguage {
+        extensions: &["py", "pyi"],
+        fence: "python",
+        name: "Python",
+    },
+    FileLanguage {
+        extensions: &["js", "mjs", "cjs", "jsx"],
+        fence: "javascript",
+        name: "JavaScript",
+    },
+    FileLanguage {
+        extensions: &["ts", "mts", "cts", "tsx"],
+        fence: "typescript",
+        name: "TypeScript",
+    },
+    FileLanguage {
+        extensions: &["go"],
+        fence: "go",
+        name: "Go",
+    },
+    FileLanguage {
+        extensions: &["java"],
+        fence: "java",
+        name: "Java",
+    },
+    FileLanguage {
+        extensions: &["kt", "kts"],
+        fence: "kotlin",
+        name: "Kotlin",
+    },
+    FileLanguage {
+        extensions: &["c", "h"],
+        fence: "c",
+        name: "C",
+    },
+    FileLanguage {
+        extensions: &["cc", "cpp", "cxx", "hpp", "hh"],
+        fence: "cpp",
+        name: "C++",
+    },
+    FileLanguage {
+        extensions: &["cs"],
+        fence: "csharp",
+        name: "C#",
+    },
+    FileLanguage {
+        extensions: &["rb"],
+        fence: "ruby",
+        name: "Ruby",
+    },
+    FileLanguage {
+        extensions: &["php"],
+        fence: "php",
+        name: "PHP",
+    },
+    FileLanguage {
+        extensions: &["swift"],
+        fence: "swift",
+        name: "Swift",
+    },
+    FileLanguage {
+        extensions: &["sh", "bash", "zsh"],
+        fence: "bash",
+        name: "Shell",
+    },
+    FileLanguage {
+        extensions: &["html", "htm"],
+        fence: "html",
+        name: "HTML",
+    },
+    FileLanguage {
+        extensions: &["css"],
+        fence: "css",
+        name: "CSS",
+    },
+    FileLanguage {
+        extensions: &["scss"],
+        fence: "scss",
+        name: "CSS",
+    },
+    FileLanguage {
+        extensions: &["md", "markdown"],
+        fence: "markdown",
+        name: "Markdown",
+    },
+    FileLanguage {
+        extensions: &["json"],
+        fence: "json",
+        name: "JSON",
+    },
+    FileLanguage {
+        extensions: &["toml"],
+        fence: "toml",
+        name: "TOML",
+    },
+    FileLanguage {
+        extensions: &["yaml", "yml"],
+        fence: "yaml",
+        name: "YAML",
+    },
+    FileLanguage {
+        extensions: &["sql"],
+        fence: "sql",
+        name: "SQL",
+    },
+    FileLanguage {
+        extensions: &["hs"],
+        fence: "haskell",
+        name: "Haskell",
+    },
+    FileLanguage {
+        extensions: &["ex", "exs"],
+        fence: "elixir",
+        name: "Elixir",
+    },
+    FileLanguage {
+        extensions: &["proto"],
+        fence: "protobuf",
+        name: "Protocol Buffers",
+    },
+    FileLanguage {
+        extensions: &["tf"],
+        fence: "hcl",
+        name: "HCL",
+    },
+    FileLanguage {
+        extensions: &["ps1"],
+        fence: "powershell",
+        name: "PowerShell",
+    },
+];
+
+// The language of `path`'s extension, matched case-insensitively
+fn file_language(path: &Path) -> Option<&'static FileLanguage> {
+    let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+    LANGUAGES
+        .iter()
+        .find(|language| language.extensions.contains(&extension.as_str()))
+}
+
+/// Code fence info string for a file: the language name LLMs and highlighters expect, from
+/// well-known file names first, then the extension. Unknown extensions are used as-is.
+fn fence_language_for(path: &Path) -> &str {
+    let file_name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    let by_name = match file_name {
+        "Dockerfile" | "Containerfile" => Some("dockerfile"),
+        "Makefile" | "GNUmakefile" | "makefile" => Some("makefile"),
+        "CMakeLists.txt" => Some("cmake"),
+        "Gemfile" | "Rakefile" | "Vagrantfile" => Some("ruby"),
+        "Jenkinsfile" => Some("groovy"),
+        ".gitignore" | ".dockerignore" | ".gitattributes" => Some("gitignore"),
+        ".bashrc" | ".bash_profile" | ".zshrc" | ".profile" => Some("bash"),
+        ".editorconfig" => Some("ini"),
+        _ if file_name.starts_with("Dockerfile.") => Some("dockerfile"),
+        _ => None,
+    };
+    if let Some(language) = by_name {
+        return language;
+    }
+
+    match file_language(path) {
+        Some(language) => language.fence,
+        None => path.extension().and_then(|e| e.to_str()).unwrap_or(""),
+    }
+}
+
+fn language_for(path: &Path) -> &'static str {
+    file_language(path).map_or("Other", |language| language.name)
+}
+
+fn tsv_field(value: &str) -> String {
+    value.replace(['\t', '\n', '\r'], " ")
+}
+
+fn format_tsv(output: &ContextOutput) -> String {
+    let mut result = String::from("path\tlanguage\tbytes\tlines\ttokens\n");
+
+    for (file, (_, tokens)) in output.files.iter().zip(&output.file_tokens) {
+        let (bytes, lines) = match file.binary_bytes {
+            Some(bytes) => (bytes, 0),
+            None => (file.content.len() as u64, file.content.lines().count()),
+        };
+        result.push_str(&format!(
+            "{}\t{}\t{}\t{}\t{}\n",
+            tsv_field(&file.path.display().to_string()),
+            language_for(&file.path),
+            bytes,
+            lines,
+            tokens
+        ));
+    }
+
+    result
+}
+
+fn extension_key(path: &Path) -> String {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase())
+        .unwrap_or_else(|| "(none)".to_string())
+}
+
+fn build_json_context(output: &ContextOutput) -> JsonContext {
+    let mut by_extension: BTreeMap<String, ExtensionSummary> = BTreeMap::new();
+    let mut totals = ExtensionSummary::default();
+    let mut files = Vec::with_capacity(output.files.len());
+
+    for (index, (file, (_, tokens))) in output.files.iter().zip(&output.file_tokens).enumerate() {
+        let summary = by_extension.entry(extension_key(&file.path)).or_default();
+        summary.files += 1;
+        summary.tokens += tokens;
+        totals.files += 1;
+        totals.tokens += tokens;
+
+        files.push(JsonFile {
+            path: file.path.display().to_string(),
+            content: file.content.clone(),
+            token_count: *tokens,
+            binary_bytes: file.binary_bytes,
+            hash: output.file_hashes.get(index).cloned().flatten(),
+        });
+    }
+
+    JsonContext {
+        project: output.project.clone(),
+        todos: output.todos.clone(),
+        dependency_graph: output.dependency_graph.clone(),
+        priority_files: output
+            .priority_files
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect(),
+        command_outputs: output.command_outputs.clone(),
+        file_map: output.file_map.clone(),
+        files,
+        user_instructions: output.user_instructions.clone(),
+        token_count: output.token_count,
+        by_extension,
+        totals,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_bpe_token_count() {
+        let text = "fn main() {\n    println!(\"Hello, world!\");\n}\n";
+
+        // 45 characters with indentation: 12 estimated, plus 10% for code
+        assert_eq!(count_tokens(text, None), 13);
+        assert_eq!(count_tokens(text, Some(Tokenizer::Cl100kBase)), 12);
+        assert_eq!(count_tokens(text, Some(Tokenizer::O200kBase)), 12);
+        assert_eq!(count_tokens("", Some(Tokenizer::Cl100kBase)), 0);
+    }
+
+    #[test]
+    fn test_count_tokens() {
+        assert!(count_tokens("hello world", None) > 0);
+        assert!(count_tokens("", None) == 0);
+        assert!(count_tokens("one\ntwo\nthree", None) > 2);
+
+        let normal_text = "This is some normal text with a few words.";
+        let code_text = "```rust\nfn main() {\n    println!(\"Hello, world!\");\n}\n```";
+
+        assert!(count_tokens(code_text, None) > count_tokens(normal_text, None));
+    }
+
+    #[test]
+    fn test_build_context_output() {
+        let files = vec![
+            FileContext::new(PathBuf::from("test/file1.rs"), "fn test() {}".to_string()),
+            FileContext::new(PathBuf::from("test/file2.rs"), "struct Test {}".to_string()),
+        ];
+
+        let file_map = "test\n├── test/file1.rs\n├── test/file2.rs\n".to_string();
+        let user_prompt = Some("Refactor this code".to_string());
+
+        let output =
+            build_context_output(files, file_map, user_prompt, &OutputOptions::default()).unwrap();
+
+        assert!(output.token_count > 0);
+        assert_eq!(output.user_instructions, "Refactor this code");
+        assert!(output.file_contents.contains("fn test() {}"));
+        assert!(output.file_contents.contains("struct Test {}"));
+    }
+
+    #[test]
+    fn test_trailing_newline_does_not_change_fence_spacing() {
+        let with_newline = vec![FileContext::new(
+            PathBuf::from("a.rs"),
+            "fn a() {}\n".to_string(),
+        )];
+        let without_newline = vec![FileContext::new(
+            PathBuf::from("a.rs"),
+            "fn a() {}".to_string(),
+        )];
+
+        let first =
+            build_context_output(with_newline, String::new(), None, &OutputOptions::default())
+                .unwrap();
+        let second = build_context_output(
+            without_newline,
+            String::new(),
+            None,
+            &OutputOptions::default(),
+        )
+        .unwrap();
+
+        assert_eq!(first.file_contents, second.file_contents);
+        assert_eq!(
+            first.file_contents,
+            "\nFile: a.rs\n```rust\nfn a() {}\n```\n"
+        );
+    }
+
+    #[test]
+    fn test_fence_language_for() {
+        let language = |path: &str| fence_language_for(Path::new(path)).to_string();
+
+        assert_eq!(language("src/main.rs"), "rust");
+        assert_eq!(language("app/models.py"), "python");
+        assert_eq!(language("web/App.TSX"), "typescript");
+        assert_eq!(language("deploy/Dockerfile"), "dockerfile");
+        assert_eq!(language("Dockerfile.dev"), "dockerfile");
+        assert_eq!(language("Makefile"), "makefile");
+        assert_eq!(language(".gitignore"), "gitignore");
+        assert_eq!(language("config.json"), "json");
+        assert_eq!(language("schema.graphql"), "graphql");
+        assert_eq!(language("LICENSE"), "");
+
+        // File stats name languages from the same mapping
+        assert_eq!(language_for(Path::new("web/App.TSX")), "TypeScript");
+        assert_eq!(language_for(Path::new("stubs/os.pyi")), "Python");
+        assert_eq!(language_for(Path::new("schema.graphql")), "Other");
+    }
+
+    #[test]
+    fn test_no_trailing_fence_newline() {
+        let files = || {
+            vec![
+                FileContext::new(PathBuf::from("a.rs"), "fn a() {}\n".to_string()),
+                FileContext::new(PathBuf::from("b.rs"), "fn b() {}".to_string()),
+            ]
+        };
+        let render = |no_trailing_fence_newline| {
+            let options = OutputOptions {
+                no_trailing_fence_newline,
+                ..OutputOptions::default()
+            };
+            build_context_output(files(), String::new(), None, &options)
+                .unwrap()
+                .file_contents
+        };
+
+        assert_eq!(
+            render(false),
+            "\nFile: a.rs\n```rust\nfn a() {}\n```\n\nFile: b.rs\n```rust\nfn b() {}\n```\n"
+        );
+        assert_eq!(
+            render(true),
+            "\nFile: a.rs\n```rust\nfn a() {}\n```\nFile: b.rs\n```rust\nfn b() {}\n```\n"
+        );
+    }
+
+    #[test]
+    fn test_fixed_fence_length() {
+        let files = vec![FileContext::new(
+            PathBuf::from("README.md"),
+            "Use ``` to open a code block".to_string(),
+        )];
+        let options = OutputOptions {
+            fence: Some(4),
+            ..Default::default()
+        };
+
+        let output = build_context_output(files, String::new(), None, &options).unwrap();
+
+        assert!(output.file_contents.contains("\n````markdown\n"));
+        assert!(output.file_contents.ends_with("\n````\n"));
+    }
+
+    #[test]
+    fn test_fixed_fence_rejects_longer_backtick_run() {
+        let files = vec![FileContext::new(
+            PathBuf::from("notes.md"),
+            "````\nnested\n````".to_string(),
+        )];
+        let options = OutputOptions {
+            fence: Some(4),
+            ..Default::default()
+        };
+
+        let err = build_context_output(files, String::new(), None, &options).unwrap_err();
+
+        assert!(err.to_string().contains("notes.md"));
+        assert!(err.to_string().contains("4-backtick fence"));
+    }
+
+    #[test]
+    fn test_binary_placeholder() {
+        let files = vec![
+            FileContext::new(PathBuf::from("src/main.rs"), "fn main() {}".to_string()),
+            FileContext::binary(PathBuf::from("assets/logo.png"), 20481),
+        ];
+        let options = OutputOptions {
+            binary_placeholder: true,
+            ..Default::default()
+        };
+
+        let output = build_context_output(files, String::new(), None, &options).unwrap();
+
+        assert!(
+            output
+                .file_contents
+                .contains("<binary path=\"assets/logo.png\" bytes=\"20481\"/>")
+        );
+        assert!(!output.file_contents.contains("File: assets/logo.png"));
+        assert_eq!(output.file_tokens.len(), 2);
+    }
+
+    #[test]
+    fn test_binary_files_omitted_without_placeholder() {
+        let files = vec![
+            FileContext::new(PathBuf::from("src/main.rs"), "fn main() {}".to_string()),
+            FileContext::binary(PathBuf::from("assets/logo.png"), 20481),
+        ];
+
+        let output =
+            build_context_output(files, String::new(), None, &OutputOptions::default()).unwrap();
+
+        assert!(!output.file_contents.contains("logo.png"));
+        assert_eq!(output.file_tokens.len(), 1);
+    }
+
+    #[test]
+    fn test_format_output() {
+        let output = ContextOutput {
+            file_map: "dir1\n".to_string(),
+            file_contents: "content1\n".to_string(),
+            user_instructions: "prompt1".to_string(),
+            token_count: 3,
+            file_tokens: vec![(PathBuf::from("file1"), 1)],
+            files: vec![FileContext::new(
+                PathBuf::from("file1"),
+                "content1".to_string(),
+            )],
+            file_hashes: vec![None],
+            project: String::new(),
+            todos: Vec::new(),
+            dependency_graph: Vec::new(),
+            priority_files: Vec::new(),
+            command_outputs: Vec::new(),
+        };
+
+        let formatted = format_output(&output, OutputFormat::Xml).unwrap();
+
+        assert!(formatted.contains("<file_map>\ndir1\n</file_map>"));
+        assert!(formatted.contains("<file_contents>content1\n</file_contents>"));
+        assert!(formatted.contains("<user_instructions>\nprompt1\n</user_instructions>"));
+    }
+
+    #[test]
+    fn test_emitted_paths_use_forward_slashes() {
+        let files = vec![FileContext::new(
+            Path::new("src").join("cli").join("main.rs"),
+            "fn main() {}".to_string(),
+        )];
+        let output =
+            build_context_output(files, "map\n".to_string(), None, &OutputOptions::default())
+                .unwrap();
+        assert!(output.file_contents.contains("File: src/cli/main.rs\n"));
+        assert_eq!(output.files[0].path.to_str(), Some("src/cli/main.rs"));
+
+        // What Windows would emit, on any OS
+        let windows = vec![FileContext::new(
+            PathBuf::from(r"src\cli\main.rs"),
+            String::new(),
+        )];
+        let map = "src\\\n  cli\\\n".to_string();
+        let (files, map) = apply_path_separator(windows.clone(), map, PathSeparator::Slash, '\\');
+        assert_eq!(files[0].path.to_str(), Some("src/cli/main.rs"));
+        assert_eq!(map, "src/\n  cli/\n");
+
+        let (files, _) = apply_path_separator(windows, String::new(), PathSeparator::Native, '\\');
+        assert_eq!(files[0].path.to_str(), Some(r"src\cli\main.rs"));
+    }
+
+    #[test]
+    fn test_token_breakdown() {
+        let files = vec![
+            FileContext::new(PathBuf::from("src/small.rs"), "fn a() {}".to_string()),
+            FileContext::new(PathBuf::from("src/large.rs"), "fn b() {}\n".repeat(40)),
+            FileContext::new(PathBuf::from("src/medium.rs"), "fn c() {}\n".repeat(8)),
+        ];
+        let output = build_context_output(
+            files,
+            "src\n  large.rs\n".to_string(),
+            Some("Trim this".to_string()),
+            &OutputOptions::default(),
+        )
+        .unwrap();
+
+        let breakdown = token_breakdown(&output);
+
+        let paths: Vec<_> = breakdown
+            .iter()
+            .map(|(path, _)| path.to_str().unwrap())
+            .collect();
+        assert_eq!(paths, vec!["src/large.rs", "src/medium.rs", "src/small.rs"]);
+        assert_eq!(
+            breakdown.iter().map(|(_, tokens)| tokens).sum::<usize>(),
+            output.token_count
+                - count_tokens("src\n  large.rs\n", None)
+                - count_tokens("Trim this", None)
+        );
+    }
+
+    #[test]
+    fn test_dominant_files() {
+        let build = |files: Vec<FileContext>| {
+            build_context_output(files, "map\n".to_string(), None, &OutputOptions::default())
+                .unwrap()
+        };
+
+        let dominated = build(vec![
+            FileContext::new(PathBuf::from("src/a.rs"), "fn a() {}\n".repeat(4)),
+            FileContext::new(PathBuf::from("vendor/bundle.js"), "x = 1;\n".repeat(200)),
+            FileContext::new(PathBuf::from("src/b.rs"), "fn b() {}\n".repeat(4)),
+        ]);
+        let flagged = dominant_files(&dominated, 30);
+        assert_eq!(flagged.len(), 1);
+        assert_eq!(flagged[0].0, PathBuf::from("vendor/bundle.js"));
+        assert!(flagged[0].1 > 90.0);
+
+        let balanced = build(
+            ["a", "b", "c", "d"]
+                .iter()
+                .map(|name| {
+                    FileContext::new(
+                        PathBuf::from(format!("src/{}.rs", name)),
+                        "fn f() {}\n".repeat(10),
+                    )
+                })
+                .collect(),
+        );
+        assert!(dominant_files(&balanced, 30).is_empty());
+
+        let single = build(vec![FileContext::new(
+            PathBuf::from("src/main.rs"),
+            "fn main() {}\n".to_string(),
+        )]);
+        assert!(dominant_files(&single, 30).is_empty());
+    }
+
+    #[test]
+    fn test_json_round_trips_file_contents() {
+        let contents = [
+            ("src/main.rs", "fn main() {\n    println!(\"<&>\");\n}\n"),
+            ("notes.txt", "tabs\tand \"quotes\"\n"),
+        ];
+        let files = contents
+            .iter()
+            .map(|(path, content)| FileContext::new(PathBuf::from(path), content.to_string()))
+            .collect();
+        let output = build_context_output(
+            files,
+            "map\n".to_string(),
+            Some("Explain".to_string()),
+            &OutputOptions::default(),
+        )
+        .unwrap();
+
+        let json = format_output(&output, OutputFormat::Json).unwrap();
+        let parsed: JsonContext = serde_json::from_str(&json).unwrap();
+
+        let round_tripped: Vec<(&str, &str)> = parsed
+            .files
+            .iter()
+            .map(|file| (file.path.as_str(), file.content.as_str()))
+            .collect();
+        assert_eq!(round_tripped, contents);
+        assert_eq!(parsed.file_map, "map\n");
+        assert_eq!(parsed.user_instructions, "Explain");
+        assert_eq!(parsed.token_count, output.token_count);
+    }
+
+    #[test]
+    fn test_json_by_extension_summary() {
+        let files = vec![
+            FileContext::new(PathBuf::from("src/main.rs"), "fn main() {}".to_string()),
+            FileContext::new(PathBuf::from("src/lib.rs"), "pub mod cli;".to_string()),
+            FileContext::new(PathBuf::from("scripts/build.py"), "print('hi')".to_string()),
+            FileContext::new(PathBuf::from("Makefile"), "all:\n\tcargo build".to_string()),
+        ];
+        let output =
+            build_context_output(files, String::new(), None, &OutputOptions::default()).unwrap();
+
+        let json = format_output(&output, OutputFormat::Json).unwrap();
+        let parsed: JsonContext = serde_json::from_str(&json).unwrap();
+
+        let rs_tokens = count_tokens("fn main() {}", None) + count_tokens("pub mod cli;", None);
+        assert_eq!(parsed.by_extension.len(), 3);
+        assert_eq!(
+            parsed.by_extension["rs"],
+            ExtensionSummary {
+                files: 2,
+                tokens: rs_tokens
+            }
+        );
+        assert_eq!(parsed.by_extension["py"].files, 1);
+        assert_eq!(parsed.by_extension["(none)"].files, 1);
+        assert_eq!(parsed.totals.files, 4);
+        assert_eq!(
+            parsed.totals.tokens,
+            parsed.files.iter().map(|f| f.token_count).sum::<usize>()
+        );
+    }
+
+    #[test]
+    fn test_compact_whitespace() {
+        assert_eq!(
+            compact_whitespace("a\n\n\n\n\nb\n\nc\n", true, false),
+            "a\n\nb\n\nc\n"
+        );
+        assert_eq!(compact_whitespace("a  \n\tb\t\n", false, true), "a\n\tb\n");
+        assert_eq!(compact_whitespace("a\n\n\n\n", false, false), "a\n\n\n\n");
+        assert_eq!(compact_whitespace("a\n\n\n  ", true, false), "a\n");
+        assert_eq!(
+            compact_whitespace("a \r\n\r\n\r\n\r\n\r\nb\r\n", true, true),
+            "a\r\n\r\nb\r\n"
+        );
+    }
+
+    #[test]
+    fn test_build_context_output_compacts_blank_lines() {
+        let content = format!("fn a() {{}}{}fn b() {{}}\n", "\n".repeat(40));
+        let files = vec![FileContext::new(PathBuf::from("a.rs"), content)];
+
+        let plain = build_context_output(
+            files.clone(),
+            String::new(),
+            None,
+            &OutputOptions::default(),
+        )
+        .unwrap();
+        let compacted = build_context_output(
+            files,
+            String::new(),
+            None,
+            &OutputOptions {
+                compact_blank_lines: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert!(compacted.file_contents.contains("fn a() {}\n\nfn b() {}"));
+        assert!(compacted.token_count < plain.token_count);
+        assert_eq!(
+            compacted.file_tokens[0].1,
+            count_tokens("fn a() {}\n\nfn b() {}\n", None)
+        );
+    }
+
+    #[test]
+    fn test_include_directory_readmes() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let root = temp_dir.path();
+        for dir in ["src/core", "src/infra", "src/cli"] {
+            std::fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        std::fs::write(root.join("src/core/README.md"), "Core logic.").unwrap();
+        std::fs::write(root.join("src/infra/README.md"), "").unwrap();
+        let files = [
+            "src/core/a.rs",
+            "src/core/b.rs",
+            "src/infra/c.rs",
+            "src/cli/d.rs",
+        ]
+        .iter()
+        .map(|file| FileContext::new(root.join(file), String::new()))
+        .collect();
+        let reads = std::cell::RefCell::new(Vec::new());
+        let reader = |path: &PathBuf| {
+            reads.borrow_mut().push(path.clone());
+            Ok(std::fs::read_to_string(path)?)
+        };
+
+        let result = include_directory_readmes(files, reader);
+        let paths: Vec<_> = result
+            .iter()
+            .map(|f| f.path.strip_prefix(root).unwrap().to_path_buf())
+            .collect();
+
+        assert_eq!(
+            paths,
+            vec![
+                PathBuf::from("src/core/README.md"),
+                PathBuf::from("src/core/a.rs"),
+                PathBuf::from("src/core/b.rs"),
+                PathBuf::from("src/infra/c.rs"),
+                PathBuf::from("src/cli/d.rs"),
+            ]
+        );
+        assert_eq!(result[0].content, "Core logic.");
+        // Directories without a README are never read
+        assert_eq!(
+            reads.into_inner(),
+            vec![
+                root.join("src/core/README.md"),
+                root.join("src/infra/README.md")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_anchor_prefix() {
+        let content = "fn main() {\n    run();\n}\n";
+
+        let anchored = apply_anchors(content, Path::new("src/main.rs"), AnchorFormat::Prefix);
+
+        assert_eq!(
+            anchored,
+            "src/main.rs:1: fn main() {\nsrc/main.rs:2:     run();\nsrc/main.rs:3: }\n"
+        );
+    }
+
+    #[test]
+    fn test_anchor_gutter() {
+        let content = (1..=10)
+            .map(|i| format!("line{}", i))
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let anchored = apply_anchors(&content, Path::new("a.txt"), AnchorFormat::Gutter);
+        let lines: Vec<&str> = anchored.lines().collect();
+
+        assert_eq!(lines[0], " 1 | line1");
+        assert_eq!(lines[9], "10 | line10");
+        assert!(!anchored.ends_with('\n'));
+    }
+
+    #[test]
+    fn test_line_numbers_reset_per_file() {
+        let long = (1..=120)
+            .map(|i| format!("line{}", i))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let files = vec![
+            FileContext::new(PathBuf::from("long.rs"), long),
+            FileContext::new(PathBuf::from("short.rs"), "a\nb\n".to_string()),
+        ];
+        let options = OutputOptions {
+            anchor_format: AnchorFormat::Gutter,
+            ..OutputOptions::default()
+        };
+
+        let output = build_context_output(files, String::new(), None, &options).unwrap();
+
+        assert!(
+            output
+                .file_contents
+                .contains("```rust\n  1 | line1\n  2 | line2\n")
+        );
+        assert!(output.file_contents.contains("120 | line120\n```\n"));
+        assert!(
+            output
+                .file_contents
+                .contains("```rust\n1 | a\n2 | b\n```\n")
+        );
+    }
+
+    #[test]
+    fn test_anchor_min_lines() {
+        let lines = |count: usize| {
+            (1..=count)
+                .map(|i| format!("line{}\n", i))
+                .collect::<String>()
+        };
+        let files = vec![
+            FileContext::new(PathBuf::from("small.rs"), lines(5)),
+            FileContext::new(PathBuf::from("large.rs"), lines(200)),
+        ];
+        let options = OutputOptions {
+            anchor_format: AnchorFormat::Gutter,
+            anchor_min_lines: 100,
+            ..OutputOptions::default()
+        };
+
+        let output = build_context_output(files, String::new(), None, &options).unwrap();
+
+        assert_eq!(output.files[0].content, lines(5));
+        assert!(output.files[1].content.starts_with("  1 | line1\n"));
+        assert!(output.files[1].content.ends_with("200 | line200\n"));
+    }
+
+    #[test]
+    fn test_anchors_are_counted_as_tokens() {
+        let files = vec![FileContext::new(
+            PathBuf::from("src/lib.rs"),
+            "a\nb\nc\nd\n".to_string(),
+        )];
+
+        let output = build_context_output(
+            files,
+            String::new(),
+            None,
+            &OutputOptions {
+                anchor_format: AnchorFormat::Prefix,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert!(output.file_contents.contains("src/lib.rs:4: d"));
+        assert_eq!(
+            output.file_tokens[0].1,
+            count_tokens(
+                "src/lib.rs:1: a\nsrc/lib.rs:2: b\nsrc/lib.rs:3: c\nsrc/lib.rs:4: d\n",
+                None
+            )
+        );
+    }
+
+    #[test]
+    fn test_format_tsv() {
+        let files = vec![
+            FileContext::new(PathBuf::from("src/main.rs"), "fn main() {\n}\n".to_string()),
+            FileContext::new(PathBuf::from("notes"), "one line".to_string()),
+        ];
+        let output =
+            build_context_output(files, "map".to_string(), None, &OutputOptions::default())
+                .unwrap();
+
+        let tsv = format_output(&output, OutputFormat::Tsv).unwrap();
+        let rows: Vec<&str> = tsv.lines().collect();
+
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0], "path\tlanguage\tbytes\tlines\ttokens");
+        assert_eq!(
+            rows[1],
+            format!(
+                "src/main.rs\tRust\t14\t2\t{}",
+                count_tokens("fn main() {\n}\n", None)
+            )
+        );
+        assert_eq!(
+            rows[2],
+            format!("notes\tOther\t8\t1\t{}", count_tokens("one line", None))
+        );
+        assert!(!tsv.contains("fn main"));
+    }
+
+    #[test]
+    fn test_render_html() {
+        let files = vec![
+            FileContext::new(PathBuf::from("src/main.rs"), "fn main() {}".to_string()),
+            FileContext::new(PathBuf::from("index.html"), "<p>hi & bye</p>".to_string()),
+        ];
+        let output = build_context_output(
+            files,
+            "map".to_string(),
+            Some("Explain".to_string()),
+            &OutputOptions::default(),
+        )
+        .unwrap();
+
+        let html = render_html(&output);
+
+        assert_eq!(html.matches("<pre><code").count(), 2);
+        assert!(html.contains("<code class=\"language-rust\">fn main() {}</code>"));
+        assert!(html.contains("&lt;p&gt;hi &amp; bye&lt;/p&gt;"));
+        assert!(html.contains("Explain"));
+    }
+
+    #[test]
+    fn test_format_html_details() {
+        let files = vec![
+            FileContext::new(PathBuf::from("src/main.rs"), "fn main() {}".to_string()),
+            FileContext::new(PathBuf::from("src/<gen>.rs"), "a < b && c".to_string()),
+        ];
+        let output = build_context_output(
+            files,
+            "map".to_string(),
+            Some("Review".to_string()),
+            &OutputOptions::default(),
+        )
+        .unwrap();
+
+        let html = format_output(&output, OutputFormat::HtmlDetails).unwrap();
+
+        assert_eq!(html.matches("<details>").count(), 3);
+        assert!(html.starts_with("<details>\n<summary>File map (2 files)</summary>"));
+        assert!(html.contains(
+            "<summary>src/main.rs</summary>\n\n<pre><code>fn main() {}</code></pre>\n</details>"
+        ));
+        assert!(html.contains("<summary>src/&lt;gen&gt;.rs</summary>"));
+        assert!(html.contains("<pre><code>a &lt; b &amp;&amp; c</code></pre>"));
+        assert!(html.ends_with("\nReview\n"));
+
+        let extras = ContextOutput {
+            project: "Rust crate\n".to_string(),
+            dependency_graph: vec!["src/main.rs -> src/lib.rs".to_string()],
+            todos: vec!["src/main.rs:1: TODO: a < b".to_string()],
+            ..output
+        };
+        let html = format_output(&extras, OutputFormat::HtmlDetails).unwrap();
+        assert!(html.starts_with("<details>\n<summary>Project</summary>\n\n<pre>Rust crate</pre>"));
+        assert!(html.contains(
+            "<summary>Dependency graph</summary>\n\n<ul>\n<li>src/main.rs -&gt; src/lib.rs</li>\n</ul>"
+        ));
+        assert!(html.contains(
+            "<summary>TODOs (1)</summary>\n\n<ul>\n<li>src/main.rs:1: TODO: a &lt; b</li>\n</ul>"
+        ));
+    }
+
+    #[test]
+    fn test_format_markdown() {
+        let files = vec![
+            FileContext::new(PathBuf::from("src/main.rs"), "fn main() {}\n".to_string()),
+            FileContext::new(PathBuf::from("README.md"), "```sh\nrun\n```\n".to_string()),
+        ];
+        let output = build_context_output(
+            files,
+            "src/main.rs\n".to_string(),
+            Some("Review".to_string()),
+            &OutputOptions::default(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            format_output(&output, OutputFormat::Markdown).unwrap(),
+            "## File map\n\n```\nsrc/main.rs\n```\n\n## Files\n\n\
+             ### `src/main.rs`\n\n```rust\nfn main() {}\n```\n\n\
+             ### `README.md`\n\n````markdown\n```sh\nrun\n```\n````\n\n\
+             ## Instructions\n\nReview\n"
+        );
+    }
+
+    #[test]
+    fn test_prompt_file_list() {
+        let files = vec![
+            FileContext::new(PathBuf::from("/work/app/src/b.rs"), "b".to_string()),
+            FileContext::new(PathBuf::from("/work/lib/a.rs"), "a".to_string()),
+            FileContext::new(PathBuf::from("docs/c.md"), "c".to_string()),
+        ];
+        let options = OutputOptions {
+            prompt_file_list: true,
+            list_roots: vec![PathBuf::from("/work/app"), PathBuf::from("/work/lib")],
+            ..Default::default()
+        };
+
+        let with_list = build_context_output(
+            files.clone(),
+            String::new(),
+            Some("Review".to_string()),
+            &options,
+        )
+        .unwrap();
+        let without_list = build_context_output(
+            files,
+            String::new(),
+            Some("Review".to_string()),
+            &OutputOptions::default(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            with_list.user_instructions,
+            "Review\n\nIncluded files:\n- src/b.rs\n- a.rs\n- docs/c.md"
+        );
+        assert!(with_list.token_count > without_list.token_count);
+    }
+
+    #[test]
+    fn test_with_hashes() {
+        let options = OutputOptions {
+            with_hashes: true,
+            ..Default::default()
+        };
+        let build = |content: &str| {
+            let files = vec![FileContext::new(PathBuf::from("a.rs"), content.to_string())];
+            build_context_output(files, String::new(), None, &options).unwrap()
+        };
+
+        let first = build("fn a() {}");
+        let expected = content_hash("fn a() {}");
+        assert!(
+            first
+                .file_contents
+                .contains(&format!("File: a.rs hash=\"{}\"\n", expected))
+        );
+
+        let json: JsonContext =
+            serde_json::from_str(&format_output(&first, OutputFormat::Json).unwrap()).unwrap();
+        assert_eq!(json.files[0].hash.as_deref(), Some(expected.as_str()));
+
+        let second = build("fn a() { todo!() }");
+        assert_ne!(second.file_hashes[0], first.file_hashes[0]);
+
+        // Trimming changes what is emitted but not the hash of the file as read
+        let trimmed = build_context_output(
+            vec![FileContext::new(
+                PathBuf::from("a.rs"),
+                "fn a() {}   \n".to_string(),
+            )],
+            String::new(),
+            None,
+            &OutputOptions {
+                trim_trailing_whitespace: true,
+                ..options.clone()
+            },
+        )
+        .unwrap();
+        assert_eq!(trimmed.files[0].content, "fn a() {}\n");
+        assert_eq!(trimmed.file_hashes[0], Some(content_hash("fn a() {}   \n")));
+    }
+
+    #[test]
+    fn test_project_summary_section() {
+        let files = vec![FileContext::new(
+            PathBuf::from("src/main.rs"),
+            "fn main() {}".to_string(),
+        )];
+        let mut output =
+            build_context_output(files, "map\n".to_string(), None, &OutputOptions::default())
+                .unwrap();
+        let tokens_before = output.token_count;
+
+        attach_project_summary(
+            &mut output,
+            &ProjectSummary {
+                manifest: "Cargo.toml".to_string(),
+                name: "demo".to_string(),
+                version: Some("1.2.3".to_string()),
+                dependencies: vec!["anyhow".to_string(), "serde".to_string()],
+            },
+            None,
+        );
+        let formatted = format_output(&output, OutputFormat::Xml).unwrap();
+
+        assert!(formatted.starts_with(
+            "<project>\nname: demo\nversion: 1.2.3\ndependencies: anyhow, serde\nsource: Cargo.toml\n</project>\n\n<file_map>"
+        ));
+        assert!(output.token_count > tokens_before);
+    }
+
+    #[test]
+    fn test_streamed_output_matches_buffered() {
+        let files = || {
+            vec![
+                FileContext::new(PathBuf::from("src/main.rs"), "fn main() {}\n".to_string()),
+                FileContext::binary(PathBuf::from("logo.png"), 512),
+                FileContext::new(PathBuf::from("README.md"), "# Demo".to_string()),
+            ]
+        };
+        let options = OutputOptions {
+            binary_placeholder: true,
+            with_hashes: true,
+            prompt_file_list: true,
+            ..OutputOptions::default()
+        };
+        let summary = ProjectSummary {
+            manifest: "Cargo.toml".to_string(),
+            name: "demo".to_string(),
+            version: None,
+            dependencies: vec!["anyhow".to_string()],
+        };
+        let prompt = Some("Review this".to_string());
+        let runs = vec![CommandOutput {
+            command: "cargo check".to_string(),
+            exit_code: Some(101),
+            output: "error[E0425]: cannot find value".to_string(),
+        }];
+
+        let mut buffered =
+            build_context_output(files(), "map\n".to_string(), prompt.clone(), &options).unwrap();
+        attach_project_summary(&mut buffered, &summary, None);
+        attach_command_outputs(&mut buffered, runs.clone(), None);
+        let expected = format_output(&buffered, OutputFormat::Xml).unwrap();
+
+        let contents = files();
+        let chunks = std::cell::Cell::new(0);
+        // The number of chunks already written when each file is read
+        let reads = std::cell::RefCell::new(Vec::new());
+        let load = |file: FileContext| -> anyhow::Result<Option<FileContext>> {
+            reads.borrow_mut().push(chunks.get());
+            Ok(contents.iter().find(|read| read.path == file.path).cloned())
+        };
+        let unread = files()
+            .into_iter()
+            .map(|file| FileContext::new(file.path, String::new()))
+            .collect();
+        let mut streamed = String::new();
+        let output = stream_context_output(
+            UnreadFiles {
+                files: unread,
+                load: &load,
+            },
+            "map\n".to_string(),
+            prompt,
+            &options,
+            Some(&summary),
+            runs,
+            &mut |chunk: &str| {
+                streamed.push_str(chunk);
+                chunks.set(chunks.get() + 1);
+                Ok(())
+            },
+        )
+        .unwrap();
+
+        assert_eq!(streamed, expected);
+        assert!(chunks.get() > 3);
+        assert_eq!(output.token_count, buffered.token_count);
+        assert_eq!(output.file_tokens, buffered.file_tokens);
+        let reads = reads.into_inner();
+        assert!(reads[0] > 0);
+        assert!(reads.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(output.files.iter().all(|file| file.content.is_empty()));
+    }
+
+    #[test]
+    fn test_max_line_length_truncates() {
+        let long = "x".repeat(5000);
+        let files = vec![FileContext::new(
+            PathBuf::from("dist/app.min.js"),
+            format!("short\n{}\n", long),
+        )];
+        let options = OutputOptions {
+            max_line_length: Some(10),
+            ..OutputOptions::default()
+        };
+
+        let output = build_context_output(files, "map\n".to_string(), None, &options).unwrap();
+
+        assert_eq!(
+            output.files[0].content,
+            "short\nxxxxxxxxxx [truncated 4990 chars]\n"
+        );
+        assert_eq!(
+            truncate_long_lines("0123456789ab\r\nok\r\nlast line", 4),
+            "0123 [truncated 8 chars]\r\nok\r\nlast [truncated 5 chars]"
+        );
+    }
+
+    #[test]
+    fn test_snap_to_functions_cuts_after_a_whole_fn() {
+        let first = "/// First.\nfn first() {\n    let a = 1;\n    let b = 2;\n}\n\n";
+        let second = "fn second() {\n    let c = 3;\n    let d = 4;\n    let e = 5;\n}\n";
+        let content = format!("{}{}", first, second);
+        // Room for the first function and two lines of the second
+        let max = count_tokens(&format!("{}fn second() {{\n    let c = 3;\n", first), None);
+
+        let plain = truncate_to_tokens(&content, max, None, None).unwrap();
+        assert!(plain.contains("let c = 3;"));
+
+        let starts = symbol_starts(Path::new("lib.rs"), &content).unwrap();
+        let snapped = truncate_to_tokens(&content, max, Some(&starts), None).unwrap();
+        let (kept, marker) = snapped.split_once("... (truncated").unwrap();
+        assert_eq!(kept, first);
+        assert!(marker.ends_with("tokens omitted) ...\n"));
+
+        // A first function larger than the budget falls back to cutting at a line
+        let tiny = truncate_to_tokens(&content, 5, Some(&starts), None).unwrap();
+        assert!(tiny.starts_with("/// First.\n"));
+    }
+
+    #[test]
+    fn test_max_file_tokens_truncates_large_files() {
+        let large: String = (0..200)
+            .map(|i| format!("let value_{} = {};\n", i, i))
+            .collect();
+        let files = vec![
+            FileContext::new(PathBuf::from("src/large.rs"), large.clone()),
+            FileContext::new(PathBuf::from("src/small.rs"), "fn small() {}\n".to_string()),
+        ];
+        let options = OutputOptions {
+            max_file_tokens: Some(100),
+            ..OutputOptions::default()
+        };
+
+        let output = build_context_output(files, "map\n".to_string(), None, &options).unwrap();
+
+        let truncated = &output.files[0].content;
+        let (kept, marker) = truncated.trim_end().rsplit_once('\n').unwrap();
+        assert!(large.starts_with(&format!("{}\n", kept)));
+        assert!(count_tokens(kept, None) <= 100);
+        assert!(count_tokens(kept, None) > 90);
+        assert_eq!(
+            marker,
+            format!(
+                "... (truncated, {} tokens omitted) ...",
+                count_tokens(&large, None) - count_tokens(&format!("{}\n", kept), None)
+            )
+        );
+        assert_eq!(output.files[1].content, "fn small() {}\n");
+    }
+
+    #[test]
+    fn test_merge_by_extension() {
+        let files = vec![
+            FileContext::new(
+                PathBuf::from("db/001_users.sql"),
+                "CREATE TABLE users (id INT);\n".to_string(),
+            ),
+            FileContext::new(PathBuf::from("src/main.rs"), "fn main() {}\n".to_string()),
+            FileContext::new(
+                PathBuf::from("db/002_posts.SQL"),
+                "CREATE TABLE posts (id INT); -- TODO: index".to_string(),
+            ),
+        ];
+        let options = OutputOptions {
+            merge_extensions: vec!["sql".to_string()],
+            collect_todos: true,
+            ..OutputOptions::default()
+        };
+
+        let output = build_context_output(files, String::new(), None, &options).unwrap();
+
+        let paths: Vec<_> = output.files.iter().map(|file| file.path.clone()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                PathBuf::from("db/001_users.sql"),
+                PathBuf::from("src/main.rs"),
+                PathBuf::from("db/002_posts.SQL"),
+            ]
+        );
+        let token_paths: Vec<_> = output.file_tokens.iter().map(|(path, _)| path).collect();
+        assert_eq!(token_paths, paths.iter().collect::<Vec<_>>());
+        assert_eq!(output.todos, vec!["db/002_posts.SQL:1: TODO: index"]);
+        assert_eq!(
+            output.file_contents,
+            "\nFile: *.sql\n```sql\n-- file: db/001_users.sql\nCREATE TABLE users (id INT);\n\n-- file: db/002_posts.SQL\nCREATE TABLE posts (id INT); -- TODO: index\n```\n\nFile: src/main.rs\n```rust\nfn main() {}\n```\n"
+        );
+    }
+
+    #[test]
+    fn test_fill_template() {
+        let template = "You are reviewing a service.\n\n{{RICH_PROMPT_CONTEXT}}\n\nRepeat: {{RICH_PROMPT_CONTEXT}}\nThanks!\n";
+
+        assert_eq!(
+            fill_template(template, "<file_map>\nx\n</file_map>").unwrap(),
+            "You are reviewing a service.\n\n<file_map>\nx\n</file_map>\n\nRepeat: <file_map>\nx\n</file_map>\nThanks!\n"
+        );
+        let missing = fill_template("No marker here", "context").unwrap_err();
+        assert!(missing.to_string().contains(TEMPLATE_MARKER));
+        assert!(check_template("No marker here").is_err());
+        assert!(check_template(template).is_ok());
+    }
+
+    #[test]
+    fn test_collect_todos() {
+        let files = vec![
+            FileContext::new(
+                PathBuf::from("src/lib.rs"),
+                "fn a() {}\n// TODO: handle errors\nlet TODOS = 1;\nlet s = \"TODO: not a comment\";\n/*\n * FIXME: in a block\n */\n".to_string(),
+            ),
+            FileContext::new(
+                PathBuf::from("app/main.py"),
+                "# FIXME(sam) slow\n\n\n\n\nx = 1  # HACK\n".to_string(),
+            ),
+        ];
+        let options = OutputOptions {
+            collect_todos: true,
+            compact_blank_lines: true,
+            ..OutputOptions::default()
+        };
+
+        let output = build_context_output(files, "map\n".to_string(), None, &options).unwrap();
+
+        assert_eq!(
+            output.todos,
+            vec![
+                "src/lib.rs:2: TODO: handle errors",
+                "src/lib.rs:6: FIXME: in a block",
+                "app/main.py:1: FIXME(sam) slow",
+                "app/main.py:6: HACK",
+            ]
+        );
+        let formatted = format_output(&output, OutputFormat::Xml).unwrap();
+        assert!(formatted.ends_with(
+            "</file_contents>\n\n<todos>\nsrc/lib.rs:2: TODO: handle errors\nsrc/lib.rs:6: FIXME: in a block\napp/main.py:1: FIXME(sam) slow\napp/main.py:6: HACK\n</todos>"
+        ));
+    }
+
+    #[test]
+    fn test_dependency_graph_section() {
+        let files = vec![
+            FileContext::new(
+                PathBuf::from("src/main.rs"),
+                "mod util;\nuse std::io;\n\nfn main() {}\n".to_string(),
+            ),
+            FileContext::new(
+                PathBuf::from("src/util.rs"),
+                "pub fn helper() {}\n".to_string(),
+            ),
+        ];
+        let options = OutputOptions {
+            with_graph: true,
+            ..OutputOptions::default()
+        };
+
+        let output = build_context_output(files, "map\n".to_string(), None, &options).unwrap();
+
+        assert_eq!(output.dependency_graph, vec!["src/main.rs -> src/util.rs"]);
+        let formatted = format_output(&output, OutputFormat::Xml).unwrap();
+        assert!(formatted.ends_with(
+            "</file_contents>\n\n<dependency_graph>\nsrc/main.rs -> src/util.rs\n</dependency_graph>"
+        ));
+
+        let without = build_context_output(
+            vec![FileContext::new(
+                PathBuf::from("src/main.rs"),
+                "mod util;\n".to_string(),
+            )],
+            "map\n".to_string(),
+            None,
+            &OutputOptions::default(),
+        )
+        .unwrap();
+        assert!(without.dependency_graph.is_empty());
+    }
+
+    #[test]
+    fn test_priority_files_section() {
+        let mut review =
+            FileContext::new(PathBuf::from("src/auth.rs"), "fn login() {}".to_string());
+        review.priority = true;
+        let files = vec![
+            review,
+            FileContext::new(PathBuf::from("src/main.rs"), "fn main() {}".to_string()),
+        ];
+
+        let output =
+            build_context_output(files, "map\n".to_string(), None, &OutputOptions::default())
+                .unwrap();
+        let formatted = format_output(&output, OutputFormat::Xml).unwrap();
+
+        assert_eq!(output.priority_files, vec![PathBuf::from("src/auth.rs")]);
+        assert!(formatted.contains(
+            "</file_map>\n\n\n<priority_files>\nReview these files with particular attention:\n- src/auth.rs\n</priority_files>\n\n<file_contents>"
+        ));
+        assert!(formatted.find("File: src/auth.rs") < formatted.find("File: src/main.rs"));
+    }
+
+    #[test]
+    fn test_openai_chat_format() {
+        let files = vec![FileContext::new(
+            PathBuf::from("src/main.rs"),
+            "fn main() {}".to_string(),
+        )];
+        let output = build_context_output(
+            files,
+            "map\n".to_string(),
+            Some("Find the bug".to_string()),
+            &OutputOptions::default(),
+        )
+        .unwrap();
+
+        let chat: serde_json::Value =
+            serde_json::from_str(&format_chat(&output, ChatProvider::Openai).unwrap()).unwrap();
+        let messages = chat["messages"].as_array().unwrap();
+
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0]["role"], "system");
+        let system = messages[0]["content"].as_str().unwrap();
+        assert!(system.contains("<file_map>\nmap\n</file_map>"));
+        assert!(system.contains("fn main() {}"));
+        assert!(!system.contains("Find the bug"));
+        assert_eq!(messages[1]["role"], "user");
+        assert_eq!(messages[1]["content"], "Find the bug");
+
+        let anthropic: serde_json::Value =
+            serde_json::from_str(&format_chat(&output, ChatProvider::Anthropic).unwrap()).unwrap();
+        assert_eq!(anthropic["system"].as_str().unwrap(), system);
+        assert_eq!(anthropic["messages"][0]["role"], "user");
     }
 }
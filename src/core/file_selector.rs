@@ -1,4 +1,8 @@
-use crate::domain::models::FileContext;
+use crate::core::context_generator::count_tokens;
+use crate::core::outline::symbol_blocks;
+use crate::domain::models::{
+    BinaryFileError, BudgetStrategy, CliError, ErrorKind, FileContext, TokenBudget, Tokenizer,
+};
 use crossterm::{
     event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyModifiers},
     execute,
@@ -8,17 +12,64 @@ use log::{debug, info, warn};
 use ratatui::{
     Frame, Terminal,
     backend::{Backend, CrosstermBackend},
-    layout::{Constraint, Direction, Layout},
+    layout::{Constraint, Direction, Layout, Rect},
     style::{Color, Modifier, Style},
-    text::Span,
+    text::{Line, Span},
     widgets::{Block, Borders, List, ListItem, ListState, Paragraph},
 };
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
 use std::{
-    io::{self},
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    io::{self, IsTerminal},
     path::{Path, PathBuf},
-    time::Duration,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::{Duration, Instant},
 };
 
+// Quiet period after the last toggle before the in-progress selection is autosaved
+const AUTOSAVE_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Resume support for the interactive selector: the paths to start with and a callback
+/// that receives the in-progress selection, debounced, as it changes.
+#[derive(Default)]
+pub struct SelectionSession {
+    pub initial_selection: Vec<PathBuf>,
+    pub autosave: Option<AutosaveFn>,
+}
+
+pub type AutosaveFn = Box<dyn FnMut(&[PathBuf])>;
+
+/// Files added to or removed from the selection compared with the last confirmed one.
+#[derive(Debug, Default, PartialEq)]
+pub struct SelectionDiff {
+    pub added: Vec<PathBuf>,
+    pub removed: Vec<PathBuf>,
+}
+
+impl SelectionDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+pub fn diff_selections(previous: &[PathBuf], current: &[PathBuf]) -> SelectionDiff {
+    SelectionDiff {
+        added: current
+            .iter()
+            .filter(|path| !previous.contains(path))
+            .cloned()
+            .collect(),
+        removed: previous
+            .iter()
+            .filter(|path| !current.contains(path))
+            .cloned()
+            .collect(),
+    }
+}
+
 // Tree node representation to store directory structure
 enum TreeNode {
     Directory {
@@ -30,6 +81,8 @@ enum TreeNode {
         name: String,
         path: PathBuf,
         selected: bool,
+        /// Marked "must review"; always selected as well.
+        priority: bool,
     },
 }
 
@@ -47,6 +100,7 @@ impl TreeNode {
             name,
             path,
             selected: false,
+            priority: false,
         }
     }
 
@@ -62,8 +116,23 @@ impl TreeNode {
     }
 
     fn toggle_selected(&mut self) {
-        if let TreeNode::File { selected, .. } = self {
+        if let TreeNode::File {
+            selected, priority, ..
+        } = self
+        {
             *selected = !*selected;
+            // An unselected file can't be a priority
+            *priority &= *selected;
+        }
+    }
+
+    fn toggle_priority(&mut self) {
+        if let TreeNode::File {
+            selected, priority, ..
+        } = self
+        {
+            *priority = !*priority;
+            *selected |= *priority;
         }
     }
 
@@ -74,6 +143,24 @@ impl TreeNode {
         }
     }
 
+    fn is_priority(&self) -> bool {
+        match self {
+            TreeNode::File { priority, .. } => *priority,
+            _ => false,
+        }
+    }
+
+    /// Whether this file's path, or that of any file below this directory, contains the
+    /// lowercase `filter`.
+    fn matches_filter(&self, filter: &str) -> bool {
+        match self {
+            TreeNode::Directory { children, .. } => {
+                children.iter().any(|child| child.matches_filter(filter))
+            }
+            TreeNode::File { path, .. } => path.to_string_lossy().to_lowercase().contains(filter),
+        }
+    }
+
     fn is_expanded(&self) -> bool {
         match self {
             TreeNode::Directory { expanded, .. } => *expanded,
@@ -82,7 +169,6 @@ impl TreeNode {
     }
 }
 
-
 struct FlattenedTree {
     nodes: Vec<(TreeNode, usize)>,
     state: ListState,
@@ -97,8 +183,14 @@ impl FlattenedTree {
     }
 
     fn from_tree(root: &TreeNode) -> Self {
+        Self::from_tree_filtered(root, "")
+    }
+
+    /// Only files whose path contains `filter`, ignoring case, and the directories above
+    /// them, which are shown open. An empty filter keeps the whole tree.
+    fn from_tree_filtered(root: &TreeNode, filter: &str) -> Self {
         let mut flattened = FlattenedTree::new();
-        flattened.flatten_node(root, 0);
+        flattened.flatten_node(root, 0, &filter.to_lowercase());
 
         if !flattened.nodes.is_empty() {
             flattened.state.select(Some(0));
@@ -107,7 +199,10 @@ impl FlattenedTree {
         flattened
     }
 
-    fn flatten_node(&mut self, node: &TreeNode, depth: usize) {
+    fn flatten_node(&mut self, node: &TreeNode, depth: usize, filter: &str) {
+        if !filter.is_empty() && !node.matches_filter(filter) {
+            return;
+        }
         match node {
             TreeNode::Directory {
                 name,
@@ -123,9 +218,9 @@ impl FlattenedTree {
                     depth,
                 ));
 
-                if *expanded {
+                if *expanded || !filter.is_empty() {
                     for child in children {
-                        self.flatten_node(child, depth + 1);
+                        self.flatten_node(child, depth + 1, filter);
                     }
                 }
             }
@@ -133,12 +228,14 @@ impl FlattenedTree {
                 name,
                 path,
                 selected,
+                priority,
             } => {
                 self.nodes.push((
                     TreeNode::File {
                         name: name.clone(),
                         path: path.clone(),
                         selected: *selected,
+                        priority: *priority,
                     },
                     depth,
                 ));
@@ -147,6 +244,9 @@ impl FlattenedTree {
     }
 
     fn next(&mut self) {
+        if self.nodes.is_empty() {
+            return;
+        }
         let i = match self.state.selected() {
             Some(i) => {
                 if i >= self.nodes.len() - 1 {
@@ -161,6 +261,9 @@ impl FlattenedTree {
     }
 
     fn previous(&mut self) {
+        if self.nodes.is_empty() {
+            return;
+        }
         let i = match self.state.selected() {
             Some(i) => {
                 if i == 0 {
@@ -183,6 +286,26 @@ impl FlattenedTree {
         }
     }
 
+    fn toggle_priority(&mut self) {
+        if let Some(i) = self.state.selected() {
+            self.nodes[i].0.toggle_priority();
+        }
+    }
+
+    fn get_priority_paths(&self) -> Vec<PathBuf> {
+        self.nodes
+            .iter()
+            .filter_map(|(node, _)| match node {
+                TreeNode::File {
+                    path,
+                    priority: true,
+                    ..
+                } => Some(path.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
     fn selected_files_count(&self) -> usize {
         self.nodes
             .iter()
@@ -190,6 +313,51 @@ impl FlattenedTree {
             .count()
     }
 
+    /// Directory names from the root down to the directory at `index`, recovered from depths.
+    fn directory_path(&self, index: usize) -> Option<Vec<String>> {
+        let (TreeNode::Directory { name, .. }, depth) = self.nodes.get(index)? else {
+            return None;
+        };
+        let mut path = vec![name.clone()];
+        let mut depth = *depth;
+        for (node, node_depth) in self.nodes[..index].iter().rev() {
+            if *node_depth < depth {
+                path.push(node.get_display_name());
+                depth = *node_depth;
+            }
+        }
+        path.reverse();
+        Some(path)
+    }
+
+    /// Index of the directory at `path`, as returned by `directory_path`, if it is visible.
+    fn find_directory(&self, path: &[String]) -> Option<usize> {
+        let mut names: Vec<String> = Vec::new();
+        for (index, (node, depth)) in self.nodes.iter().enumerate() {
+            names.truncate(*depth);
+            names.push(node.get_display_name());
+            if !node.is_file() && names == path {
+                return Some(index);
+            }
+        }
+        None
+    }
+
+    /// Share of selected files in each of `rows` consecutive slices of the tree, or `None`
+    /// for slices holding only directories. Trees shorter than `rows` get one slice per node.
+    fn selection_density(&self, rows: usize) -> Vec<Option<f64>> {
+        let len = self.nodes.len();
+        let rows = rows.min(len);
+        (0..rows)
+            .map(|row| {
+                let slice = &self.nodes[row * len / rows..(row + 1) * len / rows];
+                let files = slice.iter().filter(|(node, _)| node.is_file()).count();
+                let selected = slice.iter().filter(|(node, _)| node.is_selected()).count();
+                (files > 0).then(|| selected as f64 / files as f64)
+            })
+            .collect()
+    }
+
     fn total_files_count(&self) -> usize {
         self.nodes.iter().filter(|(node, _)| node.is_file()).count()
     }
@@ -217,9 +385,134 @@ impl FlattenedTree {
 
     fn deselect_all_files(&mut self) {
         for (node, _) in &mut self.nodes {
-            if let TreeNode::File { selected, .. } = node {
+            if let TreeNode::File {
+                selected, priority, ..
+            } = node
+            {
                 *selected = false;
+                *priority = false;
+            }
+        }
+    }
+}
+
+// Emission order of the selected files, edited in the reorder pane
+struct SelectionOrder {
+    paths: Vec<PathBuf>,
+    state: ListState,
+}
+
+impl SelectionOrder {
+    fn new() -> Self {
+        SelectionOrder {
+            paths: Vec::new(),
+            state: ListState::default(),
+        }
+    }
+
+    // Keeps the existing order of files that are still selected and appends newly selected ones
+    fn sync(&mut self, selected: Vec<PathBuf>) {
+        self.paths.retain(|path| selected.contains(path));
+        for path in selected {
+            if !self.paths.contains(&path) {
+                self.paths.push(path);
+            }
+        }
+
+        let current = self.state.selected().unwrap_or(0);
+        if self.paths.is_empty() {
+            self.state.select(None);
+        } else {
+            self.state.select(Some(current.min(self.paths.len() - 1)));
+        }
+    }
+
+    fn next(&mut self) {
+        if let Some(i) = self.state.selected()
+            && i + 1 < self.paths.len()
+        {
+            self.state.select(Some(i + 1));
+        }
+    }
+
+    fn previous(&mut self) {
+        if let Some(i) = self.state.selected() {
+            self.state.select(Some(i.saturating_sub(1)));
+        }
+    }
+
+    fn move_up(&mut self) {
+        if let Some(i) = self.state.selected()
+            && i > 0
+        {
+            self.paths.swap(i, i - 1);
+            self.state.select(Some(i - 1));
+        }
+    }
+
+    fn move_down(&mut self) {
+        if let Some(i) = self.state.selected()
+            && i + 1 < self.paths.len()
+        {
+            self.paths.swap(i, i + 1);
+            self.state.select(Some(i + 1));
+        }
+    }
+}
+
+/// Tokens per top-level block of one file, heaviest first, or `None` when the file's
+/// language has no symbol outline.
+fn block_token_counts(
+    path: &Path,
+    content: &str,
+    tokenizer: Option<Tokenizer>,
+) -> Option<Vec<(String, usize)>> {
+    let lines: Vec<&str> = content.lines().collect();
+    let mut counts: Vec<(String, usize)> = symbol_blocks(path, content)?
+        .into_iter()
+        .map(|(label, range)| {
+            let tokens = count_tokens(&lines[range].join("\n"), tokenizer);
+            (label, tokens)
+        })
+        .collect();
+    counts.sort_by_key(|(_, tokens)| Reverse(*tokens));
+    Some(counts)
+}
+
+/// The `t` pane: where the tokens of the highlighted file go.
+struct TokenBreakdown {
+    path: PathBuf,
+    title: String,
+    rows: Vec<String>,
+}
+
+impl TokenBreakdown {
+    fn new(path: &Path, content: anyhow::Result<String>, tokenizer: Option<Tokenizer>) -> Self {
+        let content = match content {
+            Ok(content) => content,
+            Err(e) => {
+                return TokenBreakdown {
+                    path: path.to_path_buf(),
+                    title: "Tokens".to_string(),
+                    rows: vec![format!("Can't read {}: {}", path.display(), e)],
+                };
             }
+        };
+        let total = count_tokens(&content, tokenizer);
+        let rows = match block_token_counts(path, &content, tokenizer) {
+            Some(counts) => counts
+                .into_iter()
+                .map(|(label, tokens)| {
+                    let share = 100.0 * tokens as f64 / total.max(1) as f64;
+                    format!("{:>7} {:>4.0}%  {}", tokens, share, label)
+                })
+                .collect(),
+            None => vec!["No per-symbol breakdown for this file type".to_string()],
+        };
+        TokenBreakdown {
+            path: path.to_path_buf(),
+            title: format!("Tokens in {} ({} total)", path.display(), total),
+            rows,
         }
     }
 }
@@ -227,8 +520,21 @@ impl FlattenedTree {
 struct App {
     tree: TreeNode,
     flattened_tree: FlattenedTree,
+    order: SelectionOrder,
+    reordering: bool,
+    /// Show the selection density column beside the tree.
+    show_overview: bool,
+    /// Substring the tree is narrowed to; empty shows every file.
+    filter: String,
+    /// Keys go to the filter input instead of the tree.
+    editing_filter: bool,
     title: String,
     help_message: String,
+    autosave: Option<AutosaveFn>,
+    last_change: Option<Instant>,
+    token_breakdown: Option<TokenBreakdown>,
+    /// What the token breakdown counts with.
+    tokenizer: Option<Tokenizer>,
 }
 
 impl App {
@@ -244,13 +550,109 @@ impl App {
         App {
             tree: root,
             flattened_tree,
+            order: SelectionOrder::new(),
+            reordering: false,
+            show_overview: false,
+            filter: String::new(),
+            editing_filter: false,
             title,
             help_message: String::from(
-                "↑/↓: Navigate | Space: Toggle selection | Enter: Confirm | →/←: Expand/Collapse | q: Quit | a: Select all | n: Deselect all",
+                "↑/↓: Navigate | Space: Toggle file/directory | m: Must review | o: Overview | Enter: Confirm | →/←: Expand/Collapse | z: Fold subtree | /: Filter | q: Quit | a: Select all | n: Deselect all | r: Reorder | t: Token breakdown",
             ),
+            autosave: None,
+            last_change: None,
+            token_breakdown: None,
+            tokenizer: None,
+        }
+    }
+
+    // Marks the given paths as selected, e.g. when resuming an interrupted session
+    fn preselect(&mut self, paths: &[PathBuf]) {
+        fn mark(node: &mut TreeNode, paths: &[PathBuf]) {
+            match node {
+                TreeNode::Directory { children, .. } => {
+                    for child in children {
+                        mark(child, paths);
+                    }
+                }
+                TreeNode::File { path, selected, .. } => {
+                    *selected = paths.contains(path);
+                }
+            }
+        }
+
+        mark(&mut self.tree, paths);
+        self.rebuild_flattened_tree();
+    }
+
+    fn mark_changed(&mut self) {
+        self.last_change = Some(Instant::now());
+    }
+
+    // Hands the selection to the autosave callback once changes have settled, or right away when forced
+    fn flush_autosave(&mut self, now: Instant, force: bool) {
+        let Some(changed_at) = self.last_change else {
+            return;
+        };
+        if !force && now.duration_since(changed_at) < AUTOSAVE_DEBOUNCE {
+            return;
+        }
+
+        self.last_change = None;
+        if let Some(autosave) = self.autosave.as_mut() {
+            let selected = self.flattened_tree.get_selected_paths();
+            debug!("Autosaving selection of {} files", selected.len());
+            autosave(&selected);
+        }
+    }
+
+    /// Opens the token breakdown of the highlighted file, or closes it if it already shows
+    /// that file.
+    fn toggle_token_breakdown(&mut self, read: &dyn Fn(&PathBuf) -> anyhow::Result<String>) {
+        let highlighted = self
+            .flattened_tree
+            .state
+            .selected()
+            .and_then(|i| self.flattened_tree.nodes.get(i))
+            .and_then(|(node, _)| match node {
+                TreeNode::File { path, .. } => Some(path.clone()),
+                TreeNode::Directory { .. } => None,
+            });
+        let Some(path) = highlighted else {
+            return;
+        };
+        if self
+            .token_breakdown
+            .as_ref()
+            .is_some_and(|breakdown| breakdown.path == path)
+        {
+            self.token_breakdown = None;
+        } else {
+            self.token_breakdown = Some(TokenBreakdown::new(&path, read(&path), self.tokenizer));
         }
     }
 
+    fn toggle_reordering(&mut self) {
+        self.reordering = !self.reordering;
+        if self.reordering {
+            self.order.sync(self.flattened_tree.get_selected_paths());
+        }
+    }
+
+    // Selected paths in the order they should be emitted, priority files first
+    fn ordered_selection(&mut self) -> Vec<PathBuf> {
+        self.order.sync(self.flattened_tree.get_selected_paths());
+        let priority = self.flattened_tree.get_priority_paths();
+        let (mut ordered, rest): (Vec<PathBuf>, Vec<PathBuf>) = self
+            .order
+            .paths
+            .iter()
+            .cloned()
+            .partition(|path| priority.contains(path));
+        ordered.extend(rest);
+        ordered
+    }
+
     fn add_path_to_tree(root: &mut TreeNode, path: &Path) {
         let components: Vec<_> = path
             .components()
@@ -264,129 +666,224 @@ impl App {
         let mut current = root;
         let dirs_count = components.len() - 1;
 
-        for i in 0..components.len() {
-            let component_name = &components[i];
-
+        for (i, component_name) in components.iter().enumerate() {
             if i == dirs_count {
                 if let TreeNode::Directory { children, .. } = current {
                     let file_node = TreeNode::new_file(component_name.clone(), path.to_path_buf());
                     children.push(file_node);
                 }
-            } else {
-                if let TreeNode::Directory { children, .. } = current {
-                    let dir_pos = children.iter().position(|child| {
-                        if let TreeNode::Directory { name, .. } = child {
-                            name == component_name
-                        } else {
-                            false
-                        }
-                    });
-
-                    if let Some(pos) = dir_pos {
-                        current = &mut children[pos];
+            } else if let TreeNode::Directory { children, .. } = current {
+                let dir_pos = children.iter().position(|child| {
+                    if let TreeNode::Directory { name, .. } = child {
+                        name == component_name
                     } else {
-                        let new_dir = TreeNode::new_directory(component_name.clone());
-                        children.push(new_dir);
-
-                        let last_index = children.len() - 1;
-                        current = &mut children[last_index];
+                        false
                     }
+                });
+
+                if let Some(pos) = dir_pos {
+                    current = &mut children[pos];
+                } else {
+                    let new_dir = TreeNode::new_directory(component_name.clone());
+                    children.push(new_dir);
+
+                    let last_index = children.len() - 1;
+                    current = &mut children[last_index];
                 }
             }
         }
     }
 
-    fn collapse_directory_by_name(&mut self, dir_name: &str) -> bool {
-        fn find_and_collapse(node: &mut TreeNode, name: &str) -> bool {
+    /// Collapses the directory at `path` and every directory below it if any of them is
+    /// expanded, otherwise expands them all. Returns whether the directory was found.
+    fn toggle_subtree(&mut self, path: &[String]) -> bool {
+        fn any_expanded(node: &TreeNode) -> bool {
             match node {
-                TreeNode::Directory { name: node_name, expanded, children, .. } => {
-                    if node_name == name {
-                        *expanded = false;
-                        return true;
-                    }
-                    
-                    for child in children {
-                        if find_and_collapse(child, name) {
-                            return true;
-                        }
-                    }
-                },
-                _ => {}
+                TreeNode::Directory {
+                    expanded, children, ..
+                } => *expanded || children.iter().any(any_expanded),
+                TreeNode::File { .. } => false,
             }
-            false
         }
-        
-        let mut modified = false;
-        if let TreeNode::Directory { children, .. } = &mut self.tree {
-            for child in children {
-                if find_and_collapse(child, dir_name) {
-                    modified = true;
-                    break;
+
+        fn set_expanded(node: &mut TreeNode, value: bool) {
+            if let TreeNode::Directory {
+                expanded, children, ..
+            } = node
+            {
+                *expanded = value;
+                for child in children {
+                    set_expanded(child, value);
                 }
             }
         }
-        
-        modified
+
+        let Some(node) = self.directory_mut(path) else {
+            return false;
+        };
+        let expand = !any_expanded(node);
+        set_expanded(node, expand);
+        true
     }
 
-    fn expand_directory_by_name(&mut self, dir_name: &str) -> bool {
-        fn find_and_expand(node: &mut TreeNode, name: &str) -> bool {
+    /// Deselects every file below the directory at `path`, collapsed or not, if all of them
+    /// are selected, otherwise selects them all. With a filter active only the files it
+    /// matches count. Returns whether the directory was found.
+    fn toggle_directory_selection(&mut self, path: &[String]) -> bool {
+        // An empty filter matches every file
+        fn all_selected(node: &TreeNode, filter: &str) -> bool {
             match node {
-                TreeNode::Directory { name: node_name, expanded, children, .. } => {
-                    if node_name == name {
-                        *expanded = true;
-                        return true;
-                    }
-                    
-                    for child in children {
-                        if find_and_expand(child, name) {
-                            return true;
-                        }
-                    }
-                },
-                _ => {}
+                TreeNode::Directory { children, .. } => {
+                    children.iter().all(|child| all_selected(child, filter))
+                }
+                TreeNode::File { selected, .. } => *selected || !node.matches_filter(filter),
             }
-            false
         }
-        
-        let mut modified = false;
-        if let TreeNode::Directory { children, .. } = &mut self.tree {
-            for child in children {
-                if find_and_expand(child, dir_name) {
-                    modified = true;
-                    break;
+
+        fn set_selected(node: &mut TreeNode, value: bool, filter: &str) {
+            if node.is_file() && !node.matches_filter(filter) {
+                return;
+            }
+            match node {
+                TreeNode::Directory { children, .. } => {
+                    for child in children {
+                        set_selected(child, value, filter);
+                    }
+                }
+                TreeNode::File {
+                    selected, priority, ..
+                } => {
+                    *selected = value;
+                    *priority &= value;
                 }
             }
         }
-        
-        modified
+
+        self.store_selection();
+        let filter = self.filter.to_lowercase();
+        let Some(node) = self.directory_mut(path) else {
+            return false;
+        };
+        let select = !all_selected(node, &filter);
+        set_selected(node, select, &filter);
+        self.rebuild_flattened_tree();
+        true
     }
 
-    fn update_flattened_tree(&mut self) {
-        let mut path_to_selected = Vec::new();
-        if let Some(idx) = self.flattened_tree.state.selected() {
-            if idx < self.flattened_tree.nodes.len() {
-                let (node, _) = &self.flattened_tree.nodes[idx];
-                if let TreeNode::Directory { name, .. } = node {
-                    path_to_selected.push(name.clone());
-                }
+    /// Expands every directory, so the flattened view lists every file again.
+    fn expand_all(&mut self) {
+        fn expand(node: &mut TreeNode) {
+            if let TreeNode::Directory {
+                expanded, children, ..
+            } = node
+            {
+                *expanded = true;
+                children.iter_mut().for_each(expand);
             }
         }
 
-        self.flattened_tree = FlattenedTree::from_tree(&self.tree);
+        self.store_selection();
+        expand(&mut self.tree);
+        self.rebuild_flattened_tree();
+    }
+
+    /// The directory reached by following `path` from the root, matching names at each level.
+    fn directory_mut(&mut self, path: &[String]) -> Option<&mut TreeNode> {
+        let (first, rest) = path.split_first()?;
+        if self.tree.get_display_name() != *first {
+            return None;
+        }
+        let mut node = &mut self.tree;
+        for name in rest {
+            let TreeNode::Directory { children, .. } = node else {
+                return None;
+            };
+            node = children
+                .iter_mut()
+                .find(|child| !child.is_file() && child.get_display_name() == *name)?;
+        }
+        Some(node)
+    }
 
-        if !path_to_selected.is_empty() && !self.flattened_tree.nodes.is_empty() {
-            let dir_name = &path_to_selected[0];
+    /// Expands or collapses only the directory at `path`. Returns whether it was found.
+    fn set_directory_expanded(&mut self, path: &[String], value: bool) -> bool {
+        match self.directory_mut(path) {
+            Some(TreeNode::Directory { expanded, .. }) => {
+                *expanded = value;
+                true
+            }
+            _ => false,
+        }
+    }
 
-            for (i, (node, _)) in self.flattened_tree.nodes.iter().enumerate() {
-                if let TreeNode::Directory { name, .. } = node {
-                    if name == dir_name {
-                        self.flattened_tree.state.select(Some(i));
-                        break;
+    // Copies the flags toggled in the flattened view back to the tree, which keeps them for
+    // files the next view hides
+    fn store_selection(&mut self) {
+        fn store(node: &mut TreeNode, visible: &HashMap<PathBuf, (bool, bool)>) {
+            match node {
+                TreeNode::Directory { children, .. } => {
+                    for child in children {
+                        store(child, visible);
+                    }
+                }
+                TreeNode::File {
+                    path,
+                    selected,
+                    priority,
+                    ..
+                } => {
+                    if let Some(&(is_selected, is_priority)) = visible.get(path) {
+                        *selected = is_selected;
+                        *priority = is_priority;
                     }
                 }
             }
         }
+
+        let visible: HashMap<PathBuf, (bool, bool)> = self
+            .flattened_tree
+            .nodes
+            .iter()
+            .filter_map(|(node, _)| match node {
+                TreeNode::File {
+                    path,
+                    selected,
+                    priority,
+                    ..
+                } => Some((path.clone(), (*selected, *priority))),
+                TreeNode::Directory { .. } => None,
+            })
+            .collect();
+        store(&mut self.tree, &visible);
+    }
+
+    fn set_filter(&mut self, filter: String) {
+        self.filter = filter;
+        self.update_flattened_tree();
+        if self.flattened_tree.state.selected().is_none() && !self.flattened_tree.nodes.is_empty() {
+            self.flattened_tree.state.select(Some(0));
+        }
+    }
+
+    fn update_flattened_tree(&mut self) {
+        self.store_selection();
+        self.rebuild_flattened_tree();
+    }
+
+    fn rebuild_flattened_tree(&mut self) {
+        let selected_directory = self
+            .flattened_tree
+            .state
+            .selected()
+            .and_then(|idx| self.flattened_tree.directory_path(idx));
+
+        self.flattened_tree = FlattenedTree::from_tree_filtered(&self.tree, &self.filter);
+
+        if let Some(index) =
+            selected_directory.and_then(|path| self.flattened_tree.find_directory(&path))
+        {
+            self.flattened_tree.state.select(Some(index));
+        }
     }
 
     fn select_all(&mut self) {
@@ -398,7 +895,80 @@ impl App {
     }
 }
 
-fn ui<B: Backend>(f: &mut Frame, app: &mut App) {
+// Cuts `text` to at most `max_width` terminal columns, ending with an ellipsis when shortened.
+// Width is measured per character, so wide CJK/emoji glyphs count as two columns.
+fn truncate_to_width(text: &str, max_width: usize) -> String {
+    if text.width() <= max_width {
+        return text.to_string();
+    }
+    if max_width == 0 {
+        return String::new();
+    }
+
+    let mut result = String::new();
+    let mut used = 0;
+    for c in text.chars() {
+        let w = c.width().unwrap_or(0);
+        if used + w > max_width - 1 {
+            break;
+        }
+        result.push(c);
+        used += w;
+    }
+    result.push('…');
+    result
+}
+
+// A tree row (indent, marker, name) that fits in `max_width` columns; the name is truncated first
+fn fit_row(depth: usize, prefix: &str, name: &str, max_width: usize) -> String {
+    let lead = format!("{}{}", "  ".repeat(depth), prefix);
+    let lead_width = lead.width();
+    if lead_width >= max_width {
+        return truncate_to_width(&lead, max_width);
+    }
+
+    format!(
+        "{}{}",
+        lead,
+        truncate_to_width(name, max_width - lead_width)
+    )
+}
+
+// One row per slice of the tree: `█` all selected, `▒` some, `·` none, blank for directories
+fn render_overview(f: &mut Frame, tree: &FlattenedTree, area: Rect) {
+    let density = tree.selection_density(usize::from(area.height.saturating_sub(2)));
+    let rows = density.len();
+    // The slice holding the cursor, by the same boundaries `selection_density` uses
+    let cursor_row = tree
+        .state
+        .selected()
+        .and_then(|i| (0..rows).find(|row| i < (row + 1) * tree.nodes.len() / rows));
+
+    let lines: Vec<Line> = density
+        .iter()
+        .enumerate()
+        .map(|(row, share)| {
+            let (glyph, color) = match share {
+                None => (" ", Color::Reset),
+                Some(share) if *share == 0.0 => ("·", Color::DarkGray),
+                Some(share) if *share < 1.0 => ("▒", Color::Green),
+                Some(_) => ("█", Color::Green),
+            };
+            let mut style = Style::default().fg(color);
+            if cursor_row == Some(row) {
+                style = style.add_modifier(Modifier::REVERSED);
+            }
+            Line::from(Span::styled(glyph, style))
+        })
+        .collect();
+
+    f.render_widget(
+        Paragraph::new(lines).block(Block::default().borders(Borders::ALL)),
+        area,
+    );
+}
+
+fn ui(f: &mut Frame, app: &mut App) {
     let chunks = Layout::default()
         .direction(Direction::Vertical)
         .margin(2)
@@ -426,24 +996,52 @@ fn ui<B: Backend>(f: &mut Frame, app: &mut App) {
         .fg(Color::White)
         .add_modifier(Modifier::BOLD);
 
+    let (tree_area, side_area) = if app.reordering || app.token_breakdown.is_some() {
+        let panes = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(50), Constraint::Percentage(50)].as_ref())
+            .split(chunks[1]);
+        (panes[0], Some(panes[1]))
+    } else {
+        (chunks[1], None)
+    };
+    let (tree_area, overview_area) = if app.show_overview {
+        let columns = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Min(1), Constraint::Length(3)].as_ref())
+            .split(tree_area);
+        (columns[0], Some(columns[1]))
+    } else {
+        (tree_area, None)
+    };
+    // Columns available inside the list borders
+    let row_width = usize::from(tree_area.width.saturating_sub(2));
+
     let items: Vec<ListItem> = app
         .flattened_tree
         .nodes
         .iter()
         .enumerate()
         .map(|(i, (node, depth))| {
-            let indent = "  ".repeat(*depth);
             let is_file = node.is_file();
 
             let prefix = if is_file {
-                if node.is_selected() { "[✓] " } else { "[ ] " }
+                if node.is_priority() {
+                    "[★] "
+                } else if node.is_selected() {
+                    "[✓] "
+                } else {
+                    "[ ] "
+                }
             } else {
                 if node.is_expanded() { "▼ " } else { "► " }
             };
 
-            let content = format!("{}{}{}", indent, prefix, node.get_display_name());
+            let content = fit_row(*depth, prefix, &node.get_display_name(), row_width);
             let style = if app.flattened_tree.state.selected() == Some(i) {
                 selected_style
+            } else if is_file && node.is_priority() {
+                Style::default().fg(Color::Yellow)
             } else if is_file && node.is_selected() {
                 Style::default().fg(Color::Green)
             } else if !is_file {
@@ -464,71 +1062,357 @@ fn ui<B: Backend>(f: &mut Frame, app: &mut App) {
         )))
         .highlight_style(selected_style);
 
-    f.render_stateful_widget(file_list, chunks[1], &mut app.flattened_tree.state);
+    if let Some(breakdown_area) = side_area.filter(|_| !app.reordering)
+        && let Some(breakdown) = &app.token_breakdown
+    {
+        let breakdown_width = usize::from(breakdown_area.width.saturating_sub(2));
+        let rows: Vec<ListItem> = breakdown
+            .rows
+            .iter()
+            .map(|row| ListItem::new(truncate_to_width(row, breakdown_width)))
+            .collect();
+        f.render_widget(
+            List::new(rows).block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title(breakdown.title.clone()),
+            ),
+            breakdown_area,
+        );
+    }
+
+    if let Some(order_area) = side_area.filter(|_| app.reordering) {
+        // Output order pane
+        let order_width = usize::from(order_area.width.saturating_sub(2));
+        let order_items: Vec<ListItem> = app
+            .order
+            .paths
+            .iter()
+            .enumerate()
+            .map(|(i, path)| {
+                let row = format!("{:>3}. {}", i + 1, path.display());
+                ListItem::new(truncate_to_width(&row, order_width))
+            })
+            .collect();
+        let order_list = List::new(order_items)
+            .block(Block::default().borders(Borders::ALL).title("Output order"))
+            .highlight_style(selected_style);
+        f.render_stateful_widget(order_list, order_area, &mut app.order.state);
+    }
+
+    f.render_stateful_widget(file_list, tree_area, &mut app.flattened_tree.state);
+
+    if let Some(overview_area) = overview_area {
+        render_overview(f, &app.flattened_tree, overview_area);
+    }
+
+    if app.editing_filter || !app.filter.is_empty() {
+        let cursor = if app.editing_filter { "▏" } else { "" };
+        let filter = Paragraph::new(Span::styled(
+            format!("/{}{}", app.filter, cursor),
+            Style::default().fg(Color::Yellow),
+        ));
+        f.render_widget(filter, chunks[2]);
+    }
 
     // Controls help
+    let help_message = if app.reordering {
+        "↑/↓: Navigate | K/Alt+↑: Move up | J/Alt+↓: Move down | r/Esc: Back to tree | Enter: Confirm"
+    } else if app.editing_filter {
+        "Type to filter by path | Enter: Keep filter | Esc: Clear filter"
+    } else {
+        app.help_message.as_str()
+    };
     let controls = Paragraph::new(Span::styled(
-        app.help_message.clone(),
+        help_message.to_string(),
         Style::default().fg(Color::DarkGray),
     ));
     f.render_widget(controls, chunks[3]);
 }
 
 pub fn select_files(
+    files: Vec<PathBuf>,
+    file_reader: impl Fn(&PathBuf) -> anyhow::Result<String> + Sync,
+    auto: bool,
+    session: SelectionSession,
+    read_jobs: usize,
+    tokenizer: Option<Tokenizer>,
+) -> anyhow::Result<Vec<FileContext>> {
+    let (selected_paths, priority_paths) =
+        choose_paths(files, &file_reader, auto, session, tokenizer)?;
+
+    let mut selected_files = read_selected_files(selected_paths, &file_reader, read_jobs)?;
+    for file in &mut selected_files {
+        file.priority = priority_paths.contains(&file.path);
+    }
+    Ok(selected_files)
+}
+
+/// Like `select_files`, but leaves the selected files unread, with empty contents, for a
+/// caller that reads them one at a time later.
+pub fn select_paths(
     files: Vec<PathBuf>,
     file_reader: impl Fn(&PathBuf) -> anyhow::Result<String>,
     auto: bool,
+    session: SelectionSession,
+    tokenizer: Option<Tokenizer>,
 ) -> anyhow::Result<Vec<FileContext>> {
+    let (selected_paths, priority_paths) =
+        choose_paths(files, &file_reader, auto, session, tokenizer)?;
+
+    Ok(selected_paths
+        .into_iter()
+        .map(|path| FileContext {
+            priority: priority_paths.contains(&path),
+            ..FileContext::new(path, String::new())
+        })
+        .collect())
+}
+
+// The selected paths in output order and the subset marked "must review"; `auto` takes
+// every file without showing the selector
+fn choose_paths(
+    files: Vec<PathBuf>,
+    file_reader: &dyn Fn(&PathBuf) -> anyhow::Result<String>,
+    auto: bool,
+    session: SelectionSession,
+    tokenizer: Option<Tokenizer>,
+) -> anyhow::Result<(Vec<PathBuf>, Vec<PathBuf>)> {
     if files.is_empty() {
         info!("No files to select");
-        return Ok(Vec::new());
+        return Ok((Vec::new(), Vec::new()));
     }
 
     debug!("Selecting from {} available files", files.len());
 
     if auto {
         info!("Auto-selecting all {} files", files.len());
-        let mut selected_files = Vec::new();
-        for path in files {
-            debug!("Reading file: {}", path.display());
-            match file_reader(&path) {
-                Ok(content) => {
-                    selected_files.push(FileContext { path, content });
-                }
-                Err(e) => {
-                    warn!("Error reading file {}: {}", path.display(), e);
-                }
-            }
-        }
-
-        info!("Successfully loaded {} files", selected_files.len());
-        return Ok(selected_files);
+        return Ok((files, Vec::new()));
     }
 
     // Interactive TUI selection
-    let selected_paths = run_tui(&files)?;
+    require_terminal(io::stdin().is_terminal(), io::stdout().is_terminal())?;
+    run_tui(&files, session, file_reader, tokenizer)
+}
 
-    let mut selected_files = Vec::new();
-    for path in selected_paths {
-        debug!("Reading file: {}", path.display());
-        match file_reader(&path) {
-            Ok(content) => {
-                selected_files.push(FileContext {
-                    path: path.clone(),
-                    content,
-                });
+/// Outcome of fitting a selection into a token budget.
+#[derive(Debug, Default)]
+pub struct BudgetSelection {
+    pub selected: Vec<FileContext>,
+    /// Files left out, with their token counts.
+    pub skipped: Vec<(PathBuf, usize)>,
+}
+
+fn priority_rank(path: &Path, priority: &[String]) -> usize {
+    let path = path.to_string_lossy();
+    priority
+        .iter()
+        .position(|fragment| path.contains(fragment.as_str()))
+        .unwrap_or(priority.len())
+}
+
+// Indices in the order `budget.strategy` offers files to the budget
+fn budget_visit_order(paths: &[&Path], costs: &[usize], budget: &TokenBudget) -> Vec<usize> {
+    let mut visit: Vec<usize> = (0..paths.len()).collect();
+    match budget.strategy {
+        BudgetStrategy::SmallestFirst => visit.sort_by_key(|&i| costs[i]),
+        BudgetStrategy::Priority => {
+            visit.sort_by_key(|&i| priority_rank(paths[i], &budget.priority))
+        }
+        BudgetStrategy::InOrder => {}
+    }
+    visit
+}
+
+/// Read-only preview of `apply_token_budget` for the `plan` command.
+#[derive(Debug, Default, PartialEq)]
+pub struct BudgetPlan {
+    /// Files the budget keeps with their token counts, in the order the strategy visits them.
+    pub fitting: Vec<(PathBuf, usize)>,
+    /// The first file, in visiting order, that didn't fit.
+    pub overflow: Option<(PathBuf, usize)>,
+    pub used_tokens: usize,
+}
+
+/// Which of `costs` (path, tokens) fit in `budget.max_tokens` once `reserved` tokens are set
+/// aside for the rest of the output. Files are visited greedily in the order the strategy
+/// prefers; one that doesn't fit is skipped, not fatal, as a later, smaller file may still
+/// fit, except with `InOrder`, which keeps only the prefix that fits.
+pub fn plan_token_budget(
+    costs: Vec<(PathBuf, usize)>,
+    budget: &TokenBudget,
+    reserved: usize,
+) -> BudgetPlan {
+    let paths: Vec<&Path> = costs.iter().map(|(path, _)| path.as_path()).collect();
+    let tokens: Vec<usize> = costs.iter().map(|(_, tokens)| *tokens).collect();
+    let visit = budget_visit_order(&paths, &tokens, budget);
+    let available = budget.max_tokens.saturating_sub(reserved);
+    debug!(
+        "Reserved {} of {} budget tokens for the rest of the output",
+        reserved, budget.max_tokens
+    );
+
+    let mut plan = BudgetPlan::default();
+    for i in visit {
+        if plan.used_tokens + tokens[i] <= available {
+            plan.used_tokens += tokens[i];
+            plan.fitting.push(costs[i].clone());
+        } else {
+            plan.overflow.get_or_insert_with(|| costs[i].clone());
+            if budget.strategy == BudgetStrategy::InOrder {
+                break;
             }
-            Err(e) => {
-                warn!("Error reading file {}: {}", path.display(), e);
+        }
+    }
+    plan
+}
+
+/// Keeps the files `plan_token_budget` fits, each costing `file_cost` tokens, its content
+/// with the header and fences around it. Kept files retain their original order.
+pub fn apply_token_budget(
+    files: Vec<FileContext>,
+    budget: &TokenBudget,
+    reserved: usize,
+    file_cost: &dyn Fn(&FileContext) -> usize,
+) -> BudgetSelection {
+    let costs: Vec<(PathBuf, usize)> = files
+        .iter()
+        .map(|f| (f.path.clone(), file_cost(f)))
+        .collect();
+    let plan = plan_token_budget(costs.clone(), budget, reserved);
+    let fitting: HashSet<&Path> = plan
+        .fitting
+        .iter()
+        .map(|(path, _)| path.as_path())
+        .collect();
+
+    let mut result = BudgetSelection::default();
+    for (file, (_, cost)) in files.into_iter().zip(costs) {
+        if fitting.contains(file.path.as_path()) {
+            result.selected.push(file);
+        } else {
+            result.skipped.push((file.path, cost));
+        }
+    }
+
+    info!(
+        "Token budget kept {} files ({} tokens max), skipped {}",
+        result.selected.len(),
+        budget.max_tokens,
+        result.skipped.len()
+    );
+    result
+}
+
+fn require_terminal(stdin_is_tty: bool, stdout_is_tty: bool) -> anyhow::Result<()> {
+    if stdin_is_tty && stdout_is_tty {
+        return Ok(());
+    }
+
+    let stream = if stdin_is_tty { "stdout" } else { "stdin" };
+    warn!(
+        "Interactive selection unavailable: {} is not a terminal",
+        stream
+    );
+    Err(anyhow::anyhow!(
+        "Interactive file selection needs a terminal, but {} is not a TTY. Re-run with --auto to include all matched files",
+        stream
+    ))
+}
+
+/// Reads `paths` on up to `jobs` threads, each taking the next unread path, so no more than
+/// `jobs` files are open at once. Results keep the order of `paths`.
+fn read_all(
+    paths: &[PathBuf],
+    file_reader: &(impl Fn(&PathBuf) -> anyhow::Result<String> + Sync),
+    jobs: usize,
+) -> Vec<anyhow::Result<String>> {
+    let jobs = jobs.clamp(1, paths.len().max(1));
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<Option<anyhow::Result<String>>> = paths.iter().map(|_| None).collect();
+
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..jobs)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut read = Vec::new();
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(path) = paths.get(index) else {
+                            break;
+                        };
+                        debug!("Reading file: {}", path.display());
+                        read.push((index, file_reader(path)));
+                    }
+                    read
+                })
+            })
+            .collect();
+
+        for worker in workers {
+            for (index, result) in worker.join().expect("file reader thread panicked") {
+                results[index] = Some(result);
             }
         }
+    });
+
+    results
+        .into_iter()
+        .map(|result| result.expect("every path is read exactly once"))
+        .collect()
+}
+
+fn read_selected_files(
+    paths: Vec<PathBuf>,
+    file_reader: &(impl Fn(&PathBuf) -> anyhow::Result<String> + Sync),
+    jobs: usize,
+) -> anyhow::Result<Vec<FileContext>> {
+    let results = read_all(&paths, file_reader, jobs);
+    let mut selected_files = Vec::new();
+    for (path, result) in paths.into_iter().zip(results) {
+        selected_files.extend(loaded_file(path, result)?);
     }
 
     info!("Successfully loaded {} files", selected_files.len());
     Ok(selected_files)
 }
 
-fn run_tui(files: &[PathBuf]) -> anyhow::Result<Vec<PathBuf>> {
+/// `path` with the content `read` returned for it: a binary file keeps only its size, and
+/// one that couldn't be read is skipped with a warning (`None`).
+pub fn loaded_file(
+    path: PathBuf,
+    read: anyhow::Result<String>,
+) -> anyhow::Result<Option<FileContext>> {
+    match read {
+        Ok(content) => Ok(Some(FileContext::new(path, content))),
+        Err(e) => match e.downcast_ref::<BinaryFileError>() {
+            Some(binary) => {
+                debug!("Detected binary file: {}", path.display());
+                Ok(Some(FileContext::binary(path, binary.bytes)))
+            }
+            // A run out of time fails as a whole rather than skipping the unread files
+            None if e
+                .downcast_ref::<CliError>()
+                .is_some_and(|error| error.kind == ErrorKind::Timeout) =>
+            {
+                Err(e)
+            }
+            None => {
+                warn!("Error reading file {}: {}", path.display(), e);
+                Ok(None)
+            }
+        },
+    }
+}
+
+/// Runs the interactive selector, returning the selected paths in output order and the
+/// subset marked "must review".
+fn run_tui(
+    files: &[PathBuf],
+    session: SelectionSession,
+    read: &dyn Fn(&PathBuf) -> anyhow::Result<String>,
+    tokenizer: Option<Tokenizer>,
+) -> anyhow::Result<(Vec<PathBuf>, Vec<PathBuf>)> {
     enable_raw_mode()?;
     let mut stdout = io::stdout();
     execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
@@ -539,8 +1423,21 @@ fn run_tui(files: &[PathBuf]) -> anyhow::Result<Vec<PathBuf>> {
         files.to_vec(),
         "Select files to include in your LLM context".to_string(),
     );
+    if !session.initial_selection.is_empty() {
+        info!(
+            "Resuming selection of {} files",
+            session.initial_selection.len()
+        );
+        app.preselect(&session.initial_selection);
+    }
+    app.autosave = session.autosave;
+    app.tokenizer = tokenizer;
 
-    let result = run_app(&mut terminal, &mut app);
+    let result = run_app(&mut terminal, &mut app, read);
+    // Files hidden by the filter or a collapsed directory keep their selection in the tree
+    app.set_filter(String::new());
+    app.expand_all();
+    app.flush_autosave(Instant::now(), true);
 
     disable_raw_mode()?;
     execute!(
@@ -552,9 +1449,14 @@ fn run_tui(files: &[PathBuf]) -> anyhow::Result<Vec<PathBuf>> {
 
     match result {
         Ok(_) => {
-            let selected = app.flattened_tree.get_selected_paths();
-            info!("Selected {} files", selected.len());
-            Ok(selected)
+            let selected = app.ordered_selection();
+            let priority = app.flattened_tree.get_priority_paths();
+            info!(
+                "Selected {} files, {} marked for review",
+                selected.len(),
+                priority.len()
+            );
+            Ok((selected, priority))
         }
         Err(err) => {
             warn!("Error during file selection: {}", err);
@@ -563,97 +1465,163 @@ fn run_tui(files: &[PathBuf]) -> anyhow::Result<Vec<PathBuf>> {
     }
 }
 
-fn run_app<B: Backend>(terminal: &mut Terminal<B>, app: &mut App) -> anyhow::Result<()> {
+fn run_app<B: Backend>(
+    terminal: &mut Terminal<B>,
+    app: &mut App,
+    read: &dyn Fn(&PathBuf) -> anyhow::Result<String>,
+) -> anyhow::Result<()> {
     loop {
-        terminal.draw(|f| ui::<B>(f, app))?;
-
-        if crossterm::event::poll(Duration::from_millis(100))? {
-            if let Event::Key(key) = event::read()? {
+        app.flush_autosave(Instant::now(), false);
+        terminal.draw(|f| ui(f, app))?;
+
+        if crossterm::event::poll(Duration::from_millis(100))?
+            && let Event::Key(key) = event::read()?
+        {
+            if app.reordering {
+                let alt = key.modifiers.contains(KeyModifiers::ALT);
                 match key.code {
-                    KeyCode::Char('q') | KeyCode::Esc => {
-                        if app.flattened_tree.selected_files_count() > 0 {
-                            return Ok(());
-                        } else {
-                            return Err(anyhow::anyhow!("No files selected"));
-                        }
+                    KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                        return Err(anyhow::anyhow!("Selection cancelled"));
                     }
+                    KeyCode::Char('r') | KeyCode::Esc => app.toggle_reordering(),
+                    KeyCode::Char('K') => app.order.move_up(),
+                    KeyCode::Char('J') => app.order.move_down(),
+                    KeyCode::Up if alt => app.order.move_up(),
+                    KeyCode::Down if alt => app.order.move_down(),
+                    KeyCode::Up => app.order.previous(),
+                    KeyCode::Down => app.order.next(),
+                    KeyCode::Enter if !app.order.paths.is_empty() => return Ok(()),
+                    _ => {}
+                }
+                continue;
+            }
+
+            if app.editing_filter {
+                match key.code {
                     KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
                         return Err(anyhow::anyhow!("Selection cancelled"));
                     }
-                    KeyCode::Char('a') => app.select_all(),
-                    KeyCode::Char('n') => app.deselect_all(),
-                    KeyCode::Char(' ') => {
-                        app.flattened_tree.toggle_selected();
+                    KeyCode::Char(c) => {
+                        let filter = format!("{}{}", app.filter, c);
+                        app.set_filter(filter);
                     }
-                    KeyCode::Right => {
-                        let dir_name_to_expand = if let Some(i) = app.flattened_tree.state.selected() {
-                            let (node, _) = &app.flattened_tree.nodes[i];
-                            if !node.is_file() {
-                                if let TreeNode::Directory { name, .. } = node {
-                                    Some(name.clone())
-                                } else {
-                                    None
-                                }
-                            } else {
-                                None
-                            }
-                        } else {
-                            None
-                        };
-                        
-                        if let Some(name) = dir_name_to_expand {
-                            if app.expand_directory_by_name(&name) {
-                                let current_selection = app.flattened_tree.state.selected();
-                                app.update_flattened_tree();
-                                if let Some(idx) = current_selection {
-                                    if idx < app.flattened_tree.nodes.len() {
-                                        app.flattened_tree.state.select(Some(idx));
-                                    } else if !app.flattened_tree.nodes.is_empty() {
-                                        app.flattened_tree.state.select(Some(0));
-                                    }
-                                }
-                            }
+                    KeyCode::Backspace => {
+                        let mut filter = app.filter.clone();
+                        filter.pop();
+                        app.set_filter(filter);
+                    }
+                    KeyCode::Enter => app.editing_filter = false,
+                    KeyCode::Esc => {
+                        app.editing_filter = false;
+                        app.set_filter(String::new());
+                    }
+                    KeyCode::Down => app.flattened_tree.next(),
+                    KeyCode::Up => app.flattened_tree.previous(),
+                    _ => {}
+                }
+                continue;
+            }
+
+            match key.code {
+                KeyCode::Char('/') => app.editing_filter = true,
+                KeyCode::Esc if !app.filter.is_empty() => app.set_filter(String::new()),
+                KeyCode::Char('q') | KeyCode::Esc => {
+                    if app.flattened_tree.selected_files_count() > 0 {
+                        return Ok(());
+                    } else {
+                        return Err(anyhow::anyhow!("No files selected"));
+                    }
+                }
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                    return Err(anyhow::anyhow!("Selection cancelled"));
+                }
+                KeyCode::Char('a') => {
+                    app.select_all();
+                    app.mark_changed();
+                }
+                KeyCode::Char('n') => {
+                    app.deselect_all();
+                    app.mark_changed();
+                }
+                KeyCode::Char('r') => app.toggle_reordering(),
+                KeyCode::Char('o') => app.show_overview = !app.show_overview,
+                KeyCode::Char('t') => app.toggle_token_breakdown(read),
+                KeyCode::Char(' ') => {
+                    let directory = app
+                        .flattened_tree
+                        .state
+                        .selected()
+                        .and_then(|i| app.flattened_tree.directory_path(i));
+                    match directory {
+                        Some(path) => {
+                            app.toggle_directory_selection(&path);
                         }
+                        None => app.flattened_tree.toggle_selected(),
                     }
-                    KeyCode::Left => {
-                        let dir_name_to_collapse = if let Some(i) = app.flattened_tree.state.selected() {
-                            let (node, _) = &app.flattened_tree.nodes[i];
-                            if !node.is_file() {
-                                if let TreeNode::Directory { name, .. } = node {
-                                    Some(name.clone())
-                                } else {
-                                    None
-                                }
-                            } else {
-                                None
-                            }
-                        } else {
-                            None
-                        };
-                        
-                        if let Some(name) = dir_name_to_collapse {
-                            if app.collapse_directory_by_name(&name) {
-                                let current_selection = app.flattened_tree.state.selected();
-                                app.update_flattened_tree();
-                                if let Some(idx) = current_selection {
-                                    if idx < app.flattened_tree.nodes.len() {
-                                        app.flattened_tree.state.select(Some(idx));
-                                    } else if !app.flattened_tree.nodes.is_empty() {
-                                        app.flattened_tree.state.select(Some(0));
-                                    }
-                                }
+                    app.mark_changed();
+                }
+                KeyCode::Char('m') => {
+                    app.flattened_tree.toggle_priority();
+                    app.mark_changed();
+                }
+                KeyCode::Right => {
+                    let directory = app
+                        .flattened_tree
+                        .state
+                        .selected()
+                        .and_then(|i| app.flattened_tree.directory_path(i));
+
+                    if let Some(path) = directory
+                        && app.set_directory_expanded(&path, true)
+                    {
+                        let current_selection = app.flattened_tree.state.selected();
+                        app.update_flattened_tree();
+                        if let Some(idx) = current_selection {
+                            if idx < app.flattened_tree.nodes.len() {
+                                app.flattened_tree.state.select(Some(idx));
+                            } else if !app.flattened_tree.nodes.is_empty() {
+                                app.flattened_tree.state.select(Some(0));
                             }
                         }
                     }
-                    KeyCode::Down => app.flattened_tree.next(),
-                    KeyCode::Up => app.flattened_tree.previous(),
-                    KeyCode::Enter => {
-                        if app.flattened_tree.selected_files_count() > 0 {
-                            return Ok(());
+                }
+                KeyCode::Left => {
+                    let directory = app
+                        .flattened_tree
+                        .state
+                        .selected()
+                        .and_then(|i| app.flattened_tree.directory_path(i));
+
+                    if let Some(path) = directory
+                        && app.set_directory_expanded(&path, false)
+                    {
+                        let current_selection = app.flattened_tree.state.selected();
+                        app.update_flattened_tree();
+                        if let Some(idx) = current_selection {
+                            if idx < app.flattened_tree.nodes.len() {
+                                app.flattened_tree.state.select(Some(idx));
+                            } else if !app.flattened_tree.nodes.is_empty() {
+                                app.flattened_tree.state.select(Some(0));
+                            }
                         }
                     }
-                    _ => {}
                 }
+                KeyCode::Char('z') => {
+                    let current_selection = app.flattened_tree.state.selected();
+                    let path = current_selection.and_then(|i| app.flattened_tree.directory_path(i));
+                    if let Some(path) = path
+                        && app.toggle_subtree(&path)
+                    {
+                        app.update_flattened_tree();
+                        app.flattened_tree.state.select(current_selection);
+                    }
+                }
+                KeyCode::Down => app.flattened_tree.next(),
+                KeyCode::Up => app.flattened_tree.previous(),
+                KeyCode::Enter if app.flattened_tree.selected_files_count() > 0 => {
+                    return Ok(());
+                }
+                _ => {}
             }
         }
     }
@@ -697,19 +1665,42 @@ mod tests {
 
         let reader = |path: &PathBuf| mock_fs.read_file(path);
 
-        let selected = select_files(files, reader, true).unwrap();
+        let selected =
+            select_files(files, reader, true, SelectionSession::default(), 1, None).unwrap();
 
         assert_eq!(selected.len(), 2);
         assert_eq!(selected[0].content, "content1");
         assert_eq!(selected[1].content, "content2");
     }
 
+    #[test]
+    fn test_select_paths_leaves_files_unread() {
+        let files = vec![PathBuf::from("file1.rs"), PathBuf::from("file2.rs")];
+        let reader = |path: &PathBuf| -> anyhow::Result<String> {
+            panic!("{} was read during selection", path.display())
+        };
+
+        let selected = select_paths(
+            files.clone(),
+            reader,
+            true,
+            SelectionSession::default(),
+            None,
+        )
+        .unwrap();
+
+        let paths: Vec<PathBuf> = selected.iter().map(|file| file.path.clone()).collect();
+        assert_eq!(paths, files);
+        assert!(selected.iter().all(|file| file.content.is_empty()));
+    }
+
     #[test]
     fn test_select_files_with_empty_input() {
         let files: Vec<PathBuf> = vec![];
         let reader = |_: &PathBuf| -> anyhow::Result<String> { Ok("".to_string()) };
 
-        let selected = select_files(files, reader, true).unwrap();
+        let selected =
+            select_files(files, reader, true, SelectionSession::default(), 1, None).unwrap();
 
         assert_eq!(selected.len(), 0);
     }
@@ -720,11 +1711,660 @@ mod tests {
         let reader =
             |_: &PathBuf| -> anyhow::Result<String> { Err(anyhow::anyhow!("File not found")) };
 
-        let selected = select_files(files, reader, true).unwrap();
+        let selected =
+            select_files(files, reader, true, SelectionSession::default(), 1, None).unwrap();
 
         assert_eq!(selected.len(), 0);
     }
 
+    #[test]
+    fn test_selection_order_reordering() {
+        let mut order = SelectionOrder::new();
+        order.sync(vec![
+            PathBuf::from("a.rs"),
+            PathBuf::from("b.rs"),
+            PathBuf::from("c.rs"),
+        ]);
+        assert_eq!(order.state.selected(), Some(0));
+
+        // Moving the first entry up is clamped
+        order.move_up();
+        assert_eq!(order.paths[0], PathBuf::from("a.rs"));
+        assert_eq!(order.state.selected(), Some(0));
+
+        order.move_down();
+        order.move_down();
+        assert_eq!(
+            order.paths,
+            vec![
+                PathBuf::from("b.rs"),
+                PathBuf::from("c.rs"),
+                PathBuf::from("a.rs"),
+            ]
+        );
+        assert_eq!(order.state.selected(), Some(2));
+
+        // Moving the last entry down is clamped
+        order.move_down();
+        assert_eq!(order.paths[2], PathBuf::from("a.rs"));
+
+        order.previous();
+        order.move_up();
+        assert_eq!(
+            order.paths,
+            vec![
+                PathBuf::from("c.rs"),
+                PathBuf::from("b.rs"),
+                PathBuf::from("a.rs"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_selection_order_sync_keeps_manual_order() {
+        let mut order = SelectionOrder::new();
+        order.sync(vec![PathBuf::from("a.rs"), PathBuf::from("b.rs")]);
+        order.move_down();
+
+        order.sync(vec![PathBuf::from("a.rs"), PathBuf::from("c.rs")]);
+
+        assert_eq!(
+            order.paths,
+            vec![PathBuf::from("a.rs"), PathBuf::from("c.rs")]
+        );
+
+        order.sync(vec![
+            PathBuf::from("a.rs"),
+            PathBuf::from("b.rs"),
+            PathBuf::from("c.rs"),
+        ]);
+        assert_eq!(
+            order.paths,
+            vec![
+                PathBuf::from("a.rs"),
+                PathBuf::from("c.rs"),
+                PathBuf::from("b.rs"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_select_files_keeps_binary_files() {
+        let files = vec![PathBuf::from("main.rs"), PathBuf::from("logo.png")];
+        let reader = |path: &PathBuf| -> anyhow::Result<String> {
+            if path.ends_with("logo.png") {
+                Err(BinaryFileError { bytes: 512 }.into())
+            } else {
+                Ok("fn main() {}".to_string())
+            }
+        };
+
+        let selected =
+            select_files(files, reader, true, SelectionSession::default(), 1, None).unwrap();
+
+        assert_eq!(selected.len(), 2);
+        assert_eq!(selected[0].binary_bytes, None);
+        assert_eq!(selected[1].binary_bytes, Some(512));
+        assert!(selected[1].content.is_empty());
+    }
+
+    #[test]
+    fn test_read_jobs_bounds_concurrency_and_keeps_order() {
+        let files: Vec<PathBuf> = (0..200)
+            .map(|i| PathBuf::from(format!("file{}.rs", i)))
+            .collect();
+        let open = AtomicUsize::new(0);
+        let peak = AtomicUsize::new(0);
+        let reader = |path: &PathBuf| -> anyhow::Result<String> {
+            let now_open = open.fetch_add(1, Ordering::SeqCst) + 1;
+            peak.fetch_max(now_open, Ordering::SeqCst);
+            thread::sleep(Duration::from_micros(200));
+            open.fetch_sub(1, Ordering::SeqCst);
+            Ok(path.display().to_string())
+        };
+
+        let selected = select_files(
+            files.clone(),
+            reader,
+            true,
+            SelectionSession::default(),
+            4,
+            None,
+        )
+        .unwrap();
+
+        assert!(peak.load(Ordering::SeqCst) <= 4);
+        assert_eq!(selected.len(), files.len());
+        for (file, path) in selected.iter().zip(&files) {
+            assert_eq!(&file.path, path);
+            assert_eq!(file.content, path.display().to_string());
+        }
+    }
+
+    #[test]
+    fn test_preselect_resumes_session() {
+        let files = vec![
+            PathBuf::from("src/a.rs"),
+            PathBuf::from("src/b.rs"),
+            PathBuf::from("c.rs"),
+        ];
+        let mut app = App::new(files, "Test".to_string());
+
+        app.preselect(&[PathBuf::from("c.rs"), PathBuf::from("src/a.rs")]);
+
+        assert_eq!(
+            app.flattened_tree.get_selected_paths(),
+            vec![PathBuf::from("src/a.rs"), PathBuf::from("c.rs")]
+        );
+    }
+
+    #[test]
+    fn test_priority_files_come_first() {
+        let files = vec![
+            PathBuf::from("src/a.rs"),
+            PathBuf::from("src/b.rs"),
+            PathBuf::from("c.rs"),
+        ];
+        let mut app = App::new(files, "Test".to_string());
+        app.select_all();
+        let position = |app: &App, path: &str| {
+            app.flattened_tree
+                .nodes
+                .iter()
+                .position(|(node, _)| matches!(node, TreeNode::File { path: p, .. } if p == Path::new(path)))
+                .unwrap()
+        };
+
+        let c = position(&app, "c.rs");
+        app.flattened_tree.state.select(Some(c));
+        app.flattened_tree.toggle_priority();
+
+        assert_eq!(
+            app.ordered_selection(),
+            vec![
+                PathBuf::from("c.rs"),
+                PathBuf::from("src/a.rs"),
+                PathBuf::from("src/b.rs")
+            ]
+        );
+        assert_eq!(
+            app.flattened_tree.get_priority_paths(),
+            vec![PathBuf::from("c.rs")]
+        );
+
+        // Deselecting drops the mark; marking an unselected file selects it
+        app.flattened_tree.toggle_selected();
+        assert!(app.flattened_tree.get_priority_paths().is_empty());
+        app.flattened_tree.toggle_priority();
+        assert_eq!(app.flattened_tree.selected_files_count(), 3);
+    }
+
+    #[test]
+    fn test_autosave_is_debounced() {
+        let saved = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let sink = std::rc::Rc::clone(&saved);
+        let mut app = App::new(vec![PathBuf::from("a.rs")], "Test".to_string());
+        app.autosave = Some(Box::new(move |paths: &[PathBuf]| {
+            sink.borrow_mut().push(paths.to_vec())
+        }));
+
+        app.flattened_tree.next();
+        app.flattened_tree.toggle_selected();
+        app.mark_changed();
+        let changed_at = app.last_change.unwrap();
+
+        app.flush_autosave(changed_at, false);
+        assert!(saved.borrow().is_empty());
+
+        app.flush_autosave(changed_at + AUTOSAVE_DEBOUNCE, false);
+        assert_eq!(*saved.borrow(), vec![vec![PathBuf::from("a.rs")]]);
+
+        app.flush_autosave(changed_at + AUTOSAVE_DEBOUNCE * 2, true);
+        assert_eq!(saved.borrow().len(), 1);
+    }
+
+    fn tree_with(nodes: Vec<TreeNode>) -> FlattenedTree {
+        FlattenedTree {
+            nodes: nodes.into_iter().map(|node| (node, 1)).collect(),
+            state: ListState::default(),
+        }
+    }
+
+    fn file(name: &str, selected: bool) -> TreeNode {
+        let mut node = TreeNode::new_file(name.to_string(), PathBuf::from(name));
+        if selected {
+            node.toggle_selected();
+        }
+        node
+    }
+
+    #[test]
+    fn test_block_token_counts() {
+        let heavy_body =
+            "    let total = values.iter().map(|value| value * 2).sum::<i64>();\n".repeat(20);
+        let source = format!(
+            "use std::fmt;\n\nfn small() {{}}\n\n/// Doubles and sums.\nfn heavy(values: &[i64]) -> i64 {{\n{}    0\n}}\n\nstruct Empty;\n",
+            heavy_body
+        );
+
+        let counts = block_token_counts(Path::new("lib.rs"), &source, None).unwrap();
+        let labels: Vec<&str> = counts.iter().map(|(label, _)| label.as_str()).collect();
+        assert_eq!(labels[0], "fn heavy(values: &[i64]) -> i64");
+        assert_eq!(labels.len(), 4);
+        assert!(labels.contains(&"(top of file)"));
+        assert!(labels.contains(&"fn small() {}"));
+        assert!(labels.contains(&"struct Empty;"));
+        assert!(counts[0].1 > counts[1..].iter().map(|(_, tokens)| tokens).sum::<usize>());
+
+        assert_eq!(
+            block_token_counts(Path::new("notes.txt"), "text", None),
+            None
+        );
+
+        let breakdown = TokenBreakdown::new(Path::new("lib.rs"), Ok(source), None);
+        assert_eq!(breakdown.rows.len(), 4);
+        assert!(breakdown.rows[0].ends_with("fn heavy(values: &[i64]) -> i64"));
+    }
+
+    #[test]
+    fn test_selection_density() {
+        let files = |selected: &dyn Fn(usize) -> bool| {
+            tree_with(
+                (0..100)
+                    .map(|i| file(&format!("{i}.rs"), selected(i)))
+                    .collect(),
+            )
+        };
+
+        assert_eq!(files(&|_| false).selection_density(4), vec![Some(0.0); 4]);
+        assert_eq!(files(&|_| true).selection_density(4), vec![Some(1.0); 4]);
+        assert_eq!(
+            files(&|i| i < 50).selection_density(4),
+            vec![Some(1.0), Some(1.0), Some(0.0), Some(0.0)]
+        );
+        assert_eq!(
+            files(&|i| i % 2 == 0).selection_density(5),
+            vec![Some(0.5); 5]
+        );
+    }
+
+    #[test]
+    fn test_selection_density_small_tree_and_directories() {
+        let tree = tree_with(vec![
+            TreeNode::new_directory("src".to_string()),
+            file("a.rs", true),
+            file("b.rs", false),
+        ]);
+
+        assert_eq!(tree.selection_density(10), vec![None, Some(1.0), Some(0.0)]);
+        assert_eq!(tree.selection_density(1), vec![Some(0.5)]);
+        assert!(tree_with(Vec::new()).selection_density(10).is_empty());
+    }
+
+    #[test]
+    fn test_diff_selections() {
+        let previous = vec![
+            PathBuf::from("src/a.rs"),
+            PathBuf::from("src/b.rs"),
+            PathBuf::from("src/c.rs"),
+        ];
+        let current = vec![
+            PathBuf::from("src/c.rs"),
+            PathBuf::from("src/d.rs"),
+            PathBuf::from("src/a.rs"),
+        ];
+
+        let diff = diff_selections(&previous, &current);
+
+        assert_eq!(diff.added, vec![PathBuf::from("src/d.rs")]);
+        assert_eq!(diff.removed, vec![PathBuf::from("src/b.rs")]);
+        assert!(diff_selections(&previous, &previous).is_empty());
+    }
+
+    fn sized_file(path: &str, tokens: usize) -> FileContext {
+        // count_tokens estimates four characters per token
+        FileContext::new(PathBuf::from(path), "x".repeat(tokens * 4))
+    }
+
+    fn paths(files: &[FileContext]) -> Vec<&str> {
+        files.iter().map(|f| f.path.to_str().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_token_budget_smallest_first() {
+        let files = vec![
+            sized_file("big.rs", 60),
+            sized_file("a.rs", 10),
+            sized_file("b.rs", 30),
+            sized_file("c.rs", 20),
+        ];
+        let budget = TokenBudget {
+            max_tokens: 65,
+            strategy: BudgetStrategy::SmallestFirst,
+            priority: Vec::new(),
+        };
+
+        let result =
+            apply_token_budget(files, &budget, 0, &|file| count_tokens(&file.content, None));
+
+        assert_eq!(paths(&result.selected), vec!["a.rs", "b.rs", "c.rs"]);
+        assert_eq!(result.skipped, vec![(PathBuf::from("big.rs"), 60)]);
+    }
+
+    #[test]
+    fn test_token_budget_priority() {
+        let files = vec![
+            sized_file("tests/cli.rs", 30),
+            sized_file("src/main.rs", 40),
+            sized_file("src/core/mod.rs", 30),
+            sized_file("README.md", 5),
+        ];
+        let budget = TokenBudget {
+            max_tokens: 75,
+            strategy: BudgetStrategy::Priority,
+            priority: vec!["src/core".to_string(), "src/".to_string()],
+        };
+
+        let result =
+            apply_token_budget(files, &budget, 0, &|file| count_tokens(&file.content, None));
+
+        assert_eq!(
+            paths(&result.selected),
+            vec!["src/main.rs", "src/core/mod.rs", "README.md"]
+        );
+        assert_eq!(result.skipped, vec![(PathBuf::from("tests/cli.rs"), 30)]);
+    }
+
+    #[test]
+    fn test_token_budget_in_order() {
+        let files = vec![
+            sized_file("a.rs", 20),
+            sized_file("b.rs", 30),
+            sized_file("big.rs", 60),
+            sized_file("c.rs", 5),
+        ];
+        let budget = TokenBudget {
+            max_tokens: 65,
+            strategy: BudgetStrategy::InOrder,
+            priority: Vec::new(),
+        };
+
+        let result =
+            apply_token_budget(files, &budget, 0, &|file| count_tokens(&file.content, None));
+
+        // c.rs would still fit, but nothing after the first overflow is kept
+        assert_eq!(paths(&result.selected), vec!["a.rs", "b.rs"]);
+        assert_eq!(
+            result.skipped,
+            vec![(PathBuf::from("big.rs"), 60), (PathBuf::from("c.rs"), 5)]
+        );
+    }
+
+    #[test]
+    fn test_token_budget_reserves_overhead() {
+        let files = vec![sized_file("a.rs", 20), sized_file("b.rs", 20)];
+        let budget = TokenBudget {
+            max_tokens: 60,
+            strategy: BudgetStrategy::InOrder,
+            priority: Vec::new(),
+        };
+
+        let result = apply_token_budget(files.clone(), &budget, 10, &|file| {
+            count_tokens(&file.content, None) + 5
+        });
+        assert_eq!(paths(&result.selected), vec!["a.rs", "b.rs"]);
+
+        let result = apply_token_budget(files, &budget, 11, &|file| {
+            count_tokens(&file.content, None) + 5
+        });
+        assert_eq!(paths(&result.selected), vec!["a.rs"]);
+        assert_eq!(result.skipped, vec![(PathBuf::from("b.rs"), 25)]);
+    }
+
+    #[test]
+    fn test_plan_token_budget() {
+        let costs = vec![
+            (PathBuf::from("big.rs"), 60),
+            (PathBuf::from("a.rs"), 10),
+            (PathBuf::from("b.rs"), 30),
+            (PathBuf::from("c.rs"), 20),
+        ];
+        let budget = TokenBudget {
+            max_tokens: 40,
+            strategy: BudgetStrategy::SmallestFirst,
+            priority: Vec::new(),
+        };
+
+        let plan = plan_token_budget(costs, &budget, 0);
+
+        assert_eq!(
+            plan,
+            BudgetPlan {
+                fitting: vec![(PathBuf::from("a.rs"), 10), (PathBuf::from("c.rs"), 20)],
+                overflow: Some((PathBuf::from("b.rs"), 30)),
+                used_tokens: 30,
+            }
+        );
+    }
+
+    #[test]
+    fn test_fit_row_measures_display_width() {
+        // "漢字" is four columns wide, the emoji two
+        assert_eq!(fit_row(1, "[ ] ", "ab漢字.rs", 40), "  [ ] ab漢字.rs");
+        assert_eq!(fit_row(1, "[ ] ", "ab漢字.rs", 11), "  [ ] ab漢…");
+        assert_eq!(fit_row(1, "[ ] ", "ab漢字.rs", 10), "  [ ] ab…");
+        assert!(fit_row(0, "▼ ", "🚀🚀🚀🚀", 7).width() <= 7);
+        assert_eq!(truncate_to_width("漢字", 0), "");
+    }
+
+    #[test]
+    fn test_require_terminal() {
+        assert!(require_terminal(true, true).is_ok());
+
+        let err = require_terminal(false, true).unwrap_err().to_string();
+        assert!(err.contains("stdin is not a TTY"));
+        assert!(err.contains("--auto"));
+
+        let err = require_terminal(true, false).unwrap_err().to_string();
+        assert!(err.contains("stdout is not a TTY"));
+    }
+
+    #[test]
+    fn test_toggle_subtree() {
+        let files = vec![
+            PathBuf::from("src/main.rs"),
+            PathBuf::from("src/core/mod.rs"),
+            PathBuf::from("src/core/parse/lexer.rs"),
+            PathBuf::from("tests/cli.rs"),
+        ];
+        let mut app = App::new(files, "Test".to_string());
+        let visible = |app: &App| -> Vec<String> {
+            app.flattened_tree
+                .nodes
+                .iter()
+                .map(|(node, _)| node.get_display_name())
+                .collect()
+        };
+        let core = visible(&app)
+            .iter()
+            .position(|name| name == "core")
+            .unwrap();
+        let path = app.flattened_tree.directory_path(core).unwrap();
+        assert_eq!(path, vec!["", "src", "core"]);
+
+        // Everything starts expanded, so the first toggle folds core and parse
+        assert!(app.toggle_subtree(&path));
+        app.update_flattened_tree();
+        assert_eq!(
+            visible(&app),
+            vec!["", "src", "main.rs", "core", "tests", "cli.rs"]
+        );
+
+        assert!(app.toggle_subtree(&path));
+        app.update_flattened_tree();
+        assert_eq!(visible(&app).len(), 9);
+
+        // A partly folded subtree still has something expanded, so it folds again
+        let parse = visible(&app)
+            .iter()
+            .position(|name| name == "parse")
+            .unwrap();
+        let parse_path = app.flattened_tree.directory_path(parse).unwrap();
+        assert!(app.toggle_subtree(&parse_path));
+        assert!(app.toggle_subtree(&path));
+        app.update_flattened_tree();
+        assert!(!visible(&app).contains(&"mod.rs".to_string()));
+
+        assert!(!app.toggle_subtree(&["".to_string(), "missing".to_string()]));
+        assert_eq!(app.flattened_tree.directory_path(2), None);
+    }
+
+    #[test]
+    fn test_toggle_directory_selection_includes_collapsed_files() {
+        let files = vec![
+            PathBuf::from("src/main.rs"),
+            PathBuf::from("src/core/mod.rs"),
+            PathBuf::from("src/core/parse/lexer.rs"),
+            PathBuf::from("tests/cli.rs"),
+        ];
+        let mut app = App::new(files, "Test".to_string());
+        let src = vec!["".to_string(), "src".to_string()];
+        let parse = vec![
+            "".to_string(),
+            "src".to_string(),
+            "core".to_string(),
+            "parse".to_string(),
+        ];
+        assert!(app.set_directory_expanded(&parse, false));
+        app.update_flattened_tree();
+
+        assert!(app.toggle_directory_selection(&src));
+        assert_eq!(
+            app.flattened_tree.get_selected_paths(),
+            vec![
+                PathBuf::from("src/main.rs"),
+                PathBuf::from("src/core/mod.rs"),
+            ]
+        );
+        app.expand_all();
+        assert_eq!(
+            app.flattened_tree.get_selected_paths(),
+            vec![
+                PathBuf::from("src/main.rs"),
+                PathBuf::from("src/core/mod.rs"),
+                PathBuf::from("src/core/parse/lexer.rs"),
+            ]
+        );
+
+        // Partly selected directories get selected; fully selected ones are cleared
+        assert!(app.toggle_directory_selection(&parse));
+        assert!(app.toggle_directory_selection(&src));
+        assert_eq!(app.flattened_tree.get_selected_paths().len(), 3);
+        assert!(app.toggle_directory_selection(&src));
+        assert!(app.flattened_tree.get_selected_paths().is_empty());
+
+        // Under a filter only the matching files below the directory change
+        app.set_filter("CORE".to_string());
+        assert!(app.toggle_directory_selection(&src));
+        app.set_filter(String::new());
+        assert_eq!(
+            app.flattened_tree.get_selected_paths(),
+            vec![
+                PathBuf::from("src/core/mod.rs"),
+                PathBuf::from("src/core/parse/lexer.rs"),
+            ]
+        );
+
+        assert!(!app.toggle_directory_selection(&["".to_string(), "missing".to_string()]));
+    }
+
+    #[test]
+    fn test_filter_keeps_matches_and_their_directories() {
+        let files = vec![
+            PathBuf::from("src/utils/Strings.rs"),
+            PathBuf::from("src/main.rs"),
+            PathBuf::from("tests/strings_test.rs"),
+            PathBuf::from("README.md"),
+        ];
+        let mut app = App::new(files, "Test".to_string());
+        let visible = |app: &App| -> Vec<String> {
+            app.flattened_tree
+                .nodes
+                .iter()
+                .map(|(node, _)| node.get_display_name())
+                .collect()
+        };
+
+        // Matching ignores case, and a collapsed directory still shows its matches
+        app.set_directory_expanded(&["".to_string(), "src".to_string()], false);
+        app.set_filter("STRING".to_string());
+        assert_eq!(
+            visible(&app),
+            vec!["", "src", "utils", "Strings.rs", "tests", "strings_test.rs"]
+        );
+
+        app.flattened_tree.state.select(Some(5));
+        app.flattened_tree.toggle_selected();
+        app.set_filter("nothing matches".to_string());
+        assert_eq!(visible(&app), Vec::<String>::new());
+        app.flattened_tree.next();
+
+        app.set_directory_expanded(&["".to_string(), "src".to_string()], true);
+        app.set_filter(String::new());
+        assert_eq!(visible(&app).len(), 8);
+        assert_eq!(
+            app.flattened_tree.get_selected_paths(),
+            vec![PathBuf::from("tests/strings_test.rs")]
+        );
+    }
+
+    #[test]
+    fn test_navigate_empty_tree() {
+        let mut tree = FlattenedTree::new();
+
+        tree.next();
+        assert_eq!(tree.state.selected(), None);
+        tree.previous();
+        assert_eq!(tree.state.selected(), None);
+    }
+
+    #[test]
+    fn test_collapse_duplicate_directory_names() {
+        let files = vec![
+            PathBuf::from("src/utils/fs.rs"),
+            PathBuf::from("tests/utils/fixtures.rs"),
+        ];
+        let mut app = App::new(files, "Test".to_string());
+        let visible = |app: &App| -> Vec<String> {
+            app.flattened_tree
+                .nodes
+                .iter()
+                .map(|(node, _)| node.get_display_name())
+                .collect()
+        };
+        let tests_utils = visible(&app)
+            .iter()
+            .rposition(|name| name == "utils")
+            .unwrap();
+        let path = app.flattened_tree.directory_path(tests_utils).unwrap();
+        assert_eq!(path, vec!["", "tests", "utils"]);
+
+        app.flattened_tree.state.select(Some(tests_utils));
+        assert!(app.set_directory_expanded(&path, false));
+        app.update_flattened_tree();
+        assert_eq!(
+            visible(&app),
+            vec!["", "src", "utils", "fs.rs", "tests", "utils"]
+        );
+        // The cursor stays on the collapsed directory, not the first `utils`
+        assert_eq!(app.flattened_tree.state.selected(), Some(5));
+
+        assert!(app.set_directory_expanded(&path, true));
+        app.update_flattened_tree();
+        assert!(visible(&app).contains(&"fixtures.rs".to_string()));
+        assert!(!app.set_directory_expanded(&["".to_string(), "lib".to_string()], false));
+    }
+
     #[test]
     fn test_tree_structure() {
         let files = vec![
@@ -758,4 +2398,4 @@ mod tests {
             }
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,145 @@
+use crate::domain::models::FileContext;
+use log::debug;
+use std::path::{Component, Path, PathBuf};
+
+/// Group key for files that sit above the requested directory depth.
+const ROOT_GROUP: &str = "root";
+
+/// Key of the directory `depth` levels below `root` that contains `path`.
+fn group_key(path: &Path, root: &Path, depth: usize) -> String {
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    let dirs: Vec<String> = relative
+        .parent()
+        .map(|parent| {
+            parent
+                .components()
+                .filter_map(|component| match component {
+                    Component::Normal(name) => Some(name.to_string_lossy().to_string()),
+                    _ => None,
+                })
+                .take(depth)
+                .collect()
+        })
+        .unwrap_or_default();
+
+    if dirs.is_empty() {
+        ROOT_GROUP.to_string()
+    } else {
+        dirs.join("/")
+    }
+}
+
+/// Groups files by their directory at `depth` below `root_of(path)`, keeping the order in
+/// which each group was first seen and the selection order within a group.
+pub fn group_by_directory<'r>(
+    files: Vec<FileContext>,
+    root_of: impl Fn(&Path) -> &'r str,
+    depth: usize,
+) -> Vec<(String, Vec<FileContext>)> {
+    let mut groups: Vec<(String, Vec<FileContext>)> = Vec::new();
+
+    for file in files {
+        let key = group_key(&file.path, Path::new(root_of(&file.path)), depth);
+        match groups.iter_mut().find(|(name, _)| *name == key) {
+            Some((_, group)) => group.push(file),
+            None => groups.push((key, vec![file])),
+        }
+    }
+
+    debug!("Split selection into {} groups", groups.len());
+    groups
+}
+
+/// Output path for one group: `out/context.md` + `src/core` -> `out/context.src-core.md`.
+pub fn split_output_path(output: &Path, group: &str) -> PathBuf {
+    let stem = output
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let suffix: String = group
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '_' || c == '-' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    let name = match output.extension() {
+        Some(ext) => format!("{}.{}.{}", stem, suffix, ext.to_string_lossy()),
+        None => format!("{}.{}", stem, suffix),
+    };
+    output.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(path: &str) -> FileContext {
+        FileContext::new(PathBuf::from(path), String::new())
+    }
+
+    #[test]
+    fn test_group_by_top_level_directory() {
+        let files = vec![
+            file("./src/main.rs"),
+            file("./tests/cli.rs"),
+            file("./src/core/mod.rs"),
+            file("./build.rs"),
+        ];
+
+        let groups = group_by_directory(files, |_| ".", 1);
+        let summary: Vec<(String, Vec<PathBuf>)> = groups
+            .into_iter()
+            .map(|(name, files)| (name, files.into_iter().map(|f| f.path).collect()))
+            .collect();
+
+        assert_eq!(
+            summary,
+            vec![
+                (
+                    "src".to_string(),
+                    vec![
+                        PathBuf::from("./src/main.rs"),
+                        PathBuf::from("./src/core/mod.rs")
+                    ]
+                ),
+                ("tests".to_string(), vec![PathBuf::from("./tests/cli.rs")]),
+                ("root".to_string(), vec![PathBuf::from("./build.rs")]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_group_by_nested_directory() {
+        let files = vec![
+            file("project/src/core/a.rs"),
+            file("project/src/b.rs"),
+            file("vendor/lib/src/c.rs"),
+        ];
+
+        let root_of = |path: &Path| match path.starts_with("vendor/lib") {
+            true => "vendor/lib",
+            false => "project",
+        };
+        let groups = group_by_directory(files, root_of, 2);
+        let names: Vec<&str> = groups.iter().map(|(name, _)| name.as_str()).collect();
+
+        assert_eq!(names, vec!["src/core", "src"]);
+        assert_eq!(groups[1].1.len(), 2);
+    }
+
+    #[test]
+    fn test_split_output_path() {
+        assert_eq!(
+            split_output_path(Path::new("out/context.md"), "src/core"),
+            PathBuf::from("out/context.src-core.md")
+        );
+        assert_eq!(
+            split_output_path(Path::new("context"), "src"),
+            PathBuf::from("context.src")
+        );
+    }
+}
@@ -0,0 +1,120 @@
+use log::debug;
+
+// Sections that may differ between otherwise identical runs and are therefore left out of
+// the canonical form: `--attach-command` output reflects when the command ran (timings,
+// dates, git state), not the files.
+const VOLATILE_SECTIONS: &[&str] = &["command_output"];
+
+pub fn content_hash(content: &str) -> String {
+    blake3::hash(content.as_bytes()).to_hex().to_string()
+}
+
+// Removes every `<tag>` or `<tag attr="...">` section along with the blank lines after it
+fn strip_section(text: &str, tag: &str) -> String {
+    let open = format!("<{}", tag);
+    let close = format!("</{}>", tag);
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = find_open_tag(rest, &open) {
+        let Some(end) = rest[start..].find(&close) else {
+            break;
+        };
+        result.push_str(&rest[..start]);
+        rest = rest[start + end + close.len()..].trim_start_matches('\n');
+    }
+    result.push_str(rest);
+    result
+}
+
+fn find_open_tag(text: &str, open: &str) -> Option<usize> {
+    text.match_indices(open)
+        .map(|(start, _)| start)
+        .find(|&start| matches!(text.as_bytes().get(start + open.len()), Some(b'>' | b' ')))
+}
+
+/// Canonical form of a formatted context: volatile sections removed and line endings normalized.
+fn canonical_form(formatted: &str) -> String {
+    let mut canonical = formatted.replace("\r\n", "\n");
+    for tag in VOLATILE_SECTIONS {
+        canonical = strip_section(&canonical, tag);
+    }
+    canonical.trim_end().to_string()
+}
+
+pub fn canonical_context_hash(formatted: &str) -> String {
+    let hash = content_hash(&canonical_form(formatted));
+    debug!("Canonical context hash: {}", hash);
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::context_generator::{
+        attach_command_outputs, build_context_output, format_output,
+    };
+    use crate::domain::models::{CommandOutput, FileContext, OutputFormat, OutputOptions};
+    use std::path::PathBuf;
+
+    const CONTEXT: &str = "<file_map>\nsrc\n├── src/main.rs\n</file_map>\n\n\n<file_contents>\nFile: src/main.rs\n```rs\nfn main() {}\n```\n</file_contents>";
+
+    // A render with `date` attached, as a run at `timestamp` would produce it
+    fn render_at(timestamp: &str) -> String {
+        let mut output = build_context_output(
+            vec![FileContext::new(
+                PathBuf::from("src/main.rs"),
+                "fn main() {}\n".to_string(),
+            )],
+            "src/main.rs\n".to_string(),
+            Some("Review".to_string()),
+            &OutputOptions::default(),
+        )
+        .unwrap();
+        attach_command_outputs(
+            &mut output,
+            vec![CommandOutput {
+                command: "date".to_string(),
+                exit_code: Some(0),
+                output: format!("{}\n", timestamp),
+            }],
+            None,
+        );
+        format_output(&output, OutputFormat::Xml).unwrap()
+    }
+
+    #[test]
+    fn test_hash_ignores_volatile_sections() {
+        let first = render_at("Thu Jan  1 10:00:00 UTC 2026");
+        let second = render_at("Sun Mar 15 18:42:07 UTC 2026");
+        assert_ne!(first, second);
+        assert!(first.contains("<command_output cmd=\"date\" exit_code=\"0\">"));
+
+        assert_eq!(
+            canonical_context_hash(&first),
+            canonical_context_hash(&second)
+        );
+        assert!(!canonical_form(&first).contains("command_output"));
+        assert!(canonical_form(&first).ends_with("Review\n</user_instructions>"));
+    }
+
+    #[test]
+    fn test_hash_normalizes_line_endings() {
+        let crlf = CONTEXT.replace('\n', "\r\n");
+
+        assert_eq!(
+            canonical_context_hash(&crlf),
+            canonical_context_hash(CONTEXT)
+        );
+    }
+
+    #[test]
+    fn test_hash_changes_with_content() {
+        let changed = CONTEXT.replace("fn main() {}", "fn main() { run(); }");
+
+        assert_ne!(
+            canonical_context_hash(&changed),
+            canonical_context_hash(CONTEXT)
+        );
+    }
+}
@@ -0,0 +1,414 @@
+use regex::Regex;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Component, Path, PathBuf};
+use std::sync::LazyLock;
+
+static RUST_MOD: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?m)^\s*(?:pub(?:\([^)]*\))?\s+)?mod\s+([A-Za-z_][A-Za-z0-9_]*)\s*;")
+        .expect("valid mod pattern")
+});
+static RUST_USE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?m)^\s*(?:pub(?:\([^)]*\))?\s+)?use\s+([^;]+);").expect("valid use pattern")
+});
+static PYTHON_IMPORT: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?m)^\s*import\s+([\w.]+(?:\s*,\s*[\w.]+)*)").expect("valid import pattern")
+});
+static PYTHON_FROM: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?m)^\s*from\s+(\.*)([\w.]*)\s+import\s+\(?([\w \t,*]+)")
+        .expect("valid from pattern")
+});
+static JS_IMPORT: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"(?:\bfrom|\bimport|\brequire\s*\(|\bimport\s*\()\s*['"](\.{1,2}/[^'"]+)['"]"#)
+        .expect("valid import pattern")
+});
+
+const JS_EXTENSIONS: &[&str] = &["ts", "tsx", "js", "jsx", "mjs", "cjs"];
+
+/// Lexically resolves `.` and `..`, so paths from the walk and from joined imports compare equal.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    normalized.push("..");
+                }
+            }
+            other => normalized.push(other),
+        }
+    }
+    normalized
+}
+
+/// Scanned files by normalized path, to resolve import candidates against.
+pub struct KnownFiles<'a> {
+    by_path: HashMap<PathBuf, &'a PathBuf>,
+}
+
+impl<'a> KnownFiles<'a> {
+    pub fn new(files: &'a [PathBuf]) -> Self {
+        Self {
+            by_path: files.iter().map(|file| (normalize(file), file)).collect(),
+        }
+    }
+
+    fn get(&self, path: &Path) -> Option<&'a PathBuf> {
+        self.by_path.get(&normalize(path)).copied()
+    }
+
+    fn first(&self, candidates: impl IntoIterator<Item = PathBuf>) -> Option<&'a PathBuf> {
+        candidates
+            .into_iter()
+            .find_map(|candidate| self.get(&candidate))
+    }
+}
+
+/// Flattens a `use` tree such as `crate::a::{b, c::{d, e as f}}` into one path per import.
+fn use_paths(tree: &str) -> Vec<String> {
+    let tree: String = tree.split_whitespace().collect::<Vec<_>>().join(" ");
+    let tree = tree.trim();
+    let Some(open) = tree.find('{').filter(|_| tree.ends_with('}')) else {
+        let path = tree.split(" as ").next().unwrap_or(tree).replace(' ', "");
+        return vec![path];
+    };
+
+    let prefix = tree[..open].trim().trim_end_matches("::");
+    let mut items = Vec::new();
+    let mut depth = 0;
+    let mut start = open + 1;
+    let inner_end = tree.len() - 1;
+    for (index, c) in tree[..inner_end].char_indices().skip(open + 1) {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            ',' if depth == 0 => {
+                items.push(&tree[start..index]);
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    items.push(&tree[start..inner_end]);
+
+    items
+        .into_iter()
+        .filter(|item| !item.trim().is_empty())
+        .flat_map(use_paths)
+        .map(|item| match item.as_str() {
+            "self" => prefix.to_string(),
+            _ if prefix.is_empty() => item,
+            _ => format!("{}::{}", prefix, item),
+        })
+        .collect()
+}
+
+// Directory holding the submodules of the module defined by `path`
+fn rust_module_dir(path: &Path) -> PathBuf {
+    let parent = path.parent().unwrap_or(Path::new(""));
+    match path.file_name().and_then(|name| name.to_str()) {
+        Some("mod.rs" | "lib.rs" | "main.rs") => parent.to_path_buf(),
+        _ => parent.join(path.file_stem().unwrap_or_default()),
+    }
+}
+
+// The longest prefix of `segments` below `base` that names a module file
+fn rust_module_file<'a>(
+    base: &Path,
+    segments: &[&str],
+    known: &KnownFiles<'a>,
+) -> Option<&'a PathBuf> {
+    (1..=segments.len()).rev().find_map(|len| {
+        let module = base.join(segments[..len].join("/"));
+        known.first([module.with_extension("rs"), module.join("mod.rs")])
+    })
+}
+
+fn rust_imports<'a>(path: &Path, content: &str, known: &KnownFiles<'a>) -> Vec<&'a PathBuf> {
+    let module_dir = rust_module_dir(path);
+    let crate_root = path.ancestors().skip(1).find(|dir| {
+        known.get(&dir.join("lib.rs")).is_some() || known.get(&dir.join("main.rs")).is_some()
+    });
+
+    let mut imports: Vec<&PathBuf> = RUST_MOD
+        .captures_iter(content)
+        .filter_map(|captures| {
+            let module = module_dir.join(&captures[1]);
+            known.first([module.with_extension("rs"), module.join("mod.rs")])
+        })
+        .collect();
+
+    for tree in RUST_USE.captures_iter(content) {
+        for use_path in use_paths(&tree[1]) {
+            let mut segments: &[&str] = &use_path.split("::").collect::<Vec<_>>();
+            let mut base = match segments.first() {
+                Some(&"crate") => match crate_root {
+                    Some(root) => root,
+                    None => continue,
+                },
+                Some(&"self" | &"super") => module_dir.as_path(),
+                // External crates, or modules that a `mod` above already resolved
+                _ => continue,
+            };
+            if segments[0] != "super" {
+                segments = &segments[1..];
+            }
+            while let Some((&"super", rest)) = segments.split_first() {
+                let Some(parent) = base.parent() else {
+                    break;
+                };
+                base = parent;
+                segments = rest;
+            }
+            imports.extend(rust_module_file(base, segments, known));
+        }
+    }
+    imports
+}
+
+fn python_module<'a>(base: &Path, module: &str, known: &KnownFiles<'a>) -> Option<&'a PathBuf> {
+    let module = base.join(module.replace('.', "/"));
+    known.first([module.with_extension("py"), module.join("__init__.py")])
+}
+
+fn python_imports<'a>(path: &Path, content: &str, known: &KnownFiles<'a>) -> Vec<&'a PathBuf> {
+    let dir = path.parent().unwrap_or(Path::new(""));
+    // Absolute imports may be rooted at any directory above the file
+    let bases: Vec<&Path> = dir.ancestors().collect();
+    let absolute = |module: &str| {
+        bases
+            .iter()
+            .find_map(|base| python_module(base, module, known))
+    };
+
+    let mut imports = Vec::new();
+    for captures in PYTHON_IMPORT.captures_iter(content) {
+        for module in captures[1].split(',') {
+            imports.extend(absolute(module.trim()));
+        }
+    }
+    for captures in PYTHON_FROM.captures_iter(content) {
+        let (dots, module) = (captures[1].len(), &captures[2]);
+        let names = captures[3]
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty() && *name != "*");
+        if dots == 0 {
+            imports.extend(absolute(module));
+            for name in names {
+                imports.extend(absolute(&format!("{}.{}", module, name)));
+            }
+            continue;
+        }
+
+        let Some(base) = dir.ancestors().nth(dots - 1) else {
+            continue;
+        };
+        if !module.is_empty() {
+            imports.extend(python_module(base, module, known));
+        }
+        for name in names {
+            let submodule = match module {
+                "" => name.to_string(),
+                _ => format!("{}.{}", module, name),
+            };
+            imports.extend(python_module(base, &submodule, known));
+        }
+    }
+    imports
+}
+
+fn js_imports<'a>(path: &Path, content: &str, known: &KnownFiles<'a>) -> Vec<&'a PathBuf> {
+    let dir = path.parent().unwrap_or(Path::new(""));
+    JS_IMPORT
+        .captures_iter(content)
+        .filter_map(|captures| {
+            let target = dir.join(&captures[1]);
+            let with_extension = JS_EXTENSIONS.iter().map(|ext| {
+                let mut file = target.clone().into_os_string();
+                file.push(".");
+                file.push(ext);
+                PathBuf::from(file)
+            });
+            let index = JS_EXTENSIONS
+                .iter()
+                .map(|ext| target.join(format!("index.{}", ext)));
+            known.first(
+                std::iter::once(target.clone())
+                    .chain(with_extension)
+                    .chain(index),
+            )
+        })
+        .collect()
+}
+
+/// Files among `known` that `path` imports through `mod`/`use`, `import`/`from`, or
+/// `import`/`require` of a relative path. Best effort: anything that doesn't resolve to a
+/// scanned file, such as external crates or packages, is ignored.
+pub fn imports_among(path: &Path, content: &str, known: &KnownFiles) -> Vec<PathBuf> {
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+    let imports = match extension {
+        "rs" => rust_imports(path, content, known),
+        "py" => python_imports(path, content, known),
+        _ if JS_EXTENSIONS.contains(&extension) => js_imports(path, content, known),
+        _ => Vec::new(),
+    };
+
+    let mut seen = HashSet::new();
+    imports
+        .into_iter()
+        .filter(|import| normalize(import) != normalize(path) && seen.insert(*import))
+        .cloned()
+        .collect()
+}
+
+/// `entry` and every file among `files` it reaches through local imports, in `files` order.
+pub fn dependency_closure(
+    entry: &Path,
+    files: &[PathBuf],
+    read: impl Fn(&PathBuf) -> anyhow::Result<String>,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let known = KnownFiles::new(files);
+    let entry = known.get(entry).ok_or_else(|| {
+        anyhow::anyhow!(
+            "closure entry {} is not among the scanned files",
+            entry.display()
+        )
+    })?;
+
+    let mut reached: HashSet<&PathBuf> = HashSet::from([entry]);
+    let mut queue = VecDeque::from([entry]);
+    while let Some(file) = queue.pop_front() {
+        let content = match read(file) {
+            Ok(content) => content,
+            Err(e) => {
+                log::warn!("Skipping imports of {}: {}", file.display(), e);
+                continue;
+            }
+        };
+        for import in imports_among(file, &content, &known) {
+            if let Some(import) = known.get(&import)
+                && reached.insert(import)
+            {
+                queue.push_back(import);
+            }
+        }
+    }
+
+    log::debug!("Closure of {} has {} files", entry.display(), reached.len());
+    Ok(files
+        .iter()
+        .filter(|file| reached.contains(file))
+        .cloned()
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn closure(entry: &str, sources: &[(&str, &str)]) -> Vec<String> {
+        let files: Vec<PathBuf> = sources
+            .iter()
+            .map(|(path, _)| PathBuf::from(path))
+            .collect();
+        let contents: HashMap<PathBuf, String> = sources
+            .iter()
+            .map(|(path, content)| (PathBuf::from(path), content.to_string()))
+            .collect();
+        dependency_closure(Path::new(entry), &files, |path| Ok(contents[path].clone()))
+            .unwrap()
+            .into_iter()
+            .map(|path| path.display().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_rust_closure() {
+        let sources = [
+            (
+                "./src/main.rs",
+                "mod cli;\nmod util;\n\nuse crate::cli::run;\n",
+            ),
+            (
+                "./src/cli/mod.rs",
+                "pub mod args;\nuse crate::core::{engine::Engine, engine};\nuse std::io;\n",
+            ),
+            ("./src/cli/args.rs", "use super::super::util;\n"),
+            ("./src/core/mod.rs", "pub mod engine;\npub mod unused;\n"),
+            ("./src/core/engine.rs", "pub struct Engine;\n"),
+            ("./src/core/unused.rs", "pub fn unused() {}\n"),
+            ("./src/util.rs", "pub fn helper() {}\n"),
+            ("./src/orphan.rs", "use crate::util;\n"),
+        ];
+
+        assert_eq!(
+            closure("src/main.rs", &sources),
+            vec![
+                "./src/main.rs",
+                "./src/cli/mod.rs",
+                "./src/cli/args.rs",
+                "./src/core/engine.rs",
+                "./src/util.rs",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_python_and_js_imports() {
+        let files: Vec<PathBuf> = [
+            "app/main.py",
+            "app/models/__init__.py",
+            "app/models/user.py",
+            "app/views.py",
+            "web/index.ts",
+            "web/lib/api.ts",
+            "web/components/index.tsx",
+        ]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+
+        let known = KnownFiles::new(&files);
+        let python = imports_among(
+            Path::new("app/main.py"),
+            "import os\nfrom .models import user\nfrom app import views\n",
+            &known,
+        );
+        assert_eq!(
+            python,
+            vec![
+                PathBuf::from("app/models/__init__.py"),
+                PathBuf::from("app/models/user.py"),
+                PathBuf::from("app/views.py"),
+            ]
+        );
+
+        let js = imports_among(
+            Path::new("web/index.ts"),
+            "import { get } from './lib/api';\nconst ui = require(\"./components\");\nimport React from 'react';\n",
+            &known,
+        );
+        assert_eq!(
+            js,
+            vec![
+                PathBuf::from("web/lib/api.ts"),
+                PathBuf::from("web/components/index.tsx"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_use_paths() {
+        assert_eq!(
+            use_paths("crate::a::{b, c::{d, e as f}, self}"),
+            vec![
+                "crate::a::b",
+                "crate::a::c::d",
+                "crate::a::c::e",
+                "crate::a"
+            ]
+        );
+        assert_eq!(use_paths("super::x as y"), vec!["super::x"]);
+    }
+}
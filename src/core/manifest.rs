@@ -0,0 +1,164 @@
+use crate::domain::models::{ContextOutput, Manifest, ManifestEntry};
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+// A token change counts as significant when it moves by at least this share of the
+// previous count and by at least MIN_SIGNIFICANT_TOKEN_DELTA tokens.
+const SIGNIFICANT_TOKEN_CHANGE_RATIO: f64 = 0.1;
+const MIN_SIGNIFICANT_TOKEN_DELTA: usize = 20;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ManifestDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    /// Files present in both runs whose token count changed significantly: (path, before, after).
+    pub changed: Vec<(String, usize, usize)>,
+}
+
+impl ManifestDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+pub fn build_manifest(output: &ContextOutput) -> Manifest {
+    Manifest {
+        token_count: output.token_count,
+        files: output
+            .file_tokens
+            .iter()
+            .map(|(path, tokens)| ManifestEntry {
+                path: path.display().to_string(),
+                tokens: *tokens,
+            })
+            .collect(),
+    }
+}
+
+/// The files recorded in a manifest, in manifest order, skipping any that no longer exist.
+pub fn selection_from_manifest(
+    manifest: &Manifest,
+    exists: impl Fn(&Path) -> bool,
+) -> Vec<PathBuf> {
+    manifest
+        .files
+        .iter()
+        .map(|entry| PathBuf::from(&entry.path))
+        .filter(|path| {
+            let found = exists(path);
+            if !found {
+                warn!(
+                    "Manifest entry {} no longer exists, skipping",
+                    path.display()
+                );
+            }
+            found
+        })
+        .collect()
+}
+
+fn is_significant_change(before: usize, after: usize) -> bool {
+    let delta = before.abs_diff(after);
+    delta >= MIN_SIGNIFICANT_TOKEN_DELTA
+        && delta as f64 >= before.max(1) as f64 * SIGNIFICANT_TOKEN_CHANGE_RATIO
+}
+
+pub fn diff_manifests(previous: &Manifest, current: &Manifest) -> ManifestDiff {
+    let previous_tokens: HashMap<&str, usize> = previous
+        .files
+        .iter()
+        .map(|entry| (entry.path.as_str(), entry.tokens))
+        .collect();
+    let current_tokens: HashMap<&str, usize> = current
+        .files
+        .iter()
+        .map(|entry| (entry.path.as_str(), entry.tokens))
+        .collect();
+
+    let mut diff = ManifestDiff::default();
+
+    for entry in &current.files {
+        match previous_tokens.get(entry.path.as_str()) {
+            None => diff.added.push(entry.path.clone()),
+            Some(&before) if is_significant_change(before, entry.tokens) => {
+                diff.changed
+                    .push((entry.path.clone(), before, entry.tokens));
+            }
+            Some(_) => {}
+        }
+    }
+
+    for entry in &previous.files {
+        if !current_tokens.contains_key(entry.path.as_str()) {
+            diff.removed.push(entry.path.clone());
+        }
+    }
+
+    debug!(
+        "Manifest diff: {} added, {} removed, {} changed",
+        diff.added.len(),
+        diff.removed.len(),
+        diff.changed.len()
+    );
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(entries: &[(&str, usize)]) -> Manifest {
+        Manifest {
+            token_count: entries.iter().map(|(_, tokens)| tokens).sum(),
+            files: entries
+                .iter()
+                .map(|(path, tokens)| ManifestEntry {
+                    path: path.to_string(),
+                    tokens: *tokens,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_diff_manifests() {
+        let previous = manifest(&[
+            ("src/main.rs", 100),
+            ("src/lib.rs", 400),
+            ("src/old.rs", 50),
+            ("src/tiny.rs", 2),
+        ]);
+        let current = manifest(&[
+            ("src/main.rs", 105),
+            ("src/lib.rs", 900),
+            ("src/new.rs", 70),
+            ("src/tiny.rs", 8),
+        ]);
+
+        let diff = diff_manifests(&previous, &current);
+
+        assert_eq!(diff.added, vec!["src/new.rs".to_string()]);
+        assert_eq!(diff.removed, vec!["src/old.rs".to_string()]);
+        assert_eq!(diff.changed, vec![("src/lib.rs".to_string(), 400, 900)]);
+    }
+
+    #[test]
+    fn test_diff_identical_manifests_is_empty() {
+        let previous = manifest(&[("a.rs", 10), ("b.rs", 20)]);
+
+        assert!(diff_manifests(&previous, &previous.clone()).is_empty());
+    }
+
+    #[test]
+    fn test_selection_from_manifest() {
+        let manifest = manifest(&[("src/main.rs", 10), ("src/gone.rs", 5), ("README.md", 3)]);
+
+        let selection = selection_from_manifest(&manifest, |path| path != Path::new("src/gone.rs"));
+
+        assert_eq!(
+            selection,
+            vec![PathBuf::from("src/main.rs"), PathBuf::from("README.md")]
+        );
+    }
+}
@@ -1,2 +1,9 @@
 pub mod context_generator;
 pub mod file_selector;
+pub mod grouping;
+pub mod hashing;
+pub mod imports;
+pub mod manifest;
+pub mod notebook;
+pub mod outline;
+pub mod prefix;
@@ -0,0 +1,71 @@
+use serde_json::Value;
+use std::path::Path;
+
+pub fn is_notebook(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("ipynb"))
+}
+
+// Cell sources are either one string or a list of lines that keep their own newlines
+fn cell_source(cell: &Value) -> String {
+    match cell.get("source") {
+        Some(Value::String(source)) => source.clone(),
+        Some(Value::Array(lines)) => lines.iter().filter_map(Value::as_str).collect(),
+        _ => String::new(),
+    }
+}
+
+/// The code and markdown cells of a Jupyter notebook as one readable sequence, each under a
+/// `# %%` or `# %% [markdown]` marker. Outputs, raw cells, and metadata are dropped.
+pub fn extract_notebook(content: &str) -> anyhow::Result<String> {
+    let notebook: Value = serde_json::from_str(content)?;
+    let cells = notebook
+        .get("cells")
+        .and_then(Value::as_array)
+        .ok_or_else(|| anyhow::anyhow!("no cells array"))?;
+
+    let mut extracted = Vec::new();
+    for cell in cells {
+        let marker = match cell.get("cell_type").and_then(Value::as_str) {
+            Some("code") => "# %%",
+            Some("markdown") => "# %% [markdown]",
+            _ => continue,
+        };
+        let source = cell_source(cell);
+        if source.trim().is_empty() {
+            continue;
+        }
+        extracted.push(format!("{}\n{}\n", marker, source.trim_end()));
+    }
+    Ok(extracted.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_notebook() {
+        let notebook = r##"{
+            "cells": [
+                {"cell_type": "markdown", "metadata": {}, "source": ["# Title\n", "Some notes"]},
+                {"cell_type": "code", "execution_count": 1, "metadata": {},
+                 "outputs": [{"output_type": "stream", "text": ["3\n"]}],
+                 "source": ["x = 1\n", "print(x + 2)"]},
+                {"cell_type": "raw", "metadata": {}, "source": "ignored"},
+                {"cell_type": "code", "metadata": {}, "outputs": [], "source": []},
+                {"cell_type": "code", "metadata": {}, "outputs": [], "source": "y = x\n"}
+            ],
+            "metadata": {"kernelspec": {"name": "python3"}},
+            "nbformat": 4,
+            "nbformat_minor": 5
+        }"##;
+
+        assert_eq!(
+            extract_notebook(notebook).unwrap(),
+            "# %% [markdown]\n# Title\nSome notes\n\n# %%\nx = 1\nprint(x + 2)\n\n# %%\ny = x\n"
+        );
+        assert!(extract_notebook("{\"nbformat\": 4}").is_err());
+        assert!(is_notebook(Path::new("analysis.IPYNB")));
+    }
+}
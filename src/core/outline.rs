@@ -0,0 +1,315 @@
+use regex::Regex;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::LazyLock;
+
+/// How to pick top-level symbols out of one language's source.
+struct Language {
+    extensions: &'static [&'static str],
+    /// Matches the first line of a top-level declaration.
+    signature: &'static str,
+    /// Line prefixes (after indentation) of the comments and decorators kept above a symbol.
+    doc_prefixes: &'static [&'static str],
+    /// Whether bodies open with `{`, so a signature ends at the brace rather than a `:`.
+    braces: bool,
+    /// Whether a brace language ends body-less declarations with `;`; without one, a
+    /// declaration ends on the line that closes its brackets.
+    semicolons: bool,
+    /// Keywords that open a parenthesized group of declarations, as in Go's `const (`.
+    groups: &'static [&'static str],
+}
+
+const LANGUAGES: &[Language] = &[
+    Language {
+        extensions: &["rs"],
+        signature: r#"^(pub(\([^)]*\))?\s+)?((const|async|unsafe|extern\s+"[^"]*")\s+)*(fn|struct|enum|trait|type|union|impl|mod|macro_rules!|const|static)\b"#,
+        doc_prefixes: &["///", "//!", "#["],
+        braces: true,
+        semicolons: true,
+        groups: &[],
+    },
+    Language {
+        extensions: &["py"],
+        signature: r"^(async\s+)?(def|class)\s",
+        doc_prefixes: &["#", "@"],
+        braces: false,
+        semicolons: false,
+        groups: &[],
+    },
+    Language {
+        extensions: &["js", "jsx", "ts", "tsx", "mjs", "cjs"],
+        signature: r"^(export\s+)?(default\s+)?(declare\s+)?(abstract\s+)?(async\s+)?(function\*?|class|interface|type|enum)\b",
+        doc_prefixes: &["/**", "*", "*/", "//", "@"],
+        braces: true,
+        semicolons: true,
+        groups: &[],
+    },
+    Language {
+        extensions: &["go"],
+        signature: r"^(func|type|const|var)\s",
+        doc_prefixes: &["//"],
+        braces: true,
+        semicolons: false,
+        groups: &["type", "const", "var"],
+    },
+];
+
+static SIGNATURES: LazyLock<Vec<Regex>> = LazyLock::new(|| {
+    LANGUAGES
+        .iter()
+        .map(|language| Regex::new(language.signature).expect("valid signature pattern"))
+        .collect()
+});
+
+fn language_for(path: &Path) -> Option<(&'static Language, &'static Regex)> {
+    let extension = path.extension()?.to_str()?;
+    LANGUAGES
+        .iter()
+        .zip(SIGNATURES.iter())
+        .find(|(language, _)| language.extensions.contains(&extension))
+}
+
+/// Index of the first doc comment or decorator line in the run directly above `start`.
+fn docs_start(lines: &[&str], start: usize, language: &Language) -> usize {
+    let mut first = start;
+    while first > 0 {
+        let line = lines[first - 1].trim_start();
+        if !language
+            .doc_prefixes
+            .iter()
+            .any(|prefix| line.starts_with(prefix))
+        {
+            break;
+        }
+        first -= 1;
+    }
+    first
+}
+
+/// Tracks `depth` through the brackets of `line` and reports whether a `:` appears outside
+/// any of them.
+fn top_level_colon(line: &str, depth: &mut i32) -> bool {
+    let mut colon = false;
+    for c in line.chars() {
+        match c {
+            '(' | '[' | '{' => *depth += 1,
+            ')' | ']' | '}' => *depth -= 1,
+            ':' if *depth <= 0 => colon = true,
+            _ => {}
+        }
+    }
+    colon
+}
+
+/// The signature starting at `start`, which may span several lines, with any body elided.
+/// A group such as Go's `const (` is shown as its opening line alone.
+fn signature_lines(lines: &[&str], start: usize, language: &Language) -> Vec<String> {
+    let first = lines[start].trim_end();
+    if language.groups.iter().any(|keyword| {
+        first
+            .strip_prefix(keyword)
+            .is_some_and(|rest| rest.trim() == "(")
+    }) {
+        return vec![format!("{} ... )", first)];
+    }
+
+    let mut signature = Vec::new();
+    let mut depth = 0;
+    for line in &lines[start..] {
+        if language.braces {
+            if let Some(brace) = line.find('{') {
+                signature.push(format!("{}{{ ... }}", &line[..brace]));
+                break;
+            }
+            signature.push(line.to_string());
+            top_level_colon(line, &mut depth);
+            let ended = match language.semicolons {
+                true => line.trim_end().ends_with(';'),
+                false => depth <= 0,
+            };
+            if ended {
+                break;
+            }
+        } else {
+            // A body on the same line, as in `class Empty: pass`, ends the signature too
+            signature.push(line.to_string());
+            if top_level_colon(line, &mut depth) && depth <= 0 {
+                break;
+            }
+        }
+    }
+    signature
+}
+
+/// Line indices where top-level symbols of `content` start, counting the doc comments above
+/// them, or `None` when the language of `path` isn't known.
+pub fn symbol_starts(path: &Path, content: &str) -> Option<Vec<usize>> {
+    let (language, signature) = language_for(path)?;
+    let lines: Vec<&str> = content.lines().collect();
+    Some(
+        lines
+            .iter()
+            .enumerate()
+            .filter(|(_, line)| signature.is_match(line))
+            .map(|(index, _)| docs_start(&lines, index, language))
+            .collect(),
+    )
+}
+
+/// `content` split at its top-level symbols: each block runs from a symbol's doc comments up
+/// to the next symbol and is labelled with its signature line. Lines before the first symbol,
+/// if any, form a leading block labelled `(top of file)`. `None` when the language of `path`
+/// isn't known.
+pub fn symbol_blocks(path: &Path, content: &str) -> Option<Vec<(String, Range<usize>)>> {
+    let (language, signature) = language_for(path)?;
+    let lines: Vec<&str> = content.lines().collect();
+    let starts: Vec<(usize, &str)> = lines
+        .iter()
+        .enumerate()
+        .filter(|(_, line)| signature.is_match(line))
+        .map(|(index, line)| {
+            let label = line.trim().trim_end_matches('{').trim_end();
+            (docs_start(&lines, index, language), label)
+        })
+        .collect();
+
+    let mut blocks = Vec::new();
+    let first = starts.first().map_or(lines.len(), |(start, _)| *start);
+    if lines[..first].iter().any(|line| !line.trim().is_empty()) {
+        blocks.push(("(top of file)".to_string(), 0..first));
+    }
+    for (i, (start, label)) in starts.iter().enumerate() {
+        let end = starts.get(i + 1).map_or(lines.len(), |(next, _)| *next);
+        blocks.push((label.to_string(), *start..end));
+    }
+    Some(blocks)
+}
+
+/// Top-level signatures of `content` with the doc comments above them, bodies elided, or
+/// `None` when the language of `path` isn't known or no symbols were found, and the full
+/// content should be kept.
+pub fn outline(path: &Path, content: &str) -> Option<String> {
+    let (language, signature) = language_for(path)?;
+    let lines: Vec<&str> = content.lines().collect();
+
+    let mut symbols = Vec::new();
+    for (index, line) in lines.iter().enumerate() {
+        if !signature.is_match(line) {
+            continue;
+        }
+        let mut symbol: Vec<String> = lines[docs_start(&lines, index, language)..index]
+            .iter()
+            .map(|line| line.to_string())
+            .collect();
+        symbol.extend(signature_lines(&lines, index, language));
+        symbols.push(symbol.join("\n"));
+    }
+
+    if symbols.is_empty() {
+        return None;
+    }
+    let mut outline = symbols.join("\n\n");
+    outline.push('\n');
+    Some(outline)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_symbol_starts() {
+        let source = "use std::io;\n\n/// Docs.\n#[inline]\nfn a() {\n    b();\n}\n\nstruct B;\n";
+        assert_eq!(symbol_starts(Path::new("lib.rs"), source), Some(vec![2, 8]));
+        assert_eq!(symbol_starts(Path::new("notes.txt"), source), None);
+    }
+
+    #[test]
+    fn test_symbol_blocks() {
+        let source = "use std::io;\n\n/// Docs.\nfn a() {\n    b();\n}\n\nstruct B;\n";
+        assert_eq!(
+            symbol_blocks(Path::new("lib.rs"), source),
+            Some(vec![
+                ("(top of file)".to_string(), 0..2),
+                ("fn a()".to_string(), 2..7),
+                ("struct B;".to_string(), 7..8),
+            ])
+        );
+        assert_eq!(
+            symbol_blocks(Path::new("lib.rs"), "fn a() {}\n"),
+            Some(vec![("fn a() {}".to_string(), 0..1)])
+        );
+    }
+
+    #[test]
+    fn test_rust_outline() {
+        let source = r#"use std::fmt;
+
+/// A parsed token.
+#[derive(Debug)]
+pub struct Token {
+    kind: Kind,
+}
+
+/// Splits `input` into tokens.
+pub fn tokenize(
+    input: &str,
+) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    tokens
+}
+
+fn helper() {}
+"#;
+
+        assert_eq!(
+            outline(Path::new("src/lexer.rs"), source).unwrap(),
+            "/// A parsed token.\n#[derive(Debug)]\npub struct Token { ... }\n\n/// Splits `input` into tokens.\npub fn tokenize(\n    input: &str,\n) -> Vec<Token> { ... }\n\nfn helper() { ... }\n"
+        );
+    }
+
+    #[test]
+    fn test_python_outline() {
+        let source = "import os\n\n# Loads settings.\n@cache\ndef load(path):\n    return os.read(path)\n\nclass Config(Base):\n    def get(self, key):\n        return self.values[key]\n";
+
+        assert_eq!(
+            outline(Path::new("app/config.py"), source).unwrap(),
+            "# Loads settings.\n@cache\ndef load(path):\n\nclass Config(Base):\n"
+        );
+
+        let inline = "class Empty(Exception): pass\n\ndef parse(\n    text: str,\n) -> dict[str, int]:\n    return {}\n";
+        assert_eq!(
+            outline(Path::new("errors.py"), inline).unwrap(),
+            "class Empty(Exception): pass\n\ndef parse(\n    text: str,\n) -> dict[str, int]:\n"
+        );
+    }
+
+    #[test]
+    fn test_go_outline() {
+        let source = "package main\n\nconst (\n\tA = 1\n\tB = 2\n)\n\ntype ID int\n\n// Run starts it.\nfunc Run(id ID) error {\n\treturn nil\n}\n";
+
+        assert_eq!(
+            outline(Path::new("main.go"), source).unwrap(),
+            "const ( ... )\n\ntype ID int\n\n// Run starts it.\nfunc Run(id ID) error { ... }\n"
+        );
+    }
+
+    #[test]
+    fn test_rust_constants_and_statics() {
+        let source = "/// Limit.\npub const MAX: usize = 3;\n\nstatic NAMES: &[&str] = &[\"a\"];\n";
+
+        assert_eq!(
+            outline(Path::new("lib.rs"), source).unwrap(),
+            "/// Limit.\npub const MAX: usize = 3;\n\nstatic NAMES: &[&str] = &[\"a\"];\n"
+        );
+    }
+
+    #[test]
+    fn test_unknown_language_keeps_content() {
+        assert_eq!(
+            outline(Path::new("notes.txt"), "fn looks_like_rust() {}"),
+            None
+        );
+        assert_eq!(outline(Path::new("main.rs"), "use std::io;\n"), None);
+    }
+}
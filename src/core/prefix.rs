@@ -0,0 +1,149 @@
+use crate::domain::models::FileContext;
+use log::debug;
+use std::collections::{HashMap, HashSet};
+use std::path::{Component, Path, PathBuf};
+
+// Marks a file entry in the file map, ahead of the file's path
+const MAP_ENTRY_MARKER: &str = "├── ";
+
+/// `path` without `.` components, so `./src` and `src` compare equal.
+fn normalized(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| *component != Component::CurDir)
+        .collect()
+}
+
+/// `path` relative to `prefix`. The prefix itself and paths outside it are kept as they are,
+/// so the map still names the stripped directory once.
+fn strip(path: &Path, prefix: &Path) -> PathBuf {
+    match normalized(path).strip_prefix(normalized(prefix)) {
+        Ok(rest) if !rest.as_os_str().is_empty() => rest.to_path_buf(),
+        _ => path.to_path_buf(),
+    }
+}
+
+fn split_map_line(line: &str) -> (&str, &str) {
+    match line.strip_prefix(MAP_ENTRY_MARKER) {
+        Some(path) => (MAP_ENTRY_MARKER, path),
+        None => ("", line),
+    }
+}
+
+/// Longest directory shared by every selected file and every file map entry. Since every
+/// path lies under it, stripping it can't make two paths collide.
+pub fn common_prefix(files: &[FileContext], file_map: &str) -> PathBuf {
+    let dirs = files
+        .iter()
+        .filter_map(|file| file.path.parent())
+        .map(normalized)
+        .chain(file_map.lines().map(|line| {
+            let (marker, path) = split_map_line(line);
+            let path = Path::new(path);
+            // Directory lines are prefix candidates themselves; file entries offer their parent
+            match (marker.is_empty(), path.parent()) {
+                (true, _) => normalized(path),
+                (false, Some(parent)) => normalized(parent),
+                (false, None) => PathBuf::new(),
+            }
+        }));
+
+    let mut common: Option<Vec<Component>> = None;
+    let dirs: Vec<PathBuf> = dirs.collect();
+    for dir in &dirs {
+        let components: Vec<Component> = dir.components().collect();
+        common = Some(match common {
+            None => components,
+            Some(prefix) => prefix
+                .into_iter()
+                .zip(components)
+                .take_while(|(a, b)| a == b)
+                .map(|(a, _)| a)
+                .collect(),
+        });
+    }
+
+    let prefix: PathBuf = common.unwrap_or_default().into_iter().collect();
+    debug!("Common path prefix: {}", prefix.display());
+    prefix
+}
+
+/// Removes `prefix` from the path of every file, returning each shortened path with the
+/// original it replaced.
+pub fn strip_file_prefix(files: &mut [FileContext], prefix: &Path) -> HashMap<PathBuf, PathBuf> {
+    let mut originals = HashMap::with_capacity(files.len());
+    for file in files {
+        let short = strip(&file.path, prefix);
+        let original = std::mem::replace(&mut file.path, short.clone());
+        originals.insert(short, original);
+    }
+    originals
+}
+
+/// Removes `prefix` from every directory and file entry of the file map. Fails if that would
+/// give two entries the same path, e.g. `src/Cargo.toml` and `Cargo.toml` outside `src`.
+pub fn strip_map_prefix(file_map: &str, prefix: &Path) -> anyhow::Result<String> {
+    let mut stripped = String::with_capacity(file_map.len());
+    let mut seen = HashSet::new();
+    for line in file_map.lines() {
+        let (marker, path) = split_map_line(line);
+        let short = strip(Path::new(path), prefix);
+        if !seen.insert(normalized(&short)) {
+            return Err(anyhow::anyhow!(
+                "Stripping {} would make {} ambiguous",
+                prefix.display(),
+                short.display()
+            ));
+        }
+        stripped.push_str(marker);
+        stripped.push_str(&short.to_string_lossy());
+        stripped.push('\n');
+    }
+    Ok(stripped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_common_prefix_stripped_from_map_and_headers() {
+        let root = "/home/me/projects/app/src";
+        let file_map = format!(
+            "{root}\n├── {root}/main.rs\n{root}/cli\n├── {root}/cli/mod.rs\n├── {root}/cli/main.rs\n"
+        );
+        let mut files = vec![
+            FileContext::new(PathBuf::from(format!("{root}/main.rs")), String::new()),
+            FileContext::new(PathBuf::from(format!("{root}/cli/main.rs")), String::new()),
+        ];
+
+        let prefix = common_prefix(&files, &file_map);
+        assert_eq!(prefix, PathBuf::from(root));
+
+        let originals = strip_file_prefix(&mut files, &prefix);
+        assert_eq!(
+            originals[Path::new("cli/main.rs")],
+            PathBuf::from(format!("{root}/cli/main.rs"))
+        );
+        let paths: Vec<PathBuf> = files.into_iter().map(|file| file.path).collect();
+        assert_eq!(
+            paths,
+            vec![PathBuf::from("main.rs"), PathBuf::from("cli/main.rs")]
+        );
+        assert_eq!(
+            strip_map_prefix(&file_map, &prefix).unwrap(),
+            format!("{root}\n├── main.rs\ncli\n├── cli/mod.rs\n├── cli/main.rs\n")
+        );
+    }
+
+    #[test]
+    fn test_explicit_prefix_keeps_paths_unambiguous() {
+        let file_map = ".\n├── ./Cargo.toml\n./src\n├── ./src/lib.rs\n";
+        assert_eq!(
+            strip_map_prefix(file_map, Path::new("src")).unwrap(),
+            ".\n├── ./Cargo.toml\n./src\n├── lib.rs\n"
+        );
+
+        let clashing = ".\n├── ./Cargo.toml\n./src\n├── ./src/Cargo.toml\n";
+        assert!(strip_map_prefix(clashing, Path::new("src")).is_err());
+    }
+}
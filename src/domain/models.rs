@@ -1,28 +1,609 @@
-use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
 #[derive(Debug, Clone)]
 pub struct FileContext {
     pub path: PathBuf,
     pub content: String,
+    /// Size of a binary file whose content was not decoded; `None` for text files.
+    pub binary_bytes: Option<u64>,
+    /// Marked "must review" in the selector; listed in `<priority_files>` and emitted first.
+    pub priority: bool,
 }
 
-#[derive(Debug, Clone)]
+impl FileContext {
+    pub fn new(path: PathBuf, content: String) -> Self {
+        Self {
+            path,
+            content,
+            binary_bytes: None,
+            priority: false,
+        }
+    }
+
+    pub fn binary(path: PathBuf, bytes: u64) -> Self {
+        Self {
+            path,
+            content: String::new(),
+            binary_bytes: Some(bytes),
+            priority: false,
+        }
+    }
+}
+
+/// Returned by file readers when a file's content is binary rather than text.
+#[derive(Debug)]
+pub struct BinaryFileError {
+    pub bytes: u64,
+}
+
+impl fmt::Display for BinaryFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "binary content ({} bytes)", self.bytes)
+    }
+}
+
+impl std::error::Error for BinaryFileError {}
+
+/// Machine-readable category of a failed run, reported by `--error-format json`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    NoFilesFound,
+    NoFilesSelected,
+    ManifestNotFound,
+    NotAGitRepo,
+    Timeout,
+    Io,
+    Other,
+}
+
+/// An error tagged with its `ErrorKind`; displays as the plain message.
+#[derive(Debug)]
+pub struct CliError {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl CliError {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for CliError {}
+
+/// When a `--timeout` run has to be done by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Deadline {
+    at: Instant,
+    timeout: Duration,
+}
+
+impl Deadline {
+    /// `None` when the deadline is too far off for an `Instant`, so it could never pass.
+    pub fn after(timeout: Duration) -> Option<Self> {
+        Some(Self {
+            at: Instant::now().checked_add(timeout)?,
+            timeout,
+        })
+    }
+
+    pub fn remaining(&self) -> Duration {
+        self.at.saturating_duration_since(Instant::now())
+    }
+
+    /// A `Timeout` error once the deadline has passed.
+    pub fn check(&self) -> Result<(), CliError> {
+        if Instant::now() < self.at {
+            return Ok(());
+        }
+        Err(CliError::new(
+            ErrorKind::Timeout,
+            format!("Timed out after {:?}", self.timeout),
+        ))
+    }
+}
+
+/// Coordinates a run with its `--timeout` watchdog thread.
+#[derive(Debug, Clone, Default)]
+pub struct WatchdogGuard {
+    writing: Arc<AtomicBool>,
+    settled: Arc<AtomicBool>,
+}
+
+impl WatchdogGuard {
+    /// Runs `write` with the watchdog held off, so a timeout never cuts output short.
+    pub fn while_writing<T>(&self, write: impl FnOnce() -> T) -> T {
+        self.writing.store(true, Ordering::SeqCst);
+        let result = write();
+        self.writing.store(false, Ordering::SeqCst);
+        result
+    }
+
+    pub fn is_writing(&self) -> bool {
+        self.writing.load(Ordering::SeqCst)
+    }
+
+    /// Claims the outcome of the run for the caller; `false` if the other side already
+    /// has, so only one of them reports.
+    pub fn settle(&self) -> bool {
+        !self.settled.swap(true, Ordering::SeqCst)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ErrorFormat {
+    /// Plain-text error messages
+    #[default]
+    Human,
+    /// One JSON object per error on stderr: {"error": "...", "kind": "..."}
+    Json,
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct ContextConfig {
-    pub root_path: String,
+    /// Directories to scan, in order; the first also holds the session and git state.
+    pub root_paths: Vec<String>,
     pub extensions: Vec<String>,
     pub exclude_patterns: Vec<String>,
+    /// Globs pruning matching directories, relative to the root, during the walk.
+    pub exclude_dir_globs: Vec<String>,
+    /// Root-relative globs a file must match one of; empty keeps everything.
+    pub include_patterns: Vec<String>,
     pub output_path: Option<String>,
     pub auto_select: bool,
     pub user_prompt: Option<String>,
     pub exclude_version_control_dir: String,
     pub apply_dot_git_ignore: bool,
     pub clipboard_output: bool,
+    pub clipboard_html: bool,
+    /// Go through the clipboard path without touching the system clipboard.
+    pub dry_clipboard: bool,
+    pub output_options: OutputOptions,
+    pub manifest_path: Option<String>,
+    pub print_hash: bool,
+    /// Print per-file token counts, largest first, after generating.
+    pub show_token_breakdown: bool,
+    /// Files above this percent of the output tokens are reported after generating.
+    pub warn_file_share: Option<u8>,
+    /// Stop after the scan and only report the match count and size.
+    pub count_only: bool,
+    /// `None` when `--format` wasn't given; resolved before formatting.
+    pub output_format: Option<OutputFormat>,
+    /// Infer the format from the output file extension when none was given.
+    pub output_format_auto: bool,
+    pub include_readmes: bool,
+    /// Write one output per directory at this depth below the root.
+    pub split_by_dir: Option<usize>,
+    /// Name patterns and minimum size for the generated-file heuristic; `None` disables it.
+    pub generated_filter: Option<(Vec<String>, u64)>,
+    /// Files larger than this many bytes are skipped with a warning.
+    pub max_file_size: Option<u64>,
+    /// Files with a line longer than this many characters are skipped with a warning.
+    pub skip_long_lines: Option<usize>,
+    /// Content types, e.g. `image/*`, whose files are skipped whatever their extension.
+    pub exclude_mime: Vec<String>,
+    /// `--timeout`: scanning, reading, and writing stop with a timeout error once it passes.
+    pub deadline: Option<Deadline>,
+    pub watchdog: WatchdogGuard,
+    pub respect_sparse: bool,
+    /// Fail instead of just logging when gitignore rules are applied outside a git repo.
+    pub require_git: bool,
+    pub clamp_empty_dirs: bool,
+    pub follow_symlinks: bool,
+    /// Directory levels below the root to scan; `None` is unlimited.
+    pub max_depth: Option<usize>,
+    pub project_summary: bool,
+    pub test_filter: TestFilter,
+    /// Manifest whose file list replaces scanning.
+    pub select_from_manifest: Option<String>,
+    /// Read the file list from stdin instead of scanning.
+    pub paths_from_stdin: bool,
+    /// File of glob patterns giving the order files are emitted in.
+    pub order_file: Option<String>,
+    /// Token budget `--max-tokens` puts on the selected files, interactive or `--auto`.
+    pub token_budget: Option<TokenBudget>,
+    /// Write the XML output incrementally instead of buffering it.
+    pub stream: bool,
+    /// Print why each included file was included.
+    pub explain: bool,
+    /// Maximum number of files read in parallel.
+    pub read_jobs: usize,
+    pub strip_prefix: Option<PathPrefix>,
+    /// Commit, branch, or tag to read files from instead of the working tree.
+    pub git_ref: Option<String>,
+    /// Keep only files that differ from HEAD or are untracked.
+    pub only_changed: bool,
+    /// With `only_changed`, read files that differ from HEAD as a unified diff with this many
+    /// lines of context.
+    pub diff_context: Option<u32>,
+    /// Entry file whose local import closure limits the scan.
+    pub closure: Option<String>,
+    pub progress_style: ProgressStyle,
+    /// Milliseconds between scan progress refreshes.
+    pub progress_interval_ms: u64,
+    pub clipboard_guard: Option<ClipboardGuard>,
+    /// Emit a provider-shaped chat request instead of `output_format`.
+    pub chat_format: Option<ChatProvider>,
+    /// Template text, read from `--into`, the output is spliced into at `{{RICH_PROMPT_CONTEXT}}`
+    /// markers.
+    pub into_template: Option<String>,
+    /// External command the output is piped to for a second token count.
+    pub verify_tokens: Option<String>,
+    /// Shell commands whose output is attached as `<command_output>` sections.
+    pub attach_commands: Vec<String>,
 }
 
-#[derive(Debug)]
+impl ContextConfig {
+    /// The first root, which anchors per-project state such as the session and git.
+    pub fn root_path(&self) -> &str {
+        self.root_paths.first().map_or(".", String::as_str)
+    }
+
+    /// The root `path` was scanned from, the innermost when roots nest, falling back to the
+    /// first one.
+    pub fn root_of(&self, path: &Path) -> &str {
+        self.root_paths
+            .iter()
+            .filter(|root| path.starts_with(root.as_str()))
+            .max_by_key(|root| Path::new(root.as_str()).components().count())
+            .map_or(self.root_path(), String::as_str)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TokenBudget {
+    pub max_tokens: usize,
+    pub strategy: BudgetStrategy,
+    /// Path fragments in priority order, used by `BudgetStrategy::Priority`.
+    pub priority: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum BudgetStrategy {
+    /// Cheapest files first, fitting as many files as possible
+    #[default]
+    SmallestFirst,
+    /// Files matching earlier --budget-priority entries first
+    Priority,
+    /// Files in selection order, stopping at the first one that doesn't fit
+    InOrder,
+}
+
+/// Separator between components of emitted paths.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum PathSeparator {
+    /// Forward slashes on every OS
+    #[default]
+    #[value(name = "/")]
+    Slash,
+    /// The OS separator, backslashes on Windows
+    Native,
+}
+
+/// Spinner shown while scanning files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ProgressStyle {
+    #[default]
+    Braille,
+    /// `|/-\` frames for terminals or fonts without braille glyphs
+    Ascii,
+    /// No scan progress at all
+    None,
+}
+
+/// Whether test files (by per-language naming conventions) are kept, dropped, or the only ones kept.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TestFilter {
+    #[default]
+    All,
+    ExcludeTests,
+    TestsOnly,
+}
+
+/// Clipboard writes larger than `max_bytes` need confirmation, since very large clipboard
+/// contents can freeze some desktop environments.
+#[derive(Debug, Clone, Copy)]
+pub struct ClipboardGuard {
+    pub max_bytes: u64,
+    /// `--yes`: skip the confirmation.
+    pub assume_yes: bool,
+}
+
+/// Leading path removed from file headers and the file map.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathPrefix {
+    /// `--strip-prefix <path>`.
+    Path(PathBuf),
+    /// `--flatten`: the longest directory shared by every path.
+    Common,
+}
+
+/// A criterion that put a file into the context, reported by `--explain`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InclusionReason {
+    /// Matched this `--ext` entry.
+    Extension(String),
+    /// No `--ext` filter was given.
+    AnyExtension,
+    /// Matched this `--include` glob.
+    IncludeGlob(String),
+    /// Added regardless of filters, as a directory README.
+    AlwaysInclude,
+    /// Selected up front by a resumed session or `--select-from-manifest`.
+    Preselected,
+}
+
+impl fmt::Display for InclusionReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InclusionReason::Extension(ext) => write!(f, "extension .{}", ext),
+            InclusionReason::AnyExtension => write!(f, "no extension filter"),
+            InclusionReason::IncludeGlob(pattern) => write!(f, "include glob {}", pattern),
+            InclusionReason::AlwaysInclude => write!(f, "always-include (directory README)"),
+            InclusionReason::Preselected => write!(f, "preselected"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// `<file_map>`/`<file_contents>`/`<user_instructions>` text block
+    #[default]
+    Xml,
+    /// Structured JSON with per-file entries and aggregates
+    Json,
+    /// Per-file metadata rows (path, language, bytes, lines, tokens) without contents
+    Tsv,
+    /// One collapsible `<details>` block per file, for pasting into GitHub issues and PRs
+    HtmlDetails,
+    /// Markdown headings with each file in a fenced code block
+    Markdown,
+}
+
+/// BPE encoding `--tokenizer` counts tokens with instead of the character heuristic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Tokenizer {
+    /// GPT-4 and GPT-3.5 encoding
+    #[value(name = "cl100k_base")]
+    Cl100kBase,
+    /// GPT-4o encoding
+    #[value(name = "o200k_base")]
+    O200kBase,
+}
+
+/// Named bundle of flag defaults for `--preset`; explicitly given flags still win.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Preset {
+    /// Only files changed since HEAD as diffs, in markdown, with a code review prompt
+    ReviewPr,
+}
+
+/// Built-in prompt for `--prompt-template-name`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum PromptTemplate {
+    /// Review the code like a pull request
+    CodeReview,
+    /// Look for bugs and explain how to trigger them
+    BugHunt,
+    /// Write or improve documentation
+    Docs,
+    /// Suggest refactorings that keep behavior unchanged
+    Refactor,
+}
+
+impl PromptTemplate {
+    pub fn instructions(self) -> &'static str {
+        match self {
+            PromptTemplate::CodeReview => {
+                "Review this code as you would a pull request. Point out bugs, risky edge cases, unclear naming, and missing tests, most important first."
+            }
+            PromptTemplate::BugHunt => {
+                "Find bugs in this code. For each one, name the file and function, explain the input or sequence of calls that triggers it, and propose a fix."
+            }
+            PromptTemplate::Docs => {
+                "Write documentation for this code: a short overview of what it does and how the parts fit together, then doc comments for public items that lack them."
+            }
+            PromptTemplate::Refactor => {
+                "Suggest refactorings that make this code simpler or easier to change without altering its behavior. Order them by payoff and show the changed code."
+            }
+        }
+    }
+}
+
+/// Chat API whose request shape `--chat-format` produces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ChatProvider {
+    /// `{"messages": [system, user]}`
+    Openai,
+    /// `{"system": ..., "messages": [user]}`
+    Anthropic,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OpenAiChat {
+    pub messages: Vec<ChatMessage>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AnthropicChat {
+    pub system: String,
+    pub messages: Vec<ChatMessage>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct OutputOptions {
+    /// Fixed backtick count for code fences; `None` keeps the default of three.
+    pub fence: Option<usize>,
+    /// Emit a `<binary/>` placeholder for binary files instead of omitting them.
+    pub binary_placeholder: bool,
+    /// Collapse runs of three or more blank lines into a single blank line.
+    pub compact_blank_lines: bool,
+    /// Strip trailing whitespace from every line.
+    pub trim_trailing_whitespace: bool,
+    /// How line references are embedded in file contents.
+    pub anchor_format: AnchorFormat,
+    /// Files with this many lines or fewer are left without anchors.
+    pub anchor_min_lines: usize,
+    /// Lowercase extensions, without the dot, whose files are emitted as one merged block.
+    pub merge_extensions: Vec<String>,
+    /// Append a bulleted list of the included paths to the user instructions.
+    pub prompt_file_list: bool,
+    /// Scan roots; the `prompt_file_list` paths are listed relative to the one they're under.
+    pub list_roots: Vec<PathBuf>,
+    /// Tag every file with a blake3 hash of its content as read, before any transformation.
+    pub with_hashes: bool,
+    /// Gather TODO/FIXME/HACK comments into a `<todos>` section.
+    pub collect_todos: bool,
+    /// Replace file contents with an outline of top-level signatures where the language is known.
+    pub symbols_only: bool,
+    /// Longest line, in characters, kept intact; longer lines are truncated with a marker.
+    pub max_line_length: Option<usize>,
+    /// Cut each file after the last whole line within this many tokens, marking the cut.
+    pub max_file_tokens: Option<usize>,
+    /// Move the `max_file_tokens` cut back to the end of the last whole top-level symbol.
+    pub snap_to_functions: bool,
+    /// Separator used in file headers, the file map, and every other emitted path.
+    pub path_separator: PathSeparator,
+    /// List the local imports among the included files in a `<dependency_graph>` section.
+    pub with_graph: bool,
+    /// Replace `.ipynb` JSON with the notebook's code and markdown cells.
+    pub ipynb_extract: bool,
+    /// Don't end closing fences with a newline, so no blank line separates one file's block
+    /// from the next file's header.
+    pub no_trailing_fence_newline: bool,
+    /// Encoding to count tokens with; `None` uses the character heuristic.
+    pub tokenizer: Option<Tokenizer>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum AnchorFormat {
+    /// Contents are emitted unchanged
+    #[default]
+    None,
+    /// Right-aligned line numbers in a `  12 | ` gutter
+    Gutter,
+    /// `path:12: ` before every line, ready to copy as a citation
+    Prefix,
+}
+
+#[derive(Debug, Default)]
 pub struct ContextOutput {
     pub file_map: String,
     pub file_contents: String,
     pub user_instructions: String,
     pub token_count: usize,
+    pub file_tokens: Vec<(PathBuf, usize)>,
+    /// Files emitted into the output, in order and parallel to `file_tokens`.
+    pub files: Vec<FileContext>,
+    /// Content hash per emitted file when `--with-hashes` is set, parallel to `files`.
+    pub file_hashes: Vec<Option<String>>,
+    /// Rendered `<project>` section body; empty when no project summary is attached.
+    pub project: String,
+    /// `path:line: text` entries for the `<todos>` section, with `--collect-todos`.
+    pub todos: Vec<String>,
+    /// `path -> import, ...` entries for the `<dependency_graph>` section, with `--with-graph`.
+    pub dependency_graph: Vec<String>,
+    /// Files marked "must review", for the `<priority_files>` section.
+    pub priority_files: Vec<PathBuf>,
+    /// Output of `--attach-command` runs, in the order given.
+    pub command_outputs: Vec<CommandOutput>,
+}
+
+/// Captured result of an `--attach-command` run.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CommandOutput {
+    pub command: String,
+    /// `None` when the process was killed by a signal.
+    pub exit_code: Option<i32>,
+    /// Stdout followed by stderr.
+    pub output: String,
+}
+
+/// High-level project facts read from the root manifest for `--project-summary`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProjectSummary {
+    /// Manifest file the summary came from, e.g. `Cargo.toml`.
+    pub manifest: String,
+    pub name: String,
+    pub version: Option<String>,
+    pub dependencies: Vec<String>,
+}
+
+/// Shape of the `--format json` output.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JsonContext {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub project: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub todos: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dependency_graph: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub priority_files: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub command_outputs: Vec<CommandOutput>,
+    pub file_map: String,
+    pub files: Vec<JsonFile>,
+    pub user_instructions: String,
+    pub token_count: usize,
+    pub by_extension: BTreeMap<String, ExtensionSummary>,
+    pub totals: ExtensionSummary,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JsonFile {
+    pub path: String,
+    pub content: String,
+    pub token_count: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub binary_bytes: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ExtensionSummary {
+    pub files: usize,
+    pub tokens: usize,
+}
+
+/// Record of the files included in a run, written with `--manifest`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    pub token_count: usize,
+    pub files: Vec<ManifestEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub path: String,
+    pub tokens: usize,
+}
+
+/// In-progress selection autosaved by the interactive selector.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SavedSession {
+    pub selected: Vec<PathBuf>,
 }
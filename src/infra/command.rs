@@ -0,0 +1,128 @@
+use crate::domain::models::CommandOutput;
+use log::{debug, warn};
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::thread;
+
+fn shell(command: &str) -> Command {
+    if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.args(["/C", command]);
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.args(["-c", command]);
+        shell
+    }
+}
+
+/// Runs `command` through the shell in `root` and captures its output. A failing command
+/// still yields its output and exit code; only failing to start it is an error.
+pub fn run_command(root: &str, command: &str) -> anyhow::Result<CommandOutput> {
+    debug!("Running {} in {}", command, root);
+    let result = shell(command)
+        .current_dir(root)
+        .output()
+        .map_err(|e| anyhow::anyhow!("Failed to run `{}`: {}", command, e))?;
+
+    if !result.status.success() {
+        warn!("`{}` exited with {}", command, result.status);
+    }
+
+    let mut output = String::from_utf8_lossy(&result.stdout).into_owned();
+    output.push_str(&String::from_utf8_lossy(&result.stderr));
+    Ok(CommandOutput {
+        command: command.to_string(),
+        exit_code: result.status.code(),
+        output,
+    })
+}
+
+/// Pipes `input` to `command` and parses its stdout as a token count, for checking the
+/// built-in estimate against an external tokenizer.
+pub fn external_token_count(command: &str, input: &str) -> anyhow::Result<usize> {
+    debug!("Counting tokens with {}", command);
+    let mut child = shell(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| anyhow::anyhow!("Failed to run `{}`: {}", command, e))?;
+
+    // Written from another thread so a command that answers before reading all of its
+    // input can't deadlock against a full pipe
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let input = input.to_owned();
+    let writer = thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let result = child.wait_with_output()?;
+    if let Ok(Err(e)) = writer.join() {
+        debug!("`{}` stopped reading its input: {}", command, e);
+    }
+
+    if !result.status.success() {
+        return Err(anyhow::anyhow!(
+            "`{}` exited with {}: {}",
+            command,
+            result.status,
+            String::from_utf8_lossy(&result.stderr).trim()
+        ));
+    }
+    let stdout = String::from_utf8_lossy(&result.stdout);
+    stdout.trim().parse().map_err(|_| {
+        anyhow::anyhow!(
+            "`{}` printed {:?} instead of a token count",
+            command,
+            stdout.trim()
+        )
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::core::context_generator::{
+        attach_command_outputs, build_context_output, format_output,
+    };
+    use crate::domain::models::{FileContext, OutputFormat, OutputOptions};
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_attached_command_output() {
+        let run = run_command(".", "echo hello").unwrap();
+        let mut output = build_context_output(
+            vec![FileContext::new(
+                PathBuf::from("src/lib.rs"),
+                "fn a() {}\n".to_string(),
+            )],
+            "map\n".to_string(),
+            None,
+            &OutputOptions::default(),
+        )
+        .unwrap();
+        attach_command_outputs(&mut output, vec![run], None);
+
+        let formatted = format_output(&output, OutputFormat::Xml).unwrap();
+
+        assert!(formatted.ends_with(
+            "</file_contents>\n\n<command_output cmd=\"echo hello\" exit_code=\"0\">\nhello\n</command_output>"
+        ));
+    }
+
+    #[test]
+    fn test_failing_command_keeps_output() {
+        let run = run_command(".", "echo out; echo err >&2; exit 3").unwrap();
+
+        assert_eq!(run.exit_code, Some(3));
+        assert_eq!(run.output, "out\nerr\n");
+    }
+
+    #[test]
+    fn test_external_token_count() {
+        assert_eq!(
+            external_token_count("wc -w", "three short words").unwrap(),
+            3
+        );
+        let error = external_token_count("echo many", "text").unwrap_err();
+        assert!(error.to_string().contains("instead of a token count"));
+    }
+}
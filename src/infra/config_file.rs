@@ -0,0 +1,74 @@
+use anyhow::Context;
+use log::debug;
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const CONFIG_FILE_NAME: &str = "rich-prompt.toml";
+
+/// Defaults for `generate`, read from `rich-prompt.toml`. Each key takes the same value as
+/// the flag of the same name, e.g. `ext = "rs,toml"`.
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FileConfig {
+    pub ext: Option<String>,
+    pub exclude: Option<String>,
+    pub exclude_dir_glob: Option<Vec<String>>,
+    pub include: Option<String>,
+    pub output: Option<String>,
+    pub prompt: Option<String>,
+    pub max_tokens: Option<usize>,
+    pub max_file_tokens: Option<u32>,
+}
+
+/// Nearest `rich-prompt.toml` in `start` or one of its ancestors.
+pub fn find_config_file(start: &Path) -> Option<PathBuf> {
+    start
+        .ancestors()
+        .map(|dir| dir.join(CONFIG_FILE_NAME))
+        .find(|candidate| candidate.is_file())
+}
+
+pub fn load_config_file(path: &Path) -> anyhow::Result<FileConfig> {
+    debug!("Loading defaults from {}", path.display());
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config file {}", path.display()))?;
+    toml::from_str(&contents).with_context(|| format!("Invalid config file {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_find_and_load_config_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let nested = temp_dir.path().join("crates/core/src");
+        fs::create_dir_all(&nested).unwrap();
+        assert_eq!(find_config_file(&nested), None);
+
+        let path = temp_dir.path().join(CONFIG_FILE_NAME);
+        fs::write(
+            &path,
+            "ext = \"rs,toml\"\nexclude = \"target\"\nmax_tokens = 50000\n",
+        )
+        .unwrap();
+        assert_eq!(find_config_file(&nested), Some(path.clone()));
+
+        let config = load_config_file(&path).unwrap();
+        assert_eq!(
+            config,
+            FileConfig {
+                ext: Some("rs,toml".to_string()),
+                exclude: Some("target".to_string()),
+                max_tokens: Some(50000),
+                ..FileConfig::default()
+            }
+        );
+
+        fs::write(&path, "extensions = \"rs\"\n").unwrap();
+        let error = load_config_file(&path).unwrap_err();
+        assert!(format!("{:#}", error).contains("unknown field `extensions`"));
+    }
+}
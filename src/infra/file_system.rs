@@ -1,20 +1,42 @@
+use crate::domain::models::{
+    BinaryFileError, Deadline, FileContext, InclusionReason, ProgressStyle, TestFilter,
+};
+use crate::infra::git::{global_excludes_file, sparse_checkout_file};
 use crossterm::{
     ExecutableCommand, cursor,
     terminal::{Clear, ClearType},
 };
+use globset::{Glob, GlobBuilder, GlobMatcher, GlobSet, GlobSetBuilder};
 use log::{debug, info, warn};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
 use std::io::{self, BufRead, Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
 use std::time::{Duration, Instant};
 
 fn parse_gitignore(root: &str) -> anyhow::Result<HashSet<String>> {
-    let gitignore_path = Path::new(root).join(".gitignore");
+    read_ignore_file(&Path::new(root).join(".gitignore"))
+}
+
+/// Tool-specific ignore file, same syntax as `.gitignore`, applied even when `.gitignore` isn't.
+const RPIGNORE_FILE: &str = ".rpignore";
+
+// Patterns of `dir`'s `.rpignore`, plus those of its `.gitignore` when those rules apply
+fn parse_ignore_files(dir: &str, dot_git_ignore: bool) -> anyhow::Result<HashSet<String>> {
+    let mut patterns = read_ignore_file(&Path::new(dir).join(RPIGNORE_FILE))?;
+    if dot_git_ignore {
+        patterns.extend(parse_gitignore(dir)?);
+    }
+    Ok(patterns)
+}
+
+fn read_ignore_file(gitignore_path: &Path) -> anyhow::Result<HashSet<String>> {
     let mut patterns = HashSet::new();
 
-    if gitignore_path.exists() && gitignore_path.is_file() {
-        debug!("Parsing .gitignore file at: {}", gitignore_path.display());
+    // `fs::metadata` follows symlinks, so a `.gitignore` linked to a shared file is read too
+    if fs::metadata(gitignore_path).is_ok_and(|metadata| metadata.is_file()) {
+        debug!("Parsing ignore file at: {}", gitignore_path.display());
         let file = fs::File::open(gitignore_path)?;
         let reader = std::io::BufReader::new(file);
 
@@ -31,14 +53,105 @@ fn parse_gitignore(root: &str) -> anyhow::Result<HashSet<String>> {
             }
         }
 
-        info!("Loaded {} patterns from .gitignore", patterns.len());
+        info!(
+            "Loaded {} patterns from {}",
+            patterns.len(),
+            gitignore_path.display()
+        );
     } else {
-        debug!("No .gitignore file found at: {}", gitignore_path.display());
+        debug!("No ignore file found at: {}", gitignore_path.display());
     }
 
     Ok(patterns)
 }
 
+type IgnoreFileReader<'a> = Box<dyn FnMut(&Path) -> anyhow::Result<HashSet<String>> + 'a>;
+
+/// Parsed ignore-file patterns (`.gitignore`, `.rpignore`) by directory, so each file is read
+/// once per walk however many entries below it are checked.
+struct IgnoreFileCache<'a> {
+    read: IgnoreFileReader<'a>,
+    by_dir: HashMap<PathBuf, HashSet<String>>,
+    error: Option<anyhow::Error>,
+}
+
+impl<'a> IgnoreFileCache<'a> {
+    fn new(read: impl FnMut(&Path) -> anyhow::Result<HashSet<String>> + 'a) -> Self {
+        IgnoreFileCache {
+            read: Box::new(read),
+            by_dir: HashMap::new(),
+            error: None,
+        }
+    }
+
+    /// Reads each directory's `.rpignore`, and `.gitignore` if `dot_git_ignore` is set, from
+    /// disk. The patterns of git's global excludes file count as if they were in `root`'s own
+    /// `.gitignore`, which can still negate them.
+    fn from_disk(root: &Path, global_excludes: Option<PathBuf>, dot_git_ignore: bool) -> Self {
+        let global = global_excludes
+            .map(|path| {
+                read_ignore_file(&path).unwrap_or_else(|e| {
+                    warn!("Failed to read global excludes {}: {}", path.display(), e);
+                    HashSet::new()
+                })
+            })
+            .unwrap_or_default();
+        let root = root.to_path_buf();
+        Self::new(move |dir: &Path| {
+            let mut patterns = parse_ignore_files(&dir.to_string_lossy(), dot_git_ignore)?;
+            if dir == root {
+                patterns.extend(global.iter().cloned());
+            }
+            Ok(patterns)
+        })
+    }
+
+    // A walk's filter can't fail, so the first read error is kept for `finish` to return
+    fn patterns(&mut self, dir: &Path) -> &HashSet<String> {
+        if !self.by_dir.contains_key(dir) {
+            let patterns = (self.read)(dir).unwrap_or_else(|e| {
+                self.error.get_or_insert(
+                    e.context(format!("Failed to read ignore files in {}", dir.display())),
+                );
+                HashSet::new()
+            });
+            self.by_dir.insert(dir.to_path_buf(), patterns);
+        }
+        &self.by_dir[dir]
+    }
+
+    /// The first error hit reading an ignore file, if any.
+    fn finish(self) -> anyhow::Result<()> {
+        self.error.map_or(Ok(()), Err)
+    }
+
+    /// Whether the ignore files of `root`, or of any directory between it and `path`,
+    /// ignore `path`. Each file's patterns are relative to its own directory.
+    fn is_ignored(&mut self, path: &Path, root: &Path) -> bool {
+        let Some(parent) = path.parent().filter(|parent| parent.starts_with(root)) else {
+            return false;
+        };
+        parent
+            .ancestors()
+            .take_while(|dir| dir.starts_with(root))
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+            .any(|dir| should_ignore_by_gitignore(path, dir, self.patterns(dir)))
+    }
+
+    /// `is_ignored` for a path that wasn't reached by a walk, so its ignored parent
+    /// directories weren't pruned on the way down.
+    fn is_ignored_listed(&mut self, path: &Path, root: &Path) -> bool {
+        path.ancestors()
+            .take_while(|entry| *entry != root && entry.starts_with(root))
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+            .any(|entry| self.is_ignored(entry, root))
+    }
+}
+
 fn should_ignore_by_gitignore(
     path: &Path,
     root: &Path,
@@ -90,13 +203,12 @@ fn matches_gitignore_pattern(path: &str, pattern: &str, is_dir: bool) -> bool {
 
     let clean_pattern = pattern.trim_end_matches('/');
 
-    if !clean_pattern.contains('*') {
-        if path == clean_pattern
+    if !clean_pattern.contains('*')
+        && (path == clean_pattern
             || path.starts_with(&format!("{}/", clean_pattern))
-            || path.ends_with(&format!("/{}", clean_pattern))
-        {
-            return true;
-        }
+            || path.ends_with(&format!("/{}", clean_pattern)))
+    {
+        return true;
     }
 
     if clean_pattern.contains('*') {
@@ -130,8 +242,80 @@ fn matches_gitignore_pattern(path: &str, pattern: &str, is_dir: bool) -> bool {
     path.contains(clean_pattern)
 }
 
+// Entries the walk could not read (e.g. permission denied), reported once the scan finishes
+#[derive(Default)]
+struct WalkErrors {
+    errors: Vec<walkdir::Error>,
+    /// Followed symlinks that lead back to one of their own ancestors, with that ancestor.
+    cycles: Vec<(PathBuf, PathBuf)>,
+}
+
+impl WalkErrors {
+    fn keep_ok(&mut self, entry: walkdir::Result<walkdir::DirEntry>) -> Option<walkdir::DirEntry> {
+        match entry {
+            Ok(entry) => Some(entry),
+            Err(err) => {
+                if let (Some(link), Some(ancestor)) = (err.path(), err.loop_ancestor()) {
+                    // Following the link would revisit a directory already being walked
+                    warn!(
+                        "Symlink cycle: {} leads back to {}, not following it",
+                        link.display(),
+                        ancestor.display()
+                    );
+                    self.cycles
+                        .push((link.to_path_buf(), ancestor.to_path_buf()));
+                    return None;
+                }
+                debug!("Walk error: {}", err);
+                self.errors.push(err);
+                None
+            }
+        }
+    }
+
+    fn paths(&self) -> Vec<String> {
+        self.errors
+            .iter()
+            .map(|err| match err.path() {
+                Some(path) => path.display().to_string(),
+                None => "<unknown path>".to_string(),
+            })
+            .collect()
+    }
+
+    fn report(&self, stage: &str) {
+        if self.errors.is_empty() {
+            return;
+        }
+
+        warn!(
+            "{}: {} path(s) could not be read and were skipped, results may be incomplete",
+            stage,
+            self.errors.len()
+        );
+        for (path, err) in self.paths().iter().zip(&self.errors) {
+            let reason = err
+                .io_error()
+                .map(|e| e.to_string())
+                .unwrap_or_else(|| err.to_string());
+            warn!("  {} ({})", path, reason);
+        }
+    }
+}
+
+const DEFAULT_PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+fn spinner_frames(style: ProgressStyle) -> &'static [char] {
+    match style {
+        ProgressStyle::Braille => &['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'],
+        ProgressStyle::Ascii => &['|', '/', '-', '\\'],
+        ProgressStyle::None => &[],
+    }
+}
+
 // Progress indicator for file scanning
 struct ScanProgress {
+    style: ProgressStyle,
     start_time: Instant,
     update_interval: Duration,
     last_update: Instant,
@@ -140,10 +324,15 @@ struct ScanProgress {
 }
 
 impl ScanProgress {
-    fn new() -> Self {
+    fn new(walk: WalkOptions) -> Self {
+        Self::with_style(walk.progress_style, walk.progress_interval)
+    }
+
+    fn with_style(style: ProgressStyle, update_interval: Duration) -> Self {
         Self {
+            style,
             start_time: Instant::now(),
-            update_interval: Duration::from_millis(250),
+            update_interval,
             last_update: Instant::now(),
             scanned_count: 0,
             matched_count: 0,
@@ -156,35 +345,42 @@ impl ScanProgress {
             self.matched_count += 1;
         }
 
-        let now = Instant::now();
-        if now.duration_since(self.last_update) >= self.update_interval {
-            self.last_update = now;
-            let elapsed = now.duration_since(self.start_time).as_secs_f32();
-            let files_per_sec = if elapsed > 0.0 {
-                self.scanned_count as f32 / elapsed
-            } else {
-                0.0
-            };
-
-            let spinner_chars = ['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
-            let spinner_idx =
-                ((now.elapsed().as_millis() / 100) % spinner_chars.len() as u128) as usize;
-
+        if let Some(line) = self.status_line(Instant::now()) {
             let mut stdout = io::stdout();
             stdout.execute(cursor::SavePosition)?;
             stdout.execute(Clear(ClearType::CurrentLine))?;
-            write!(
-                stdout,
-                "{} Scanning files: {} scanned, {} matched ({:.1} files/sec)",
-                spinner_chars[spinner_idx], self.scanned_count, self.matched_count, files_per_sec
-            )?;
+            write!(stdout, "{}", line)?;
             stdout.flush()?;
             stdout.execute(cursor::RestorePosition)?;
         }
         Ok(())
     }
 
+    // The spinner line once a refresh is due, never with the `none` style
+    fn status_line(&mut self, now: Instant) -> Option<String> {
+        let frames = spinner_frames(self.style);
+        if frames.is_empty() || now.duration_since(self.last_update) < self.update_interval {
+            return None;
+        }
+        self.last_update = now;
+        let elapsed = now.duration_since(self.start_time);
+        let files_per_sec = if elapsed.as_secs_f32() > 0.0 {
+            self.scanned_count as f32 / elapsed.as_secs_f32()
+        } else {
+            0.0
+        };
+        let frame = frames[(elapsed.as_millis() / 100) as usize % frames.len()];
+
+        Some(format!(
+            "{} Scanning files: {} scanned, {} matched ({:.1} files/sec)",
+            frame, self.scanned_count, self.matched_count, files_per_sec
+        ))
+    }
+
     fn finish(&self) -> io::Result<()> {
+        if self.style == ProgressStyle::None {
+            return Ok(());
+        }
         let elapsed = self.start_time.elapsed().as_secs_f32();
         let files_per_sec = if elapsed > 0.0 {
             self.scanned_count as f32 / elapsed
@@ -203,54 +399,182 @@ impl ScanProgress {
     }
 }
 
+// Special `--ext` token selecting files without an extension (Dockerfile, Makefile, LICENSE)
+const NO_EXTENSION: &str = "none";
+
+fn matches_extension(path: &Path, extensions: &[&str]) -> bool {
+    if extensions.is_empty() {
+        return true;
+    }
+
+    match path.extension().and_then(|e| e.to_str()) {
+        Some(e) => extensions
+            .iter()
+            .any(|ext| ext.trim_start_matches('.') == e),
+        None => extensions.contains(&NO_EXTENSION),
+    }
+}
+
+/// Applies the `--ext`, `--exclude`, and `.rpignore` filters to files listed under `root`
+/// without walking the tree.
+pub fn filter_listed_files(
+    files: Vec<PathBuf>,
+    root: &str,
+    extensions: &[&str],
+    exclude_patterns: &[&str],
+    include_patterns: &[&str],
+) -> anyhow::Result<Vec<PathBuf>> {
+    let root = Path::new(root);
+    let mut rpignore = IgnoreFileCache::from_disk(root, None, false);
+    let files = files
+        .into_iter()
+        .filter(|path| {
+            matches_extension(path, extensions)
+                && !is_excluded(path, root, exclude_patterns)
+                && is_included(path, root, include_patterns)
+                && !rpignore.is_ignored_listed(path, root)
+        })
+        .collect();
+    rpignore.finish()?;
+    Ok(files)
+}
+
+/// `path` as `root` joined with its path below `root`, so a relative path listed against an
+/// absolute root, or the other way around, is spelled like a scan of `root` would spell it.
+/// `None` when `path` isn't below `root`.
+pub fn rebase_on_root(path: &Path, root: &Path) -> Option<PathBuf> {
+    if path.starts_with(root) {
+        return Some(path.to_path_buf());
+    }
+    let path = std::path::absolute(path).ok()?;
+    let absolute_root = std::path::absolute(root).ok()?;
+    let relative = path.strip_prefix(&absolute_root).ok()?;
+    Some(root.join(relative))
+}
+
+/// Compiles `--exclude-dir-glob` patterns, matched against root-relative directory paths.
+pub fn build_dir_globs(patterns: &[String]) -> anyhow::Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| anyhow::anyhow!("Invalid --exclude-dir-glob {}: {}", pattern, e))?;
+        builder.add(glob);
+    }
+    Ok(builder.build()?)
+}
+
+// Directories matching an `--exclude-dir-glob` are pruned with everything below them;
+// files are never matched, whatever their name
+fn is_pruned_dir(entry: &walkdir::DirEntry, root: &Path, dir_globs: &GlobSet) -> bool {
+    if dir_globs.is_empty() || entry.depth() == 0 || !entry.file_type().is_dir() {
+        return false;
+    }
+    let rel_path = entry.path().strip_prefix(root).unwrap_or(entry.path());
+    let pruned = dir_globs.is_match(rel_path.to_string_lossy().replace('\\', "/"));
+    if pruned {
+        debug!("Pruning directory {}", entry.path().display());
+    }
+    pruned
+}
+
+// `--exclude` patterns match the root-relative path, so the root's own name can't exclude it
+fn is_excluded(path: &Path, root: &Path, exclude_patterns: &[&str]) -> bool {
+    let rel_path = path.strip_prefix(root).unwrap_or(path).to_string_lossy();
+    exclude_patterns.iter().any(|pat| rel_path.contains(pat))
+}
+
+// `--include` globs match the root-relative path too; an empty list keeps every file
+fn is_included(path: &Path, root: &Path, include_patterns: &[&str]) -> bool {
+    include_patterns.is_empty() || first_matching_pattern(path, root, include_patterns).is_some()
+}
+
+/// How a scan walks the tree below its root.
+#[derive(Debug, Clone, Copy)]
+pub struct WalkOptions<'a> {
+    pub follow_symlinks: bool,
+    /// Directories to descend below the root; 0 lists only the root's own entries.
+    pub max_depth: Option<usize>,
+    /// Abandon the walk with a timeout error once this passes.
+    pub deadline: Option<Deadline>,
+    pub progress_style: ProgressStyle,
+    /// Time between scan spinner refreshes.
+    pub progress_interval: Duration,
+    /// Root-relative globs a listed file must match one of; empty lists every file.
+    pub include_patterns: &'a [&'a str],
+}
+
+impl Default for WalkOptions<'_> {
+    fn default() -> Self {
+        Self {
+            follow_symlinks: false,
+            max_depth: None,
+            deadline: None,
+            progress_style: ProgressStyle::default(),
+            progress_interval: DEFAULT_PROGRESS_INTERVAL,
+            include_patterns: &[],
+        }
+    }
+}
+
+impl WalkOptions<'_> {
+    fn check_deadline(&self) -> anyhow::Result<()> {
+        match self.deadline {
+            Some(deadline) => Ok(deadline.check()?),
+            None => Ok(()),
+        }
+    }
+}
+
+fn walk_dir(root: &str, walk: WalkOptions) -> walkdir::WalkDir {
+    let walker = walkdir::WalkDir::new(root).follow_links(walk.follow_symlinks);
+    match walk.max_depth {
+        // WalkDir counts the root itself as depth 0
+        Some(depth) => walker.max_depth(depth + 1),
+        None => walker,
+    }
+}
+
 pub fn list_code_files(
     root: &str,
     extensions: &[&str],
     exclude_patterns: &[&str],
+    dir_globs: &GlobSet,
+    walk: WalkOptions,
 ) -> anyhow::Result<Vec<PathBuf>> {
     info!("Listing code files in: {}", root);
     debug!("Extensions: {:?}", extensions);
     debug!("Exclude patterns: {:?}", exclude_patterns);
 
     let mut result = Vec::new();
-    let mut progress = ScanProgress::new();
+    let mut progress = ScanProgress::new(walk);
+    let mut walk_errors = WalkErrors::default();
+    let mut rpignore = IgnoreFileCache::from_disk(Path::new(root), None, false);
 
-    for entry in walkdir::WalkDir::new(root)
+    for entry in walk_dir(root, walk)
         .into_iter()
         .filter_entry(|e| {
-            let path = e.path().to_string_lossy();
-            let exclude_match = exclude_patterns.is_empty()
-                || !exclude_patterns.iter().any(|pat| path.contains(pat));
-
-            exclude_match
+            !is_excluded(e.path(), Path::new(root), exclude_patterns)
+                && !rpignore.is_ignored(e.path(), Path::new(root))
+                && !is_pruned_dir(e, Path::new(root), dir_globs)
         })
-        .filter_map(Result::ok)
+        .filter_map(|entry| walk_errors.keep_ok(entry))
     {
+        walk.check_deadline()?;
         if entry.file_type().is_dir() || entry.file_type().is_symlink() {
             continue;
         }
 
         let path = entry.path();
 
-        let ext_matches = if extensions.is_empty() {
-            true
-        } else {
-            path.extension()
-                .and_then(|e| e.to_str())
-                .map(|e| {
-                    extensions
-                        .iter()
-                        .any(|ext| ext.trim_start_matches('.') == e)
-                })
-                .unwrap_or(false)
-        };
+        let ext_matches = matches_extension(path, extensions);
 
-        let excluded = !exclude_patterns.is_empty()
-            && exclude_patterns
-                .iter()
-                .any(|pattern| path.to_string_lossy().contains(pattern));
+        let excluded = is_excluded(path, Path::new(root), exclude_patterns);
+
+        let included = is_included(path, Path::new(root), walk.include_patterns);
 
-        let matched = ext_matches && !excluded;
+        let matched = ext_matches && !excluded && included;
         progress.update(matched)?;
 
         if matched {
@@ -259,7 +583,9 @@ pub fn list_code_files(
         }
     }
 
+    rpignore.finish()?;
     progress.finish()?;
+    walk_errors.report("File scan");
     info!("Found {} matching files", result.len());
     Ok(result)
 }
@@ -270,6 +596,8 @@ pub fn list_code_files_with_gitignore(
     exclude_patterns: &[&str],
     exclude_version_control_dir: &str,
     apply_dot_git_ignore: bool,
+    dir_globs: &GlobSet,
+    walk: WalkOptions,
 ) -> anyhow::Result<Vec<PathBuf>> {
     info!("Listing code files in: {} with gitignore support", root);
     debug!("Extensions: {:?}", extensions);
@@ -277,59 +605,66 @@ pub fn list_code_files_with_gitignore(
     debug!("Exclude VCS dir: {}", exclude_version_control_dir);
     debug!("Apply .gitignore: {}", apply_dot_git_ignore);
 
-    let mut result = Vec::new();
     let mut all_exclude_patterns = exclude_patterns.to_vec();
-    let mut progress = ScanProgress::new();
-
     if !exclude_version_control_dir.is_empty() {
         all_exclude_patterns.push(exclude_version_control_dir);
     }
 
-    let gitignore_patterns = if apply_dot_git_ignore {
-        parse_gitignore(root)?
-    } else {
-        HashSet::new()
-    };
+    walk_code_files(
+        root,
+        extensions,
+        &all_exclude_patterns,
+        Some(IgnoreFileCache::from_disk(
+            Path::new(root),
+            apply_dot_git_ignore
+                .then(|| global_excludes_file(root))
+                .flatten(),
+            apply_dot_git_ignore,
+        )),
+        dir_globs,
+        walk,
+    )
+}
 
+fn walk_code_files(
+    root: &str,
+    extensions: &[&str],
+    all_exclude_patterns: &[&str],
+    mut gitignore: Option<IgnoreFileCache>,
+    dir_globs: &GlobSet,
+    walk: WalkOptions,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let mut result = Vec::new();
+    let mut progress = ScanProgress::new(walk);
+    let mut walk_errors = WalkErrors::default();
     let root_path = Path::new(root);
 
-    for entry in walkdir::WalkDir::new(root)
+    for entry in walk_dir(root, walk)
         .into_iter()
         .filter_entry(|e| {
-            let path = e.path().to_string_lossy();
-            let exclude_match = all_exclude_patterns.is_empty()
-                || !all_exclude_patterns.iter().any(|pat| path.contains(pat));
+            let exclude_match = !is_excluded(e.path(), root_path, all_exclude_patterns);
 
-            let gitignore_match = if apply_dot_git_ignore {
-                !should_ignore_by_gitignore(e.path(), root_path, &gitignore_patterns)
-            } else {
-                true
+            let gitignore_match = match &mut gitignore {
+                Some(gitignore) => !gitignore.is_ignored(e.path(), root_path),
+                None => true,
             };
 
-            exclude_match && gitignore_match
+            exclude_match && gitignore_match && !is_pruned_dir(e, root_path, dir_globs)
         })
-        .filter_map(Result::ok)
+        .filter_map(|entry| walk_errors.keep_ok(entry))
     {
+        walk.check_deadline()?;
         if entry.file_type().is_dir() || entry.file_type().is_symlink() {
             continue;
         }
 
         let path = entry.path();
 
-        let ext_matches = if extensions.is_empty() {
-            true
-        } else {
-            path.extension()
-                .and_then(|e| e.to_str())
-                .map(|e| {
-                    extensions
-                        .iter()
-                        .any(|ext| ext.trim_start_matches('.') == e)
-                })
-                .unwrap_or(false)
-        };
+        let ext_matches = matches_extension(path, extensions);
 
-        let matched = ext_matches;
+        let included = is_included(path, root_path, walk.include_patterns);
+
+        let matched = ext_matches && included;
         progress.update(matched)?;
 
         if matched {
@@ -338,11 +673,456 @@ pub fn list_code_files_with_gitignore(
         }
     }
 
+    if let Some(gitignore) = gitignore {
+        gitignore.finish()?;
+    }
     progress.finish()?;
+    walk_errors.report("File scan");
     info!("Found {} matching files", result.len());
     Ok(result)
 }
 
+/// One line of a sparse-checkout file, which uses `.gitignore` syntax.
+struct SparsePattern {
+    matcher: GlobMatcher,
+    negated: bool,
+    dir_only: bool,
+}
+
+impl SparsePattern {
+    fn parse(line: &str) -> anyhow::Result<Self> {
+        let (negated, body) = match line.strip_prefix('!') {
+            Some(body) => (true, body),
+            None => (false, line),
+        };
+        let dir_only = body.ends_with('/');
+        let body = body.trim_end_matches('/');
+        // A pattern with a `/` is relative to the top of the work tree; one without matches
+        // a name at any depth
+        let glob = match body.contains('/') {
+            true => body.trim_start_matches('/').to_string(),
+            false => format!("**/{}", body),
+        };
+        let matcher = GlobBuilder::new(&glob)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| anyhow::anyhow!("Invalid sparse-checkout pattern {}: {}", line, e))?
+            .compile_matcher();
+        Ok(Self {
+            matcher,
+            negated,
+            dir_only,
+        })
+    }
+}
+
+fn parse_sparse_checkout(sparse_path: &Path) -> anyhow::Result<Vec<SparsePattern>> {
+    // Order matters here: later patterns override earlier ones, so keep them as a list.
+    let patterns = fs::read_to_string(sparse_path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(SparsePattern::parse)
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    info!("Loaded {} sparse-checkout patterns", patterns.len());
+    Ok(patterns)
+}
+
+// Git's rule: the last pattern matching the file or any of its parent directories decides.
+fn in_sparse_checkout(rel_path: &Path, patterns: &[SparsePattern]) -> bool {
+    let parents: Vec<&Path> = rel_path
+        .ancestors()
+        .skip(1)
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .collect();
+
+    let mut included = false;
+    for pattern in patterns {
+        let matches = parents.iter().any(|dir| pattern.matcher.is_match(dir))
+            || (!pattern.dir_only && pattern.matcher.is_match(rel_path));
+        if matches {
+            included = !pattern.negated;
+        }
+    }
+    included
+}
+
+/// Keeps only the files inside the repository's sparse-checkout definition. Outside a git
+/// work tree, or without a sparse-checkout file, the list is returned as is.
+pub fn restrict_to_sparse_checkout(
+    files: Vec<PathBuf>,
+    root: &str,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let (sparse_path, toplevel) = match sparse_checkout_file(root) {
+        Ok(found) => found,
+        Err(e) => {
+            warn!(
+                "--respect-sparse was set but {} isn't in a git work tree: {}",
+                root, e
+            );
+            return Ok(files);
+        }
+    };
+    if !sparse_path.is_file() {
+        warn!("--respect-sparse was set but no sparse-checkout file was found");
+        return Ok(files);
+    }
+    let patterns = parse_sparse_checkout(&sparse_path)?;
+
+    let before = files.len();
+    let kept: Vec<PathBuf> = files
+        .into_iter()
+        .filter(|path| {
+            // git reports the top level with symlinks resolved
+            let rel_path = fs::canonicalize(path)
+                .ok()
+                .and_then(|path| Some(path.strip_prefix(&toplevel).ok()?.to_path_buf()));
+            match rel_path {
+                Some(rel_path) => in_sparse_checkout(&rel_path, &patterns),
+                None => true,
+            }
+        })
+        .collect();
+
+    debug!("Sparse checkout kept {} of {} files", kept.len(), before);
+    Ok(kept)
+}
+
+/// Removes files that resolve to one of `excluded` (files) or live under one of `excluded`
+/// (directories), comparing canonical paths so `./out.txt` and `/abs/out.txt` agree.
+/// Exclusions that don't exist yet are ignored.
+pub fn exclude_resolved_paths(files: Vec<PathBuf>, excluded: &[PathBuf]) -> Vec<PathBuf> {
+    let excluded = canonical_paths(excluded);
+    if excluded.is_empty() {
+        return files;
+    }
+
+    files
+        .into_iter()
+        .filter(|path| !resolves_under(path, &excluded))
+        .collect()
+}
+
+fn canonical_paths(paths: &[PathBuf]) -> Vec<PathBuf> {
+    paths
+        .iter()
+        .filter_map(|path| fs::canonicalize(path).ok())
+        .collect()
+}
+
+// Whether `path` is, or lives under, one of the already canonical `excluded` paths
+fn resolves_under(path: &Path, excluded: &[PathBuf]) -> bool {
+    let Ok(canonical) = fs::canonicalize(path) else {
+        return false;
+    };
+    let skip = excluded.iter().any(|ex| canonical.starts_with(ex));
+    if skip {
+        debug!("Excluding rich-prompt artifact: {}", path.display());
+    }
+    skip
+}
+
+/// The first of `patterns` that the root-relative path of `path` matches.
+fn first_matching_pattern<'a>(path: &Path, root: &Path, patterns: &[&'a str]) -> Option<&'a str> {
+    let rel_path = path
+        .strip_prefix(root)
+        .unwrap_or(path)
+        .to_string_lossy()
+        .replace('\\', "/");
+    patterns
+        .iter()
+        .copied()
+        .find(|pattern| matches_gitignore_pattern(&rel_path, pattern, false))
+}
+
+/// Glob patterns from an `--order-file`, one per line, skipping blank and `#` lines.
+pub fn read_order_file(path: &Path) -> anyhow::Result<Vec<String>> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Failed to read order file {}: {}", path.display(), e))?;
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+/// Sorts files by the first of `patterns` their path relative to `root_of(path)` matches,
+/// keeping "must review" files first; files matching no pattern go last. Ties sort by path.
+pub fn order_by_patterns<'r>(
+    mut files: Vec<FileContext>,
+    root_of: impl Fn(&Path) -> &'r str,
+    patterns: &[String],
+) -> Vec<FileContext> {
+    let patterns: Vec<&str> = patterns.iter().map(String::as_str).collect();
+    files.sort_by_cached_key(|file| {
+        let rank = first_matching_pattern(&file.path, Path::new(root_of(&file.path)), &patterns)
+            .and_then(|matched| patterns.iter().position(|pattern| *pattern == matched))
+            .unwrap_or(patterns.len());
+        (!file.priority, rank, file.path.clone())
+    });
+    files
+}
+
+/// The scan criteria that admit `path`: the `--ext` entry it matched and the `--include`
+/// glob, if any.
+pub fn inclusion_reasons(
+    path: &Path,
+    root: &str,
+    extensions: &[&str],
+    include_patterns: &[&str],
+) -> Vec<InclusionReason> {
+    let mut reasons = Vec::new();
+
+    if extensions.is_empty() {
+        reasons.push(InclusionReason::AnyExtension);
+    } else if let Some(ext) = extensions
+        .iter()
+        .find(|ext| matches_extension(path, &[ext]))
+    {
+        reasons.push(InclusionReason::Extension(
+            ext.trim_start_matches('.').to_string(),
+        ));
+    }
+
+    if let Some(pattern) = first_matching_pattern(path, Path::new(root), include_patterns) {
+        reasons.push(InclusionReason::IncludeGlob(pattern.to_string()));
+    }
+
+    reasons
+}
+
+struct TestConvention {
+    extensions: &'static [&'static str],
+    file_patterns: &'static [&'static str],
+    /// Directory names whose files (with a matching extension) are all tests.
+    dirs: &'static [&'static str],
+}
+
+const TEST_CONVENTIONS: &[TestConvention] = &[
+    // Rust
+    TestConvention {
+        extensions: &["rs"],
+        file_patterns: &["*_test.rs", "*_tests.rs"],
+        dirs: &["tests"],
+    },
+    // Python
+    TestConvention {
+        extensions: &["py"],
+        file_patterns: &["test_*.py", "*_test.py"],
+        dirs: &[],
+    },
+    // JavaScript / TypeScript
+    TestConvention {
+        extensions: &["js", "jsx", "ts", "tsx", "mjs", "cjs"],
+        file_patterns: &["*.test.*", "*.spec.*"],
+        dirs: &["__tests__"],
+    },
+    // Go
+    TestConvention {
+        extensions: &["go"],
+        file_patterns: &["*_test.go"],
+        dirs: &[],
+    },
+];
+
+// File name patterns of each of `TEST_CONVENTIONS`, compiled once
+static TEST_FILE_GLOBS: LazyLock<Vec<GlobSet>> = LazyLock::new(|| {
+    TEST_CONVENTIONS
+        .iter()
+        .map(|convention| {
+            let mut builder = GlobSetBuilder::new();
+            for pattern in convention.file_patterns {
+                builder.add(Glob::new(pattern).expect("valid test file pattern"));
+            }
+            builder.build().expect("valid test file patterns")
+        })
+        .collect()
+});
+
+// Only directories below `root` count, so a checkout under e.g. `~/tests/` isn't all tests
+fn is_test_file(path: &Path, root: &Path) -> bool {
+    let Some(extension) = path.extension().and_then(|e| e.to_str()) else {
+        return false;
+    };
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let dirs: Vec<String> = path
+        .strip_prefix(root)
+        .unwrap_or(path)
+        .parent()
+        .map(|parent| {
+            parent
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy().to_string())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    TEST_CONVENTIONS
+        .iter()
+        .zip(TEST_FILE_GLOBS.iter())
+        .filter(|(convention, _)| convention.extensions.contains(&extension))
+        .any(|(convention, file_globs)| {
+            file_globs.is_match(&name)
+                || dirs
+                    .iter()
+                    .any(|dir| convention.dirs.contains(&dir.as_str()))
+        })
+}
+
+/// Applies `--exclude-tests` / `--tests-only` using per-language test naming conventions.
+pub fn filter_test_files(files: Vec<PathBuf>, root: &str, filter: TestFilter) -> Vec<PathBuf> {
+    let root = Path::new(root);
+    match filter {
+        TestFilter::All => files,
+        TestFilter::ExcludeTests => files
+            .into_iter()
+            .filter(|p| !is_test_file(p, root))
+            .collect(),
+        TestFilter::TestsOnly => files
+            .into_iter()
+            .filter(|p| is_test_file(p, root))
+            .collect(),
+    }
+}
+
+/// Drops files larger than `max_bytes`, warning about each. Files whose size can't be read
+/// are kept, so reading them reports the actual error.
+pub fn exclude_large_files(files: Vec<PathBuf>, max_bytes: u64) -> Vec<PathBuf> {
+    files
+        .into_iter()
+        .filter(|path| match fs::metadata(path) {
+            Ok(metadata) if metadata.len() > max_bytes => {
+                warn!(
+                    "Skipping {}: {} bytes is over --max-file-size {}",
+                    path.display(),
+                    metadata.len(),
+                    max_bytes
+                );
+                false
+            }
+            _ => true,
+        })
+        .collect()
+}
+
+/// Drops files with a line longer than `max_chars`, warning about each. Unreadable files are
+/// kept, so reading them reports the actual error.
+pub fn exclude_long_line_files(files: Vec<PathBuf>, max_chars: usize) -> Vec<PathBuf> {
+    files
+        .into_iter()
+        .filter(|path| {
+            let Ok(bytes) = fs::read(path) else {
+                return true;
+            };
+            let too_long = String::from_utf8_lossy(&bytes)
+                .lines()
+                .any(|line| line.chars().count() > max_chars);
+            if too_long {
+                warn!(
+                    "Skipping {}: it has a line longer than --max-line-length {}",
+                    path.display(),
+                    max_chars
+                );
+            }
+            !too_long
+        })
+        .collect()
+}
+
+// Content-sniffed type of a file's first bytes; unrecognized binary content is reported as
+// `application/octet-stream` and plain text as `None`
+fn sniff_mime(path: &Path) -> Option<String> {
+    const SNIFF_LEN: u64 = 8192;
+    let mut bytes = Vec::new();
+    fs::File::open(path)
+        .and_then(|file| file.take(SNIFF_LEN).read_to_end(&mut bytes))
+        .ok()?;
+    // Text with a byte order mark is text, however many NUL bytes UTF-16 gives it
+    if utf16_byte_order(&bytes).is_some() || bytes.starts_with(UTF8_BOM) {
+        return None;
+    }
+    match infer::get(&bytes) {
+        Some(kind) => Some(kind.mime_type().to_string()),
+        None if looks_binary(&bytes) => Some("application/octet-stream".to_string()),
+        None => None,
+    }
+}
+
+// `image/*` matches every image type; anything else must match exactly
+fn matches_mime_pattern(mime: &str, pattern: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some(top_level) => mime
+            .split_once('/')
+            .is_some_and(|(kind, _)| kind.eq_ignore_ascii_case(top_level)),
+        None => mime.eq_ignore_ascii_case(pattern),
+    }
+}
+
+/// Drops files whose sniffed content type matches one of `patterns`, whatever their extension.
+pub fn exclude_by_mime(files: Vec<PathBuf>, patterns: &[String]) -> Vec<PathBuf> {
+    files
+        .into_iter()
+        .filter(|path| {
+            let Some(mime) = sniff_mime(path) else {
+                return true;
+            };
+            match patterns.iter().find(|p| matches_mime_pattern(&mime, p)) {
+                Some(pattern) => {
+                    warn!(
+                        "Skipping {}: its content is {}, matching --exclude-mime {}",
+                        path.display(),
+                        mime,
+                        pattern
+                    );
+                    false
+                }
+                None => true,
+            }
+        })
+        .collect()
+}
+
+/// Drops files whose name matches one of `patterns` *and* whose size is at least `min_bytes`,
+/// so large lockfiles and generated code go while small hand-written matches stay.
+pub fn exclude_generated_files(
+    files: Vec<PathBuf>,
+    patterns: &[&str],
+    min_bytes: u64,
+) -> Vec<PathBuf> {
+    files
+        .into_iter()
+        .filter(|path| {
+            let name = path
+                .file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_default();
+            if !patterns
+                .iter()
+                .any(|pattern| matches_gitignore_pattern(&name, pattern, false))
+            {
+                return true;
+            }
+
+            let size = fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+            if size >= min_bytes {
+                debug!(
+                    "Excluding generated file {} ({} bytes)",
+                    path.display(),
+                    size
+                );
+                false
+            } else {
+                true
+            }
+        })
+        .collect()
+}
+
 pub fn read_file_contents(path: &Path) -> anyhow::Result<String> {
     if !path.exists() {
         warn!("File does not exist: {}", path.display());
@@ -359,10 +1139,85 @@ pub fn read_file_contents(path: &Path) -> anyhow::Result<String> {
 
     debug!("Reading file contents: {}", path.display());
     let mut file = fs::File::open(path)?;
-    let mut contents = String::new();
-    file.read_to_string(&mut contents)?;
-    debug!("Read {} bytes from file", contents.len());
-    Ok(contents)
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)?;
+    debug!("Read {} bytes from file", bytes.len());
+    decode_file_bytes(path, bytes)
+}
+
+const UTF8_BOM: &[u8] = &[0xEF, 0xBB, 0xBF];
+
+// How to read a code unit of UTF-16 text that starts with a byte order mark
+fn utf16_byte_order(bytes: &[u8]) -> Option<fn([u8; 2]) -> u16> {
+    match bytes.get(..2) {
+        Some([0xFF, 0xFE]) => Some(u16::from_le_bytes),
+        Some([0xFE, 0xFF]) => Some(u16::from_be_bytes),
+        _ => None,
+    }
+}
+
+/// Text content of the file at `path` read as `bytes`, or a `BinaryFileError` if it isn't
+/// text. A leading byte order mark is dropped, and UTF-16 text marked by one is converted to
+/// UTF-8. Text in another encoding, such as Latin-1, is decoded with its invalid bytes
+/// replaced, and a warning.
+pub fn decode_file_bytes(path: &Path, mut bytes: Vec<u8>) -> anyhow::Result<String> {
+    // UTF-16 text is full of NUL bytes, so it has to be recognized before the binary check
+    if let Some(to_unit) = utf16_byte_order(&bytes) {
+        debug!("Converting UTF-16 content to UTF-8");
+        return decode_utf16(&bytes[2..], to_unit).ok_or_else(|| {
+            BinaryFileError {
+                bytes: bytes.len() as u64,
+            }
+            .into()
+        });
+    }
+    if bytes.starts_with(UTF8_BOM) {
+        bytes.drain(..UTF8_BOM.len());
+    }
+
+    if looks_binary(&bytes) {
+        return Err(BinaryFileError {
+            bytes: bytes.len() as u64,
+        }
+        .into());
+    }
+
+    Ok(match String::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(e) => {
+            warn!(
+                "{} isn't valid UTF-8, replacing the invalid bytes",
+                path.display()
+            );
+            String::from_utf8_lossy(e.as_bytes()).into_owned()
+        }
+    })
+}
+
+fn decode_utf16(bytes: &[u8], to_unit: fn([u8; 2]) -> u16) -> Option<String> {
+    let chunks = bytes.chunks_exact(2);
+    if !chunks.remainder().is_empty() {
+        return None;
+    }
+    let units = chunks.map(|pair| to_unit([pair[0], pair[1]]));
+    char::decode_utf16(units)
+        .collect::<Result<String, _>>()
+        .ok()
+}
+
+// Same heuristic as git: a NUL byte near the start means the file is not text
+fn looks_binary(bytes: &[u8]) -> bool {
+    const SNIFF_LEN: usize = 8000;
+    bytes[..bytes.len().min(SNIFF_LEN)].contains(&0)
+}
+
+/// What to leave out of a file map beyond the walk's own filters.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FileMapPruning<'a> {
+    /// Drop directories whose subtree has no file with one of these extensions.
+    pub clamp_to_extensions: Option<&'a [&'a str]>,
+    /// Drop these files and everything under these directories, compared by canonical path.
+    pub excluded: &'a [PathBuf],
 }
 
 pub fn generate_file_map(
@@ -370,6 +1225,9 @@ pub fn generate_file_map(
     exclude_patterns: &[&str],
     exclude_version_control_dir: &str,
     apply_dot_git_ignore: bool,
+    pruning: FileMapPruning,
+    dir_globs: &GlobSet,
+    walk: WalkOptions,
 ) -> anyhow::Result<String> {
     info!("Generating file map for: {}", root);
     let mut output = String::new();
@@ -380,35 +1238,91 @@ pub fn generate_file_map(
         all_exclude_patterns.push(exclude_version_control_dir);
     }
 
-    let gitignore_patterns = if apply_dot_git_ignore {
-        parse_gitignore(root)?
-    } else {
-        HashSet::new()
-    };
-
-    let dir_map = list_dir_structure_with_gitignore(
+    let mut dir_map = list_dir_structure_with_gitignore(
         root,
         &all_exclude_patterns,
-        &gitignore_patterns,
         apply_dot_git_ignore,
+        dir_globs,
+        walk,
     )?;
 
-    for (dir, files) in &dir_map {
+    prune_excluded_paths(&mut dir_map, pruning.excluded);
+    if let Some(extensions) = pruning.clamp_to_extensions {
+        prune_empty_dirs(&mut dir_map, extensions);
+    }
+
+    output.push_str(&render_file_map(&dir_map));
+
+    debug!("Generated file map with {} directories", dir_map.len());
+    Ok(output)
+}
+
+fn render_file_map<'a>(dirs: impl IntoIterator<Item = (&'a String, &'a Vec<String>)>) -> String {
+    let mut output = String::new();
+    for (dir, files) in dirs {
         output.push_str(&format!("{}\n", dir));
         for file in files {
             output.push_str(&format!("├── {}\n", file));
         }
     }
+    output
+}
+
+/// File map for a fixed list of files, such as those tracked at a git ref, with directories
+/// in sorted order.
+pub fn file_map_from_paths(files: &[PathBuf]) -> String {
+    let mut dir_map: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for file in files {
+        let parent = file.parent().unwrap_or_else(|| Path::new(""));
+        dir_map
+            .entry(parent.to_string_lossy().to_string())
+            .or_default()
+            .push(file.to_string_lossy().to_string());
+    }
 
     debug!("Generated file map with {} directories", dir_map.len());
-    Ok(output)
+    render_file_map(&dir_map)
+}
+
+fn prune_excluded_paths(dir_map: &mut HashMap<String, Vec<String>>, excluded: &[PathBuf]) {
+    let excluded = canonical_paths(excluded);
+    if excluded.is_empty() {
+        return;
+    }
+
+    dir_map.retain(|dir, _| !resolves_under(Path::new(dir), &excluded));
+    for files in dir_map.values_mut() {
+        files.retain(|file| !resolves_under(Path::new(file), &excluded));
+    }
+}
+
+// Drops directories whose whole subtree contains no file with one of `extensions`, the
+// files the scan would include
+fn prune_empty_dirs(dir_map: &mut HashMap<String, Vec<String>>, extensions: &[&str]) {
+    let non_empty: Vec<PathBuf> = dir_map
+        .iter()
+        .filter(|(_, files)| {
+            files
+                .iter()
+                .any(|file| matches_extension(Path::new(file), extensions))
+        })
+        .map(|(dir, _)| PathBuf::from(dir))
+        .collect();
+
+    let before = dir_map.len();
+    dir_map.retain(|dir, _| non_empty.iter().any(|path| path.starts_with(dir)));
+    debug!(
+        "Pruned {} empty directories from the file map",
+        before - dir_map.len()
+    );
 }
 
 pub fn list_dir_structure_with_gitignore(
     root: &str,
     exclude_patterns: &[&str],
-    gitignore_patterns: &HashSet<String>,
     apply_dot_git_ignore: bool,
+    dir_globs: &GlobSet,
+    walk: WalkOptions,
 ) -> anyhow::Result<HashMap<String, Vec<String>>> {
     debug!(
         "Listing directory structure in: {} with gitignore support",
@@ -416,25 +1330,26 @@ pub fn list_dir_structure_with_gitignore(
     );
     let mut dir_map = HashMap::new();
     let root_path = Path::new(root);
-    let mut progress = ScanProgress::new();
+    let mut ignore_files = IgnoreFileCache::from_disk(
+        root_path,
+        apply_dot_git_ignore
+            .then(|| global_excludes_file(root))
+            .flatten(),
+        apply_dot_git_ignore,
+    );
+    let mut progress = ScanProgress::new(walk);
+    let mut walk_errors = WalkErrors::default();
 
-    for entry in walkdir::WalkDir::new(root)
+    for entry in walk_dir(root, walk)
         .into_iter()
         .filter_entry(|e| {
-            let path = e.path().to_string_lossy();
-            let exclude_match = exclude_patterns.is_empty()
-                || !exclude_patterns.iter().any(|pat| path.contains(pat));
-
-            let gitignore_match = if apply_dot_git_ignore {
-                !should_ignore_by_gitignore(e.path(), root_path, gitignore_patterns)
-            } else {
-                true
-            };
-
-            exclude_match && gitignore_match
+            !is_excluded(e.path(), root_path, exclude_patterns)
+                && !ignore_files.is_ignored(e.path(), root_path)
+                && !is_pruned_dir(e, root_path, dir_globs)
         })
-        .filter_map(Result::ok)
+        .filter_map(|entry| walk_errors.keep_ok(entry))
     {
+        walk.check_deadline()?;
         if entry.file_type().is_dir() {
             let path = entry.path().to_string_lossy().to_string();
             dir_map.entry(path).or_insert_with(Vec::new);
@@ -450,7 +1365,9 @@ pub fn list_dir_structure_with_gitignore(
         progress.update(true)?;
     }
 
+    ignore_files.finish()?;
     progress.finish()?;
+    walk_errors.report("File map");
     debug!("Found {} directories in structure", dir_map.len());
     Ok(dir_map)
 }
@@ -476,6 +1393,366 @@ mod tests {
         assert_eq!(contents, "Test content\n");
     }
 
+    #[test]
+    fn test_exclude_generated_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let cargo_lock = temp_dir.path().join("Cargo.lock");
+        let schema_lock = temp_dir.path().join("schema.lock");
+        let main_rs = temp_dir.path().join("main.rs");
+        fs::write(&cargo_lock, "x".repeat(4096)).unwrap();
+        fs::write(&schema_lock, "version = 1\n").unwrap();
+        fs::write(&main_rs, "x".repeat(4096)).unwrap();
+
+        let kept = exclude_generated_files(
+            vec![cargo_lock, schema_lock.clone(), main_rs.clone()],
+            &["*.lock", "*.generated.*", "*_pb2.py"],
+            1024,
+        );
+
+        assert_eq!(kept, vec![schema_lock, main_rs]);
+    }
+
+    #[test]
+    fn test_exclude_large_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let bundle = temp_dir.path().join("bundle.min.js");
+        let main_rs = temp_dir.path().join("main.rs");
+        fs::write(&bundle, "x".repeat(65)).unwrap();
+        fs::write(&main_rs, "x".repeat(64)).unwrap();
+
+        let kept = exclude_large_files(vec![bundle, main_rs.clone()], 64);
+
+        assert_eq!(kept, vec![main_rs]);
+    }
+
+    #[test]
+    fn test_exclude_long_line_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let minified = temp_dir.path().join("app.min.js");
+        let source = temp_dir.path().join("app.js");
+        fs::write(&minified, "y".repeat(5000)).unwrap();
+        fs::write(&source, "let a = 1;\r\n").unwrap();
+
+        let kept = exclude_long_line_files(vec![minified, source.clone()], 100);
+
+        assert_eq!(kept, vec![source]);
+    }
+
+    #[test]
+    fn test_exclude_by_mime() {
+        let temp_dir = TempDir::new().unwrap();
+        let disguised_png = temp_dir.path().join("notes.txt");
+        let blob = temp_dir.path().join("data.txt");
+        let text = temp_dir.path().join("readme.txt");
+        fs::write(
+            &disguised_png,
+            b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR\0\0\0\x01\0\0\0\x01",
+        )
+        .unwrap();
+        fs::write(&blob, b"\x01\x02\0\x03").unwrap();
+        fs::write(&text, "plain text").unwrap();
+        let utf16 = temp_dir.path().join("utf16.txt");
+        fs::write(&utf16, b"\xFF\xFEh\0i\0").unwrap();
+        let files = vec![disguised_png, blob.clone(), text.clone(), utf16.clone()];
+
+        let kept = exclude_by_mime(files.clone(), &["image/*".to_string()]);
+        assert_eq!(kept, vec![blob, text.clone(), utf16.clone()]);
+
+        let kept = exclude_by_mime(
+            files,
+            &[
+                "image/png".to_string(),
+                "application/octet-stream".to_string(),
+            ],
+        );
+        assert_eq!(kept, vec![text, utf16]);
+    }
+
+    #[test]
+    fn test_restrict_to_sparse_checkout() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        let git_init = std::process::Command::new("git")
+            .args(["init", "-q"])
+            .current_dir(root)
+            .status()
+            .unwrap();
+        assert!(git_init.success());
+        fs::write(
+            root.join(".git/info/sparse-checkout"),
+            "/*\n!/*/\n/src/\n!/src/*/\n/src/core/\n*.md\n",
+        )
+        .unwrap();
+
+        let files: Vec<PathBuf> = [
+            "Cargo.toml",
+            "src/main.rs",
+            "src/core/mod.rs",
+            "src/infra/mod.rs",
+            "src/infra/README.md",
+            "docs/guide.md",
+            "docs/api/index.html",
+        ]
+        .iter()
+        .map(|f| root.join(f))
+        .collect();
+        for file in &files {
+            fs::create_dir_all(file.parent().unwrap()).unwrap();
+            File::create(file).unwrap();
+        }
+
+        let kept = restrict_to_sparse_checkout(files.clone(), &root.to_string_lossy()).unwrap();
+        assert_eq!(
+            kept,
+            vec![
+                root.join("Cargo.toml"),
+                root.join("src/main.rs"),
+                root.join("src/core/mod.rs"),
+                root.join("src/infra/README.md"),
+                root.join("docs/guide.md"),
+            ]
+        );
+
+        // Patterns stay relative to the top of the work tree when scanning a subdirectory
+        let below_src: Vec<PathBuf> = files
+            .into_iter()
+            .filter(|file| file.starts_with(root.join("src")))
+            .collect();
+        let kept =
+            restrict_to_sparse_checkout(below_src, &root.join("src").to_string_lossy()).unwrap();
+        assert_eq!(
+            kept,
+            vec![
+                root.join("src/main.rs"),
+                root.join("src/core/mod.rs"),
+                root.join("src/infra/README.md"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_generate_file_map_clamps_empty_dirs() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("src/core")).unwrap();
+        fs::create_dir_all(root.join("logs/archive")).unwrap();
+        fs::write(root.join("src/core/mod.rs"), "pub mod a;").unwrap();
+        fs::write(root.join("logs/archive/run.log"), "noise").unwrap();
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::write(root.join("docs/guide.md"), "# Guide").unwrap();
+        let root_str = root.to_string_lossy().to_string();
+
+        let full = generate_file_map(
+            &root_str,
+            &[".log"],
+            "",
+            false,
+            FileMapPruning::default(),
+            &GlobSet::empty(),
+            WalkOptions::default(),
+        )
+        .unwrap();
+        assert!(full.contains("logs/archive"));
+
+        let clamped = generate_file_map(
+            &root_str,
+            &[".log"],
+            "",
+            false,
+            FileMapPruning {
+                clamp_to_extensions: Some(&["rs"]),
+                ..Default::default()
+            },
+            &GlobSet::empty(),
+            WalkOptions::default(),
+        )
+        .unwrap();
+        assert!(!clamped.contains("logs"));
+        assert!(!clamped.contains("docs"));
+        assert!(clamped.contains("src/core/mod.rs"));
+        assert!(clamped.lines().any(|line| line.ends_with("/src")));
+    }
+
+    #[test]
+    fn test_exclude_resolved_paths() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join(".rich-prompt")).unwrap();
+        fs::write(root.join("main.rs"), "fn main() {}").unwrap();
+        fs::write(root.join("context.txt"), "<file_map>").unwrap();
+        fs::write(root.join(".rich-prompt/session.json"), "{}").unwrap();
+
+        let scanned = vec![
+            root.join("main.rs"),
+            root.join("context.txt"),
+            root.join(".rich-prompt/session.json"),
+        ];
+        let excluded = [
+            root.join(".rich-prompt/../context.txt"),
+            root.join(".rich-prompt"),
+            root.join("not-written-yet.json"),
+        ];
+        let kept = exclude_resolved_paths(scanned, &excluded);
+
+        assert_eq!(kept, vec![root.join("main.rs")]);
+
+        let map = generate_file_map(
+            &root.to_string_lossy(),
+            &[],
+            "",
+            false,
+            FileMapPruning {
+                excluded: &excluded,
+                ..Default::default()
+            },
+            &GlobSet::empty(),
+            WalkOptions::default(),
+        )
+        .unwrap();
+        assert!(map.contains("main.rs"));
+        assert!(!map.contains("context.txt"));
+        assert!(!map.contains(".rich-prompt"));
+    }
+
+    #[test]
+    fn test_ext_none_includes_extensionless_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        fs::write(root.join("Dockerfile"), "FROM rust").unwrap();
+        fs::write(root.join("main.rs"), "fn main() {}").unwrap();
+        let root_str = root.to_string_lossy().to_string();
+
+        let files = list_code_files(
+            &root_str,
+            &["none"],
+            &[],
+            &GlobSet::empty(),
+            WalkOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(files, vec![root.join("Dockerfile")]);
+
+        let mut files = list_code_files(
+            &root_str,
+            &["rs", "none"],
+            &[],
+            &GlobSet::empty(),
+            WalkOptions::default(),
+        )
+        .unwrap();
+        files.sort();
+        assert_eq!(files, vec![root.join("Dockerfile"), root.join("main.rs")]);
+    }
+
+    #[test]
+    fn test_inclusion_reason_for_include_glob() {
+        let root = "/repo";
+        let path = Path::new("/repo/src/cli/commands.rs");
+
+        let reasons = inclusion_reasons(path, root, &[".rs"], &["docs/*", "src/*"]);
+        assert_eq!(
+            reasons,
+            vec![
+                InclusionReason::Extension("rs".to_string()),
+                InclusionReason::IncludeGlob("src/*".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_exclude_wins_over_include() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        for file in [
+            "src/main.rs",
+            "src/generated/schema.rs",
+            "tests/cli.rs",
+            "tests/fixtures/data.json",
+            "docs/guide.md",
+            "build.rs",
+        ] {
+            let path = root.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "x").unwrap();
+        }
+        let root_str = root.to_string_lossy().to_string();
+        let excludes = ["generated", "fixtures"];
+        let walk = WalkOptions {
+            include_patterns: &["src/**", "tests/**"],
+            ..WalkOptions::default()
+        };
+        let expected = vec![root.join("src/main.rs"), root.join("tests/cli.rs")];
+
+        let mut files =
+            list_code_files(&root_str, &[], &excludes, &GlobSet::empty(), walk).unwrap();
+        files.sort();
+        assert_eq!(files, expected);
+
+        let mut files = list_code_files_with_gitignore(
+            &root_str,
+            &[],
+            &excludes,
+            ".git",
+            true,
+            &GlobSet::empty(),
+            walk,
+        )
+        .unwrap();
+        files.sort();
+        assert_eq!(files, expected);
+    }
+
+    #[test]
+    fn test_filter_test_files() {
+        let files: Vec<PathBuf> = [
+            "src/lib.rs",
+            "src/parser_test.rs",
+            "tests/cli.rs",
+            "app/models.py",
+            "app/test_models.py",
+            "app/models_test.py",
+            "web/button.ts",
+            "web/button.test.ts",
+            "web/__tests__/form.tsx",
+            "docs/tests/notes.md",
+            "pkg/server_test.go",
+        ]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+
+        let kept = filter_test_files(files.clone(), "", TestFilter::ExcludeTests);
+        assert_eq!(
+            kept,
+            [
+                "src/lib.rs",
+                "app/models.py",
+                "web/button.ts",
+                "docs/tests/notes.md"
+            ]
+            .iter()
+            .map(PathBuf::from)
+            .collect::<Vec<_>>()
+        );
+
+        let tests = filter_test_files(files.clone(), "", TestFilter::TestsOnly);
+        assert_eq!(tests.len(), 7);
+        assert!(!tests.contains(&PathBuf::from("docs/tests/notes.md")));
+
+        assert_eq!(filter_test_files(files.clone(), "", TestFilter::All), files);
+
+        // Test directories above the scan root don't make everything below it a test
+        let root = "/home/me/tests/project";
+        let under_tests_dir = vec![
+            Path::new(root).join("src/lib.rs"),
+            Path::new(root).join("tests/cli.rs"),
+        ];
+        assert_eq!(
+            filter_test_files(under_tests_dir, root, TestFilter::ExcludeTests),
+            vec![Path::new(root).join("src/lib.rs")]
+        );
+    }
+
     #[test]
     fn test_read_nonexistent_file() {
         let temp_dir = TempDir::new().unwrap();
@@ -485,6 +1762,70 @@ mod tests {
         assert_eq!(contents, "");
     }
 
+    #[test]
+    fn test_read_binary_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.path().join("logo.png");
+        fs::write(&file_path, b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR").unwrap();
+
+        let err = read_file_contents(&file_path).unwrap_err();
+        let binary = err.downcast_ref::<BinaryFileError>().unwrap();
+
+        assert_eq!(binary.bytes, 16);
+    }
+
+    #[test]
+    fn test_read_file_with_byte_order_mark() {
+        let temp_dir = TempDir::new().unwrap();
+        let utf8 = temp_dir.path().join("utf8.txt");
+        fs::write(&utf8, b"\xEF\xBB\xBFfn main() {}\n").unwrap();
+        assert_eq!(read_file_contents(&utf8).unwrap(), "fn main() {}\n");
+
+        let text = "h\u{e9}llo \u{1F600}\n";
+        let utf16_le = temp_dir.path().join("utf16le.txt");
+        let mut bytes = vec![0xFF, 0xFE];
+        bytes.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+        fs::write(&utf16_le, &bytes).unwrap();
+        assert_eq!(read_file_contents(&utf16_le).unwrap(), text);
+
+        let utf16_be = temp_dir.path().join("utf16be.txt");
+        let mut bytes = vec![0xFE, 0xFF];
+        bytes.extend(text.encode_utf16().flat_map(u16::to_be_bytes));
+        fs::write(&utf16_be, &bytes).unwrap();
+        assert_eq!(read_file_contents(&utf16_be).unwrap(), text);
+
+        // An odd byte count can't be UTF-16
+        let truncated = temp_dir.path().join("truncated.txt");
+        fs::write(&truncated, b"\xFF\xFEh\0i").unwrap();
+        let err = read_file_contents(&truncated).unwrap_err();
+        assert!(err.downcast_ref::<BinaryFileError>().is_some());
+
+        let latin1 = temp_dir.path().join("latin1.txt");
+        fs::write(&latin1, b"caf\xE9\n").unwrap();
+        assert_eq!(read_file_contents(&latin1).unwrap(), "caf\u{FFFD}\n");
+    }
+
+    #[test]
+    fn test_progress_styles() {
+        assert!(
+            spinner_frames(ProgressStyle::Ascii)
+                .iter()
+                .all(char::is_ascii)
+        );
+
+        let mut ascii = ScanProgress::with_style(ProgressStyle::Ascii, Duration::ZERO);
+        ascii.scanned_count = 3;
+        let line = ascii.status_line(Instant::now()).unwrap();
+        assert!(line.is_ascii());
+        assert!(line.contains("3 scanned"));
+
+        let mut none = ScanProgress::with_style(ProgressStyle::None, Duration::ZERO);
+        assert_eq!(none.status_line(Instant::now()), None);
+
+        let mut slow = ScanProgress::with_style(ProgressStyle::Braille, Duration::from_secs(60));
+        assert_eq!(slow.status_line(Instant::now()), None);
+    }
+
     #[test]
     fn test_parse_gitignore() {
         let temp_dir = TempDir::new().unwrap();
@@ -497,7 +1838,7 @@ mod tests {
             writeln!(file, "node_modules/").unwrap();
             writeln!(file, "*.log").unwrap();
             writeln!(file, "build").unwrap();
-            writeln!(file, "").unwrap();
+            writeln!(file).unwrap();
             writeln!(file, "/dist").unwrap();
             writeln!(file, "temp*").unwrap();
             writeln!(file, "!important.log").unwrap();
@@ -516,6 +1857,309 @@ mod tests {
         assert!(patterns.contains("**/coverage"));
     }
 
+    #[cfg(unix)]
+    #[test]
+    fn test_symlinked_gitignore_is_parsed() {
+        let temp_dir = TempDir::new().unwrap();
+        let shared = temp_dir.path().join("shared");
+        let project = temp_dir.path().join("project");
+        fs::create_dir_all(&shared).unwrap();
+        fs::create_dir_all(&project).unwrap();
+        fs::write(shared.join("ignore"), "target/\n*.log\n").unwrap();
+        std::os::unix::fs::symlink(shared.join("ignore"), project.join(".gitignore")).unwrap();
+
+        let patterns = parse_gitignore(&project.to_string_lossy()).unwrap();
+
+        assert_eq!(
+            patterns,
+            HashSet::from(["target/".to_string(), "*.log".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_nested_gitignores_are_parsed_once() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path().to_string_lossy().to_string();
+        for dir in ["app/src", "app/tests", "lib"] {
+            fs::create_dir_all(temp_dir.path().join(dir)).unwrap();
+        }
+        for file in [
+            "main.rs",
+            "app/src/a.rs",
+            "app/src/b.rs",
+            "app/src/gen.rs",
+            "app/tests/t.rs",
+            "lib/c.rs",
+            "lib/d.log",
+        ] {
+            File::create(temp_dir.path().join(file)).unwrap();
+        }
+        fs::write(temp_dir.path().join(".gitignore"), "*.log\n").unwrap();
+        fs::write(temp_dir.path().join("app/.gitignore"), "tests\n").unwrap();
+        fs::write(temp_dir.path().join("app/src/.gitignore"), "gen.rs\n").unwrap();
+
+        let reads = std::cell::RefCell::new(HashMap::<PathBuf, usize>::new());
+        let gitignore = IgnoreFileCache::new(|dir: &Path| {
+            *reads.borrow_mut().entry(dir.to_path_buf()).or_default() += 1;
+            parse_gitignore(&dir.to_string_lossy())
+        });
+        let mut files = walk_code_files(
+            &root,
+            &[],
+            &[],
+            Some(gitignore),
+            &GlobSet::empty(),
+            WalkOptions::default(),
+        )
+        .unwrap();
+        files.sort();
+
+        let relative: Vec<_> = files
+            .iter()
+            .map(|file| file.strip_prefix(temp_dir.path()).unwrap().to_path_buf())
+            .collect();
+        assert_eq!(
+            relative,
+            vec![
+                PathBuf::from(".gitignore"),
+                PathBuf::from("app/.gitignore"),
+                PathBuf::from("app/src/.gitignore"),
+                PathBuf::from("app/src/a.rs"),
+                PathBuf::from("app/src/b.rs"),
+                PathBuf::from("lib/c.rs"),
+                PathBuf::from("main.rs"),
+            ]
+        );
+        let reads = reads.into_inner();
+        assert_eq!(reads.len(), 4);
+        assert!(reads.values().all(|&count| count == 1));
+    }
+
+    #[test]
+    fn test_unreadable_nested_ignore_file_is_an_error() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path().to_string_lossy().to_string();
+        fs::create_dir_all(temp_dir.path().join("app")).unwrap();
+        File::create(temp_dir.path().join("app/main.rs")).unwrap();
+        fs::write(temp_dir.path().join("app/.rpignore"), b"\xff\xfe*.rs\n").unwrap();
+
+        let error = list_code_files(
+            &root,
+            &["rs"],
+            &[],
+            &GlobSet::empty(),
+            WalkOptions::default(),
+        )
+        .unwrap_err();
+        assert!(format!("{:#}", error).contains(&format!(
+            "Failed to read ignore files in {}",
+            temp_dir.path().join("app").display()
+        )));
+    }
+
+    #[test]
+    fn test_global_excludes_are_applied() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path().join("repo");
+        fs::create_dir_all(root.join("src")).unwrap();
+        for file in ["src/main.rs", "src/main.rs.bak", "keep.bak", ".DS_Store"] {
+            File::create(root.join(file)).unwrap();
+        }
+        fs::write(root.join(".gitignore"), "!keep.bak\n").unwrap();
+        let global = temp_dir.path().join("global-ignore");
+        fs::write(&global, "# editor and OS junk\n*.bak\n.DS_Store\n").unwrap();
+
+        let walk = |global_excludes: Option<PathBuf>| {
+            let mut files = walk_code_files(
+                &root.to_string_lossy(),
+                &[],
+                &[],
+                Some(IgnoreFileCache::from_disk(&root, global_excludes, true)),
+                &GlobSet::empty(),
+                WalkOptions::default(),
+            )
+            .unwrap();
+            files.sort();
+            files
+                .iter()
+                .map(|file| file.strip_prefix(&root).unwrap().to_path_buf())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            walk(Some(global)),
+            vec![
+                PathBuf::from(".gitignore"),
+                PathBuf::from("keep.bak"),
+                PathBuf::from("src/main.rs"),
+            ]
+        );
+        assert_eq!(walk(None).len(), 5);
+    }
+
+    #[test]
+    fn test_rpignore_applies_with_or_without_gitignore() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        fs::create_dir_all(root.join("data")).unwrap();
+        for file in [
+            "main.rs",
+            "debug.log",
+            "data/fixtures.json",
+            "data/schema.json",
+        ] {
+            File::create(root.join(file)).unwrap();
+        }
+        fs::write(root.join(".gitignore"), "*.log\n").unwrap();
+        fs::write(root.join("data/.rpignore"), "fixtures.json\n").unwrap();
+        let root_str = root.to_string_lossy().to_string();
+        let relative = |mut files: Vec<PathBuf>| {
+            files.sort();
+            files
+                .iter()
+                .map(|file| file.strip_prefix(root).unwrap().to_path_buf())
+                .collect::<Vec<_>>()
+        };
+
+        // No global excludes, so the machine's own git config can't change the result
+        let with_gitignore = walk_code_files(
+            &root_str,
+            &["rs", "json", "log"],
+            &[],
+            Some(IgnoreFileCache::from_disk(root, None, true)),
+            &GlobSet::empty(),
+            WalkOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            relative(with_gitignore),
+            vec![PathBuf::from("data/schema.json"), PathBuf::from("main.rs")]
+        );
+
+        let without_gitignore = list_code_files(
+            &root_str,
+            &["rs", "json", "log"],
+            &[],
+            &GlobSet::empty(),
+            WalkOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            relative(without_gitignore),
+            vec![
+                PathBuf::from("data/schema.json"),
+                PathBuf::from("debug.log"),
+                PathBuf::from("main.rs"),
+            ]
+        );
+
+        // Listings that skip the walk, like --git-ref and --stdin, honor it too, including
+        // patterns that name a parent directory
+        fs::create_dir_all(root.join("vendor/lib")).unwrap();
+        File::create(root.join("vendor/lib/dep.rs")).unwrap();
+        fs::write(root.join(".rpignore"), "vendor/\n").unwrap();
+        let listed = ["main.rs", "data/fixtures.json", "vendor/lib/dep.rs"]
+            .iter()
+            .map(|file| root.join(file))
+            .collect();
+        assert_eq!(
+            relative(filter_listed_files(listed, &root_str, &[], &[], &[]).unwrap()),
+            vec![PathBuf::from("main.rs")]
+        );
+    }
+
+    #[test]
+    fn test_order_by_patterns() {
+        let temp_dir = TempDir::new().unwrap();
+        let order_file = temp_dir.path().join("order.txt");
+        fs::write(
+            &order_file,
+            "# entry points first\nsrc/main.rs\n\nsrc/core/*\n*.toml\n",
+        )
+        .unwrap();
+        let files = [
+            "/repo/tests/cli.rs",
+            "/repo/Cargo.toml",
+            "/repo/src/core/b.rs",
+            "/repo/README.md",
+            "/lib/src/main.rs",
+            "/repo/src/core/a.rs",
+            "/repo/src/main.rs",
+        ]
+        .iter()
+        .map(|path| FileContext::new(PathBuf::from(path), String::new()))
+        .collect();
+
+        let patterns = read_order_file(&order_file).unwrap();
+        // A second root's files match the patterns relative to that root
+        let root_of = |path: &Path| match path.starts_with("/lib") {
+            true => "/lib",
+            false => "/repo",
+        };
+        let ordered: Vec<_> = order_by_patterns(files, root_of, &patterns)
+            .into_iter()
+            .map(|file| file.path)
+            .collect();
+
+        assert_eq!(patterns, vec!["src/main.rs", "src/core/*", "*.toml"]);
+        assert_eq!(
+            ordered,
+            vec![
+                PathBuf::from("/lib/src/main.rs"),
+                PathBuf::from("/repo/src/main.rs"),
+                PathBuf::from("/repo/src/core/a.rs"),
+                PathBuf::from("/repo/src/core/b.rs"),
+                PathBuf::from("/repo/Cargo.toml"),
+                PathBuf::from("/repo/README.md"),
+                PathBuf::from("/repo/tests/cli.rs"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_exclude_dir_glob_keeps_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path().to_string_lossy().to_string();
+        for dir in ["generated-v1", "src/generated-v2", "src/generated"] {
+            fs::create_dir_all(temp_dir.path().join(dir)).unwrap();
+        }
+        for file in [
+            "generated-v1/api.md",
+            "src/generated-v2/api.md",
+            "src/generated/api.md",
+            "generated-notes.md",
+        ] {
+            File::create(temp_dir.path().join(file)).unwrap();
+        }
+        let dir_globs = build_dir_globs(&["**/generated-*".to_string()]).unwrap();
+
+        let mut files =
+            list_code_files(&root, &["md"], &[], &dir_globs, WalkOptions::default()).unwrap();
+        files.sort();
+        let map = generate_file_map(
+            &root,
+            &[],
+            "",
+            false,
+            FileMapPruning::default(),
+            &dir_globs,
+            WalkOptions::default(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            files,
+            vec![
+                temp_dir.path().join("generated-notes.md"),
+                temp_dir.path().join("src/generated/api.md"),
+            ]
+        );
+        assert!(!map.contains("generated-v1"));
+        assert!(!map.contains("generated-v2"));
+        assert!(map.contains("generated-notes.md"));
+        assert!(build_dir_globs(&["a[".to_string()]).is_err());
+    }
+
     #[test]
     fn test_matches_gitignore_pattern() {
         assert!(matches_gitignore_pattern("test.log", "*.log", false));
@@ -561,41 +2205,299 @@ mod tests {
         patterns.insert("!important.log".to_string());
 
         assert!(should_ignore_by_gitignore(
-            &Path::new("/test/logs/server.log"),
+            Path::new("/test/logs/server.log"),
             root,
             &patterns
         ));
         assert!(should_ignore_by_gitignore(
-            &Path::new("/test/build/index.js"),
+            Path::new("/test/build/index.js"),
             root,
             &patterns
         ));
         assert!(should_ignore_by_gitignore(
-            &Path::new("/test/dist/main.js"),
+            Path::new("/test/dist/main.js"),
             root,
             &patterns
         ));
         assert!(should_ignore_by_gitignore(
-            &Path::new("/test/temporary.txt"),
+            Path::new("/test/temporary.txt"),
             root,
             &patterns
         ));
 
         assert!(!should_ignore_by_gitignore(
-            &Path::new("/test/logs/important.log"),
+            Path::new("/test/logs/important.log"),
             root,
             &patterns
         ));
 
         assert!(!should_ignore_by_gitignore(
-            &Path::new("/test/src/index.js"),
+            Path::new("/test/src/index.js"),
             root,
             &patterns
         ));
         assert!(!should_ignore_by_gitignore(
-            &Path::new("/test/package.json"),
+            Path::new("/test/package.json"),
             root,
             &patterns
         ));
     }
+
+    // Walks `root` the way the scans do, returning the paths kept and the errors hit
+    fn walk_collecting_errors(root: &str, walk: WalkOptions) -> (Vec<PathBuf>, WalkErrors) {
+        let mut walk_errors = WalkErrors::default();
+        let paths = walk_dir(root, walk)
+            .into_iter()
+            .filter_map(|entry| walk_errors.keep_ok(entry))
+            .map(walkdir::DirEntry::into_path)
+            .collect();
+        (paths, walk_errors)
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unreadable_directory_is_reported() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path().to_string_lossy().to_string();
+        let locked = temp_dir.path().join("locked");
+        fs::create_dir(&locked).unwrap();
+        File::create(locked.join("hidden.rs")).unwrap();
+        File::create(temp_dir.path().join("visible.rs")).unwrap();
+
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o000)).unwrap();
+        if fs::read_dir(&locked).is_ok() {
+            fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+            eprintln!(
+                "skipping test_unreadable_directory_is_reported: running as root, so directory permissions aren't enforced"
+            );
+            return;
+        }
+
+        let (paths, walk_errors) = walk_collecting_errors(&root, WalkOptions::default());
+        let files = list_code_files(
+            &root,
+            &["rs"],
+            &[],
+            &GlobSet::empty(),
+            WalkOptions::default(),
+        )
+        .unwrap();
+
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+
+        assert!(paths.contains(&temp_dir.path().join("visible.rs")));
+        assert_eq!(walk_errors.paths(), vec![locked.display().to_string()]);
+        assert_eq!(files, vec![temp_dir.path().join("visible.rs")]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_cycle_is_reported() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path().to_string_lossy().to_string();
+        fs::create_dir(temp_dir.path().join("src")).unwrap();
+        File::create(temp_dir.path().join("src/lib.rs")).unwrap();
+        let link = temp_dir.path().join("src/again");
+        std::os::unix::fs::symlink(temp_dir.path().join("src"), &link).unwrap();
+
+        let following = WalkOptions {
+            follow_symlinks: true,
+            ..WalkOptions::default()
+        };
+
+        // The walk ends, keeps the files, and names the link as a cycle rather than an error
+        let (paths, walk_errors) = walk_collecting_errors(&root, following);
+        assert!(paths.contains(&temp_dir.path().join("src/lib.rs")));
+        assert!(!paths.contains(&link));
+        assert_eq!(
+            walk_errors.cycles,
+            vec![(link, temp_dir.path().join("src"))]
+        );
+        assert!(walk_errors.errors.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlinked_directory_needs_follow_symlinks() {
+        let temp_dir = TempDir::new().unwrap();
+        let repo = temp_dir.path().join("repo");
+        let shared = temp_dir.path().join("shared");
+        fs::create_dir_all(&repo).unwrap();
+        fs::create_dir_all(&shared).unwrap();
+        fs::write(repo.join("main.rs"), "fn main() {}").unwrap();
+        fs::write(shared.join("util.rs"), "pub fn util() {}").unwrap();
+        std::os::unix::fs::symlink(&shared, repo.join("shared")).unwrap();
+        let root = repo.to_string_lossy().to_string();
+
+        for follow in [false, true] {
+            let mut plain = list_code_files(
+                &root,
+                &["rs"],
+                &[],
+                &GlobSet::empty(),
+                WalkOptions {
+                    follow_symlinks: follow,
+                    max_depth: None,
+                    ..WalkOptions::default()
+                },
+            )
+            .unwrap();
+            let mut ignoring = walk_code_files(
+                &root,
+                &["rs"],
+                &[],
+                Some(IgnoreFileCache::from_disk(&repo, None, true)),
+                &GlobSet::empty(),
+                WalkOptions {
+                    follow_symlinks: follow,
+                    max_depth: None,
+                    ..WalkOptions::default()
+                },
+            )
+            .unwrap();
+            plain.sort();
+            ignoring.sort();
+
+            let mut expected = vec![repo.join("main.rs")];
+            if follow {
+                expected.push(repo.join("shared/util.rs"));
+            }
+            assert_eq!(plain, expected);
+            assert_eq!(ignoring, expected);
+        }
+    }
+
+    #[test]
+    fn test_rebase_on_root() {
+        let cwd = std::env::current_dir().unwrap();
+        assert_eq!(
+            rebase_on_root(Path::new("src/main.rs"), Path::new(".")),
+            Some(PathBuf::from("./src/main.rs"))
+        );
+        assert_eq!(
+            rebase_on_root(&cwd.join("src/main.rs"), Path::new("src")),
+            Some(PathBuf::from("src/main.rs"))
+        );
+        assert_eq!(
+            rebase_on_root(Path::new("/repo/src/lib.rs"), Path::new("/repo")),
+            Some(PathBuf::from("/repo/src/lib.rs"))
+        );
+        assert_eq!(
+            rebase_on_root(Path::new("/elsewhere/lib.rs"), Path::new("/repo")),
+            None
+        );
+    }
+
+    #[test]
+    fn test_exclude_ignores_root_name() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path().join("node_modules_project");
+        for file in ["src/main.rs", "node_modules/dep/index.rs"] {
+            let path = root.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "fn f() {}").unwrap();
+        }
+        let root_str = root.to_string_lossy().to_string();
+
+        let files = list_code_files(
+            &root_str,
+            &["rs"],
+            &["node_modules"],
+            &GlobSet::empty(),
+            WalkOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(files, vec![root.join("src/main.rs")]);
+
+        let files = list_code_files_with_gitignore(
+            &root_str,
+            &["rs"],
+            &["node_modules"],
+            ".git",
+            false,
+            &GlobSet::empty(),
+            WalkOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(files, vec![root.join("src/main.rs")]);
+
+        let listed = vec![
+            root.join("src/main.rs"),
+            root.join("node_modules/dep/index.rs"),
+        ];
+        assert_eq!(
+            filter_listed_files(listed, &root_str, &["rs"], &["node_modules"], &[]).unwrap(),
+            vec![root.join("src/main.rs")]
+        );
+    }
+
+    #[test]
+    fn test_max_depth_limits_recursion() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        for file in ["top.rs", "a/one.rs", "a/b/two.rs", "a/b/c/three.rs"] {
+            let path = root.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "fn f() {}").unwrap();
+        }
+        let root_str = root.to_string_lossy().to_string();
+        let list = |max_depth| {
+            let mut files = list_code_files(
+                &root_str,
+                &["rs"],
+                &[],
+                &GlobSet::empty(),
+                WalkOptions {
+                    follow_symlinks: false,
+                    max_depth,
+                    ..WalkOptions::default()
+                },
+            )
+            .unwrap();
+            files.sort();
+            files
+        };
+
+        assert_eq!(list(Some(0)), vec![root.join("top.rs")]);
+        assert_eq!(
+            list(Some(1)),
+            vec![root.join("a/one.rs"), root.join("top.rs")]
+        );
+        assert_eq!(list(None).len(), 4);
+
+        let ignoring = list_code_files_with_gitignore(
+            &root_str,
+            &["rs"],
+            &[],
+            ".git",
+            false,
+            &GlobSet::empty(),
+            WalkOptions {
+                follow_symlinks: false,
+                max_depth: Some(0),
+                ..WalkOptions::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(ignoring, vec![root.join("top.rs")]);
+
+        let map = generate_file_map(
+            &root_str,
+            &[],
+            "",
+            false,
+            FileMapPruning::default(),
+            &GlobSet::empty(),
+            WalkOptions {
+                follow_symlinks: false,
+                max_depth: Some(1),
+                ..WalkOptions::default()
+            },
+        )
+        .unwrap();
+        assert!(map.contains("one.rs"));
+        assert!(!map.contains("two.rs"));
+    }
 }
@@ -0,0 +1,333 @@
+use crate::infra::file_system::{decode_file_bytes, read_file_contents};
+use log::debug;
+use std::env;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+fn run_git(root: &str, args: &[&str]) -> anyhow::Result<Vec<u8>> {
+    debug!("Running git {} in {}", args.join(" "), root);
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(root)
+        .args(args)
+        .output()
+        .map_err(|e| anyhow::anyhow!("Failed to run git: {}", e))?;
+
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(output.stdout)
+}
+
+/// The nearest directory at or above `root` with a `.git` entry, which is a file rather
+/// than a directory in worktrees and submodules.
+pub fn find_repo_root(root: &str) -> Option<PathBuf> {
+    let root = Path::new(root).canonicalize().ok()?;
+    root.ancestors()
+        .find(|dir| dir.join(".git").exists())
+        .map(Path::to_path_buf)
+}
+
+/// The sparse-checkout file of the repository holding `root`, wherever git keeps it (a
+/// worktree's lives in the main git directory), and the top of the work tree its patterns
+/// are relative to.
+pub fn sparse_checkout_file(root: &str) -> anyhow::Result<(PathBuf, PathBuf)> {
+    let line = |args: &[&str]| -> anyhow::Result<String> {
+        Ok(String::from_utf8_lossy(&run_git(root, args)?)
+            .trim()
+            .to_string())
+    };
+    let toplevel = line(&["rev-parse", "--show-toplevel"])?;
+    // Relative answers are relative to the directory git ran in
+    let file = Path::new(root).join(line(&["rev-parse", "--git-path", "info/sparse-checkout"])?);
+    Ok((file, PathBuf::from(toplevel)))
+}
+
+/// Files tracked under `root` at `git_ref`, joined onto `root` like the paths of a
+/// working-tree scan.
+pub fn list_files_at_ref(root: &str, git_ref: &str) -> anyhow::Result<Vec<PathBuf>> {
+    let listing = run_git(root, &["ls-tree", "-r", "-z", "--name-only", git_ref])?;
+    let files = join_listing(root, &listing);
+
+    debug!("Found {} files at {}", files.len(), git_ref);
+    Ok(files)
+}
+
+// NUL-separated names relative to `root`, as printed by git's `-z` options
+fn join_listing(root: &str, listing: &[u8]) -> Vec<PathBuf> {
+    listing
+        .split(|&b| b == 0)
+        .filter(|name| !name.is_empty())
+        .map(|name| Path::new(root).join(String::from_utf8_lossy(name).as_ref()))
+        .collect()
+}
+
+// False in a repository whose branch has no commits yet, where `HEAD` names nothing
+fn has_head(root: &str) -> bool {
+    run_git(root, &["rev-parse", "--verify", "--quiet", "HEAD"]).is_ok()
+}
+
+/// Files under `root` that differ from HEAD, staged or not, and untracked files that
+/// aren't ignored. Before the first commit every staged file counts as changed. Deleted
+/// files are left out since there is nothing to read.
+pub fn list_changed_files(root: &str) -> anyhow::Result<Vec<PathBuf>> {
+    let diff = if has_head(root) {
+        run_git(root, &["diff", "--name-only", "--relative", "-z", "HEAD"])?
+    } else {
+        run_git(root, &["ls-files", "--cached", "-z"])?
+    };
+    let untracked = run_git(root, &["ls-files", "--others", "--exclude-standard", "-z"])?;
+    let mut files = join_listing(root, &diff);
+    files.extend(join_listing(root, &untracked));
+    files.retain(|path| path.exists());
+
+    debug!("Found {} changed files", files.len());
+    Ok(files)
+}
+
+/// Content of `path` as of `git_ref`, read from the object store without a checkout.
+pub fn read_file_at_ref(root: &str, git_ref: &str, path: &Path) -> anyhow::Result<String> {
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    // `./` resolves the path against `root` rather than the top of the repository
+    let object = format!("{}:./{}", git_ref, relative.to_string_lossy());
+    decode_file_bytes(path, run_git(root, &["show", &object])?)
+}
+
+/// Changes to `path` since HEAD as a unified diff with `context` lines around each hunk.
+/// Files git doesn't know about yet, or any file before the first commit, are read whole.
+pub fn read_changes_since_head(root: &str, path: &Path, context: u32) -> anyhow::Result<String> {
+    if has_head(root) {
+        let relative = path.strip_prefix(root).unwrap_or(path);
+        let diff = run_git(
+            root,
+            &[
+                "diff",
+                "--relative",
+                &format!("-U{}", context),
+                "HEAD",
+                "--",
+                &relative.to_string_lossy(),
+            ],
+        )?;
+        if !diff.is_empty() {
+            return decode_file_bytes(path, diff);
+        }
+    }
+    read_file_contents(path)
+}
+
+/// Git's global excludes file: `core.excludesFile` when set, else `$XDG_CONFIG_HOME/git/ignore`,
+/// else `~/.config/git/ignore`, the same lookup git does. The file may not exist.
+pub fn global_excludes_file(root: &str) -> Option<PathBuf> {
+    // `git config` honors GIT_CONFIG_GLOBAL and friends; outside a repository it still
+    // reads the global and system files
+    let configured = run_git(root, &["config", "--path", "--get", "core.excludesFile"])
+        .ok()
+        .map(|value| String::from_utf8_lossy(&value).trim().to_string())
+        .filter(|path| !path.is_empty());
+    resolve_excludes_file(
+        configured,
+        env::var_os("XDG_CONFIG_HOME"),
+        env::var_os("HOME"),
+    )
+}
+
+fn resolve_excludes_file(
+    configured: Option<String>,
+    xdg_config_home: Option<OsString>,
+    home: Option<OsString>,
+) -> Option<PathBuf> {
+    if let Some(path) = configured {
+        return Some(PathBuf::from(path));
+    }
+    match xdg_config_home.filter(|dir| !dir.is_empty()) {
+        Some(dir) => Some(Path::new(&dir).join("git").join("ignore")),
+        None => home.map(|home| Path::new(&home).join(".config").join("git").join("ignore")),
+    }
+}
+
+/// Full message of the commit `git_ref` names, without its trailing newlines.
+pub fn commit_message(root: &str, git_ref: &str) -> anyhow::Result<String> {
+    if find_repo_root(root).is_none() {
+        return Err(anyhow::anyhow!(
+            "Cannot read the message of {}: {} is not inside a git repository",
+            git_ref,
+            root
+        ));
+    }
+    let message = run_git(root, &["log", "-1", "--format=%B", git_ref, "--"])?;
+    Ok(String::from_utf8_lossy(&message).trim_end().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::context_generator::build_context_output;
+    use crate::domain::models::{FileContext, OutputOptions};
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn commit_all(root: &str, message: &str) {
+        for args in [
+            vec!["add", "-A"],
+            vec![
+                "-c",
+                "user.name=Test",
+                "-c",
+                "user.email=test@example.com",
+                "commit",
+                "-q",
+                "-m",
+                message,
+            ],
+        ] {
+            run_git(root, &args).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_context_from_earlier_commit() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path().to_str().unwrap();
+        run_git(root, &["init", "-q"]).unwrap();
+
+        fs::create_dir(temp_dir.path().join("src")).unwrap();
+        fs::write(temp_dir.path().join("src/lib.rs"), "fn old() {}\n").unwrap();
+        commit_all(root, "first");
+        fs::write(temp_dir.path().join("src/lib.rs"), "fn new() {}\n").unwrap();
+        fs::write(temp_dir.path().join("added.rs"), "fn added() {}\n").unwrap();
+        commit_all(root, "second");
+
+        let files = list_files_at_ref(root, "HEAD~1").unwrap();
+        assert_eq!(files, vec![temp_dir.path().join("src/lib.rs")]);
+
+        let contexts: Vec<FileContext> = files
+            .into_iter()
+            .map(|path| {
+                let content = read_file_at_ref(root, "HEAD~1", &path).unwrap();
+                FileContext::new(path, content)
+            })
+            .collect();
+        let output =
+            build_context_output(contexts, String::new(), None, &OutputOptions::default()).unwrap();
+
+        assert!(output.file_contents.contains("fn old() {}"));
+        assert!(!output.file_contents.contains("fn new() {}"));
+    }
+
+    #[test]
+    fn test_resolve_excludes_file() {
+        assert_eq!(
+            resolve_excludes_file(
+                Some("/etc/gitignore".to_string()),
+                Some("/xdg".into()),
+                Some("/home/me".into())
+            ),
+            Some(PathBuf::from("/etc/gitignore"))
+        );
+        assert_eq!(
+            resolve_excludes_file(None, Some("/xdg".into()), Some("/home/me".into())),
+            Some(PathBuf::from("/xdg/git/ignore"))
+        );
+        assert_eq!(
+            resolve_excludes_file(None, Some("".into()), Some("/home/me".into())),
+            Some(PathBuf::from("/home/me/.config/git/ignore"))
+        );
+        assert_eq!(resolve_excludes_file(None, None, None), None);
+    }
+
+    #[test]
+    fn test_commit_message_as_prompt() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path().to_str().unwrap();
+        run_git(root, &["init", "-q"]).unwrap();
+        fs::write(temp_dir.path().join("lib.rs"), "fn a() {}\n").unwrap();
+        commit_all(root, "Add the parser\n\nHandles nested blocks.");
+        fs::write(temp_dir.path().join("lib.rs"), "fn b() {}\n").unwrap();
+        commit_all(root, "Rename a to b");
+
+        let message = commit_message(root, "HEAD~1").unwrap();
+        assert_eq!(message, "Add the parser\n\nHandles nested blocks.");
+
+        let output = build_context_output(
+            vec![FileContext::new(
+                temp_dir.path().join("lib.rs"),
+                "fn b() {}\n".to_string(),
+            )],
+            String::new(),
+            Some(commit_message(root, "HEAD").unwrap()),
+            &OutputOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(output.user_instructions, "Rename a to b");
+
+        let outside = TempDir::new().unwrap();
+        let error = commit_message(outside.path().to_str().unwrap(), "HEAD").unwrap_err();
+        assert!(error.to_string().contains("is not inside a git repository"));
+    }
+
+    #[test]
+    fn test_unknown_ref_fails() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path().to_str().unwrap();
+        run_git(root, &["init", "-q"]).unwrap();
+
+        assert!(list_files_at_ref(root, "no-such-ref").is_err());
+    }
+
+    #[test]
+    fn test_changed_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path().to_str().unwrap();
+        run_git(root, &["init", "-q"]).unwrap();
+        for file in ["kept.rs", "edited.rs", "removed.rs"] {
+            fs::write(temp_dir.path().join(file), "fn a() {}\n").unwrap();
+        }
+        commit_all(root, "first");
+
+        fs::write(temp_dir.path().join("edited.rs"), "fn b() {}\n").unwrap();
+        fs::remove_file(temp_dir.path().join("removed.rs")).unwrap();
+        fs::write(temp_dir.path().join("new.rs"), "fn c() {}\n").unwrap();
+
+        assert_eq!(
+            list_changed_files(root).unwrap(),
+            vec![
+                temp_dir.path().join("edited.rs"),
+                temp_dir.path().join("new.rs")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_changes_before_and_after_the_first_commit() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path().to_str().unwrap();
+        let lib = temp_dir.path().join("lib.rs");
+        run_git(root, &["init", "-q"]).unwrap();
+        fs::write(&lib, "fn a() {}\nfn b() {}\n").unwrap();
+        run_git(root, &["add", "lib.rs"]).unwrap();
+        fs::write(temp_dir.path().join("notes.rs"), "fn c() {}\n").unwrap();
+
+        // No HEAD yet: everything staged or untracked is new, and read whole
+        assert_eq!(
+            list_changed_files(root).unwrap(),
+            vec![lib.clone(), temp_dir.path().join("notes.rs")]
+        );
+        assert_eq!(
+            read_changes_since_head(root, &lib, 3).unwrap(),
+            "fn a() {}\nfn b() {}\n"
+        );
+
+        commit_all(root, "first");
+        fs::write(&lib, "fn a() {}\nfn renamed() {}\n").unwrap();
+        let diff = read_changes_since_head(root, &lib, 0).unwrap();
+        assert!(diff.starts_with("diff --git a/lib.rs b/lib.rs\n"));
+        assert!(diff.contains("@@ -2 +2 @@"));
+        assert!(diff.ends_with("\n-fn b() {}\n+fn renamed() {}\n"));
+    }
+}
@@ -1,6 +1,9 @@
-use crossterm::{style::{Color, SetForegroundColor, ResetColor}, ExecutableCommand};
+use crossterm::{
+    ExecutableCommand,
+    style::{Color, ResetColor, SetForegroundColor},
+};
 use env_logger::Builder;
-use log::{debug, info, Level};
+use log::{Level, debug, info};
 use std::io::Write;
 
 pub fn setup_logger(verbosity: u8) -> Result<(), log::SetLoggerError> {
@@ -10,9 +13,9 @@ pub fn setup_logger(verbosity: u8) -> Result<(), log::SetLoggerError> {
         2 => "info",
         _ => "debug",
     };
-    
+
     let env = env_logger::Env::default().filter_or("RICH_PROMPT_LOG_LEVEL", level);
-    
+
     Builder::from_env(env)
         .format(|buf, record| {
             let level_color = match record.level() {
@@ -37,16 +40,25 @@ pub fn setup_logger(verbosity: u8) -> Result<(), log::SetLoggerError> {
     Ok(())
 }
 
-pub fn print_welcome_message() {
-    let mut stdout = std::io::stdout();
-    
-    writeln!(stdout).unwrap();
-    stdout.execute(SetForegroundColor(Color::Cyan)).unwrap();
-    writeln!(stdout, "🚀 Rich Prompt v0.3.0").unwrap();
-    stdout.execute(ResetColor).unwrap();
-    writeln!(stdout, "🧠 Supercharge your LLM interactions with structured context").unwrap();
-    writeln!(stdout).unwrap();
-    
+pub fn print_welcome_message(show_banner: bool) {
+    write_welcome_message(&mut std::io::stdout(), show_banner);
+}
+
+/// Writes the banner to `out` unless `--no-banner` turned it off; the startup logs are kept.
+fn write_welcome_message(out: &mut impl Write, show_banner: bool) {
+    if show_banner {
+        writeln!(out).unwrap();
+        out.execute(SetForegroundColor(Color::Cyan)).unwrap();
+        writeln!(out, "🚀 Rich Prompt v0.3.0").unwrap();
+        out.execute(ResetColor).unwrap();
+        writeln!(
+            out,
+            "🧠 Supercharge your LLM interactions with structured context"
+        )
+        .unwrap();
+        writeln!(out).unwrap();
+    }
+
     debug!("Debug logging enabled");
     info!("Starting Rich Prompt...");
 }
@@ -64,4 +76,15 @@ mod tests {
             assert!(setup_logger(0).is_ok());
         });
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_no_banner() {
+        let mut banner = Vec::new();
+        write_welcome_message(&mut banner, true);
+        assert!(String::from_utf8(banner).unwrap().contains("Rich Prompt"));
+
+        let mut quiet = Vec::new();
+        write_welcome_message(&mut quiet, false);
+        assert!(quiet.is_empty());
+    }
+}
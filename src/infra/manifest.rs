@@ -0,0 +1,55 @@
+use crate::domain::models::Manifest;
+use log::{debug, info};
+use std::fs;
+use std::path::Path;
+
+pub fn read_manifest(path: &Path) -> anyhow::Result<Option<Manifest>> {
+    if !path.exists() {
+        debug!("No previous manifest at: {}", path.display());
+        return Ok(None);
+    }
+
+    debug!("Reading manifest: {}", path.display());
+    let contents = fs::read_to_string(path)?;
+    let manifest = serde_json::from_str(&contents)
+        .map_err(|e| anyhow::anyhow!("Invalid manifest {}: {}", path.display(), e))?;
+    Ok(Some(manifest))
+}
+
+pub fn write_manifest(path: &Path, manifest: &Manifest) -> anyhow::Result<()> {
+    debug!("Writing manifest to: {}", path.display());
+    fs::write(path, serde_json::to_string_pretty(manifest)?)?;
+    info!(
+        "Manifest with {} files written to: {}",
+        manifest.files.len(),
+        path.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::models::ManifestEntry;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_manifest_round_trip() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("manifest.json");
+        let manifest = Manifest {
+            token_count: 42,
+            files: vec![ManifestEntry {
+                path: "src/main.rs".to_string(),
+                tokens: 42,
+            }],
+        };
+
+        assert!(read_manifest(&path).unwrap().is_none());
+        write_manifest(&path, &manifest).unwrap();
+
+        let loaded = read_manifest(&path).unwrap().unwrap();
+        assert_eq!(loaded.token_count, 42);
+        assert_eq!(loaded.files, manifest.files);
+    }
+}
@@ -1,3 +1,9 @@
+pub mod command;
+pub mod config_file;
 pub mod file_system;
+pub mod git;
 pub mod logger;
+pub mod manifest;
 pub mod output;
+pub mod project;
+pub mod session;
@@ -1,24 +1,34 @@
+use crate::domain::models::ClipboardGuard;
 use clipboard::{ClipboardContext, ClipboardProvider};
 use crossterm::{
     ExecutableCommand,
     style::{Color, ResetColor, SetForegroundColor},
 };
 use log::{debug, info, warn};
-use std::fs;
-use std::io::{self, Write};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, IsTerminal, Write};
 use std::path::Path;
+use std::sync::{Arc, mpsc};
+use std::thread;
+use std::time::Duration;
 
 pub trait OutputWriter {
     fn write(&self, content: &str) -> anyhow::Result<()>;
+
+    /// Appends one piece of a streamed output. The result is complete after `finish`.
+    fn write_chunk(&mut self, chunk: &str) -> anyhow::Result<()>;
+
+    fn finish(&mut self) -> anyhow::Result<()>;
 }
 
 pub struct FileWriter {
     path: String,
+    stream: Option<BufWriter<File>>,
 }
 
 impl FileWriter {
     pub fn new(path: String) -> Self {
-        Self { path }
+        Self { path, stream: None }
     }
 }
 
@@ -29,6 +39,29 @@ impl OutputWriter for FileWriter {
         info!("Output written to file: {}", self.path);
         Ok(())
     }
+
+    fn write_chunk(&mut self, chunk: &str) -> anyhow::Result<()> {
+        let stream = match &mut self.stream {
+            Some(stream) => stream,
+            None => {
+                debug!("Streaming output to file: {}", self.path);
+                self.stream
+                    .insert(BufWriter::new(File::create(&self.path)?))
+            }
+        };
+        stream.write_all(chunk.as_bytes())?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        match self.stream.take() {
+            Some(mut stream) => stream.flush()?,
+            // Nothing was streamed; still leave an empty file behind
+            None => fs::write(Path::new(&self.path), "")?,
+        }
+        info!("Output written to file: {}", self.path);
+        Ok(())
+    }
 }
 
 pub struct ConsoleWriter;
@@ -40,41 +73,218 @@ impl OutputWriter for ConsoleWriter {
         io::stdout().write_all(b"\n")?;
         Ok(())
     }
+
+    fn write_chunk(&mut self, chunk: &str) -> anyhow::Result<()> {
+        io::stdout().write_all(chunk.as_bytes())?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        let mut stdout = io::stdout();
+        stdout.write_all(b"\n")?;
+        stdout.flush()?;
+        Ok(())
+    }
 }
 
-pub struct ClipboardWriter;
+/// Passes content within the guard's limit; above it, `--yes` or a yes from `confirm`.
+fn check_clipboard_size(
+    guard: &ClipboardGuard,
+    bytes: u64,
+    confirm: impl FnOnce(u64) -> anyhow::Result<bool>,
+) -> anyhow::Result<()> {
+    if bytes <= guard.max_bytes || guard.assume_yes {
+        return Ok(());
+    }
 
-impl OutputWriter for ClipboardWriter {
-    fn write(&self, content: &str) -> anyhow::Result<()> {
-        debug!("Writing output to clipboard");
+    warn!(
+        "Clipboard content is {} bytes, above the {}-byte limit",
+        bytes, guard.max_bytes
+    );
+    if confirm(bytes)? {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "Clipboard content is {} bytes, above the {}-byte limit; pass --yes or raise --confirm-clipboard-size",
+            bytes,
+            guard.max_bytes
+        ))
+    }
+}
 
-        let mut ctx: ClipboardContext = match ClipboardProvider::new() {
-            Ok(ctx) => ctx,
-            Err(e) => {
-                warn!("Failed to access clipboard: {}", e);
-                return Err(anyhow::anyhow!("Failed to access clipboard: {}", e));
-            }
-        };
+// Asks on the terminal; without one there is nobody to ask, so the write is refused
+fn confirm_large_clipboard(bytes: u64) -> anyhow::Result<bool> {
+    if !io::stdin().is_terminal() {
+        return Ok(false);
+    }
+
+    let mut stdout = io::stdout();
+    stdout.execute(SetForegroundColor(Color::Yellow))?;
+    write!(
+        stdout,
+        "\n⚠️  Copy {} bytes to the clipboard? [y/N] ",
+        bytes
+    )?;
+    stdout.execute(ResetColor)?;
+    stdout.flush()?;
+
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    let answer = answer.trim().to_lowercase();
+    Ok(answer == "y" || answer == "yes")
+}
+
+const CLIPBOARD_ATTEMPTS: usize = 3;
+const CLIPBOARD_BACKOFF: Duration = Duration::from_millis(100);
+const CLIPBOARD_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub struct ClipboardWriter {
+    attempts: usize,
+    backoff: Duration,
+    timeout: Duration,
+    html: Option<String>,
+    guard: Option<ClipboardGuard>,
+    /// Log the size instead of touching the clipboard, for `--dry-clipboard`.
+    dry_run: bool,
+    // The clipboard takes the whole content at once, so streamed chunks collect here
+    buffer: String,
+}
+
+impl Default for ClipboardWriter {
+    fn default() -> Self {
+        Self {
+            attempts: CLIPBOARD_ATTEMPTS,
+            backoff: CLIPBOARD_BACKOFF,
+            timeout: CLIPBOARD_TIMEOUT,
+            html: None,
+            guard: None,
+            dry_run: false,
+            buffer: String::new(),
+        }
+    }
+}
+
+fn set_system_clipboard(content: String) -> anyhow::Result<()> {
+    let mut ctx: ClipboardContext = ClipboardProvider::new()
+        .map_err(|e| anyhow::anyhow!("Failed to access clipboard: {}", e))?;
+    ctx.set_contents(content)
+        .map_err(|e| anyhow::anyhow!("Failed to copy to clipboard: {}", e))
+}
+
+// Sets an HTML flavor alongside the plain text; falls back to plain text if the backend refuses.
+fn set_system_clipboard_html(html: &str, content: String) -> anyhow::Result<()> {
+    let result = arboard::Clipboard::new()
+        .and_then(|mut clipboard| clipboard.set_html(html, Some(content.as_str())));
+    match result {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            warn!("HTML clipboard unavailable, copying plain text: {}", e);
+            set_system_clipboard(content)
+        }
+    }
+}
+
+impl ClipboardWriter {
+    pub fn new(html: Option<String>, guard: Option<ClipboardGuard>, dry_run: bool) -> Self {
+        Self {
+            html,
+            guard,
+            dry_run,
+            ..Self::default()
+        }
+    }
 
-        match ctx.set_contents(content.to_owned()) {
-            Ok(_) => {
-                info!("Output copied to clipboard (size: {} bytes)", content.len());
-                Ok(())
+    /// Runs `set` up to `attempts` times, doubling the backoff between tries. Each try is
+    /// abandoned after `timeout` so a wedged clipboard backend cannot hang the process.
+    fn write_with<F>(&self, content: &str, set: F) -> anyhow::Result<()>
+    where
+        F: Fn(String) -> anyhow::Result<()> + Send + Sync + 'static,
+    {
+        let set = Arc::new(set);
+        let mut backoff = self.backoff;
+        let mut last_error = anyhow::anyhow!("Clipboard was never attempted");
+
+        for attempt in 1..=self.attempts {
+            let (sender, receiver) = mpsc::channel();
+            let set = Arc::clone(&set);
+            let owned = content.to_owned();
+            thread::spawn(move || {
+                let _ = sender.send(set(owned));
+            });
+
+            last_error = match receiver.recv_timeout(self.timeout) {
+                Ok(Ok(())) => {
+                    info!("Output copied to clipboard (size: {} bytes)", content.len());
+                    return Ok(());
+                }
+                Ok(Err(e)) => e,
+                Err(_) => anyhow::anyhow!("Clipboard did not respond within {:?}", self.timeout),
+            };
+
+            warn!(
+                "Clipboard attempt {}/{} failed: {}",
+                attempt, self.attempts, last_error
+            );
+            if attempt < self.attempts {
+                thread::sleep(backoff);
+                backoff *= 2;
             }
-            Err(e) => {
-                warn!("Failed to copy to clipboard: {}", e);
-                Err(anyhow::anyhow!("Failed to copy to clipboard: {}", e))
+        }
+
+        Err(last_error)
+    }
+}
+
+impl OutputWriter for ClipboardWriter {
+    fn write(&self, content: &str) -> anyhow::Result<()> {
+        debug!("Writing output to clipboard");
+        if let Some(guard) = &self.guard {
+            check_clipboard_size(guard, content.len() as u64, confirm_large_clipboard)?;
+        }
+        if self.dry_run {
+            info!(
+                "Dry run: would copy {} bytes to the clipboard{}",
+                content.len(),
+                if self.html.is_some() {
+                    " with HTML"
+                } else {
+                    ""
+                }
+            );
+            return Ok(());
+        }
+        match &self.html {
+            Some(html) => {
+                let html = html.clone();
+                self.write_with(content, move |plain| {
+                    set_system_clipboard_html(&html, plain)
+                })
             }
+            None => self.write_with(content, set_system_clipboard),
         }
     }
+
+    fn write_chunk(&mut self, chunk: &str) -> anyhow::Result<()> {
+        self.buffer.push_str(chunk);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        let content = std::mem::take(&mut self.buffer);
+        self.write(&content)
+    }
 }
 
 pub fn create_writer(
     output_path: &Option<String>,
     clipboard_output: bool,
+    clipboard_html: Option<String>,
+    clipboard_guard: Option<ClipboardGuard>,
+    dry_clipboard: bool,
 ) -> Box<dyn OutputWriter> {
     if clipboard_output {
-        return Box::new(ClipboardWriter) as Box<dyn OutputWriter>;
+        let writer = ClipboardWriter::new(clipboard_html, clipboard_guard, dry_clipboard);
+        return Box::new(writer) as Box<dyn OutputWriter>;
     }
 
     match output_path {
@@ -87,38 +297,65 @@ pub fn write_output(
     formatted_content: &str,
     output_path: Option<String>,
     clipboard_output: bool,
+    clipboard_html: Option<String>,
+    clipboard_guard: Option<ClipboardGuard>,
+    dry_clipboard: bool,
 ) -> anyhow::Result<()> {
     let mut stdout = io::stdout();
 
-    let writer = create_writer(&output_path, clipboard_output);
-    if let Err(e) = writer.write(formatted_content) {
-        return Err(e);
-    }
+    let writer = create_writer(
+        &output_path,
+        clipboard_output,
+        clipboard_html,
+        clipboard_guard,
+        dry_clipboard,
+    );
+    writer.write(formatted_content)?;
 
     if clipboard_output && output_path.is_none() {
         stdout.execute(SetForegroundColor(Color::Green))?;
-        writeln!(stdout, "\n📋 Content copied to clipboard!")?;
+        writeln!(
+            stdout,
+            "\n{}",
+            clipboard_summary(formatted_content, dry_clipboard)
+        )?;
         stdout.execute(ResetColor)?;
 
         writeln!(stdout, "\nPreview of copied content:\n")?;
+        writeln!(stdout, "{}", clipboard_preview(formatted_content))?;
+    }
 
-        let preview_length = 200;
-        let preview = if formatted_content.chars().count() > preview_length {
-            let safe_substring: String = formatted_content.chars().take(preview_length).collect();
-            format!("{}...", safe_substring)
-        } else {
-            formatted_content.to_string()
-        };
+    Ok(())
+}
 
-        writeln!(stdout, "{}", preview)?;
+fn clipboard_summary(content: &str, dry_clipboard: bool) -> String {
+    if dry_clipboard {
+        format!(
+            "📋 Dry run: {} bytes would be copied to the clipboard",
+            content.len()
+        )
+    } else {
+        "📋 Content copied to clipboard!".to_string()
     }
+}
 
-    Ok(())
+const PREVIEW_CHARS: usize = 200;
+
+// Counted in chars so the cut never splits a UTF-8 sequence
+fn clipboard_preview(content: &str) -> String {
+    if content.chars().count() > PREVIEW_CHARS {
+        let safe_substring: String = content.chars().take(PREVIEW_CHARS).collect();
+        format!("{}...", safe_substring)
+    } else {
+        content.to_string()
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use tempfile::NamedTempFile;
 
     #[test]
@@ -134,27 +371,148 @@ mod tests {
         assert_eq!(read_content, content);
     }
 
+    #[test]
+    fn test_file_writer_chunks() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let path = temp_file.path().to_string_lossy().to_string();
+        let mut writer = FileWriter::new(path.clone());
+
+        writer.write_chunk("<file_map>\n").unwrap();
+        writer.write_chunk("</file_map>").unwrap();
+        writer.finish().unwrap();
+
+        let read_content = fs::read_to_string(path).unwrap();
+        assert_eq!(read_content, "<file_map>\n</file_map>");
+    }
+
     #[test]
     fn test_create_writer() {
-        let file_writer = create_writer(&Some("test.txt".to_string()), false);
+        let file_writer = create_writer(&Some("test.txt".to_string()), false, None, None, false);
         assert_eq!(
             std::any::type_name_of_val(&*file_writer),
             "dyn rich_prompt::infra::output::OutputWriter"
         );
 
-        let console_writer = create_writer(&None, false);
+        let console_writer = create_writer(&None, false, None, None, false);
         assert_eq!(
             std::any::type_name_of_val(&*console_writer),
             "dyn rich_prompt::infra::output::OutputWriter"
         );
 
-        let clipboard_writer = create_writer(&None, true);
+        let clipboard_writer = create_writer(&None, true, None, None, false);
         assert_eq!(
             std::any::type_name_of_val(&*clipboard_writer),
             "dyn rich_prompt::infra::output::OutputWriter"
         );
     }
 
+    fn fast_clipboard_writer() -> ClipboardWriter {
+        ClipboardWriter {
+            attempts: 3,
+            backoff: Duration::from_millis(1),
+            timeout: Duration::from_millis(500),
+            ..ClipboardWriter::default()
+        }
+    }
+
+    #[test]
+    fn test_clipboard_retry_recovers() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let copied = Arc::new(Mutex::new(String::new()));
+        let (calls_in, copied_in) = (Arc::clone(&calls), Arc::clone(&copied));
+
+        let result = fast_clipboard_writer().write_with("payload", move |content| {
+            if calls_in.fetch_add(1, Ordering::SeqCst) == 0 {
+                return Err(anyhow::anyhow!("selection owner changed"));
+            }
+            *copied_in.lock().unwrap() = content;
+            Ok(())
+        });
+
+        assert!(result.is_ok());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(*copied.lock().unwrap(), "payload");
+    }
+
+    #[test]
+    fn test_clipboard_gives_up_after_attempts() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let calls_in = Arc::clone(&calls);
+
+        let result = fast_clipboard_writer().write_with("payload", move |_| {
+            calls_in.fetch_add(1, Ordering::SeqCst);
+            Err(anyhow::anyhow!("no display"))
+        });
+
+        assert_eq!(result.unwrap_err().to_string(), "no display");
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_clipboard_times_out() {
+        let writer = ClipboardWriter {
+            attempts: 1,
+            backoff: Duration::from_millis(1),
+            timeout: Duration::from_millis(20),
+            ..ClipboardWriter::default()
+        };
+
+        let result = writer.write_with("payload", |_| {
+            thread::sleep(Duration::from_secs(1));
+            Ok(())
+        });
+
+        assert!(result.unwrap_err().to_string().contains("did not respond"));
+    }
+
+    #[test]
+    fn test_clipboard_guard() {
+        let guard = ClipboardGuard {
+            max_bytes: 10,
+            assume_yes: false,
+        };
+        let never_asked = |_| -> anyhow::Result<bool> { panic!("confirmation not expected") };
+
+        assert!(check_clipboard_size(&guard, 10, never_asked).is_ok());
+        assert!(check_clipboard_size(&guard, 11, |_| Ok(true)).is_ok());
+        let refused = check_clipboard_size(&guard, 11, |_| Ok(false)).unwrap_err();
+        assert!(refused.to_string().contains("--yes"));
+
+        let assume_yes = ClipboardGuard {
+            assume_yes: true,
+            ..guard
+        };
+        assert!(check_clipboard_size(&assume_yes, 1_000_000, never_asked).is_ok());
+    }
+
+    #[test]
+    fn test_dry_clipboard_skips_provider() {
+        let content = "x".repeat(500);
+        // A real provider call would fail here, with no display to copy to
+        let writer = ClipboardWriter {
+            attempts: 1,
+            timeout: Duration::from_millis(1),
+            ..ClipboardWriter::new(
+                Some("<pre>x</pre>".to_string()),
+                Some(ClipboardGuard {
+                    max_bytes: 1_000,
+                    assume_yes: false,
+                }),
+                true,
+            )
+        };
+
+        assert!(writer.write(&content).is_ok());
+        assert_eq!(
+            clipboard_summary(&content, true),
+            "📋 Dry run: 500 bytes would be copied to the clipboard"
+        );
+        assert_eq!(
+            clipboard_preview(&content),
+            format!("{}...", "x".repeat(200))
+        );
+    }
+
     #[test]
     fn test_utf8_safe_preview() {
         let content =
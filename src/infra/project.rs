@@ -0,0 +1,132 @@
+use crate::domain::models::ProjectSummary;
+use log::{debug, warn};
+use std::fs;
+use std::path::Path;
+
+const CARGO_MANIFEST: &str = "Cargo.toml";
+const NPM_MANIFEST: &str = "package.json";
+
+type ManifestParser = fn(&str) -> anyhow::Result<Option<ProjectSummary>>;
+
+fn parse_cargo_manifest(contents: &str) -> anyhow::Result<Option<ProjectSummary>> {
+    let manifest: toml::Table = toml::from_str(contents)?;
+    let Some(package) = manifest.get("package").and_then(|p| p.as_table()) else {
+        // A virtual workspace manifest has no [package] of its own
+        return Ok(None);
+    };
+    let Some(name) = package.get("name").and_then(|n| n.as_str()) else {
+        return Ok(None);
+    };
+
+    let dependencies = manifest
+        .get("dependencies")
+        .and_then(|d| d.as_table())
+        .map(|deps| deps.keys().cloned().collect())
+        .unwrap_or_default();
+
+    Ok(Some(ProjectSummary {
+        manifest: CARGO_MANIFEST.to_string(),
+        name: name.to_string(),
+        version: package
+            .get("version")
+            .and_then(|v| v.as_str())
+            .map(str::to_string),
+        dependencies,
+    }))
+}
+
+fn parse_package_json(contents: &str) -> anyhow::Result<Option<ProjectSummary>> {
+    let package: serde_json::Value = serde_json::from_str(contents)?;
+    let Some(name) = package.get("name").and_then(|n| n.as_str()) else {
+        return Ok(None);
+    };
+
+    let mut dependencies: Vec<String> = package
+        .get("dependencies")
+        .and_then(|d| d.as_object())
+        .map(|deps| deps.keys().cloned().collect())
+        .unwrap_or_default();
+    dependencies.sort();
+
+    Ok(Some(ProjectSummary {
+        manifest: NPM_MANIFEST.to_string(),
+        name: name.to_string(),
+        version: package
+            .get("version")
+            .and_then(|v| v.as_str())
+            .map(str::to_string),
+        dependencies,
+    }))
+}
+
+/// Summary of the first recognized manifest at `root` (Cargo.toml, then package.json).
+/// Unparseable manifests are reported and skipped.
+pub fn read_project_summary(root: &str) -> anyhow::Result<Option<ProjectSummary>> {
+    let parsers: [(&str, ManifestParser); 2] = [
+        (CARGO_MANIFEST, parse_cargo_manifest),
+        (NPM_MANIFEST, parse_package_json),
+    ];
+
+    for (file_name, parse) in parsers {
+        let path = Path::new(root).join(file_name);
+        if !path.is_file() {
+            continue;
+        }
+
+        debug!("Reading project manifest: {}", path.display());
+        match parse(&fs::read_to_string(&path)?) {
+            Ok(Some(summary)) => return Ok(Some(summary)),
+            Ok(None) => debug!("{} has no package name", path.display()),
+            Err(e) => warn!("Could not parse {}: {}", path.display(), e),
+        }
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_cargo_project_summary() {
+        let temp_dir = TempDir::new().unwrap();
+        fs::write(
+            temp_dir.path().join(CARGO_MANIFEST),
+            "[package]\nname = \"demo\"\nversion = \"1.2.3\"\n\n[dependencies]\nserde = \"1\"\nanyhow = \"1\"\n",
+        )
+        .unwrap();
+
+        let summary = read_project_summary(&temp_dir.path().to_string_lossy())
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(summary.name, "demo");
+        assert_eq!(summary.version.as_deref(), Some("1.2.3"));
+        assert_eq!(summary.dependencies, vec!["anyhow", "serde"]);
+    }
+
+    #[test]
+    fn test_package_json_summary() {
+        let summary = parse_package_json(
+            r#"{"name": "web", "version": "0.1.0", "dependencies": {"react": "^18", "axios": "^1"}}"#,
+        )
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(summary.manifest, NPM_MANIFEST);
+        assert_eq!(summary.dependencies, vec!["axios", "react"]);
+    }
+
+    #[test]
+    fn test_no_manifest() {
+        let temp_dir = TempDir::new().unwrap();
+
+        assert!(
+            read_project_summary(&temp_dir.path().to_string_lossy())
+                .unwrap()
+                .is_none()
+        );
+    }
+}
@@ -0,0 +1,94 @@
+use crate::domain::models::SavedSession;
+use log::{debug, warn};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const SESSION_DIR: &str = ".rich-prompt";
+const SESSION_FILE: &str = "session.json";
+const LAST_SELECTION_FILE: &str = "last_selection.json";
+
+/// Directory holding rich-prompt's own state files under the scanned root.
+pub fn state_dir(root: &str) -> PathBuf {
+    Path::new(root).join(SESSION_DIR)
+}
+
+pub fn session_path(root: &str) -> PathBuf {
+    Path::new(root).join(SESSION_DIR).join(SESSION_FILE)
+}
+
+/// Where the most recently confirmed selection is kept; it uses the same format as the session.
+pub fn last_selection_path(root: &str) -> PathBuf {
+    Path::new(root).join(SESSION_DIR).join(LAST_SELECTION_FILE)
+}
+
+/// Loads an in-progress selection left behind by an interrupted run. A corrupt session is
+/// reported and ignored rather than failing the run.
+pub fn read_session(path: &Path) -> anyhow::Result<Option<SavedSession>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    debug!("Reading selection session: {}", path.display());
+    let contents = fs::read_to_string(path)?;
+    match serde_json::from_str(&contents) {
+        Ok(session) => Ok(Some(session)),
+        Err(e) => {
+            warn!("Ignoring invalid session {}: {}", path.display(), e);
+            Ok(None)
+        }
+    }
+}
+
+pub fn write_session(path: &Path, session: &SavedSession) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, serde_json::to_string_pretty(session)?)?;
+    debug!(
+        "Saved selection session with {} files",
+        session.selected.len()
+    );
+    Ok(())
+}
+
+pub fn clear_session(path: &Path) -> anyhow::Result<()> {
+    if path.exists() {
+        debug!("Removing selection session: {}", path.display());
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_session_written_mid_selection_is_loaded() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = session_path(&temp_dir.path().to_string_lossy());
+        assert!(read_session(&path).unwrap().is_none());
+
+        let session = SavedSession {
+            selected: vec![PathBuf::from("src/main.rs"), PathBuf::from("src/lib.rs")],
+        };
+        write_session(&path, &session).unwrap();
+
+        let loaded = read_session(&path).unwrap().unwrap();
+        assert_eq!(loaded.selected, session.selected);
+
+        clear_session(&path).unwrap();
+        assert!(read_session(&path).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_invalid_session_is_ignored() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = session_path(&temp_dir.path().to_string_lossy());
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, "{ not json").unwrap();
+
+        assert!(read_session(&path).unwrap().is_none());
+    }
+}
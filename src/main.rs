@@ -4,7 +4,8 @@ mod domain;
 mod infra;
 
 use cli::commands::run;
+use std::process::ExitCode;
 
-fn main() -> anyhow::Result<()> {
+fn main() -> anyhow::Result<ExitCode> {
     run()
 }